@@ -5,16 +5,56 @@ use std::{
         Write,
         ErrorKind,
     },
+    time::{Duration, Instant},
+    collections::HashMap,
     thread,
 };
+use rustls::{ClientConnection, ServerName, StreamOwned};
+use socket2::{SockRef, TcpKeepalive};
 use super::{
     error::{RorError,Result},
+    store::cdc::ChangeEvent,
     user::user_error::UserError,
     request::*,
+    tls::TlsOptions,
 };
 
+// Idle time before the OS starts probing whether the server is still there.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+// How often `operate` follows a Redirect before it returns it, and how long it waits
+// for an election when the cluster has no leader.
+const MAX_REDIRECTS: u32 = 10;
+const ELECTION_WAIT: Duration = Duration::from_millis(500);
+// Requests `pipeline` sends ahead of their replies, well below the requests the server
+// queues for a connection.
+const MAX_IN_FLIGHT: usize = 32;
+
 pub struct Client {
-    stream: TcpStream,
+    stream: Transport,
+    next_id: u64,
+    version: u16,
+    capabilities: u32,
+    // The largest frame the server accepts, and the largest reply this client reads.
+    server_max_frame_size: usize,
+    max_frame_size: usize,
+    // What the client logged in with, to log in at the leader of a Raft cluster again.
+    login: Login,
+    timeout: Option<Duration>,
+    follow_redirects: bool,
+}
+
+#[derive(Clone)]
+struct Login {
+    user_name: String,
+    password: String,
+    db_path: String,
+    tls: Option<TlsOptions>,
+}
+
+// The socket to the server, wrapped in a rustls session on TLS connections.
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
 }
 
 impl Client {
@@ -24,65 +64,415 @@ impl Client {
         user_name: String, 
         password: String, 
         db_path: String
+    ) -> Result<Self> {
+        Self::open(ip, port, user_name, password, db_path, None)
+    }
+
+    // Like `connect`, but over TLS. The server certificate must be signed by the CA
+    // in `tls` and be issued for `ip`, which may be a host name or an IP address.
+    pub fn connect_tls(
+        ip: String,
+        port: String,
+        user_name: String,
+        password: String,
+        db_path: String,
+        tls: &TlsOptions,
+    ) -> Result<Self> {
+        Self::open(ip, port, user_name, password, db_path, Some(tls))
+    }
+
+    fn open(
+        ip: String,
+        port: String,
+        user_name: String,
+        password: String,
+        db_path: String,
+        tls: Option<&TlsOptions>,
     ) -> Result<Self> {
         let address = format!("{0}:{1}", &ip, &port);
-        let mut stream = match TcpStream::connect(&address) {
+        let socket = match TcpStream::connect(&address) {
             Ok(s) => s,
             Err(e) => return Err(RorError::ConnectFailed(e)),
         };
+        let keepalive = TcpKeepalive::new().with_time(KEEPALIVE_TIME);
+        SockRef::from(&socket).set_tcp_keepalive(&keepalive)?;
+
+        let mut capabilities = CAPABILITIES;
+        let mut stream = match tls {
+            Some(tls) => {
+                let server_name = match ServerName::try_from(ip.as_str()) {
+                    Ok(name) => name,
+                    Err(_) => return Err(RorError::TlsConfigError(format!("'{}' is not a valid server name", ip))),
+                };
+                let session = ClientConnection::new(tls.client_config()?, server_name)?;
+                capabilities |= CAP_TLS;
+                Transport::Tls(Box::new(StreamOwned::new(session, socket)))
+            }
+            None => Transport::Plain(socket),
+        };
 
+        let login = Login {
+            user_name: user_name.clone(),
+            password: password.clone(),
+            db_path: db_path.clone(),
+            tls: tls.cloned(),
+        };
         let (buf,_) = Message::new(ConnectRequest {
+            version: PROTOCOL_VERSION,
+            capabilities,
             db_path,
             user_name: user_name.clone(),
             password }
         ).as_bytes()?;
-        stream.write(&buf.as_slice())?;
-
-        let mut size_buffer = [0 as u8; USIZE_SIZE];
-        stream.read_exact(&mut size_buffer)?;
-        let reply_size = usize::from_be_bytes(size_buffer);
-        let mut reply_buffer = vec![0; reply_size];
-        stream.read_exact(&mut reply_buffer)?;
+        stream.write_all(buf.as_slice())?;
 
-        let result: ConnectReply = bincode::deserialize(&reply_buffer)?;
+        let reply_buffer = read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE as usize)?;
+        let result: ConnectReply = Message::from_frame(&reply_buffer)?.message;
         match result {
-            ConnectReply::Success => return Ok(Client {stream}),
-            ConnectReply::Error(ConnectError::UserNotFound) => return Err(RorError::UserError(UserError::UserNotFound(user_name))),
-            ConnectReply::Error(ConnectError::PasswordError) => return Err(RorError::UserError(UserError::WrongPassWord)),
-            ConnectReply::Error(ConnectError::OpenFileError) => return Err(RorError::OpenFileFailed),
-            ConnectReply::Error(ConnectError::RequestError) => return Err(RorError::RequestError),
-            ConnectReply::Error(ConnectError::PathError) => return Err(RorError::PathError),
-            ConnectReply::Error(ConnectError::ServerError) => return Err(RorError::ServerError),
+            ConnectReply::Success { version, capabilities, max_frame_size } => {
+                Ok(Client {
+                    stream,
+                    next_id: 1,
+                    version,
+                    capabilities,
+                    server_max_frame_size: max_frame_size as usize,
+                    max_frame_size: DEFAULT_MAX_FRAME_SIZE as usize,
+                    login,
+                    timeout: None,
+                    follow_redirects: true,
+                })
+            },
+            ConnectReply::Error(ConnectError::UnsupportedVersion { min, max }) => {
+                Err(RorError::UnsupportedVersion(PROTOCOL_VERSION, min, max))
+            },
+            ConnectReply::Error(ConnectError::UserNotFound) => Err(RorError::UserError(UserError::UserNotFound(user_name))),
+            ConnectReply::Error(ConnectError::PasswordError) => Err(RorError::UserError(UserError::WrongPassWord)),
+            ConnectReply::Error(ConnectError::OpenFileError) => Err(RorError::OpenFileFailed),
+            ConnectReply::Error(ConnectError::RequestError) => Err(RorError::RequestError),
+            ConnectReply::Error(ConnectError::PathError) => Err(RorError::PathError),
+            ConnectReply::Error(ConnectError::ServerError) => Err(RorError::ServerError),
+            ConnectReply::Error(ConnectError::TooManyConnections) => Err(RorError::TooManyConnections),
         }
     }
     
+    // The protocol version and the capability flags agreed on with the server.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    // Replies larger than `size` bytes fail with RorError::FrameTooLarge.
+    pub fn set_max_frame_size(&mut self, size: usize) {
+        self.max_frame_size = size;
+    }
+
+    // A follower of a Raft cluster answers writes with Redirect. Unless that is turned
+    // off with `set_follow_redirects`, the client then logs in at the leader and sends
+    // the request again. While the cluster has no leader, or the one it named cannot be
+    // reached, the client waits for an election and asks the same server again. A write
+    // the leader lost the lead over is not sent again, since the next leader may apply
+    // it anyway: it fails with RorError::OutcomeUnknown, after the client has logged in
+    // at the next leader if it is known.
     pub fn operate(&mut self, request: OperateRequest) -> Result<OperateResult> {
-        let body = Message::new(request.clone());
-        let (buf,_) = body.as_bytes()?;
+        let mut redirects = 0;
+        loop {
+            let leader = match self.operate_once(request.clone())? {
+                OperateResult::Redirect { leader } if self.follow_redirects && redirects < MAX_REDIRECTS => leader,
+                OperateResult::Uncommitted { leader } if self.follow_redirects => {
+                    if let Some(address) = leader {
+                        let _ = self.reconnect(&address);
+                    }
+                    return Err(RorError::OutcomeUnknown(request));
+                }
+                OperateResult::Success => {
+                    if let OperateRequest::Open { path } = &request {
+                        self.login.db_path = path.clone();
+                    }
+                    return Ok(OperateResult::Success);
+                }
+                result => return Ok(result),
+            };
+            redirects += 1;
+            match leader {
+                Some(address) if self.reconnect(&address).is_ok() => (),
+                _ => thread::sleep(ELECTION_WAIT),
+            }
+        }
+    }
+
+    // Whether `operate` follows redirects to the leader of a Raft cluster, on by default.
+    pub fn set_follow_redirects(&mut self, follow: bool) {
+        self.follow_redirects = follow;
+    }
 
-        if let Err(_) = self.stream.write(&buf) {
-            return Err(RorError::ConnectionLost(request));
+    fn operate_once(&mut self, request: OperateRequest) -> Result<OperateResult> {
+        let id = self.send(request)?;
+        let reply = self.read_reply()?;
+        if reply.id != id {
+            return Err(RorError::UnexpectedReply(reply.id));
         }
-        thread::sleep(std::time::Duration::from_millis(50));
+        Ok(reply.message)
+    }
+
+    // Logs in at `address` like this client did, with the datafile it has open now, and
+    // replaces the connection.
+    fn reconnect(&mut self, address: &str) -> Result<()> {
+        let (ip, port) = match address.rsplit_once(':') {
+            Some(a) => a,
+            None => return Err(RorError::RequestError),
+        };
+        let login = self.login.clone();
+        let mut client = Self::open(ip.to_string(), port.to_string(), login.user_name, login.password, login.db_path, login.tls.as_ref())?;
+        client.set_timeout(self.timeout)?;
+        client.max_frame_size = self.max_frame_size;
+        client.follow_redirects = self.follow_redirects;
+        *self = client;
+        Ok(())
+    }
+
+    // Sends the requests without waiting for each reply, so they cost about one round
+    // trip instead of one each. At most MAX_IN_FLIGHT requests, and half a frame of the
+    // server's size in bytes, are sent ahead of their replies; the server reads no more
+    // than that from a connection before it answers. The results are returned in the
+    // order of the requests. Subscribe cannot be pipelined, a Watch holds back the
+    // replies of the requests after it until it fires, and redirects are returned
+    // rather than followed.
+    pub fn pipeline(&mut self, requests: Vec<OperateRequest>) -> Result<Vec<OperateResult>> {
+        if self.capabilities & CAP_PIPELINING == 0 {
+            return requests.into_iter().map(|r| self.operate_once(r)).collect();
+        }
+        let mut frames = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let id = self.take_id();
+            let (frame, size) = Message::with_id(id, request.clone()).as_bytes()?;
+            if size > self.server_max_frame_size {
+                return Err(RorError::FrameTooLarge(size, self.server_max_frame_size));
+            }
+            frames.push((id, frame));
+        }
+
+        let max_bytes = self.server_max_frame_size / 2;
+        let mut slots = HashMap::new();
+        let mut in_flight = 0;
+        let mut results: Vec<Option<OperateResult>> = (0..frames.len()).map(|_| None).collect();
+        let mut frames = frames.into_iter().enumerate().peekable();
+        for _ in 0..results.len() {
+            let mut buf = Vec::new();
+            while let Some((_, (_, frame))) = frames.peek() {
+                let full = slots.len() >= MAX_IN_FLIGHT || in_flight + frame.len() > max_bytes;
+                if full && !slots.is_empty() {
+                    break;
+                }
+                let (index, (id, frame)) = frames.next().unwrap();
+                in_flight += frame.len();
+                buf.extend_from_slice(&frame);
+                slots.insert(id, (index, frame.len()));
+            }
+            if !buf.is_empty() {
+                match self.stream.write_all(&buf) {
+                    Ok(()) => (),
+                    Err(e) if is_timeout(&e) => return Err(RorError::TimedOut),
+                    Err(_) => return Err(RorError::ConnectionLost(requests[results.iter().position(Option::is_none).unwrap()].clone())),
+                }
+            }
 
-        let mut size_buffer = [0 as u8; USIZE_SIZE];
-        match self.stream.read_exact(&mut size_buffer) {
-            Ok(_) => (),
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    return Err(RorError::AbnormalConnection);
+            let reply = self.read_reply()?;
+            match slots.remove(&reply.id) {
+                Some((index, size)) => {
+                    in_flight -= size;
+                    results[index] = Some(reply.message);
                 }
-                return Err(RorError::IOError(e));
+                None => return Err(RorError::UnexpectedReply(reply.id)),
             }
         }
-        let reply_size = usize::from_be_bytes(size_buffer);
-        let mut reply_buffer = vec![0; reply_size];
-        self.stream.read_exact(&mut reply_buffer)?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    // Round trip time of a Ping, to check that the server is still answering.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        match self.operate(OperateRequest::Ping)? {
+            OperateResult::Pong => Ok(start.elapsed()),
+            _ => Err(RorError::IncompleteData),
+        }
+    }
+
+    // Fails reads and writes that take longer than `timeout` with RorError::TimedOut.
+    // None waits forever, which is the default. A connection that timed out may have
+    // a reply half read and should be dropped.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let socket = self.stream.socket();
+        socket.set_read_timeout(timeout)?;
+        socket.set_write_timeout(timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.stream, Transport::Tls(_))
+    }
+
+    // Starts a change stream. Events are read with `next_change`; after `unsubscribe`
+    // keep calling `next_change` until it returns None. Save `next_offset` of the last
+    // event to resume from the same place after reconnecting.
+    pub fn subscribe(&mut self, from_offset: Option<u64>, prefix: Option<String>) -> Result<()> {
+        if self.capabilities & CAP_STREAMING == 0 {
+            return Err(RorError::SubscribeFailed);
+        }
+        self.send(OperateRequest::Subscribe { from_offset, prefix })?;
+        Ok(())
+    }
+
+    pub fn next_change(&mut self) -> Result<Option<ChangeEvent>> {
+        match self.read_reply()?.message {
+            OperateResult::Change(event) => Ok(Some(event)),
+            OperateResult::Success => Ok(None),
+            _ => Err(RorError::SubscribeFailed),
+        }
+    }
+
+    pub fn unsubscribe(&mut self) -> Result<()> {
+        self.send(OperateRequest::Unsubscribe)?;
+        Ok(())
+    }
+
+    // Starts receiving the messages published to `channels` and to the channels matching
+    // one of the glob `patterns`. Messages are read with `next_message`; after
+    // `unsubscribe` keep calling `next_message` until it returns None.
+    pub fn subscribe_channels(&mut self, channels: Vec<String>, patterns: Vec<String>) -> Result<()> {
+        if self.capabilities & CAP_STREAMING == 0 {
+            return Err(RorError::SubscribeFailed);
+        }
+        self.send(OperateRequest::SubscribeChannels { channels, patterns })?;
+        Ok(())
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<ChannelMessage>> {
+        match self.read_reply()?.message {
+            OperateResult::Message(message) => Ok(Some(message)),
+            OperateResult::Success => Ok(None),
+            _ => Err(RorError::SubscribeFailed),
+        }
+    }
+
+    // Starts a replication stream of the datafile. Read it with `next_replicated`: the
+    // whole log arrives as Change events, then a Position marks that it has been
+    // replayed, and the live changes and a Position every second follow.
+    pub fn replicate(&mut self) -> Result<()> {
+        if self.capabilities & CAP_STREAMING == 0 {
+            return Err(RorError::SubscribeFailed);
+        }
+        self.send(OperateRequest::Replicate)?;
+        Ok(())
+    }
+
+    pub fn next_replicated(&mut self) -> Result<OperateResult> {
+        match self.read_reply()?.message {
+            result @ (OperateResult::Change(_) | OperateResult::Position { .. }) => Ok(result),
+            _ => Err(RorError::SubscribeFailed),
+        }
+    }
+
+    fn take_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn send(&mut self, request: OperateRequest) -> Result<u64> {
+        let id = self.take_id();
+        let body = Message::with_id(id, request.clone());
+        let (buf, size) = body.as_bytes()?;
+        if size > self.server_max_frame_size {
+            return Err(RorError::FrameTooLarge(size, self.server_max_frame_size));
+        }
+
+        match self.stream.write_all(&buf) {
+            Ok(()) => (),
+            Err(e) if is_timeout(&e) => return Err(RorError::TimedOut),
+            Err(_) => return Err(RorError::ConnectionLost(request)),
+        }
+        Ok(id)
+    }
 
-        let reply: OperateResult = match bincode::deserialize(&reply_buffer) {
+    fn read_reply(&mut self) -> Result<Message<OperateResult>> {
+        let reply_buffer = read_frame(&mut self.stream, self.max_frame_size)?;
+
+        let reply = match Message::from_frame(&reply_buffer) {
             Ok(r) => r,
             Err(_) => return Err(RorError::IncompleteData),
         };
-        return Ok(reply);
+        if let OperateResult::Shutdown = reply.message {
+            return Err(RorError::ServerShutdown);
+        }
+        Ok(reply)
+    }
+}
+
+impl Transport {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Transport::Plain(s) => s,
+            Transport::Tls(s) => &s.sock,
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.read(buf),
+            Transport::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.write(buf),
+            Transport::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Transport::Plain(s) => s.flush(),
+            Transport::Tls(s) => s.flush(),
+        }
+    }
+}
+
+// Reads one frame and returns it without its magic bytes and length. The length is
+// checked before anything is allocated for the body.
+pub(crate) fn read_frame<R: Read>(stream: &mut R, max_frame_size: usize) -> Result<Vec<u8>> {
+    let mut head_buffer = [0_u8; HEAD_SIZE];
+    read_exact(stream, &mut head_buffer)?;
+    let size = match frame_len(&head_buffer) {
+        Some(size) => size,
+        None => return Err(RorError::InvalidFrame),
+    };
+    if size > max_frame_size {
+        return Err(RorError::FrameTooLarge(size, max_frame_size));
     }
-}
\ No newline at end of file
+    let mut frame = vec![0; size];
+    read_exact(stream, &mut frame)?;
+    Ok(frame)
+}
+
+fn read_exact<R: Read>(stream: &mut R, buf: &mut [u8]) -> Result<()> {
+    match stream.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(RorError::AbnormalConnection),
+        Err(e) if is_timeout(&e) => Err(RorError::TimedOut),
+        Err(e) => Err(RorError::IOError(e)),
+    }
+}
+
+// Timed out socket operations report WouldBlock on Unix and TimedOut on Windows.
+fn is_timeout(e: &std::io::Error) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+}
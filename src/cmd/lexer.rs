@@ -29,7 +29,7 @@ pub fn lex(text: &str) -> Vec<Token> {
                 let mut symbol = token.to_string();
 
                 if token.has_next(&mut chars) {
-                    symbol.push(chars.next().take().unwrap());
+                    symbol.push(chars.next().unwrap());
                 } else {
                     chars.next();
                 }
@@ -39,7 +39,13 @@ pub fn lex(text: &str) -> Vec<Token> {
                 }
             }
             _ => {
-                let text = collect_until(&mut chars, |c| !c.is_alphanumeric() && c != '_' );
+                let mut text = collect_until(&mut chars, |c| !c.is_alphanumeric() && c != '_' );
+                // A character that is neither a symbol nor part of a word, such as ':'.
+                if text.is_empty() {
+                    if let Some(c) = chars.next() {
+                        text.push(c);
+                    }
+                }
                 if let Some(command) = text.as_command() {
                     tokens.push(Token::Command(command));
                 } else if let Some(datatype) = text.as_datatype() {
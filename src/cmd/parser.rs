@@ -26,7 +26,7 @@ impl Parser {
     pub fn parse(&mut self, s: &str) -> Result<Statement> {
         let tokens = lex(s);
         self.iter = tokens.clone().into_iter().peekable();
-        let statement = match tokens.get(0) {
+        let statement = match tokens.first() {
             Some(Token::Command(Command::Open)) => self.parse_open()?,
             Some(Token::Command(Command::Add)) => self.parse_add()?,
             Some(Token::Command(Command::Delete)) => self.parse_delete()?,
@@ -34,7 +34,22 @@ impl Parser {
             Some(Token::Command(Command::TypeOf)) => self.parse_typeof()?,
             Some(Token::Command(Command::User)) => self.parse_user()?,
             Some(Token::Command(Command::List)) => self.parse_list()?,
+            Some(Token::Command(Command::Dump)) => self.parse_dump()?,
+            Some(Token::Command(Command::Load)) => self.parse_load()?,
+            Some(Token::Command(Command::Backup)) => self.parse_backup()?,
+            Some(Token::Command(Command::Restore)) => self.parse_restore()?,
+            Some(Token::Command(Command::Watch)) => self.parse_watch()?,
+            Some(Token::Command(Command::Create)) => self.parse_create()?,
+            Some(Token::Command(Command::Drop)) => self.parse_drop()?,
             Some(Token::Command(Command::Compact)) => Statement::Compact,
+            Some(Token::Command(Command::Ping)) => Statement::Ping,
+            Some(Token::Command(Command::Shutdown)) => Statement::Shutdown,
+            Some(Token::Command(Command::Clients)) => self.parse_clients()?,
+            Some(Token::Command(Command::Server)) => self.parse_server()?,
+            Some(Token::Command(Command::Cluster)) => self.parse_cluster()?,
+            Some(Token::Command(Command::Shard)) => self.parse_shard()?,
+            Some(Token::Command(Command::Publish)) => self.parse_publish()?,
+            Some(Token::Command(Command::Subscribe)) | Some(Token::Command(Command::PSubscribe)) => self.parse_subscribe()?,
             Some(Token::Command(Command::Quit)) => Statement::Quit,
             Some(t) => return Err(CmdError::UnexpectedToken(t.clone())),
             None => return Err(CmdError::MissingStatement),
@@ -55,6 +70,44 @@ impl Parser {
         Ok(Statement::Add { key, value, datatype })
     }
 
+    fn parse_publish(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Publish))?;
+        let datatype = self.parse_datatype()?;
+        let channel = self.parse_key()?;
+
+        let value = match datatype {
+            ValueType::Array(_) => self.parse_array()?,
+            _ => self.parse_value()?
+        };
+
+        Ok(Statement::Publish { channel, value, datatype })
+    }
+
+    // Channel names and patterns with characters other than letters, digits and '_'
+    // must be quoted.
+    fn parse_subscribe(&mut self) -> Result<Statement> {
+        let by_pattern = match self.iter.next() {
+            Some(Token::Command(Command::Subscribe)) => false,
+            Some(Token::Command(Command::PSubscribe)) => true,
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => return Err(CmdError::MissingStatement),
+        };
+        let mut names = Vec::new();
+        for token in self.iter.by_ref() {
+            match token {
+                Token::Identifier(s) | Token::Number(s) => names.push(s),
+                t => return Err(CmdError::UnexpectedToken(t)),
+            }
+        }
+        if names.is_empty() {
+            return Err(CmdError::ParameterError("subscribe".to_string()));
+        }
+        match by_pattern {
+            true => Ok(Statement::Subscribe { channels: Vec::new(), patterns: names }),
+            false => Ok(Statement::Subscribe { channels: names, patterns: Vec::new() }),
+        }
+    }
+
     fn parse_datatype(&mut self) -> Result<ValueType> {
         let datatype = match self.iter.peek() {
             Some(Token::DataType(DataType::Null)) => ValueType::Null,
@@ -124,9 +177,10 @@ impl Parser {
             Some(Token::Command(Command::Delete)) => {
                 self.iter.next();
                 if let ValueP::Identifier(s) = self.parse_value()? {
-                    UserCmd::Delete { name: s };
+                    UserCmd::Delete { name: s }
+                } else {
+                    return Err(CmdError::MissingArg);
                 }
-                return Err(CmdError::MissingArg);
             }
             Some(t) => return Err(CmdError::UnexpectedToken(t.clone())),
             None => return Err(CmdError::MissingSubCmd),
@@ -140,20 +194,155 @@ impl Parser {
         let arg = match self.iter.next() {
             Some(Token::Arg(Arg::Values)) => List::Values,
             Some(Token::Arg(Arg::Entries)) => List::Entries,
+            Some(Token::Arg(Arg::Databases)) => List::Databases,
             Some(t) => return Err(CmdError::UnexpectedToken(t.clone())),
             None => return Err(CmdError::MissingArg),
         };
         Ok(Statement::List{list: arg})
     }
 
+    fn parse_clients(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Clients))?;
+        let cmd = match self.iter.next() {
+            Some(Token::Command(Command::List)) => ClientsCmd::List,
+            Some(Token::Command(Command::Kill)) => {
+                let address = self.parse_path()?;
+                ClientsCmd::Kill { address }
+            },
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => return Err(CmdError::MissingSubCmd),
+        };
+        Ok(Statement::Clients { cmd })
+    }
+
+    fn parse_server(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Server))?;
+        match self.iter.next() {
+            Some(Token::Arg(Arg::Info)) => Ok(Statement::ServerInfo),
+            Some(t) => Err(CmdError::UnexpectedToken(t)),
+            None => Err(CmdError::MissingSubCmd),
+        }
+    }
+
+    fn parse_cluster(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Cluster))?;
+        let cmd = match self.iter.next() {
+            Some(Token::Command(Command::Add)) => ClusterCmd::Add { address: self.parse_path()? },
+            Some(Token::Command(Command::Delete)) => ClusterCmd::Delete { address: self.parse_path()? },
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => return Err(CmdError::MissingSubCmd),
+        };
+        Ok(Statement::Cluster { cmd })
+    }
+
+    fn parse_shard(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Shard))?;
+        let cmd = match self.iter.next() {
+            Some(Token::Command(Command::Add)) => ShardCmd::Add { address: self.parse_path()? },
+            Some(Token::Command(Command::Delete)) => ShardCmd::Delete { address: self.parse_path()? },
+            Some(Token::Command(Command::List)) => ShardCmd::List,
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => return Err(CmdError::MissingSubCmd),
+        };
+        Ok(Statement::Shard { cmd })
+    }
+
     fn parse_open(&mut self) -> Result<Statement> {
         match_token(&self.iter.next(), Token::Command(Command::Open))?;
-        if let Some(ValueP::Identifier(s)) = self.iter.next().to_value() {
-            return Ok(Statement::Open { 
-                file: s
-            });
+        let file = self.parse_path()?;
+        Ok(Statement::Open { file })
+    }
+
+    fn parse_create(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Create))?;
+        match self.iter.next() {
+            Some(Token::Arg(Arg::Database)) => (),
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => return Err(CmdError::MissingArg),
         }
-        Err(CmdError::MissingPath)
+        let path = self.parse_path()?;
+        Ok(Statement::CreateDatabase { path })
+    }
+
+    fn parse_drop(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Drop))?;
+        match self.iter.next() {
+            Some(Token::Arg(Arg::Database)) => (),
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => return Err(CmdError::MissingArg),
+        }
+        let path = self.parse_path()?;
+        Ok(Statement::DropDatabase { path })
+    }
+
+    fn parse_dump(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Dump))?;
+        let path = self.parse_path()?;
+        Ok(Statement::Dump { path })
+    }
+
+    fn parse_load(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Load))?;
+        let path = self.parse_path()?;
+        Ok(Statement::Load { path })
+    }
+
+    fn parse_watch(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Watch))?;
+        let key = self.parse_key()?;
+        let timeout = match self.iter.next() {
+            Some(Token::Number(n)) => match n.parse::<u64>() {
+                Ok(t) => t,
+                Err(_) => return Err(CmdError::ParameterError("watch".to_string())),
+            },
+            Some(t) => return Err(CmdError::UnexpectedToken(t)),
+            None => 0,
+        };
+        Ok(Statement::Watch { key, timeout })
+    }
+
+    fn parse_backup(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Backup))?;
+        let name = self.parse_path()?;
+        Ok(Statement::Backup { name })
+    }
+
+    fn parse_restore(&mut self) -> Result<Statement> {
+        match_token(&self.iter.next(), Token::Command(Command::Restore))?;
+        let mut args = self.parse_paths().into_iter();
+        match (args.next(), args.next(), args.next(), args.next()) {
+            (Some(snapshot), Some(file), Some(dest), None) => Ok(Statement::Restore { snapshot, file, dest }),
+            _ => Err(CmdError::ParameterError("restore".to_string())),
+        }
+    }
+
+    // A path is lexed as several tokens (e.g. "backup", ".", "csv"), so join the rest of the input.
+    fn parse_path(&mut self) -> Result<String> {
+        let mut path = String::new();
+        for token in self.iter.by_ref() {
+            path.push_str(&token.to_string());
+        }
+        if path.is_empty() {
+            return Err(CmdError::MissingPath);
+        }
+        Ok(path)
+    }
+
+    // Like parse_path, for the rest of the input holding several paths. The spaces between
+    // them are not lexed, so a path ends where a word follows a word without a symbol in
+    // between, as in "snap" "default" "." "data".
+    fn parse_paths(&mut self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        let mut after_word = false;
+        for token in self.iter.by_ref() {
+            let word = !matches!(token, Token::Symbol(_));
+            match paths.last_mut() {
+                Some(path) if !(word && after_word) => path.push_str(&token.to_string()),
+                _ => paths.push(token.to_string()),
+            }
+            after_word = word;
+        }
+        paths
     }
 
     fn parse_str_args(&mut self) -> Result<Vec<String>> {
@@ -220,13 +409,42 @@ impl Parser {
         }
 
         self.iter.next();
-        Ok(ValueP::Array(Box::new(array)))
+        Ok(ValueP::Array(array))
     }
 }
 
 fn match_token(value: &Option<Token>, expect: Token) -> Result<()> {
-    return match value {
+    match value {
         Some(_) => Ok(()),
-        None => return Err(CmdError::MissingToken(expect))
+        None => Err(CmdError::MissingToken(expect))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn restore(input: &str) -> Option<(String, String, String)> {
+        match Parser::new().parse(input) {
+            Ok(Statement::Restore { snapshot, file, dest }) => Some((snapshot, file, dest)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn restore_with_unquoted_paths() {
+        let expected = Some(("snap".to_string(), "default.data".to_string(), "new.data".to_string()));
+        assert_eq!(restore("restore snap default.data new.data"), expected);
+        assert_eq!(restore("restore 'snap' \"default.data\" new.data"), expected);
+        assert_eq!(
+            restore("restore 2024-01-01 dir/old_file.data dir/new.data"),
+            Some(("2024-01-01".to_string(), "dir/old_file.data".to_string(), "dir/new.data".to_string()))
+        );
+    }
+
+    #[test]
+    fn restore_needs_three_paths() {
+        assert_eq!(restore("restore snap default.data"), None);
+        assert_eq!(restore("restore snap default.data new.data extra"), None);
     }
 }
@@ -1,10 +1,5 @@
-use super::token::token::*;
 use std::fmt;
 
-pub trait TokenExt {
-    fn to_value(&self) -> Option<ValueP>;
-}
-
 #[derive(Clone, Debug)]
 pub enum ValueType {
     Null,
@@ -18,17 +13,6 @@ pub enum ValueType {
     Array(Box<ValueType>)
 }
 
-impl TokenExt for Option<Token> {
-    fn to_value(&self) -> Option<ValueP> {
-        match self {
-            Some(Token::Identifier(s)) => Some(ValueP::Identifier(s.clone())),
-            Some(Token::Number(n)) => Some(ValueP::Number(n.clone())),
-            Some(Token::Bool(b)) => Some(ValueP::Bool(*b)),
-            _ => None,
-        }
-    }
-}
-
 #[derive(Clone, Debug)]
 pub enum Statement {
     Open { file: String },
@@ -43,6 +27,35 @@ pub enum Statement {
     TypeOf { key: String },
     List { list: List },
     User { cmd: UserCmd },
+    Dump { path: String },
+    Load { path: String },
+    Backup { name: String },
+    Restore {
+        snapshot: String,
+        file: String,
+        dest: String
+    },
+    Watch {
+        key: String,
+        timeout: u64
+    },
+    CreateDatabase { path: String },
+    DropDatabase { path: String },
+    Ping,
+    Shutdown,
+    Clients { cmd: ClientsCmd },
+    ServerInfo,
+    Cluster { cmd: ClusterCmd },
+    Shard { cmd: ShardCmd },
+    Publish {
+        channel: String,
+        value: ValueP,
+        datatype: ValueType
+    },
+    Subscribe {
+        channels: Vec<String>,
+        patterns: Vec<String>
+    },
     Quit
 }
 
@@ -51,7 +64,7 @@ pub enum ValueP {
     Identifier(String),
     Number(String),
     Bool(bool),
-    Array(Box<Vec<ValueP>>)
+    Array(Vec<ValueP>)
 }
 
 impl ValueP {
@@ -68,7 +81,8 @@ impl ValueP {
 #[derive(Clone, Debug)]
 pub enum List {
     Values,
-    Entries
+    Entries,
+    Databases
 }
 
 #[derive(Clone, Debug)]
@@ -77,6 +91,27 @@ pub enum UserCmd {
     Delete { name: String }
 }
 
+#[derive(Clone, Debug)]
+pub enum ClientsCmd {
+    List,
+    Kill { address: String }
+}
+
+// Membership changes of a Raft cluster, by the Raft address of the server.
+#[derive(Clone, Debug)]
+pub enum ClusterCmd {
+    Add { address: String },
+    Delete { address: String }
+}
+
+// Shard changes of a proxy, by the address of the server.
+#[derive(Clone, Debug)]
+pub enum ShardCmd {
+    Add { address: String },
+    Delete { address: String },
+    List
+}
+
 #[derive(Clone, Debug)]
 pub struct UserInfo {
     pub name: String,
@@ -104,7 +139,7 @@ impl fmt::Display for ValueP {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ValueP::Identifier(s) | ValueP::Number(s) => write!(f, "{}", s),
-            ValueP::Bool(b) => write!(f, "{}", b.to_string()),
+            ValueP::Bool(b) => write!(f, "{}", b),
             ValueP::Array(a) => write!(f, "{:?}", a),
         }
     }
@@ -3,7 +3,10 @@ use std::fmt;
 #[derive(Clone, Debug)]
 pub enum Arg {
     Values,
-    Entries
+    Entries,
+    Databases,
+    Database,
+    Info
 }
 
 impl fmt::Display for Arg {
@@ -11,6 +14,9 @@ impl fmt::Display for Arg {
         match self {
             Arg::Values => write!(f, "values"),
             Arg::Entries => write!(f, "entries"),
+            Arg::Databases => write!(f, "databases"),
+            Arg::Database => write!(f, "database"),
+            Arg::Info => write!(f, "info"),
         }
     }
 }
\ No newline at end of file
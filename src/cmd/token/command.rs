@@ -11,7 +11,23 @@ pub enum Command {
     List,
     User,
     Quit,
-    Create
+    Create,
+    Dump,
+    Load,
+    Backup,
+    Restore,
+    Watch,
+    Ping,
+    Drop,
+    Shutdown,
+    Clients,
+    Kill,
+    Server,
+    Cluster,
+    Shard,
+    Publish,
+    Subscribe,
+    PSubscribe
 }
 
 impl fmt::Display for Command {
@@ -27,6 +43,22 @@ impl fmt::Display for Command {
             Command::User => write!(f, "user"),
             Command::Quit => write!(f, "quit"),
             Command::Create => write!(f, "create"),
+            Command::Dump => write!(f, "dump"),
+            Command::Load => write!(f, "load"),
+            Command::Backup => write!(f, "backup"),
+            Command::Restore => write!(f, "restore"),
+            Command::Watch => write!(f, "watch"),
+            Command::Ping => write!(f, "ping"),
+            Command::Drop => write!(f, "drop"),
+            Command::Shutdown => write!(f, "shutdown"),
+            Command::Clients => write!(f, "clients"),
+            Command::Kill => write!(f, "kill"),
+            Command::Server => write!(f, "server"),
+            Command::Cluster => write!(f, "cluster"),
+            Command::Shard => write!(f, "shard"),
+            Command::Publish => write!(f, "publish"),
+            Command::Subscribe => write!(f, "subscribe"),
+            Command::PSubscribe => write!(f, "psubscribe"),
         }
     }
 }
@@ -2,4 +2,5 @@ pub mod command;
 pub mod datatype;
 pub mod symbol;
 pub mod arg;
+#[allow(clippy::module_inception)]
 pub mod token;
\ No newline at end of file
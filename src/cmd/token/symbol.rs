@@ -24,30 +24,30 @@ pub enum Symbol {
 
 impl Symbol {
     pub fn is_operator(&self) -> bool {
-        match self {
+        matches!(
+            self,
             Self::Comma
-            | Self::Dot
-            | Self::Asterisk
-            | Self::Plus
-            | Self::Minus
-            | Self::Slash
-            | Self::Percent
-            | Self::LeftParen
-            | Self::RightParen => true,
-            _ => false,
-        }
+                | Self::Dot
+                | Self::Asterisk
+                | Self::Plus
+                | Self::Minus
+                | Self::Slash
+                | Self::Percent
+                | Self::LeftParen
+                | Self::RightParen
+        )
     }
 
     pub fn is_comparator(&self) -> bool {
-        match self {
+        matches!(
+            self,
             Self::Equal
-            | Self::NotEqual
-            | Self::LessThan
-            | Self::GreaterThan
-            | Self::LessThanOrEqual
-            | Self::GreaterThanOrEqual => true,
-            _ => false,
-        }
+                | Self::NotEqual
+                | Self::LessThan
+                | Self::GreaterThan
+                | Self::LessThanOrEqual
+                | Self::GreaterThanOrEqual
+        )
     }
 }
 
@@ -109,12 +109,12 @@ impl SymbolExtChar for char {
         match self {
             '!' | '<' | '>' => {
                 chars.next();
-                if chars.peek().map_or(false, |c| *c == '=') {
+                if chars.peek().is_some_and(|c| *c == '=') {
                     return true;
                 }
-                return false;
+                false
             }
-            _ => return false,
+            _ => false,
         }
     }
 }
\ No newline at end of file
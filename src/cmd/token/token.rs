@@ -53,6 +53,22 @@ impl StringExt for String {
             "user" => Some(Command::User),
             "quit" => Some(Command::Quit),
             "create" => Some(Command::Create),
+            "dump" => Some(Command::Dump),
+            "load" => Some(Command::Load),
+            "backup" => Some(Command::Backup),
+            "restore" => Some(Command::Restore),
+            "watch" => Some(Command::Watch),
+            "ping" => Some(Command::Ping),
+            "drop" => Some(Command::Drop),
+            "shutdown" => Some(Command::Shutdown),
+            "clients" => Some(Command::Clients),
+            "kill" => Some(Command::Kill),
+            "server" => Some(Command::Server),
+            "cluster" => Some(Command::Cluster),
+            "shard" => Some(Command::Shard),
+            "publish" => Some(Command::Publish),
+            "subscribe" => Some(Command::Subscribe),
+            "psubscribe" => Some(Command::PSubscribe),
             _ => None
         }
     }
@@ -85,6 +101,9 @@ impl StringExt for String {
         match self.as_str() {
             "values" => Some(Arg::Values),
             "entries" => Some(Arg::Entries),
+            "databases" => Some(Arg::Databases),
+            "database" => Some(Arg::Database),
+            "info" => Some(Arg::Info),
             _ => None
         }
     }
@@ -105,7 +124,7 @@ pub trait SqlCharExt {
 
 impl SqlCharExt for char {
     fn is_symbol(&self) -> bool {
-        if to_symbol(&self.to_string().as_str()).is_some() {
+        if to_symbol(self.to_string().as_str()).is_some() {
             return true
         }
         false
@@ -0,0 +1,282 @@
+use std::{
+    io::{self, Read, Write, ErrorKind},
+    net::SocketAddr,
+    time::Instant,
+};
+use mio::net::TcpStream;
+use rustls::ServerConnection;
+use super::request::{MAGIC, MAGIC_SIZE, HEAD_SIZE, HANDSHAKE_MAX_FRAME_SIZE, frame_len};
+
+const READ_CHUNK: usize = 4096;
+const TLS_CHUNK: usize = 16 * 1024;
+
+// The byte side of a client connection in the event loop. Reads and writes never
+// block: incoming bytes are buffered until a whole frame has arrived, and outgoing
+// frames are buffered until the socket accepts them. On a TLS connection the bytes
+// pass through the rustls session, which is given the output a chunk at a time.
+pub struct Connection {
+    pub stream: TcpStream,
+    pub address: SocketAddr,
+    tls: Option<ServerConnection>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    // When the unfinished frame in read_buf and the unsent bytes in write_buf started
+    // waiting, used for the read and write deadlines.
+    read_since: Option<Instant>,
+    write_since: Option<Instant>,
+    max_frame_size: usize,
+    pub closed: bool,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, address: SocketAddr, tls: Option<ServerConnection>) -> Self {
+        let tls = tls.map(|mut t| {
+            t.set_buffer_limit(None);
+            t
+        });
+        Connection {
+            stream,
+            address,
+            tls,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            read_since: None,
+            write_since: None,
+            max_frame_size: HANDSHAKE_MAX_FRAME_SIZE as usize,
+            closed: false,
+        }
+    }
+
+    // Reads what the socket has and returns the frames that are now complete. Reading
+    // stops early once `max_frames` frames or `max_bytes` bytes of them are complete,
+    // leaving the rest in the socket. Frames are split off after every chunk, so the
+    // buffer never holds more than one unfinished frame, and an oversized frame is
+    // refused as soon as its head arrives.
+    pub fn read_frames(&mut self, max_frames: usize, max_bytes: usize) -> io::Result<Vec<Vec<u8>>> {
+        if self.tls.is_some() {
+            return self.read_tls_frames(max_frames, max_bytes);
+        }
+        let mut chunk = [0; READ_CHUNK];
+        let mut frames = Vec::new();
+        while !is_full(&frames, max_frames, max_bytes) {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.read_buf.extend_from_slice(&chunk[..n]);
+                    self.split_frames(&mut frames)?;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(frames)
+    }
+
+    fn read_tls_frames(&mut self, max_frames: usize, max_bytes: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        while !is_full(&frames, max_frames, max_bytes) {
+            let plain = match self.read_tls_chunk() {
+                Ok(Some(plain)) => plain,
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.read_buf.extend_from_slice(&plain);
+            self.split_frames(&mut frames)?;
+            if self.closed {
+                break;
+            }
+        }
+        // Send what rustls wants to answer with, such as its handshake messages.
+        self.flush()?;
+        Ok(frames)
+    }
+
+    // Reads one chunk of TLS records and returns the plaintext in it, or None once the
+    // socket has nothing more to read.
+    fn read_tls_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let tls = match &mut self.tls {
+            Some(tls) => tls,
+            None => return Ok(None),
+        };
+        match tls.read_tls(&mut self.stream) {
+            Ok(0) => {
+                self.closed = true;
+                return Ok(None);
+            }
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let state = tls.process_new_packets()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut chunk = [0; READ_CHUNK];
+        let mut plain = Vec::new();
+        loop {
+            match tls.reader().read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => plain.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if state.peer_has_closed() {
+            self.closed = true;
+        }
+        Ok(Some(plain))
+    }
+
+    fn split_frames(&mut self, frames: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        let mut start = 0;
+        while self.read_buf.len() - start >= MAGIC_SIZE {
+            if self.read_buf.len() - start < HEAD_SIZE {
+                if self.read_buf[start..start + MAGIC_SIZE] != MAGIC {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "not an rdb frame"));
+                }
+                break;
+            }
+            let body_size = match frame_len(&self.read_buf[start..start + HEAD_SIZE]) {
+                Some(size) => size,
+                None => return Err(io::Error::new(ErrorKind::InvalidData, "not an rdb frame")),
+            };
+            if body_size > self.max_frame_size {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("a frame of {0} bytes is larger than the limit of {1} bytes", body_size, self.max_frame_size),
+                ));
+            }
+            if self.read_buf.len() - start - HEAD_SIZE < body_size {
+                break;
+            }
+            let body_start = start + HEAD_SIZE;
+            frames.push(self.read_buf[body_start..body_start + body_size].to_vec());
+            start = body_start + body_size;
+        }
+        self.read_buf.drain(..start);
+        if self.read_buf.is_empty() {
+            self.read_since = None;
+        } else if start > 0 || self.read_since.is_none() {
+            self.read_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    pub fn set_max_frame_size(&mut self, size: u32) {
+        self.max_frame_size = size as usize;
+    }
+
+    pub fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.write_buf.extend_from_slice(frame);
+        self.flush()
+    }
+
+    // Writes as much of the buffered output as the socket takes; the rest is written
+    // when the loop sees the socket become writable again.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.tls.is_some() {
+            return self.flush_tls();
+        }
+        let mut written = 0;
+        while written < self.write_buf.len() {
+            match self.stream.write(&self.write_buf[written..]) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.write_buf.drain(..written);
+                    return Err(e);
+                }
+            }
+        }
+        self.write_buf.drain(..written);
+        if self.write_buf.is_empty() {
+            self.write_since = None;
+        } else if written > 0 || self.write_since.is_none() {
+            self.write_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn flush_tls(&mut self) -> io::Result<()> {
+        let tls = match &mut self.tls {
+            Some(tls) => tls,
+            None => return Ok(()),
+        };
+        let mut written = 0;
+        loop {
+            // Output is handed to rustls a chunk at a time once it sent the previous one,
+            // so what the client has not taken yet stays in write_buf, where it counts.
+            if !tls.wants_write() {
+                if self.write_buf.is_empty() {
+                    break;
+                }
+                let n = self.write_buf.len().min(TLS_CHUNK);
+                tls.writer().write_all(&self.write_buf[..n])?;
+                self.write_buf.drain(..n);
+                continue;
+            }
+            match tls.write_tls(&mut self.stream) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if !tls.wants_write() && self.write_buf.is_empty() {
+            self.write_since = None;
+        } else if written > 0 || self.write_since.is_none() {
+            self.write_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    // Bytes of output the client has not taken yet.
+    pub fn unsent(&self) -> usize {
+        self.write_buf.len()
+    }
+
+    // Restarts the read deadline of a partly received frame, for a connection the
+    // server did not read from for a while.
+    pub fn restart_read_deadline(&mut self) {
+        if self.read_since.is_some() {
+            self.read_since = Some(Instant::now());
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    // How long a partly received frame has been waiting for the rest of its bytes.
+    pub fn read_waiting(&self, now: Instant) -> Option<std::time::Duration> {
+        self.read_since.map(|t| now.duration_since(t))
+    }
+
+    // How long the peer has not taken any of the output queued for it.
+    pub fn write_waiting(&self, now: Instant) -> Option<std::time::Duration> {
+        self.write_since.map(|t| now.duration_since(t))
+    }
+
+    pub fn is_flushed(&self) -> bool {
+        match &self.tls {
+            Some(tls) => !tls.wants_write() && self.write_buf.is_empty(),
+            None => self.write_buf.is_empty(),
+        }
+    }
+}
+
+fn is_full(frames: &[Vec<u8>], max_frames: usize, max_bytes: usize) -> bool {
+    frames.len() >= max_frames || frames.iter().map(Vec::len).sum::<usize>() >= max_bytes
+}
@@ -25,6 +25,8 @@ pub enum RorError {
     UserError(#[from] UserError),
     #[error("{0}")]
     CmdError(#[from] CmdError),
+    #[error("TLS error: {0}")]
+    TlsError(#[from] rustls::Error),
 
     #[error("Datafile Not found :{0}")]
     DataFileNotFound(String),
@@ -56,6 +58,34 @@ pub enum RorError {
     AbnormalConnection,
     #[error("Unable to parse data, probably it is incomplete")]
     IncompleteData,
+    #[error("The server refused the subscription or ended it unexpectedly")]
+    SubscribeFailed,
+    #[error("The server did not reply in time, the connection may be dead")]
+    TimedOut,
+    #[error("Received a reply to request {0}, which was not sent or already answered")]
+    UnexpectedReply(u64),
+    #[error("Received data that is not an rdb frame, the other side may not be an rdb server")]
+    InvalidFrame,
+    #[error("A frame of {0} bytes is larger than the limit of {1} bytes")]
+    FrameTooLarge(usize, usize),
+    #[error("The server is shutting down")]
+    ServerShutdown,
+    #[error("Database '{0}' is open by a client")]
+    DatabaseInUse(String),
+    #[error("Invalid TLS configuration: {0}")]
+    TlsConfigError(String),
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+    #[error("Replication failed: {0}")]
+    ReplicationError(String),
+    #[error("Moving keys between shards failed: {0}")]
+    MigrationError(String),
+    #[error("The server does not support protocol version {0}, it supports versions {1} to {2}")]
+    UnsupportedVersion(u16, u16, u16),
+    #[error("The server refused the connection, it has too many connections")]
+    TooManyConnections,
+    #[error("The server lost the lead of its Raft cluster before the write was committed, it may or may not have been applied")]
+    OutcomeUnknown(OperateRequest),
 }
 
 pub type Result<T> = std::result::Result<T, RorError>;
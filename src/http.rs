@@ -0,0 +1,257 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, SocketAddr},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use super::pool::ThreadPool;
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const READ_CHUNK: usize = 4096;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A minimal HTTP/1.1 server for the side listeners of the server (metrics, REST). Every
+// connection carries one request and is closed after the response, which is all that
+// scrapers and simple API clients need.
+pub struct Request {
+    pub method: String,
+    // The path without the query string, still percent-encoded so that an encoded '/'
+    // can be told apart from a separator. `segments` decodes it.
+    pub path: String,
+    // Decoded query parameters in the order they were sent.
+    pub query: Vec<(String, String)>,
+    // Header names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub address: SocketAddr,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+pub type Handler = Arc<dyn Fn(Request) -> Response + Send + Sync>;
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    // The decoded segments of the path, None if one of them is not valid UTF-8 or
+    // has a broken escape.
+    pub fn segments(&self) -> Option<Vec<String>> {
+        self.path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| decode(s, false))
+            .collect()
+    }
+}
+
+impl Response {
+    pub fn new<B: Into<Vec<u8>>>(status: u16, content_type: &'static str, body: B) -> Self {
+        Response {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn text<B: Into<Vec<u8>>>(status: u16, body: B) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+// Accepts connections on a thread of its own and handles each one on a pool of
+// `workers` threads, so a slow client cannot hold up the others.
+pub fn serve(listener: TcpListener, workers: usize, max_body: usize, handler: Handler) {
+    thread::spawn(move || {
+        let pool = ThreadPool::new(workers);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let handler = Arc::clone(&handler);
+            pool.execute(move || {
+                let _ = handle(stream, max_body, handler.as_ref());
+            });
+        }
+    });
+}
+
+fn handle(mut stream: TcpStream, max_body: usize, handler: &(dyn Fn(Request) -> Response + Send + Sync)) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let address = stream.peer_addr()?;
+    let response = match read_request(&mut stream, address, max_body) {
+        Ok(request) => handler(request),
+        Err(response) => response,
+    };
+    write_response(&mut stream, &response)
+}
+
+// A request that cannot be read is answered with the returned error response.
+fn read_request(stream: &mut TcpStream, address: SocketAddr, max_body: usize) -> Result<Request, Response> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; READ_CHUNK];
+    let head_end = loop {
+        if let Some(end) = find(&buf, b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(Response::text(431, "Request header is too large\n"));
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return Err(Response::text(400, "Incomplete request\n")),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = match std::str::from_utf8(&buf[..head_end]) {
+        Ok(h) => h,
+        Err(_) => return Err(Response::text(400, "Request header is not UTF-8\n")),
+    };
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, target) = match (request_line.next(), request_line.next(), request_line.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m.to_string(), t),
+        _ => return Err(Response::text(400, "Invalid request line\n")),
+    };
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_lowercase(), value.trim().to_string())),
+            None => return Err(Response::text(400, "Invalid header\n")),
+        }
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p.to_string(), q),
+        None => (target.to_string(), ""),
+    };
+    let mut params = Vec::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match (decode(name, true), decode(value, true)) {
+            (Some(n), Some(v)) => params.push((n, v)),
+            _ => return Err(Response::text(400, "Invalid query string\n")),
+        }
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        query: params,
+        headers,
+        body: Vec::new(),
+        address,
+    };
+    if request.header("transfer-encoding").is_some() {
+        return Err(Response::text(501, "Chunked requests are not supported, send a Content-Length\n"));
+    }
+    let length = match request.header("content-length").map(|l| l.parse::<usize>()) {
+        Some(Ok(l)) => l,
+        Some(Err(_)) => return Err(Response::text(400, "Invalid Content-Length\n")),
+        None => 0,
+    };
+    if length > max_body {
+        return Err(Response::text(413, format!("The body is larger than the limit of {} bytes\n", max_body)));
+    }
+    let mut body = buf.split_off(head_end + 4);
+    body.truncate(length);
+    while body.len() < length {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return Err(Response::text(400, "Incomplete body\n")),
+            Ok(n) => body.extend_from_slice(&chunk[..n.min(length - body.len())]),
+        }
+    }
+    request.body = body;
+    Ok(request)
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {0} {1}\r\nContent-Type: {2}\r\nContent-Length: {3}\r\nConnection: close\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{0}: {1}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Decodes %XX escapes, and '+' as a space in query strings.
+fn decode(s: &str, query: bool) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if query => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        421 => "Misdirected Request",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
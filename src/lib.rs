@@ -1,10 +1,42 @@
 pub use repl::{RemoteRepl,LocalRepl};
 pub use server::Server;
+pub use proxy::Proxy;
+pub use client::Client;
+pub use tls::TlsOptions;
+pub use error::RorError;
+pub use request::{
+    OperateRequest,
+    OperateResult,
+    ClientInfo,
+    ServerInfo,
+    ReplicationInfo,
+    ClusterInfo,
+    MemberInfo,
+    ShardInfo,
+    ChannelMessage,
+    PROTOCOL_VERSION,
+    MAX_PAGE_SIZE,
+    CAP_TLS,
+    CAP_PIPELINING,
+    CAP_STREAMING,
+};
+pub use store::{
+    kv::{DataStore,Value},
+    verify::{verify,repair,VerifyReport,RepairReport},
+    dump::{dump,restore,DumpFormat},
+    cdc::{ChangeEvent,ChangeKind},
+};
 
 mod store;
 mod user;
 mod server;
+mod proxy;
 mod client;
+mod connection;
+mod pool;
+mod tls;
+mod http;
+mod metrics;
 mod request;
 mod error;
 mod repl;
@@ -1,10 +1,12 @@
 use std::{
-    io::{self, Write},
+    io::{self, Write, BufReader, BufWriter},
+    fs::File,
+    error::Error,
 };
 
 use clap::{arg, Command};
 
-use rdb::{Server,LocalRepl,RemoteRepl};
+use rdb::{Server,Proxy,LocalRepl,RemoteRepl,TlsOptions,DataStore,DumpFormat,verify,repair,dump,restore};
 
 fn main() {
     let matches = Command::new("ROR Key-Value Database")
@@ -24,6 +26,14 @@ fn main() {
                 .about("Initialize server")
             )
         )
+        .subcommand(
+            Command::new("proxy")
+            .about("Start a proxy spreading keys over several servers")
+            .subcommand(
+                Command::new("init")
+                .about("Initialize proxy")
+            )
+        )
         .subcommand(
             Command::new("connect")
             .about("Connect to remote database")
@@ -31,6 +41,35 @@ fn main() {
             .arg(arg!(-p --port <VALUE> "Port"))
             .arg(arg!(-u --user <VALUE> "User Info (username@password)"))
             .arg(arg!(-f --file <VALUE> "Datafile"))
+            .arg(arg!(--tls "Connect over TLS").requires("ca"))
+            .arg(arg!(--ca <Path> "CA certificate the server certificate must be signed by"))
+            .arg(arg!(--cert <Path> "Client certificate, when the server requires one").requires("key"))
+            .arg(arg!(--key <Path> "Private key of the client certificate").requires("cert"))
+        )
+        .subcommand(
+            Command::new("verify")
+            .about("Check a datafile for damaged records")
+            .arg(arg!(<file> "Datafile path"))
+        )
+        .subcommand(
+            Command::new("repair")
+            .about("Rewrite a datafile, keeping every record that can be recovered")
+            .arg(arg!(<file> "Datafile path"))
+            .arg(arg!(-o --output <Path> "Repaired datafile path (default: [file].repaired)"))
+        )
+        .subcommand(
+            Command::new("dump")
+            .about("Export every entry of a datafile as JSON Lines or CSV")
+            .arg(arg!(<file> "Datafile path"))
+            .arg(arg!(-o --output <Path> "Output path (default: standard output)"))
+            .arg(arg!(--format <FORMAT> "json or csv (default: taken from the output path)"))
+        )
+        .subcommand(
+            Command::new("restore")
+            .about("Import entries exported by 'dump' into a datafile")
+            .arg(arg!(<file> "Datafile path"))
+            .arg(arg!(-i --input <Path> "Dump file path").required(true))
+            .arg(arg!(--format <FORMAT> "json or csv (default: taken from the input path)"))
         )
     .get_matches();
     match matches.subcommand() {
@@ -41,9 +80,16 @@ fn main() {
             let mut s = Server::new();
             s.start().unwrap();
         }
+        Some(("proxy", sub_m)) => {
+            if let Some(("init",_)) = sub_m.subcommand() {
+                Proxy::init().unwrap();
+            }
+            let mut p = Proxy::new();
+            p.start().unwrap();
+        }
         Some(("local", sub_m)) => {
             if let Some(path) = sub_m.get_one::<String>("path") {
-                let mut repl = LocalRepl::open(&path.as_str()).unwrap();
+                let mut repl = LocalRepl::open(path.as_str()).unwrap();
                 repl.run();
             } else {
                 let path = input_something("datafile path");
@@ -81,7 +127,18 @@ fn main() {
                 Some(ip) => ip.clone(),
                 None => input_something("datafile path"),
             };
-            let mut repl = match RemoteRepl::new(ip,port,username,password,db_path) {
+            let tls = match sub_m.get_flag("tls") {
+                true => {
+                    let ca = sub_m.get_one::<String>("ca").unwrap().clone();
+                    let tls = TlsOptions::new(ca);
+                    match (sub_m.get_one::<String>("cert"), sub_m.get_one::<String>("key")) {
+                        (Some(cert), Some(key)) => Some(tls.with_client_cert(cert.clone(), key.clone())),
+                        _ => Some(tls),
+                    }
+                }
+                false => None,
+            };
+            let mut repl = match RemoteRepl::new(ip,port,username,password,db_path,tls) {
                 Ok(r) => r,
                 Err(e) => {
                     println!("{}",e);
@@ -91,6 +148,46 @@ fn main() {
             };
             repl.run();
         }
+        Some(("verify", sub_m)) => {
+            let path = sub_m.get_one::<String>("file").unwrap();
+            match verify(path) {
+                Ok(report) if report.is_clean() => println!("{}", report),
+                Ok(report) => {
+                    println!("{}", report);
+                    std::process::exit(1);
+                }
+                Err(e) => fail(format!("Unable to verify '{0}': {1}", path, e)),
+            }
+        }
+        Some(("repair", sub_m)) => {
+            let path = sub_m.get_one::<String>("file").unwrap();
+            let output = match sub_m.get_one::<String>("output") {
+                Some(o) => o.clone(),
+                None => format!("{}.repaired", path),
+            };
+            match repair(path, &output) {
+                Ok(report) => println!("{}", report),
+                Err(e) => fail(format!("Unable to repair '{0}': {1}", path, e)),
+            }
+        }
+        Some(("dump", sub_m)) => {
+            let path = sub_m.get_one::<String>("file").unwrap();
+            let output = sub_m.get_one::<String>("output");
+            match dump_datafile(path, output, sub_m.get_one::<String>("format")) {
+                Ok(count) => if output.is_some() {
+                    println!("Successfully dumped {0} entries from '{1}'", count, path);
+                },
+                Err(e) => fail(format!("Unable to dump '{0}': {1}", path, e)),
+            }
+        }
+        Some(("restore", sub_m)) => {
+            let path = sub_m.get_one::<String>("file").unwrap();
+            let input = sub_m.get_one::<String>("input").unwrap();
+            match restore_datafile(path, input, sub_m.get_one::<String>("format")) {
+                Ok(count) => println!("Successfully restored {0} entries into '{1}'", count, path),
+                Err(e) => fail(format!("Unable to restore '{0}': {1}", path, e)),
+            }
+        }
         _ => {
             println!("Unable to start, starting 'local'");
             let path = input_something("datafile path");
@@ -101,13 +198,43 @@ fn main() {
     
 }
 
+fn dump_datafile(path: &str, output: Option<&String>, format: Option<&String>) -> Result<usize, Box<dyn Error>> {
+    let mut db = DataStore::open(path)?;
+    let format = match (format, output) {
+        (Some(f), _) => DumpFormat::from_name(f)?,
+        (None, Some(o)) => DumpFormat::from_path(o),
+        (None, None) => DumpFormat::Json,
+    };
+    let count = match output {
+        Some(o) => dump(&mut db, format, &mut BufWriter::new(File::create(o)?))?,
+        None => dump(&mut db, format, &mut io::stdout().lock())?,
+    };
+    Ok(count)
+}
+
+fn restore_datafile(path: &str, input: &str, format: Option<&String>) -> Result<usize, Box<dyn Error>> {
+    let mut db = DataStore::open(path)?;
+    let format = match format {
+        Some(f) => DumpFormat::from_name(f)?,
+        None => DumpFormat::from_path(input),
+    };
+    let count = restore(&mut db, format, &mut BufReader::new(File::open(input)?))?;
+    Ok(count)
+}
+
+// Reports an error of a subcommand on stderr and exits with a failure status.
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
 fn input_something(p: &str) -> String {
     print!("Please enter your {0}: ",p);
     io::stdout().flush().unwrap();
     let mut parameter = String::new();
     std::io::stdin().read_line(&mut parameter).unwrap();    
     if parameter.trim() != "" {
-        return parameter.trim().to_string();
+        parameter.trim().to_string()
     } else {
         input_something(p)
     }
@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use super::request::OperateResult;
+
+// Upper bounds in seconds of the request latency histogram buckets.
+const BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Counters and histograms of the server, exported in the Prometheus text format.
+// The event loop records into it, the metrics listener renders it.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    clients: AtomicU64,
+    connections: AtomicU64,
+}
+
+// The size and dead bytes of an open datafile, taken when the metrics are scraped.
+pub struct DatabaseStats {
+    pub path: String,
+    pub keys: usize,
+    pub size: u64,
+    pub dead_bytes: u64,
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn request(&self, kind: &'static str) {
+        *self.requests.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn observe(&self, kind: &'static str, elapsed: Duration) {
+        self.latency.lock().unwrap().entry(kind).or_default().observe(elapsed.as_secs_f64());
+    }
+
+    pub fn error(&self, kind: &'static str) {
+        *self.errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    // Counts the replies that tell the client its request did not succeed.
+    pub fn result(&self, result: &OperateResult) {
+        match result {
+            OperateResult::PermissionDenied => self.error("permission_denied"),
+            OperateResult::KeyNotFound => self.error("key_not_found"),
+            OperateResult::Failure => self.error("failure"),
+            OperateResult::Timeout => self.error("watch_timeout"),
+            OperateResult::ReadOnly => self.error("read_only"),
+            OperateResult::Redirect { .. } => self.error("redirect"),
+            OperateResult::RateLimited => self.error("rate_limited"),
+            OperateResult::Uncommitted { .. } => self.error("uncommitted"),
+            _ => (),
+        }
+    }
+
+    pub fn connected(&self, clients: usize) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.clients.store(clients as u64, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self, clients: usize) {
+        self.clients.store(clients as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, databases: &[DatabaseStats]) -> String {
+        let mut out = String::new();
+        family(&mut out, "rdb_requests_total", "counter", "Requests received, by kind.");
+        for (kind, count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "rdb_requests_total{{kind=\"{0}\"}} {1}", kind, count);
+        }
+
+        family(&mut out, "rdb_request_duration_seconds", "histogram", "Time from receiving a request to its reply, by kind.");
+        for (kind, histogram) in self.latency.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.counts.iter()) {
+                cumulative += count;
+                let _ = writeln!(out, "rdb_request_duration_seconds_bucket{{kind=\"{0}\",le=\"{1}\"}} {2}", kind, bound, cumulative);
+            }
+            let _ = writeln!(out, "rdb_request_duration_seconds_bucket{{kind=\"{0}\",le=\"+Inf\"}} {1}", kind, histogram.count);
+            let _ = writeln!(out, "rdb_request_duration_seconds_sum{{kind=\"{0}\"}} {1}", kind, histogram.sum);
+            let _ = writeln!(out, "rdb_request_duration_seconds_count{{kind=\"{0}\"}} {1}", kind, histogram.count);
+        }
+
+        family(&mut out, "rdb_errors_total", "counter", "Failed requests and connections, by type.");
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "rdb_errors_total{{type=\"{0}\"}} {1}", kind, count);
+        }
+
+        family(&mut out, "rdb_connected_clients", "gauge", "Open client connections.");
+        let _ = writeln!(out, "rdb_connected_clients {}", self.clients.load(Ordering::Relaxed));
+        family(&mut out, "rdb_connections_total", "counter", "Client connections accepted.");
+        let _ = writeln!(out, "rdb_connections_total {}", self.connections.load(Ordering::Relaxed));
+
+        family(&mut out, "rdb_open_databases", "gauge", "Datafiles currently open.");
+        let _ = writeln!(out, "rdb_open_databases {}", databases.len());
+        family(&mut out, "rdb_database_keys", "gauge", "Live keys in an open datafile.");
+        for db in databases {
+            let _ = writeln!(out, "rdb_database_keys{{database=\"{0}\"}} {1}", escape(&db.path), db.keys);
+        }
+        family(&mut out, "rdb_database_size_bytes", "gauge", "Size of an open datafile.");
+        for db in databases {
+            let _ = writeln!(out, "rdb_database_size_bytes{{database=\"{0}\"}} {1}", escape(&db.path), db.size);
+        }
+        family(&mut out, "rdb_database_dead_bytes", "gauge", "Bytes of an open datafile that compaction would reclaim.");
+        for db in databases {
+            let _ = writeln!(out, "rdb_database_dead_bytes{{database=\"{0}\"}} {1}", escape(&db.path), db.dead_bytes);
+        }
+        out
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {0} {1}", name, help);
+    let _ = writeln!(out, "# TYPE {0} {1}", name, kind);
+}
+
+// Label values escape backslashes, quotes and newlines.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(out: &str, prefix: &str) -> Vec<String> {
+        out.lines().filter(|l| l.starts_with(prefix)).map(String::from).collect()
+    }
+
+    #[test]
+    fn an_empty_registry_renders_every_family() {
+        let out = Metrics::default().render(&[]);
+        for name in [
+            "rdb_requests_total",
+            "rdb_request_duration_seconds",
+            "rdb_errors_total",
+            "rdb_connected_clients",
+            "rdb_connections_total",
+            "rdb_open_databases",
+            "rdb_database_keys",
+            "rdb_database_size_bytes",
+            "rdb_database_dead_bytes",
+        ] {
+            assert_eq!(lines(&out, &format!("# TYPE {} ", name)).len(), 1, "{}", name);
+            assert_eq!(lines(&out, &format!("# HELP {} ", name)).len(), 1, "{}", name);
+        }
+        assert!(out.contains("\nrdb_connected_clients 0\n"));
+        assert!(out.contains("\nrdb_open_databases 0\n"));
+    }
+
+    #[test]
+    fn counters_are_rendered_by_label() {
+        let metrics = Metrics::default();
+        metrics.request("get");
+        metrics.request("get");
+        metrics.request("add");
+        metrics.result(&OperateResult::KeyNotFound);
+        metrics.result(&OperateResult::Success);
+        metrics.result(&OperateResult::Uncommitted { leader: None });
+        metrics.error("login");
+        metrics.connected(2);
+        metrics.connected(3);
+        metrics.disconnected(1);
+
+        let out = metrics.render(&[]);
+        assert_eq!(lines(&out, "rdb_requests_total"), ["rdb_requests_total{kind=\"add\"} 1", "rdb_requests_total{kind=\"get\"} 2"]);
+        assert_eq!(
+            lines(&out, "rdb_errors_total"),
+            ["rdb_errors_total{type=\"key_not_found\"} 1", "rdb_errors_total{type=\"login\"} 1", "rdb_errors_total{type=\"uncommitted\"} 1"]
+        );
+        assert_eq!(lines(&out, "rdb_connected_clients"), ["rdb_connected_clients 1"]);
+        assert_eq!(lines(&out, "rdb_connections_total"), ["rdb_connections_total 2"]);
+    }
+
+    #[test]
+    fn latency_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.observe("get", Duration::from_micros(200));
+        metrics.observe("get", Duration::from_millis(3));
+        metrics.observe("get", Duration::from_secs(60));
+
+        let out = metrics.render(&[]);
+        let buckets = lines(&out, "rdb_request_duration_seconds_bucket");
+        assert_eq!(buckets.len(), BUCKETS.len() + 1);
+        assert_eq!(buckets[0], "rdb_request_duration_seconds_bucket{kind=\"get\",le=\"0.0005\"} 1");
+        assert_eq!(buckets[3], "rdb_request_duration_seconds_bucket{kind=\"get\",le=\"0.005\"} 2");
+        // Past the last bound only +Inf counts it.
+        assert_eq!(buckets[BUCKETS.len() - 1], "rdb_request_duration_seconds_bucket{kind=\"get\",le=\"10\"} 2");
+        assert_eq!(buckets[BUCKETS.len()], "rdb_request_duration_seconds_bucket{kind=\"get\",le=\"+Inf\"} 3");
+        assert_eq!(lines(&out, "rdb_request_duration_seconds_count"), ["rdb_request_duration_seconds_count{kind=\"get\"} 3"]);
+        assert_eq!(lines(&out, "rdb_request_duration_seconds_sum"), ["rdb_request_duration_seconds_sum{kind=\"get\"} 60.0032"]);
+    }
+
+    #[test]
+    fn database_paths_are_escaped() {
+        let databases = [DatabaseStats { path: "data/a\"b\\c.data".to_string(), keys: 3, size: 120, dead_bytes: 40 }];
+        let out = Metrics::default().render(&databases);
+        assert!(out.contains("\nrdb_open_databases 1\n"));
+        assert!(out.contains("\nrdb_database_keys{database=\"data/a\\\"b\\\\c.data\"} 3\n"));
+        assert!(out.contains("\nrdb_database_size_bytes{database=\"data/a\\\"b\\\\c.data\"} 120\n"));
+        assert!(out.contains("\nrdb_database_dead_bytes{database=\"data/a\\\"b\\\\c.data\"} 40\n"));
+    }
+}
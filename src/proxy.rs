@@ -0,0 +1,777 @@
+use std::{
+    io::{Read, Write, BufReader, BufWriter},
+    fs::{self, File},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    collections::{BTreeMap, BTreeSet, HashMap, btree_map},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize};
+use super::{
+    error::{RorError, Result},
+    store::kv::Value,
+    user::user_error::UserError,
+    request::*,
+    client::{self, Client},
+    server::output_prompt,
+};
+use ring::Ring;
+
+mod ring;
+
+// Keys are locked in this many stripes, by their hash.
+const STRIPES: usize = 256;
+// Time between attempts to move keys after one failed, e.g. because a shard was down.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const SHARDS_FILE: &str = "shards.toml";
+
+// A proxy speaking the client protocol in front of ordinary servers, the shards. Every
+// key belongs to one shard, found on a consistent hash ring, while scans and requests
+// about whole datafiles go to every shard. Each connection has a thread of its own and
+// logs in at every shard with the user and datafile it logged in with.
+pub struct Proxy {
+    config: Config,
+}
+
+// The shards, shared by the connections and the thread moving keys after a change.
+struct Shards {
+    config: Config,
+    state: Mutex<ShardState>,
+    // Requests for a key and moving that key take the lock of its stripe, so a key is
+    // never written on one shard while it is copied from another.
+    stripes: Vec<Mutex<()>>,
+}
+
+struct ShardState {
+    ring: Ring,
+    // The ring before the change in progress. Keys that belonged to another shard on it
+    // are read from there until they have been moved.
+    previous: Option<Ring>,
+    moved: u64,
+}
+
+// The shards as saved in state_path, so a change in progress goes on after a restart.
+#[derive(Serialize, Deserialize)]
+struct ShardsFile {
+    shards: Vec<String>,
+    previous: Option<Vec<String>>,
+}
+
+// A client connection, with the connections to the shards it made so far.
+struct Session {
+    address: SocketAddr,
+    user_name: String,
+    password: String,
+    db_path: String,
+    timeout: Option<Duration>,
+    backends: HashMap<String, Client>,
+}
+
+// A connection to a shard moving keys, and the datafile it has open.
+struct Mover {
+    client: Client,
+    db: String,
+}
+
+impl Proxy {
+    // Reads the configuration from the working directory, so there is no Default.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let config = match Config::get_proxy() {
+            Ok(config) => config,
+            Err(e) => {
+                output_prompt(format!("Could not read configuration file: {0}", e));
+                Config::default()
+            }
+        };
+        Proxy { config }
+    }
+
+    pub fn init() -> Result<()> {
+        fs::create_dir_all("config")?;
+        let proxy_config = toml::to_string(&Config::default())?;
+        let mut file = File::create("config/proxy.toml")?;
+        write!(file, "{}", proxy_config)?;
+        Ok(())
+    }
+
+    pub fn start(&mut self) -> Result<()> {
+        let address = format!("{0}:{1}", self.config.ip, self.config.port);
+        let listener = TcpListener::bind(&address)?;
+        fs::create_dir_all(&self.config.state_path)?;
+        let file = match read_shards_file(&self.config.state_path)? {
+            Some(file) => file,
+            None => ShardsFile {
+                shards: self.config.shards.clone(),
+                previous: None,
+            },
+        };
+        if file.shards.is_empty() {
+            return Err(RorError::ConfigError("the proxy needs at least one shard".to_string()));
+        }
+        let shards = Arc::new(Shards {
+            state: Mutex::new(ShardState {
+                ring: Ring::new(&file.shards, self.config.virtual_nodes),
+                previous: file.previous.as_ref().map(|p| Ring::new(p, self.config.virtual_nodes)),
+                moved: 0,
+            }),
+            config: self.config.clone(),
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        });
+
+        output_prompt(format!("Proxy start: {0}, shards: {1}", address, file.shards.join(", ")));
+        if file.previous.is_some() {
+            output_prompt("Resuming the shard change in progress");
+            let moving = Arc::clone(&shards);
+            thread::spawn(move || moving.migrate());
+        }
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let shards = Arc::clone(&shards);
+            thread::spawn(move || shards.run(stream));
+        }
+        Ok(())
+    }
+}
+
+impl Shards {
+    fn run(self: Arc<Self>, stream: TcpStream) {
+        let address = match stream.peer_addr() {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        let _ = stream.set_nodelay(true);
+        let (read_half, write_half) = match stream.try_clone() {
+            Ok(s) => (s, stream),
+            Err(_) => return,
+        };
+        let mut reader = BufReader::new(read_half);
+        let mut writer = BufWriter::new(write_half);
+
+        let frame = match client::read_frame(&mut reader, HANDSHAKE_MAX_FRAME_SIZE as usize) {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        let request = frame_id(&frame).unwrap_or(0);
+        // Replies are downgraded to what the client's protocol version can decode.
+        let version = Message::<ProtocolVersion>::from_frame(&frame).map(|m| m.message.version).unwrap_or(MIN_PROTOCOL_VERSION);
+        let (mut session, capabilities) = match self.login(&frame, address) {
+            Ok(s) => s,
+            Err((err, reason)) => {
+                output_prompt(format!("Client [{0}], failed to login. reason: {1}", address, reason));
+                let _ = send(&mut writer, request, ConnectReply::Error(err).downgrade(version));
+                let _ = writer.flush();
+                return;
+            }
+        };
+        let reply = ConnectReply::Success {
+            version,
+            capabilities,
+            max_frame_size: self.config.max_frame_size,
+        };
+        if send(&mut writer, request, reply).and_then(|_| Ok(writer.flush()?)).is_err() {
+            return;
+        }
+        output_prompt(format!("Client [{0}] logged in as '{1}'", address, session.user_name));
+
+        loop {
+            let frame = match client::read_frame(&mut reader, self.config.max_frame_size as usize) {
+                Ok(f) => f,
+                Err(_) => break,
+            };
+            let (id, result) = match Message::<OperateRequest>::from_frame(&frame) {
+                Ok(Message { message: OperateRequest::Quit, .. }) => break,
+                Ok(m) => (m.id, self.handle(&mut session, m.message)),
+                Err(_) => (frame_id(&frame).unwrap_or(0), OperateResult::Failure),
+            };
+            if send(&mut writer, id, result.downgrade(version)).is_err() {
+                break;
+            }
+            // Replies to pipelined requests are written together.
+            if reader.buffer().is_empty() && writer.flush().is_err() {
+                break;
+            }
+        }
+        output_prompt(format!("Client [{0}] disconnected", address));
+    }
+
+    // Checks the version like a server does, then logs in at every shard, which checks
+    // the user and opens the datafile.
+    fn login(&self, frame: &[u8], address: SocketAddr) -> std::result::Result<(Session, u32), (ConnectError, String)> {
+        let head = match Message::<ProtocolVersion>::from_frame(frame) {
+            Ok(Message { message: ProtocolVersion { version }, .. })
+                if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => {
+                return Err((
+                    ConnectError::UnsupportedVersion { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION },
+                    format!("unsupported protocol version {}", version),
+                ));
+            }
+            _ => match Message::<ConnectRequest>::from_frame(frame) {
+                Ok(m) => m.message,
+                Err(e) => return Err((ConnectError::RequestError, e.to_string())),
+            },
+        };
+        let mut session = Session {
+            address,
+            user_name: head.user_name,
+            password: head.password,
+            db_path: head.db_path,
+            timeout: match self.config.timeout {
+                0 => None,
+                t => Some(Duration::from_secs(t)),
+            },
+            backends: HashMap::new(),
+        };
+        for shard in self.all_shards() {
+            if let Err(e) = session.backend(&shard) {
+                let err = match &e {
+                    RorError::UserError(UserError::UserNotFound(_)) => ConnectError::UserNotFound,
+                    RorError::UserError(UserError::WrongPassWord) => ConnectError::PasswordError,
+                    RorError::OpenFileFailed => ConnectError::OpenFileError,
+                    RorError::PathError => ConnectError::PathError,
+                    RorError::RequestError => ConnectError::RequestError,
+                    RorError::TooManyConnections => ConnectError::TooManyConnections,
+                    _ => ConnectError::ServerError,
+                };
+                return Err((err, format!("shard {0}: {1}", shard, e)));
+            }
+        }
+        // Change streams cannot be merged from several shards.
+        Ok((session, head.capabilities & CAP_PIPELINING))
+    }
+
+    fn handle(self: &Arc<Self>, session: &mut Session, request: OperateRequest) -> OperateResult {
+        match request {
+            OperateRequest::Get { ref key } | OperateRequest::GetType { ref key } => {
+                let key = key.clone();
+                self.read(session, &key, request)
+            }
+            OperateRequest::Add { ref key, .. } => {
+                let key = key.clone();
+                self.write(session, &key, request)
+            }
+            OperateRequest::Delete { key } => self.delete(session, &key),
+            // A watch waits for a change, so it does not hold the lock of the key and
+            // only sees changes on the shard the key belongs to now.
+            OperateRequest::Watch { ref key, .. } => {
+                let (owner, _) = self.route(key);
+                session.call(&owner, request)
+            }
+            OperateRequest::List { limit, .. } | OperateRequest::Scan { limit, .. } => self.scan(session, request, limit),
+            OperateRequest::ListDatabases => {
+                let mut names = BTreeSet::new();
+                for shard in self.all_shards() {
+                    match session.call(&shard, OperateRequest::ListDatabases) {
+                        OperateResult::Databases(d) => names.extend(d),
+                        result => return result,
+                    }
+                }
+                OperateResult::Databases(names.into_iter().collect())
+            }
+            OperateRequest::Open { ref path } => {
+                let path = path.clone();
+                match self.fan_out(session, request) {
+                    OperateResult::Success => {
+                        session.db_path = path;
+                        OperateResult::Success
+                    }
+                    result => {
+                        // Some shards may have opened the datafile, log in again with
+                        // the one the session had.
+                        session.backends.clear();
+                        result
+                    }
+                }
+            }
+            OperateRequest::CreateDatabase { .. }
+            | OperateRequest::DropDatabase { .. }
+            | OperateRequest::Compact
+            | OperateRequest::Backup { .. }
+            | OperateRequest::CreateUser { .. }
+            | OperateRequest::DeleteUser { .. } => self.fan_out(session, request),
+            OperateRequest::Ping => OperateResult::Pong,
+            OperateRequest::AddShard { address } => self.change(session, address, true),
+            OperateRequest::RemoveShard { address } => self.change(session, address, false),
+            OperateRequest::ListShards => OperateResult::Shards(self.list()),
+            // Requests about one server, its clients, its change stream or its channels,
+            // which have no meaning for the shards as a whole.
+            OperateRequest::Restore { .. }
+            | OperateRequest::Subscribe { .. }
+            | OperateRequest::Publish { .. }
+            | OperateRequest::SubscribeChannels { .. }
+            | OperateRequest::Unsubscribe
+            | OperateRequest::Replicate
+            | OperateRequest::Shutdown
+            | OperateRequest::ListClients
+            | OperateRequest::KillClient { .. }
+            | OperateRequest::ServerInfo
+            | OperateRequest::AddMember { .. }
+            | OperateRequest::RemoveMember { .. }
+            | OperateRequest::Quit => OperateResult::Failure,
+        }
+    }
+
+    // The shard `key` belongs to, and the one it belonged to before the change in
+    // progress when that is another.
+    fn route(&self, key: &str) -> (String, Option<String>) {
+        let state = self.state.lock().unwrap();
+        let owner = state.ring.owner(key).unwrap_or_default().to_string();
+        let previous = state.previous
+            .as_ref()
+            .and_then(|p| p.owner(key))
+            .filter(|p| *p != owner)
+            .map(|p| p.to_string());
+        (owner, previous)
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        self.stripes[(ring::hash(key.as_bytes()) % STRIPES as u64) as usize].lock().unwrap()
+    }
+
+    fn all_shards(&self) -> Vec<String> {
+        self.state.lock().unwrap().shards()
+    }
+
+    fn read(&self, session: &mut Session, key: &str, request: OperateRequest) -> OperateResult {
+        let _guard = self.lock(key);
+        let (owner, previous) = self.route(key);
+        match (session.call(&owner, request.clone()), previous) {
+            (OperateResult::KeyNotFound, Some(previous)) => session.call(&previous, request),
+            (result, _) => result,
+        }
+    }
+
+    fn write(&self, session: &mut Session, key: &str, request: OperateRequest) -> OperateResult {
+        let _guard = self.lock(key);
+        let (owner, previous) = self.route(key);
+        let result = session.call(&owner, request);
+        if let (OperateResult::Success, Some(previous)) = (&result, previous) {
+            // The old value must not be moved over the new one. Should this fail, the
+            // move finds the key on its new shard and drops the old value.
+            session.call(&previous, OperateRequest::Delete { key: key.to_string() });
+        }
+        result
+    }
+
+    fn delete(&self, session: &mut Session, key: &str) -> OperateResult {
+        let _guard = self.lock(key);
+        let (owner, previous) = self.route(key);
+        let result = session.call(&owner, OperateRequest::Delete { key: key.to_string() });
+        match previous {
+            Some(previous) => match (result, session.call(&previous, OperateRequest::Delete { key: key.to_string() })) {
+                (OperateResult::KeyNotFound, old) => old,
+                (result, _) => result,
+            },
+            None => result,
+        }
+    }
+
+    // Asks every shard for a page and merges them. Each shard returns its first `limit`
+    // keys after the cursor, so the first `limit` keys of all of them are the page, and
+    // its last key is the cursor of the next one.
+    fn scan(&self, session: &mut Session, request: OperateRequest, limit: u32) -> OperateResult {
+        let limit = match limit {
+            0 => MAX_PAGE_SIZE,
+            n => n.min(MAX_PAGE_SIZE),
+        } as usize;
+        let ring = self.state.lock().unwrap().ring.clone();
+        // A key being moved may be on two shards, the copy on the shard it belongs to
+        // wins.
+        let mut merged: BTreeMap<String, (Value, bool)> = BTreeMap::new();
+        let mut more = false;
+        for shard in self.all_shards() {
+            let items = match session.call(&shard, request.clone()) {
+                OperateResult::Entries { items, next_cursor } => {
+                    more |= next_cursor.is_some();
+                    items
+                }
+                result => return result,
+            };
+            for (key, value) in items {
+                let owned = ring.owner(&key) == Some(shard.as_str());
+                match merged.entry(key) {
+                    btree_map::Entry::Vacant(e) => {
+                        e.insert((value, owned));
+                    }
+                    btree_map::Entry::Occupied(mut e) if owned => {
+                        e.insert((value, owned));
+                    }
+                    btree_map::Entry::Occupied(_) => (),
+                }
+            }
+        }
+        more |= merged.len() > limit;
+        let items: Vec<(String, Value)> = merged.into_iter().take(limit).map(|(k, (v, _))| (k, v)).collect();
+        let next_cursor = match more {
+            true => items.last().map(|(k, _)| k.clone()),
+            false => None,
+        };
+        OperateResult::Entries { items, next_cursor }
+    }
+
+    // Sends the request to every shard, and returns the first result that is not
+    // Success. The shards before it have carried the request out.
+    fn fan_out(&self, session: &mut Session, request: OperateRequest) -> OperateResult {
+        for shard in self.all_shards() {
+            match session.call(&shard, request.clone()) {
+                OperateResult::Success => (),
+                result => return result,
+            }
+        }
+        OperateResult::Success
+    }
+
+    fn list(&self) -> Vec<ShardInfo> {
+        let state = self.state.lock().unwrap();
+        let mut shards = Vec::new();
+        for address in state.shards() {
+            let state_name = match (&state.previous, state.ring.contains(&address)) {
+                (None, _) => "serving",
+                (Some(p), true) if p.contains(&address) => "serving",
+                (Some(_), true) => "joining",
+                (Some(_), false) => "leaving",
+            };
+            shards.push(ShardInfo {
+                address,
+                state: state_name.to_string(),
+                moved: match state_name {
+                    "serving" => 0,
+                    _ => state.moved,
+                },
+            });
+        }
+        shards
+    }
+
+    // Adds or removes a shard. Only one change runs at a time, and only users that are
+    // admins on the shards may make one.
+    fn change(self: &Arc<Self>, session: &mut Session, address: String, add: bool) -> OperateResult {
+        let first = match self.all_shards().into_iter().next() {
+            Some(s) => s,
+            None => return OperateResult::Failure,
+        };
+        match session.call(&first, OperateRequest::ServerInfo) {
+            OperateResult::ServerInfo(_) => (),
+            OperateResult::PermissionDenied => return OperateResult::PermissionDenied,
+            _ => return OperateResult::Failure,
+        }
+        if add {
+            if let Err(e) = session.backend(&address) {
+                output_prompt(format!("Cannot add shard {0} for client [{1}], {2}", address, session.address, e));
+                return OperateResult::Failure;
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.previous.is_some() {
+            output_prompt("The previous shard change is still moving keys");
+            return OperateResult::Failure;
+        }
+        let old = state.ring.shards().to_vec();
+        let mut shards = old.clone();
+        match (add, state.ring.contains(&address)) {
+            (true, false) => shards.push(address.clone()),
+            (false, true) if shards.len() > 1 => shards.retain(|s| *s != address),
+            _ => return OperateResult::Failure,
+        }
+        let file = ShardsFile {
+            shards: shards.clone(),
+            previous: Some(old.clone()),
+        };
+        if let Err(e) = write_shards_file(&self.config.state_path, &file) {
+            output_prompt(format!("Unable to save the shards, {}", e));
+            return OperateResult::Failure;
+        }
+        state.ring = Ring::new(&shards, self.config.virtual_nodes);
+        state.previous = Some(Ring::new(&old, self.config.virtual_nodes));
+        state.moved = 0;
+        drop(state);
+        output_prompt(format!(
+            "Client [{0}] {1} shard {2}, moving keys",
+            session.address,
+            if add { "added" } else { "removed" },
+            address,
+        ));
+        let moving = Arc::clone(self);
+        thread::spawn(move || moving.migrate());
+        OperateResult::Success
+    }
+
+    // Moves the keys that belong to another shard since the change, until every shard
+    // has been gone through once without an error, and then ends the change.
+    fn migrate(&self) {
+        // Requests that looked the ring up before the change may still write to the
+        // shard a key belonged to, wait for them.
+        for stripe in &self.stripes {
+            drop(stripe.lock().unwrap());
+        }
+        loop {
+            match self.move_keys() {
+                Ok(()) => break,
+                Err(e) => {
+                    output_prompt(format!("Moving keys failed, retrying in {0} s: {1}", RETRY_INTERVAL.as_secs(), e));
+                    thread::sleep(RETRY_INTERVAL);
+                }
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        let file = ShardsFile {
+            shards: state.ring.shards().to_vec(),
+            previous: None,
+        };
+        // Should this fail, the change runs again after a restart, and finds nothing
+        // left to move.
+        if let Err(e) = write_shards_file(&self.config.state_path, &file) {
+            output_prompt(format!("Unable to save the shards, {}", e));
+        }
+        state.previous = None;
+        output_prompt(format!("Shard change complete, {} keys moved", state.moved));
+    }
+
+    fn move_keys(&self) -> Result<()> {
+        let (ring, previous) = {
+            let state = self.state.lock().unwrap();
+            match &state.previous {
+                Some(p) => (state.ring.clone(), p.clone()),
+                None => return Ok(()),
+            }
+        };
+        // Removing a shard moves only its own keys, adding one takes keys from all.
+        let added = ring.shards().iter().any(|s| !previous.contains(s));
+        let mut movers = HashMap::new();
+        for source in previous.shards() {
+            if !added && ring.contains(source) {
+                continue;
+            }
+            let databases = match self.mover(&mut movers, source, None)?.client.operate(OperateRequest::ListDatabases)? {
+                OperateResult::Databases(d) => d,
+                _ => return Err(RorError::MigrationError(format!("cannot list the datafiles of {}", source))),
+            };
+            for db in databases {
+                let mut cursor = None;
+                loop {
+                    let request = OperateRequest::List { cursor: cursor.take(), limit: MAX_PAGE_SIZE };
+                    let (items, next_cursor) = match self.mover(&mut movers, source, Some(&db))?.client.operate(request)? {
+                        OperateResult::Entries { items, next_cursor } => (items, next_cursor),
+                        _ => return Err(RorError::MigrationError(format!("cannot list '{0}' on {1}", db, source))),
+                    };
+                    for (key, _) in items {
+                        if previous.owner(&key) != Some(source.as_str()) {
+                            continue;
+                        }
+                        match ring.owner(&key) {
+                            Some(target) if target != source => self.move_key(&mut movers, source, target, &db, &key)?,
+                            _ => (),
+                        }
+                    }
+                    match next_cursor {
+                        Some(c) => cursor = Some(c),
+                        None => break,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Copies a key to the shard it belongs to now, unless a client wrote it there since
+    // the change, and deletes it where it was.
+    fn move_key(&self, movers: &mut HashMap<String, Mover>, source: &str, target: &str, db: &str, key: &str) -> Result<()> {
+        let _guard = self.lock(key);
+        let get = OperateRequest::Get { key: key.to_string() };
+        match self.mover(movers, target, Some(db))?.client.operate(get.clone())? {
+            OperateResult::Found(_) => (),
+            OperateResult::KeyNotFound => match self.mover(movers, source, Some(db))?.client.operate(get)? {
+                OperateResult::Found(value) => {
+                    let add = OperateRequest::Add { key: key.to_string(), value };
+                    match self.mover(movers, target, Some(db))?.client.operate(add)? {
+                        OperateResult::Success => (),
+                        _ => return Err(RorError::MigrationError(format!("cannot write '{0}' to {1}", key, target))),
+                    }
+                }
+                // Deleted by a client meanwhile.
+                OperateResult::KeyNotFound => return Ok(()),
+                _ => return Err(RorError::MigrationError(format!("cannot read '{0}' from {1}", key, source))),
+            },
+            _ => return Err(RorError::MigrationError(format!("cannot read '{0}' from {1}", key, target))),
+        }
+        match self.mover(movers, source, Some(db))?.client.operate(OperateRequest::Delete { key: key.to_string() })? {
+            OperateResult::Success | OperateResult::KeyNotFound => (),
+            _ => return Err(RorError::MigrationError(format!("cannot delete '{0}' from {1}", key, source))),
+        }
+        self.state.lock().unwrap().moved += 1;
+        Ok(())
+    }
+
+    // The connection moving keys on `shard`, logged in as admin_user, with `db` open.
+    fn mover<'a>(&self, movers: &'a mut HashMap<String, Mover>, shard: &str, db: Option<&str>) -> Result<&'a mut Mover> {
+        if !movers.contains_key(shard) {
+            let (user_name, password) = match self.config.admin_user.split_once('@') {
+                Some(u) => u,
+                None => return Err(RorError::ConfigError("admin_user must be 'user@password'".to_string())),
+            };
+            let client = connect(shard, user_name, password, &self.config.database, self.config.timeout)?;
+            movers.insert(shard.to_string(), Mover { client, db: self.config.database.clone() });
+        }
+        let mover = movers.get_mut(shard).unwrap();
+        if let Some(db) = db {
+            if mover.db != db {
+                match mover.client.operate(OperateRequest::Open { path: db.to_string() })? {
+                    OperateResult::Success => mover.db = db.to_string(),
+                    _ => return Err(RorError::MigrationError(format!("cannot open '{0}' on {1}", db, shard))),
+                }
+            }
+        }
+        Ok(mover)
+    }
+}
+
+impl ShardState {
+    // Every shard, and those being removed.
+    fn shards(&self) -> Vec<String> {
+        let mut shards = self.ring.shards().to_vec();
+        if let Some(previous) = &self.previous {
+            shards.extend(previous.shards().iter().filter(|s| !self.ring.contains(s)).cloned());
+        }
+        shards
+    }
+}
+
+impl Session {
+    // The connection to `shard`, made when it is first needed.
+    fn backend(&mut self, shard: &str) -> Result<&mut Client> {
+        if !self.backends.contains_key(shard) {
+            let timeout = self.timeout.map_or(0, |t| t.as_secs());
+            let client = connect(shard, &self.user_name, &self.password, &self.db_path, timeout)?;
+            self.backends.insert(shard.to_string(), client);
+        }
+        Ok(self.backends.get_mut(shard).unwrap())
+    }
+
+    // Sends a request to `shard`. A shard that cannot be reached answers Failure, and is
+    // connected to again with the next request.
+    fn call(&mut self, shard: &str, request: OperateRequest) -> OperateResult {
+        match self.backend(shard).and_then(|c| c.operate(request)) {
+            Ok(result) => result,
+            Err(e) => {
+                output_prompt(format!("Client [{0}], request to shard {1} failed: {2}", self.address, shard, e));
+                self.backends.remove(shard);
+                OperateResult::Failure
+            }
+        }
+    }
+}
+
+fn connect(shard: &str, user_name: &str, password: &str, db_path: &str, timeout: u64) -> Result<Client> {
+    let (ip, port) = match shard.rsplit_once(':') {
+        Some(a) => a,
+        None => return Err(RorError::ConfigError(format!("shard '{}' is not 'host:port'", shard))),
+    };
+    let mut client = Client::connect(ip.to_string(), port.to_string(), user_name.to_string(), password.to_string(), db_path.to_string())?;
+    if timeout > 0 {
+        client.set_timeout(Some(Duration::from_secs(timeout)))?;
+    }
+    Ok(client)
+}
+
+fn send<W: Write, T: Serialize + serde::de::DeserializeOwned>(writer: &mut W, id: u64, message: T) -> Result<()> {
+    let (buf, _) = Message::with_id(id, message).as_bytes()?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+fn read_shards_file(state_path: &str) -> Result<Option<ShardsFile>> {
+    let path = Path::new(state_path).join(SHARDS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut c = String::new();
+    File::open(path)?.read_to_string(&mut c)?;
+    Ok(Some(toml::from_str(&c)?))
+}
+
+// Written to a temporary file first, so a crash leaves the old or the new shards.
+fn write_shards_file(state_path: &str, file: &ShardsFile) -> Result<()> {
+    let path = Path::new(state_path).join(SHARDS_FILE);
+    let tmp = path.with_extension("toml.tmp");
+    let mut f = File::create(&tmp)?;
+    f.write_all(toml::to_string(file)?.as_bytes())?;
+    f.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[derive(Deserialize,Serialize,Clone)]
+struct Config {
+    ip: String,
+    port: String,
+    // The servers keys are spread over ("host:port"), read when state_path has no
+    // shards yet. Changes made with `shard add` and `shard delete` are kept there.
+    shards: Vec<String>,
+    // Points of every shard on the hash ring. More spread keys more evenly, and every
+    // proxy in front of the same shards needs the same number.
+    #[serde(default = "default_virtual_nodes")]
+    virtual_nodes: u32,
+    // The user moving keys between shards ("user@password"), an admin on every shard,
+    // and the datafile it logs in with.
+    #[serde(default)]
+    admin_user: String,
+    #[serde(default = "default_database")]
+    database: String,
+    #[serde(default = "default_state_path")]
+    state_path: String,
+    // Seconds a shard may take to answer, 0 waits forever.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    // Largest request in bytes a client may send.
+    #[serde(default = "default_max_frame_size")]
+    max_frame_size: u32,
+}
+
+impl Config {
+    fn default() -> Self {
+        Config {
+            ip: "127.0.0.1".to_string(),
+            port: "11450".to_string(),
+            shards: vec!["127.0.0.1:11451".to_string()],
+            virtual_nodes: default_virtual_nodes(),
+            admin_user: String::new(),
+            database: default_database(),
+            state_path: default_state_path(),
+            timeout: default_timeout(),
+            max_frame_size: default_max_frame_size(),
+        }
+    }
+
+    fn get_proxy() -> Result<Self> {
+        let mut file = File::open("config/proxy.toml")?;
+        let mut c = String::new();
+        file.read_to_string(&mut c)?;
+        let config: Config = toml::from_str(c.as_str())?;
+        Ok(config)
+    }
+}
+
+fn default_virtual_nodes() -> u32 {
+    160
+}
+
+fn default_database() -> String {
+    "default.data".to_string()
+}
+
+fn default_state_path() -> String {
+    "./proxy/".to_string()
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME_SIZE
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+// A consistent hash ring. Every shard has `points` points on it, and a key belongs to
+// the shard of the first point at or after the hash of the key, so adding or removing
+// a shard only moves the keys next to its own points.
+#[derive(Clone)]
+pub struct Ring {
+    points: BTreeMap<u64, String>,
+    shards: Vec<String>,
+}
+
+impl Ring {
+    pub fn new(shards: &[String], points: u32) -> Self {
+        let mut sorted = shards.to_vec();
+        sorted.sort();
+        let mut ring = BTreeMap::new();
+        // Shards are placed in sorted order, so two proxies with the same shards agree
+        // on the rare point two shards hash to.
+        for shard in &sorted {
+            for i in 0..points {
+                ring.entry(hash(format!("{0}#{1}", shard, i).as_bytes())).or_insert_with(|| shard.clone());
+            }
+        }
+        Ring {
+            points: ring,
+            shards: shards.to_vec(),
+        }
+    }
+
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        let h = hash(key.as_bytes());
+        self.points
+            .range(h..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, shard)| shard.as_str())
+    }
+
+    pub fn shards(&self) -> &[String] {
+        &self.shards
+    }
+
+    pub fn contains(&self, shard: &str) -> bool {
+        self.shards.iter().any(|s| s == shard)
+    }
+}
+
+// FNV-1a followed by the finalizer of SplitMix64, which spreads keys that only differ
+// in their last bytes. Where a key lives must not change between builds, which the
+// hashers of std do not promise.
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
@@ -1,10 +1,17 @@
-use std::io::{self, Write};
+use std::{
+    io::{self, Write, BufReader, BufWriter},
+    fs::File,
+    path::Path,
+};
 use super::{
     error::{RorError, Result},
     store::{
         kv::{DataStore, Value},
+        dump::{self, DumpFormat},
+        backup,
     },
     client::Client,
+    tls::TlsOptions,
     request::*,
     user::user::User,
     cmd::{
@@ -86,6 +93,9 @@ impl LocalRepl {
                             s = format!("{}\n{}", s, entry);
                         }
                         println!("{}\n", s);
+                    },
+                    List::Databases => {
+                        println!("Listing databases is only available when connected to a server\n");
                     }
                 }
             },
@@ -101,6 +111,39 @@ impl LocalRepl {
                     }
                 }
             },
+            Statement::Dump { path } => {
+                let mut writer = BufWriter::new(File::create(&path)?);
+                let count = dump::dump(&mut self.database, DumpFormat::from_path(&path), &mut writer)?;
+                println!("Successfully dumped {0} entries to '{1}'\n", count, path);
+            },
+            Statement::Load { path } => {
+                let mut reader = BufReader::new(File::open(&path)?);
+                let count = dump::restore(&mut self.database, DumpFormat::from_path(&path), &mut reader)?;
+                println!("Successfully loaded {0} entries from '{1}'\n", count, path);
+            },
+            Statement::Backup { name } => {
+                let manifest = self.database.checkpoint(&name)?;
+                println!("Successfully backed up '{0}' to '{1}' at {2}\n", self.database.path, name, manifest.created);
+            },
+            Statement::Restore { snapshot, file, dest } => {
+                backup::restore_snapshot(Path::new(&snapshot), &file, Path::new(&dest))?;
+                println!("Successfully restored '{0}' from '{1}' into '{2}'\n", file, snapshot, dest);
+            },
+            Statement::Watch { key: _, timeout: _ } => {
+                println!("Watch is only available when connected to a server\n");
+            },
+            Statement::CreateDatabase { path: _ } | Statement::DropDatabase { path: _ } => {
+                println!("Creating and dropping databases is only available when connected to a server\n");
+            },
+            Statement::Ping => {
+                println!("Ping is only available when connected to a server\n");
+            },
+            Statement::Publish { .. } | Statement::Subscribe { .. } => {
+                println!("Channels are only available when connected to a server\n");
+            },
+            Statement::Shutdown | Statement::Clients { cmd: _ } | Statement::ServerInfo | Statement::Cluster { cmd: _ } | Statement::Shard { cmd: _ } => {
+                println!("Admin commands are only available when connected to a server\n");
+            },
             Statement::Quit => quit_program()
         }
         Ok(())
@@ -114,6 +157,17 @@ pub struct ConnectionInfo {
     user_name: String, 
     password: String, 
     db_path: String,
+    tls: Option<TlsOptions>,
+}
+
+impl ConnectionInfo {
+    fn connect(&self) -> Result<Client> {
+        let info = self.clone();
+        match &self.tls {
+            Some(tls) => Client::connect_tls(info.ip, info.port, info.user_name, info.password, info.db_path, tls),
+            None => Client::connect(info.ip, info.port, info.user_name, info.password, info.db_path),
+        }
+    }
 }
 
 pub struct RemoteRepl {
@@ -127,28 +181,28 @@ impl RemoteRepl {
         port: String,
         user_name: String, 
         password: String, 
-        db_path: String
+        db_path: String,
+        tls: Option<TlsOptions>,
     ) -> Result<Self> {
-        let client = Client::connect(
-            ip.clone(),
-            port.clone(),
-            user_name.clone(),
-            password.clone(),
-            db_path.clone()
-        )?;
         let info = ConnectionInfo {
-            ip: ip,
-            port: port,
-            user_name: user_name,
-            password: password,
-            db_path: db_path,
+            ip,
+            port,
+            user_name,
+            password,
+            db_path,
+            tls,
         };
+        let client = info.connect()?;
         Ok(Self {client,info})
     }
     pub fn run(&mut self) {
         loop {
             match self.match_command() {
                 Ok(()) => continue,
+                Err(RorError::ServerShutdown) => {
+                    output_prompt("The server is shutting down, quit program");
+                    break;
+                }
                 Err(RorError::ConnectionLost(op)) => {
                     output_prompt("Lost connection, trying to reconnect...");
                     match self.reconnect() {
@@ -194,8 +248,58 @@ impl RemoteRepl {
             Statement::Get { key } => OperateRequest::Get { key },
             Statement::Compact => OperateRequest::Compact,
             Statement::TypeOf { key } => OperateRequest::GetType { key },
-            Statement::Open { file: _ } => return Ok(()),
-            Statement::List { list: _ } => return Ok(()),
+            Statement::Open { file } => {
+                match self.client.operate(OperateRequest::Open { path: file.clone() })? {
+                    OperateResult::Success => {
+                        println!("successfully opened '{}' \n", file);
+                        self.info.db_path = file;
+                    }
+                    result => Self::match_op_reply(result),
+                }
+                return Ok(());
+            },
+            Statement::List { list: List::Databases } => OperateRequest::ListDatabases,
+            Statement::List { list } => {
+                let s = self.list(list)?;
+                println!("{}\n", s);
+                return Ok(());
+            },
+            Statement::CreateDatabase { path } => OperateRequest::CreateDatabase { path },
+            Statement::Clients { cmd: ClientsCmd::List } => OperateRequest::ListClients,
+            Statement::Clients { cmd: ClientsCmd::Kill { address } } => OperateRequest::KillClient { address },
+            Statement::ServerInfo => OperateRequest::ServerInfo,
+            Statement::Cluster { cmd: ClusterCmd::Add { address } } => OperateRequest::AddMember { address },
+            Statement::Cluster { cmd: ClusterCmd::Delete { address } } => OperateRequest::RemoveMember { address },
+            Statement::Shard { cmd: ShardCmd::Add { address } } => OperateRequest::AddShard { address },
+            Statement::Shard { cmd: ShardCmd::Delete { address } } => OperateRequest::RemoveShard { address },
+            Statement::Shard { cmd: ShardCmd::List } => OperateRequest::ListShards,
+            Statement::DropDatabase { path } => OperateRequest::DropDatabase { path },
+            Statement::Dump { path: _ } | Statement::Load { path: _ } => {
+                println!("Dump and load are only available in local mode, use 'rdb dump' or 'rdb restore' on the server\n");
+                return Ok(());
+            },
+            Statement::Backup { name } => OperateRequest::Backup { dest: name },
+            Statement::Restore { snapshot, file, dest } => OperateRequest::Restore { snapshot, file, dest },
+            Statement::Watch { key, timeout } => {
+                println!("Waiting for '{}' to change...", key);
+                OperateRequest::Watch { key, timeout }
+            },
+            Statement::Publish { channel, value, datatype } => {
+                OperateRequest::Publish {
+                    channel,
+                    value: to_value(value, datatype)?,
+                }
+            },
+            // Messages are printed until the program is stopped, the prompt does not
+            // come back.
+            Statement::Subscribe { channels, patterns } => {
+                self.client.subscribe_channels(channels, patterns)?;
+                println!("Reading messages, press Ctrl-C to quit");
+                while let Some(message) = self.client.next_message()? {
+                    println!("{}", message);
+                }
+                return Ok(());
+            },
             Statement::User { cmd } => {
                 match cmd {
                     UserCmd::Create { info } => {
@@ -210,6 +314,18 @@ impl RemoteRepl {
                     }
                 }
             },
+            Statement::Ping => {
+                let elapsed = self.client.ping()?;
+                println!("Pong from {0}:{1} in {2:.2} ms\n", self.info.ip, self.info.port, elapsed.as_secs_f64() * 1000.0);
+                return Ok(());
+            },
+            Statement::Shutdown => {
+                match self.client.operate(OperateRequest::Shutdown)? {
+                    OperateResult::Success => return Err(RorError::ServerShutdown),
+                    result => Self::match_op_reply(result),
+                }
+                return Ok(());
+            },
             Statement::Quit => {
                 let _ = self.client.operate(OperateRequest::Quit);
                 quit_program();
@@ -223,24 +339,85 @@ impl RemoteRepl {
 
     fn match_op_reply(result: OperateResult) {
         match result {
-            OperateResult::Found(_) => (),
+            OperateResult::Found(v) => println!("{}\n", v),
             OperateResult::Type(t) => println!("{}\n", t),
             OperateResult::Success => println!("Successfully completed the request\n"),
             OperateResult::PermissionDenied => println!("Permission Denied\n"),
             OperateResult::KeyNotFound => println!("Key not found\n"),
             OperateResult::Failure => println!("The request failed, possibly due to a server error\n"),
+            OperateResult::Change(event) => println!("{}\n", event),
+            OperateResult::KeyChanged { key, value: Some(v) } => println!("Key '{0}' changed: {1}\n", key, v),
+            OperateResult::KeyChanged { key, value: None } => println!("Key '{0}' deleted\n", key),
+            OperateResult::Timeout => println!("Timed out\n"),
+            OperateResult::Pong => println!("Pong\n"),
+            OperateResult::Shutdown => println!("The server is shutting down\n"),
+            OperateResult::Clients(clients) => {
+                let mut s = String::new();
+                for client in clients {
+                    s = format!("{}\n{}", s, client);
+                }
+                println!("{}\n", s);
+            },
+            OperateResult::ServerInfo(info) => println!("{}\n", info),
+            OperateResult::Entries { items, next_cursor: _ } => {
+                let mut s = String::new();
+                for (key, value) in items {
+                    s = format!("{}\n{} : {}", s, key, value);
+                }
+                println!("{}\n", s);
+            },
+            OperateResult::Databases(names) => {
+                let mut s = String::new();
+                for name in names {
+                    s = format!("{}\n{}", s, name);
+                }
+                println!("{}\n", s);
+            },
+            OperateResult::Position { offset } => println!("Leader log at offset {}\n", offset),
+            OperateResult::ReadOnly => println!("The server is a read-only follower\n"),
+            OperateResult::Redirect { leader: Some(leader) } => println!("The server is not the leader, the leader is {}\n", leader),
+            OperateResult::Redirect { leader: None } => println!("The cluster has no leader at the moment\n"),
+            OperateResult::RateLimited => println!("Too many requests, try again later\n"),
+            OperateResult::Uncommitted { .. } => println!("The server lost the lead before the write was committed, it may or may not have been applied\n"),
+            OperateResult::Message(message) => println!("{}\n", message),
+            OperateResult::Published { receivers } => println!("Sent to {} subscribers\n", receivers),
+            OperateResult::Shards(shards) => {
+                let mut s = String::new();
+                for shard in shards {
+                    s = format!("{}\n{}", s, shard);
+                }
+                println!("{}\n", s);
+            },
+        }
+    }
+
+    // Fetches every page of values or entries and formats them like LocalRepl.
+    fn list(&mut self, list: List) -> Result<String> {
+        let mut s = String::new();
+        let mut cursor = None;
+        loop {
+            let (items, next_cursor) = match self.client.operate(OperateRequest::List { cursor, limit: MAX_PAGE_SIZE })? {
+                OperateResult::Entries { items, next_cursor } => (items, next_cursor),
+                result => {
+                    Self::match_op_reply(result);
+                    return Ok(s);
+                }
+            };
+            for (key, value) in items {
+                s = match list {
+                    List::Values => format!("{}\n{}", s, value),
+                    _ => format!("{}\n{} : {}", s, key, value),
+                };
+            }
+            cursor = match next_cursor {
+                Some(c) => Some(c),
+                None => return Ok(s),
+            };
         }
     }
 
     fn reconnect(&mut self) -> Result<()> {
-        let info = self.info.clone();
-        self.client = Client::connect(
-            info.ip,
-            info.port,
-            info.user_name,
-            info.password,
-            info.db_path
-        )?;
+        self.client = self.info.connect()?;
         Ok(())
     }
 }
@@ -250,7 +427,7 @@ fn to_value(v: ValueP, data_type: ValueType) -> Result<Value> {
         ValueType::Null => Value::Null,
         ValueType::Bool => {
             if let ValueP::Bool(b) = v {
-                Value::Bool(b);
+                return Ok(Value::Bool(b));
             }
             return Err(RorError::ConvertError(v.get_str(), data_type));
         },
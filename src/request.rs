@@ -1,21 +1,66 @@
 use super::{
-    store::kv::Value,
-    error::Result,
+    store::{
+        kv::Value,
+        cdc::ChangeEvent,
+    },
+    error::{RorError, Result},
 };
 use serde::{Serialize,Deserialize,de::DeserializeOwned};
+use std::fmt;
 
-pub const USIZE_SIZE: usize = std::mem::size_of::<usize>();
+// The length of a frame is a big-endian u32 on every platform.
+pub const LEN_SIZE: usize = std::mem::size_of::<u32>();
+pub const ID_SIZE: usize = std::mem::size_of::<u64>();
+pub const HEAD_SIZE: usize = MAGIC_SIZE + LEN_SIZE;
 
+// Frames larger than this are refused, so a peer cannot make the other side allocate
+// an arbitrary amount of memory. Before login the server only accepts small frames.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+pub const HANDSHAKE_MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+// Every frame starts with these bytes, so anything that is not an rdb client or server
+// is rejected before its data is parsed.
+pub const MAGIC: [u8; 4] = *b"RDB\x00";
+pub const MAGIC_SIZE: usize = MAGIC.len();
+
+// Bumped whenever the encoding of a request or reply changes. The server accepts
+// clients from MIN_PROTOCOL_VERSION up to PROTOCOL_VERSION. Version 1 has the requests
+// and replies up to Quit and Failure; version 2 adds the rest.
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// The most entries a List or Scan returns at once. Larger limits are lowered to it,
+// and a limit of 0 means this many.
+pub const MAX_PAGE_SIZE: u32 = 1000;
+
+// Capability flags exchanged in the handshake. The server answers with the flags both
+// sides support, and a client only uses what is in that answer.
+pub const CAP_TLS: u32 = 1 << 1;
+pub const CAP_PIPELINING: u32 = 1 << 2;
+pub const CAP_STREAMING: u32 = 1 << 3;
+pub const CAPABILITIES: u32 = CAP_PIPELINING | CAP_STREAMING;
+
+// `version` must stay the first field, so a server can read it from requests of any
+// version before it tries to decode the rest.
 #[derive(Serialize, Deserialize)]
 pub struct ConnectRequest {
+    pub version: u16,
+    pub capabilities: u32,
     pub db_path: String,
     pub user_name: String,
     pub password: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ProtocolVersion {
+    pub version: u16,
+}
+
+// The order of the variants must not change between versions, so every client can
+// read the UnsupportedVersion error.
 #[derive(Serialize, Deserialize)]
 pub enum ConnectReply {
-    Success,
+    Success { version: u16, capabilities: u32, max_frame_size: u32 },
     Error(ConnectError),
 }
 
@@ -27,6 +72,10 @@ pub enum ConnectError {
     OpenFileError,
     PathError,
     ServerError,
+    UnsupportedVersion { min: u16, max: u16 },
+    // The server is at its limit of connections in total, for the user or for the
+    // client's address.
+    TooManyConnections,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -39,10 +88,46 @@ pub enum OperateRequest {
     DeleteUser { name: String },
     GetType { key: String },
     Compact,
+    Backup { dest: String },
+    Restore { snapshot: String, file: String, dest: String },
+    Subscribe { from_offset: Option<u64>, prefix: Option<String> },
+    Unsubscribe,
+    Watch { key: String, timeout: u64 },
+    Ping,
     Quit,
+    ListDatabases,
+    CreateDatabase { path: String },
+    DropDatabase { path: String },
+    // Entries in key order, `limit` at a time. Pass the `next_cursor` of the previous
+    // page as `cursor` to get the next one.
+    List { cursor: Option<String>, limit: u32 },
+    Scan { prefix: String, cursor: Option<String>, limit: u32 },
+    Shutdown,
+    ListClients,
+    KillClient { address: String },
+    ServerInfo,
+    // Streams every entry of the log, then a Position once they have been replayed and
+    // again every second; the changes after it follow like with Subscribe. Followers
+    // use it to copy a datafile from their leader.
+    Replicate,
+    // Membership changes of a Raft cluster, by the Raft address of the server. Only
+    // the leader makes them, one at a time.
+    AddMember { address: String },
+    RemoveMember { address: String },
+    // Shard changes of a proxy, by the address of the server. The keys that belong to
+    // another shard afterwards are moved in the background.
+    AddShard { address: String },
+    RemoveShard { address: String },
+    ListShards,
+    // Sends a message to the clients subscribed to `channel` on this server. Messages
+    // are not stored, a client only gets those published while it is subscribed.
+    Publish { channel: String, value: Value },
+    // Streams the messages published to `channels` and to the channels matching one of
+    // the glob `patterns`. Like Subscribe, the next request ends the stream.
+    SubscribeChannels { channels: Vec<String>, patterns: Vec<String> },
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum OperateResult {
     Found(Value),
     Type(String),
@@ -50,25 +135,374 @@ pub enum OperateResult {
     PermissionDenied,
     KeyNotFound,
     Failure,
+    Change(ChangeEvent),
+    KeyChanged { key: String, value: Option<Value> },
+    Timeout,
+    Pong,
+    Databases(Vec<String>),
+    // None as `next_cursor` means there are no more entries.
+    Entries { items: Vec<(String, Value)>, next_cursor: Option<String> },
+    // Sent when the server is going away, with the id of a request it will not answer,
+    // or id 0.
+    Shutdown,
+    Clients(Vec<ClientInfo>),
+    ServerInfo(ServerInfo),
+    // The end of the log on the leader, in a replication stream.
+    Position { offset: u64 },
+    // The request would write, and the server is a follower that only serves reads.
+    ReadOnly,
+    // The server is not the leader of its Raft cluster. `leader` is the address clients
+    // reach the leader at, None while the cluster is electing one.
+    Redirect { leader: Option<String> },
+    Shards(Vec<ShardInfo>),
+    // A message on a channel, in a stream started with SubscribeChannels.
+    Message(ChannelMessage),
+    // How many subscriptions a published message was sent to.
+    Published { receivers: u64 },
+    // The user has made more requests than the server's rate limit allows, the request
+    // was not executed.
+    RateLimited,
+    // The server appended the write to its Raft log but lost the lead before the entry
+    // was committed. The next leader may still apply it or drop it, so whether the write
+    // took effect is unknown. `leader` is like in Redirect.
+    Uncommitted { leader: Option<String> },
+}
+
+// Replies are encoded for the protocol version the client speaks. Variants added in a
+// later version would not decode there, so they are replaced with an error the client
+// knows.
+pub trait Downgrade {
+    fn downgrade(self, version: u16) -> Self;
+}
+
+impl OperateResult {
+    // The version that added the variant.
+    fn since(&self) -> u16 {
+        match self {
+            OperateResult::Found(_)
+            | OperateResult::Type(_)
+            | OperateResult::Success
+            | OperateResult::PermissionDenied
+            | OperateResult::KeyNotFound
+            | OperateResult::Failure => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl Downgrade for OperateResult {
+    fn downgrade(self, version: u16) -> Self {
+        match self.since() > version {
+            true => OperateResult::Failure,
+            false => self,
+        }
+    }
+}
+
+impl Downgrade for ConnectReply {
+    fn downgrade(self, version: u16) -> Self {
+        match self {
+            ConnectReply::Error(ConnectError::TooManyConnections) if version < 2 => ConnectReply::Error(ConnectError::ServerError),
+            reply => reply,
+        }
+    }
+}
+
+// A connected client as shown by `clients list`. The user and database are empty until
+// the client has logged in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientInfo {
+    pub address: String,
+    pub user: String,
+    pub database: String,
+    pub connected_since: String,
+    pub last_command: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+    pub protocol_version: u16,
+    pub uptime: u64,
+    pub clients: usize,
+    pub workers: usize,
+    pub databases: Vec<String>,
+    // Resident memory in bytes, where the platform reports it.
+    pub memory: Option<u64>,
+    // The server configuration as TOML, without the password of local_user.
+    pub config: String,
+    // "leader" or "follower", and on a follower how far each datafile has been copied.
+    pub role: String,
+    pub replication: Vec<ReplicationInfo>,
+    // The Raft state of a server in a cluster.
+    pub cluster: Option<Box<ClusterInfo>>,
+}
+
+// The replication of one datafile on a follower. Offsets are positions in the log of
+// the leader, and `lag` the seconds since the follower last had everything the leader
+// had, None while it has not caught up once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicationInfo {
+    pub database: String,
+    pub state: String,
+    pub offset: u64,
+    pub leader_offset: u64,
+    pub lag: Option<u64>,
+}
+
+// Log positions are entry indexes. `snapshot` is the last entry the snapshot replaced
+// the log up to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClusterInfo {
+    pub term: u64,
+    pub leader: Option<String>,
+    pub last_index: u64,
+    pub commit: u64,
+    pub applied: u64,
+    pub snapshot: u64,
+    pub members: Vec<MemberInfo>,
+}
+
+// A member as this server sees it. Only the leader knows how much of its log the others
+// have, `matched`, and the leader's `last_contact` is the seconds since it answered,
+// while a follower reports the seconds since it last heard from the leader.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberInfo {
+    pub address: String,
+    pub state: String,
+    pub matched: Option<u64>,
+    pub last_contact: Option<u64>,
+}
+
+// `pattern` is the pattern the channel matched, None for a subscription to the channel
+// itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelMessage {
+    pub channel: String,
+    pub pattern: Option<String>,
+    pub value: Value,
+}
+
+// A backend of a proxy. `state` is "serving", or "joining" or "leaving" while keys are
+// moved to or from it, and `moved` the keys moved so far.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardInfo {
+    pub address: String,
+    pub state: String,
+    pub moved: u64,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{0}  user: {1}  database: {2}  connected since: {3}  last command: {4}",
+            self.address,
+            self.user,
+            self.database,
+            self.connected_since,
+            self.last_command.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+impl fmt::Display for ServerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "version: {0} (protocol {1})", self.version, self.protocol_version)?;
+        writeln!(f, "uptime: {} s", self.uptime)?;
+        writeln!(f, "clients: {}", self.clients)?;
+        writeln!(f, "workers: {}", self.workers)?;
+        match self.memory {
+            Some(m) => writeln!(f, "memory: {:.1} MiB", m as f64 / (1024.0 * 1024.0))?,
+            None => writeln!(f, "memory: unknown")?,
+        }
+        writeln!(f, "open databases:")?;
+        for db in &self.databases {
+            writeln!(f, "  {}", db)?;
+        }
+        writeln!(f, "role: {}", self.role)?;
+        for replica in &self.replication {
+            writeln!(f, "  {}", replica)?;
+        }
+        if let Some(cluster) = &self.cluster {
+            writeln!(f, "  {}", cluster)?;
+            for member in &cluster.members {
+                writeln!(f, "    {}", member)?;
+            }
+        }
+        write!(f, "config:\n{}", self.config)
+    }
+}
+
+impl fmt::Display for ReplicationInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lag = match self.lag {
+            Some(s) => format!("{} s", s),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{0}  {1}  offset: {2}/{3}  behind: {4} bytes  lag: {5}",
+            self.database,
+            self.state,
+            self.offset,
+            self.leader_offset,
+            self.leader_offset.saturating_sub(self.offset),
+            lag,
+        )
+    }
 }
 
+impl fmt::Display for ClusterInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "term: {0}  leader: {1}  log: {2} (committed {3}, applied {4}, snapshot {5})",
+            self.term,
+            self.leader.as_deref().unwrap_or("-"),
+            self.last_index,
+            self.commit,
+            self.applied,
+            self.snapshot,
+        )
+    }
+}
+
+impl fmt::Display for MemberInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let matched = match self.matched {
+            Some(m) => m.to_string(),
+            None => "-".to_string(),
+        };
+        let contact = match self.last_contact {
+            Some(s) => format!("{} s", s),
+            None => "-".to_string(),
+        };
+        write!(f, "{0}  {1}  matched: {2}  last contact: {3}", self.address, self.state, matched, contact)
+    }
+}
+
+impl fmt::Display for ChannelMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Some(pattern) => write!(f, "[{0}] ({1}) {2}", self.channel, pattern, self.value),
+            None => write!(f, "[{0}] {1}", self.channel, self.value),
+        }
+    }
+}
+
+impl fmt::Display for ShardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state.as_str() {
+            "serving" => write!(f, "{0}  {1}", self.address, self.state),
+            _ => write!(f, "{0}  {1}  moved: {2} keys", self.address, self.state, self.moved),
+        }
+    }
+}
+
+impl OperateRequest {
+    // The name of the request as shown by `clients list`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OperateRequest::Open { .. } => "open",
+            OperateRequest::Get { .. } => "get",
+            OperateRequest::Add { .. } => "add",
+            OperateRequest::Delete { .. } => "delete",
+            OperateRequest::CreateUser { .. } => "user create",
+            OperateRequest::DeleteUser { .. } => "user delete",
+            OperateRequest::GetType { .. } => "typeof",
+            OperateRequest::Compact => "compact",
+            OperateRequest::Backup { .. } => "backup",
+            OperateRequest::Restore { .. } => "restore",
+            OperateRequest::Subscribe { .. } => "subscribe",
+            OperateRequest::Unsubscribe => "unsubscribe",
+            OperateRequest::Watch { .. } => "watch",
+            OperateRequest::Ping => "ping",
+            OperateRequest::Quit => "quit",
+            OperateRequest::ListDatabases => "list databases",
+            OperateRequest::CreateDatabase { .. } => "create database",
+            OperateRequest::DropDatabase { .. } => "drop database",
+            OperateRequest::List { .. } => "list",
+            OperateRequest::Scan { .. } => "scan",
+            OperateRequest::Shutdown => "shutdown",
+            OperateRequest::ListClients => "clients list",
+            OperateRequest::KillClient { .. } => "clients kill",
+            OperateRequest::ServerInfo => "server info",
+            OperateRequest::Replicate => "replicate",
+            OperateRequest::AddMember { .. } => "cluster add",
+            OperateRequest::RemoveMember { .. } => "cluster delete",
+            OperateRequest::AddShard { .. } => "shard add",
+            OperateRequest::RemoveShard { .. } => "shard delete",
+            OperateRequest::ListShards => "shard list",
+            OperateRequest::Publish { .. } => "publish",
+            OperateRequest::SubscribeChannels { .. } => "subscribe channels",
+        }
+    }
+}
+
+// A frame is the magic bytes, the body length, the request id and the bincode body. Replies carry the
+// id of the request they answer, and change events and watch results the id of the
+// Subscribe or Watch request, so a client can have many requests in flight.
 pub struct Message<T> { 
-    message: T,
+    pub id: u64,
+    pub message: T,
 }
 
 impl<T: Serialize + DeserializeOwned> Message<T>{
     pub fn new(message: T) -> Self {
+        Self::with_id(0, message)
+    }
+
+    pub fn with_id(id: u64, message: T) -> Self {
         Self {
+            id,
             message
         }
     }
+
     pub fn as_bytes(&self) -> Result<(Vec<u8>, usize)> {
         let body_buf = bincode::serialize(&self.message)?;
-        let body_size = body_buf.len();
-        let size = USIZE_SIZE + body_size;
-        let mut buf = vec![0; size];
-        buf[0..USIZE_SIZE].copy_from_slice(&body_size.to_be_bytes());
-        buf[USIZE_SIZE..].copy_from_slice(&body_buf);
+        let body_size = ID_SIZE + body_buf.len();
+        let len = match u32::try_from(body_size) {
+            Ok(l) => l,
+            Err(_) => return Err(RorError::FrameTooLarge(body_size, u32::MAX as usize)),
+        };
+        let mut buf = vec![0; HEAD_SIZE + body_size];
+        buf[0..MAGIC_SIZE].copy_from_slice(&MAGIC);
+        buf[MAGIC_SIZE..HEAD_SIZE].copy_from_slice(&len.to_be_bytes());
+        buf[HEAD_SIZE..HEAD_SIZE + ID_SIZE].copy_from_slice(&self.id.to_be_bytes());
+        buf[HEAD_SIZE + ID_SIZE..].copy_from_slice(&body_buf);
         Ok((buf, body_size))
     }
+
+    // Decodes a frame without its magic bytes and length.
+    pub fn from_frame(frame: &[u8]) -> Result<Self> {
+        let id = match frame_id(frame) {
+            Some(id) => id,
+            None => return Err(RorError::IncompleteData),
+        };
+        Ok(Self {
+            id,
+            message: bincode::deserialize(&frame[ID_SIZE..])?,
+        })
+    }
+}
+
+// Reads the length from the head of a frame, None if the magic bytes are wrong.
+pub fn frame_len(head: &[u8]) -> Option<usize> {
+    if head.get(..MAGIC_SIZE)? != MAGIC {
+        return None;
+    }
+    let mut len_buffer = [0; LEN_SIZE];
+    len_buffer.copy_from_slice(head.get(MAGIC_SIZE..HEAD_SIZE)?);
+    Some(u32::from_be_bytes(len_buffer) as usize)
+}
+
+// The id of a frame whose body may not decode, to answer it with an error.
+pub fn frame_id(frame: &[u8]) -> Option<u64> {
+    let mut id_buffer = [0; ID_SIZE];
+    id_buffer.copy_from_slice(frame.get(..ID_SIZE)?);
+    Some(u64::from_be_bytes(id_buffer))
 }
\ No newline at end of file
@@ -2,23 +2,38 @@ use std::{
     io::{
         Read,
         Write,
-        BufReader,
         ErrorKind,
     },
     fs,
-    net::{TcpListener, TcpStream, Shutdown, SocketAddr},
+    net::SocketAddr,
     fs::File,
-    collections::HashMap,
-    sync::{Arc,Mutex},
-    thread::{self, JoinHandle},
-    time,
-    path::{PathBuf,Path},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+    panic::{self, AssertUnwindSafe},
+    path::{PathBuf,Path,Component},
+};
+use mio::{
+    Events,
+    Interest,
+    Poll,
+    Token,
+    Waker,
+    net::TcpListener,
 };
 use super::{
     error::{RorError,Result},
     store::{
-        kv::DataStore,
+        kv::{DataStore, Value, hint_path},
         kv_error::KvError,
+        backup::{self, Checkpoint, Manifest},
+        cdc::{ChangeEvent, ChangeKind},
     },
     user::{
         user::{self,User},
@@ -26,25 +41,190 @@ use super::{
     },
     request::*,
     repl::RemoteRepl,
+    connection::Connection,
+    pool::ThreadPool,
+    tls::{self, TlsOptions},
+    http::{self, Response},
+    metrics::{Metrics, DatabaseStats},
 };
 use serde::{Serialize,Deserialize};
+use socket2::{SockRef, TcpKeepalive};
 use same_file::is_same_file;
-use chrono::prelude::Local;
-use bincode;
+use chrono::prelude::{DateTime, Local};
 use colored::Colorize;
 
+mod rest;
+mod redis;
+mod memcached;
+mod replica;
+mod raft;
+mod audit;
+mod limit;
+
+type Databases = Arc<Mutex<HashMap<String, Arc<Mutex<DataStore>>>>>;
+type Watchers = Arc<Mutex<WatchRegistry>>;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+// Connections are checked for the idle timeout and the read and write deadlines at
+// this interval.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// Time between keepalive probes once a connection has been idle for `keepalive` seconds.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+// Threads answering scrapes of the metrics endpoint.
+const METRICS_WORKERS: usize = 2;
+// A connection may queue this many requests, and up to max_frame_size bytes of them,
+// behind the one running. Past either the server stops reading from it until the queue
+// drains, and it starts no request while more than MAX_UNSENT bytes of replies wait for
+// the client. A client that sends without reading fills its own socket buffers rather
+// than the memory of the server.
+const MAX_PENDING_REQUESTS: usize = 64;
+const MAX_UNSENT: usize = 4 * 1024 * 1024;
+// Changes a stream may have on their way to the client while its output is over
+// MAX_UNSENT. A replay waits for the client to catch up, STREAM_WAIT at a time, but a
+// subscriber that falls this far behind live changes is disconnected.
+const MAX_STREAM_BACKLOG: usize = 1024;
+const STREAM_WAIT: Duration = Duration::from_millis(10);
+
+// The server runs a single event loop that owns every socket. Requests are decoded in
+// the loop and executed on a thread pool; the workers hand the result back through
+// `Event`s, so a connection only costs a buffer and a state, not a thread.
 pub struct Server {
     config: Config,
-    dbs: HashMap<String, Arc<Mutex<DataStore>>>,
-    clients: HashMap<String, (String, JoinHandle<()>)>,
+    dbs: Databases,
+    watchers: Watchers,
+    peers: HashMap<Token, Peer>,
+    next_token: usize,
+    next_stream: u64,
+    next_sweep: Instant,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    // Set once the server is shutting down, to the time it stops waiting for the
+    // requests in progress.
+    shutdown: Option<Instant>,
+    // The same as `shutdown.is_some()`, for the listeners outside the event loop.
+    stopping: Arc<AtomicBool>,
+    started: Instant,
+    metrics: Arc<Metrics>,
+    // How far a follower has copied each datafile of its leader.
+    replication: replica::Status,
+    // This server's member of its Raft cluster.
+    raft: Option<raft::Raft>,
+    channels: ChannelRegistry,
+    limiter: Arc<limit::Limiter>,
+    audit: audit::Audit,
+}
+
+// Clients blocked in a Watch, keyed by datafile and key. A watch fires once, so the Add
+// and Delete handlers take every watcher of the key they changed.
+#[derive(Default)]
+struct WatchRegistry {
+    next_id: u64,
+    watchers: HashMap<(String, String), Vec<Watcher>>,
+}
+
+// Connections subscribed to channels. Messages are published in the event loop, which
+// owns the connections, so they go straight into the write buffers of the subscribers,
+// up to MAX_UNSENT bytes each.
+#[derive(Default)]
+struct ChannelRegistry {
+    subscriptions: HashMap<Token, Subscription>,
+}
+
+// Messages are sent with the id of the SubscribeChannels request.
+struct Subscription {
+    request: u64,
+    channels: HashSet<String>,
+    patterns: Vec<String>,
+}
+
+type Notify = Box<dyn FnOnce(u64, OperateResult) + Send>;
+type Watcher = (u64, Notify);
+
+struct Peer {
+    conn: Connection,
+    state: State,
+    // Frames that arrived while an earlier request was still running. Requests on one
+    // connection are executed one at a time, so replies keep the request order.
+    pending: VecDeque<Vec<u8>>,
+    // Set while the server does not read from the connection because `pending` is full.
+    paused: bool,
+    stream: Option<Stream>,
+    last_active: Instant,
+    // What `clients list` shows. The user and database are set at login, and the
+    // database again after every request since Open may change it.
+    user: String,
+    db_path: String,
+    connected_since: DateTime<Local>,
+    last_command: Option<&'static str>,
+    // When the request in progress was received, for the latency metrics.
+    request_started: Option<Instant>,
+    // Taken when the connection is accepted, or why it was not. A connection without
+    // one is refused at login.
+    slot: std::result::Result<limit::Slot, String>,
+    // The protocol version of the client, replies it could not decode are downgraded.
+    version: u16,
+}
+
+// The change stream of a connection. Events are sent with the id of the Subscribe
+// request, and `active` tells the store handler whether they are still wanted. Events
+// wait in `held` while the client has more than MAX_UNSENT bytes to read; `backlog`
+// counts them together with those still on their way from the handler. A follower's
+// stream also gets the position of the log every sweep.
+struct Stream {
+    id: u64,
+    request: u64,
+    active: Arc<AtomicBool>,
+    backlog: Arc<AtomicUsize>,
+    held: VecDeque<OperateResult>,
+    replica: bool,
 }
 
-enum DataPath {
-    Exists(Arc<Mutex<DataStore>>),
-    None,
+// The store handler's end of a stream.
+#[derive(Clone)]
+struct Feed {
+    token: Token,
+    stream: u64,
+    active: Arc<AtomicBool>,
+    backlog: Arc<AtomicUsize>,
+    // Set once the replay is over and events come from writers, which must not wait.
+    live: Arc<AtomicBool>,
+    notifier: Notifier,
+}
+
+enum State {
+    Connecting,
+    Idle(Client),
+    Busy,
+    Watching { client: Client, id: u64, request: u64, key: String, deadline: Option<Instant> },
+    Streaming(Client),
+    Closing,
+}
+
+enum Reply {
+    Result(OperateResult),
+    Stream,
+}
+
+enum Event {
+    Connected { token: Token, request: u64, user: String, result: std::result::Result<Client, (ConnectError, RorError)> },
+    Done { token: Token, request: u64, client: Client, result: Result<Reply> },
+    Push { token: Token, stream: u64, result: OperateResult },
+    Overflowed { token: Token, stream: u64 },
+    Fired { token: Token, id: u64, result: OperateResult },
+    Panicked { token: Token },
+    Signal(i32),
+}
+
+// Hands events from the workers back to the event loop and wakes it up.
+#[derive(Clone)]
+struct Notifier {
+    sender: Sender<Event>,
+    waker: Arc<Waker>,
 }
 
 impl Server {
+    // Reads the configuration from the working directory, so there is no Default.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let config: Config = match Config::get_server() {
             Ok(config) => config,
@@ -53,13 +233,27 @@ impl Server {
                 Config::default()
             }
         };
-        return Self {
+        Self {
+            limiter: limit::Limiter::new(&config),
             config,
-            dbs: HashMap::new(),
-            clients: HashMap::new(),
-        };
+            dbs: Arc::new(Mutex::new(HashMap::new())),
+            watchers: Arc::new(Mutex::new(WatchRegistry::default())),
+            peers: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            next_stream: 0,
+            next_sweep: Instant::now(),
+            tls: None,
+            shutdown: None,
+            stopping: Arc::new(AtomicBool::new(false)),
+            started: Instant::now(),
+            metrics: Arc::new(Metrics::default()),
+            replication: Arc::new(Mutex::new(BTreeMap::new())),
+            raft: None,
+            channels: ChannelRegistry::default(),
+            audit: None,
+        }
     }
-    
+
     pub fn init() -> Result<()> {
         fs::create_dir("config")?;
         let server_config = toml::to_string(&Config::default())?;
@@ -79,12 +273,111 @@ impl Server {
 
     pub fn start(&mut self) -> Result<()> {
         let address = format!("{0}:{1}", self.config.ip, &self.config.port);
-        let listener = TcpListener::bind(address.clone())?;
+        let std_listener = std::net::TcpListener::bind(address.clone())?;
+        std_listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(std_listener);
         User::test_file()?;
+        self.tls = self.config.tls_config()?;
+
+        match self.config.role.as_str() {
+            "leader" => (),
+            "follower" if self.config.leader_address.is_empty() || !self.config.leader_user.contains('@') => {
+                return Err(RorError::ConfigError("a follower needs leader_address and leader_user".to_string()));
+            }
+            "follower" => (),
+            "raft" if self.config.raft_address.is_empty() => {
+                return Err(RorError::ConfigError("a Raft member needs raft_address".to_string()));
+            }
+            "raft" => (),
+            role => return Err(RorError::ConfigError(format!("role must be 'leader', 'follower' or 'raft', not '{}'", role))),
+        }
+
+        let mut poll = Poll::new()?;
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+        let (sender, receiver) = mpsc::channel();
+        let notifier = Notifier {
+            sender,
+            waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+        };
+        let pool = ThreadPool::new(self.config.workers);
+        #[cfg(unix)]
+        Self::handle_signals(&notifier)?;
 
-        output_prompt(format!("Server start: {}", address));
+        output_prompt(format!("Server start: {0}, {1} workers", address, pool.size()));
+        if self.tls.is_some() {
+            output_prompt("TLS is enabled");
+        }
+        if self.config.is_follower() {
+            let replica = replica::Replica::new(
+                self.config.clone(),
+                Arc::clone(&self.dbs),
+                Arc::clone(&self.watchers),
+                Arc::clone(&self.stopping),
+                Arc::clone(&self.replication),
+            );
+            Arc::new(replica).start();
+            output_prompt(format!("Following leader {}, only reads are served", self.config.leader_address));
+        }
+        if self.config.is_raft() {
+            self.raft = Some(raft::Node::start(
+                self.config.clone(),
+                Arc::clone(&self.dbs),
+                Arc::clone(&self.watchers),
+                Arc::clone(&self.stopping),
+            )?);
+        }
+        self.audit = audit::AuditLog::open(&self.config)?;
+        if self.audit.is_some() {
+            output_prompt(format!("Audit log: {}", self.config.audit_path));
+        }
+        if !self.config.metrics_address.is_empty() {
+            self.serve_metrics()?;
+            output_prompt(format!("Metrics: http://{}/metrics", self.config.metrics_address));
+        }
+        if !self.config.rest_address.is_empty() {
+            let listener = std::net::TcpListener::bind(&self.config.rest_address)?;
+            let gateway = rest::Gateway::new(
+                self.config.clone(),
+                Arc::clone(&self.dbs),
+                Arc::clone(&self.watchers),
+                self.raft.clone(),
+                self.audit.clone(),
+                Arc::clone(&self.limiter),
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.stopping),
+            );
+            let handler: http::Handler = Arc::new(move |request| gateway.handle(request));
+            http::serve(listener, self.config.workers, self.config.max_frame_size as usize, handler);
+            output_prompt(format!("REST gateway: http://{}/db/", self.config.rest_address));
+        }
+        if !self.config.redis_address.is_empty() {
+            let listener = std::net::TcpListener::bind(&self.config.redis_address)?;
+            let redis = redis::Redis::new(
+                self.config.clone(),
+                Arc::clone(&self.dbs),
+                Arc::clone(&self.watchers),
+                self.audit.clone(),
+                Arc::clone(&self.limiter),
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.stopping),
+            );
+            Arc::new(redis).serve(listener);
+            output_prompt(format!("Redis protocol: {}", self.config.redis_address));
+        }
+        if !self.config.memcached_address.is_empty() {
+            let listener = std::net::TcpListener::bind(&self.config.memcached_address)?;
+            let client = self.memcached_client(listener.local_addr()?)?;
+            let memcached = memcached::Memcached::new(
+                self.config.clone(),
+                client,
+                Arc::clone(&self.limiter),
+                Arc::clone(&self.metrics),
+                Arc::clone(&self.stopping),
+            );
+            Arc::new(memcached).serve(listener);
+            output_prompt(format!("memcached protocol: {}", self.config.memcached_address));
+        }
 
-        
         if self.config.repl {
             output_prompt(format!("Connect to local server in REPL mode, user: {}", &self.config.local_user));
 
@@ -97,386 +390,1844 @@ impl Server {
                     output_prompt("Failed to start REPL mode: invalid user");
                     return;
                 }
-                let mut repl = RemoteRepl::new(
+                let repl = RemoteRepl::new(
                     config_copy.ip.clone(),
                     config_copy.port.clone(),
                     user[0].to_string(),
                     user[1].to_string(),
-                    config_copy.default_db.clone()
-                ).unwrap();
-                repl.run();
+                    config_copy.default_db.clone(),
+                    config_copy.repl_tls(),
+                );
+                match repl {
+                    Ok(mut repl) => repl.run(),
+                    Err(e) => output_prompt(format!("Failed to start REPL mode: {}", e)),
+                }
             });
         }
 
-        std::thread::spawn( || {
-
-        });
-
+        let mut events = Events::with_capacity(1024);
         let mut accepted_times = 0;
+        let mut listener = Some(listener);
 
         loop {
-            if self.config.auto_refresh > 0 && accepted_times >= self.config.auto_refresh {
-                output_prompt("The server starts to refresh automatically...");
-                self.refresh()?;
-                output_prompt("Done!");
-                accepted_times = 0;
-            }
-            let (stream, adr) = match listener.accept() {
-                Ok(r) => r,
-                Err(e) => {
-                    output_prompt(format!("Unable to accept connection from a client: {0}",e));
+            let timeout = self.next_sweep.saturating_duration_since(Instant::now());
+            if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
                     continue;
                 }
-            };
-            output_prompt(format!("New connection: {}", adr));
-            accepted_times += 1;
-
-            match self.handle_connection(stream,adr) {
-                Ok(()) => continue,
-                Err(e) => output_prompt(
-                    format!("Client [{0}], failed to login. reason: {1}", 
-                        adr, 
-                        e,
-                    )
-                ),
+                return Err(RorError::IOError(e));
             }
-        }
-    }
 
-    fn handle_connection(&mut self, mut stream: TcpStream, address: SocketAddr) -> Result<()> {
-        let mut size_buffer = [0 as u8; USIZE_SIZE];
-        stream.read_exact(&mut size_buffer)?;
-        let size = usize::from_be_bytes(size_buffer);
-        let mut head_buffer = vec![0; size];
-        stream.read_exact(&mut head_buffer)?;
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => {
+                        if let Some(listener) = &listener {
+                            self.accept(listener, &poll, &mut accepted_times);
+                        }
+                    }
+                    WAKER => (),
+                    token => {
+                        if event.is_writable() {
+                            self.flush(token);
+                            self.drain_stream(token);
+                        }
+                        if event.is_readable() || event.is_read_closed() {
+                            self.receive(token);
+                        }
+                        // Also after a flush, which may have made room for the next reply.
+                        self.dispatch(token, &pool, &notifier);
+                    }
+                }
+            }
 
-        let head: ConnectRequest = match bincode::deserialize(&head_buffer) {
-            Ok(buf) => buf,
-            Err(e) => {
-                Self::send_error(&mut stream, ConnectError::RequestError)?;
-                return Err(RorError::BincodeError(e));
-            },
-        };
-        let user = match User::login(head.user_name,head.password) {
-            Ok(u) => u,
-            Err(UserError::UserNotFound(n)) => {
-                Self::send_error(&mut stream, ConnectError::UserNotFound)?;
-                return Err(RorError::UserError(UserError::UserNotFound(n)));
-            },
-            Err(UserError::WrongPassWord) => {
-                Self::send_error(&mut stream, ConnectError::PasswordError)?;
-                return Err(RorError::UserError(UserError::WrongPassWord));
-            },
-            Err(e) => {
-                Self::send_error(&mut stream, ConnectError::ServerError)?;
-                return Err(RorError::UserError(e));
+            while let Ok(event) = receiver.try_recv() {
+                self.handle_event(event, &pool, &notifier);
             }
-        };
-        let mut db_path_buf = PathBuf::new();
-        db_path_buf.push(&self.config.data_path);
-        db_path_buf.push(&head.db_path);
-        let db_path = match db_path_buf.into_os_string().into_string() {
-            Ok(s) => s,
-            Err(_) => {
-                Self::send_error(&mut stream, ConnectError::PathError)?;
-                return Err(RorError::PathError);
-            },
-        };
 
-        let stream_clone = match stream.try_clone() {
-            Ok(s) => s,
-            Err(e) => {
-                output_prompt(format!("[{0}] The reader cannot be created by the clone method, and the client is disconnected", &address));
-                Self::send_error(&mut stream, ConnectError::ServerError)?;
-                return Err(RorError::IOError(e));
+            if Instant::now() >= self.next_sweep {
+                self.sweep(&pool, &notifier);
             }
-        };
-        let reader = BufReader::new(stream_clone);
 
-        let target_path = Path::new(&db_path);
-        if !target_path.exists() {
-            File::create(&db_path)?;
-        }
+            if let Some(deadline) = self.shutdown {
+                if let Some(mut listener) = listener.take() {
+                    let _ = poll.registry().deregister(&mut listener);
+                }
+                if self.peers.is_empty() {
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    output_prompt(format!("{} clients did not finish in time and are disconnected", self.peers.len()));
+                    break;
+                }
+            }
 
-        let opened_db = match self.compare(db_path.clone(), &mut stream)? {
-            DataPath::Exists(db) => db,
-            DataPath::None => {
-                let db = match self.open_new_db(db_path.clone()) {
-                    Ok(db) => db,
-                    Err(e) => {
-                        Self::send_error(&mut stream, ConnectError::OpenFileError)?;
-                        return Err(e);
-                    },
-                };
-                db
+            if self.config.auto_refresh > 0 && accepted_times >= self.config.auto_refresh {
+                output_prompt("The server starts to refresh automatically...");
+                self.refresh()?;
+                output_prompt("Done!");
+                accepted_times = 0;
             }
-        };
+        }
 
-        if let Err(e) = Self::send_reply(&mut stream) {
-            stream.shutdown(Shutdown::Both)?;
-            return Err(e);
+        let tokens: Vec<Token> = self.peers.keys().copied().collect();
+        for token in tokens {
+            self.close(token);
         }
-        let client = Client {
-            stream,
-            reader,
-            db: opened_db,
-            level: user.level,
-            address,
-            timeout: 0,
-            set_timeout: self.config.timeout.clone(),
-        };
-        self.new_client(client,db_path);
+        // Waits for the workers and the Raft applier, so nothing writes to a datafile
+        // after it is closed.
+        drop(pool);
+        if let Some(raft) = &self.raft {
+            raft.stop();
+        }
+        self.close_stores();
+        output_prompt("Server stopped");
         Ok(())
     }
 
-    pub fn refresh(&mut self) -> Result<()> {
-        let mut should_close: HashMap<String, ()> = HashMap::new();
-        let mut dead_client: Vec<String> = Vec::new();
-        for (address, (path, thread_handle)) in &self.clients {
-            if thread_handle.is_finished() {
-                dead_client.push(address.clone());
-                should_close.insert(path.clone(),());
-                continue;
-            } else {
-                should_close.remove(path);
+    // SIGTERM and SIGINT start a graceful shutdown. A second signal stops waiting for
+    // the requests in progress.
+    #[cfg(unix)]
+    fn handle_signals(notifier: &Notifier) -> Result<()> {
+        use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let notifier = notifier.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if !notifier.send(Event::Signal(signal)) {
+                    break;
+                }
             }
-        }
-        for address in &dead_client {
-            let _ = &self.clients.remove(address);
-        }
-        for (path,()) in &should_close {
-            self.dbs.remove(path);
-        }
+        });
         Ok(())
     }
 
-    fn new_client(&mut self, mut client: Client, datafile_index: String) {
-        let address = client.address.to_string();
-        let thread_handle = thread::spawn(move || {
-            client.handle_client();
-        });
+    // Stops accepting connections and tells every client that the server is going
+    // away. Requests in progress may finish until the shutdown_timeout has passed.
+    fn begin_shutdown(&mut self, reason: &str) {
+        if self.shutdown.is_some() {
+            return;
+        }
+        let deadline = Instant::now() + Duration::from_secs(self.config.shutdown_timeout);
+        output_prompt(format!("Shutting down ({0}), waiting up to {1} seconds for requests in progress", reason, self.config.shutdown_timeout));
+        self.shutdown = Some(deadline);
+        self.stopping.store(true, Ordering::SeqCst);
+        self.next_sweep = self.next_sweep.min(deadline);
+        let tokens: Vec<Token> = self.peers.keys().copied().collect();
+        for token in tokens {
+            self.stop(token);
+        }
+    }
 
-        let _ = &self.clients.insert(
-            address,
-            (datafile_index, thread_handle)
-        );
+    // Answers a waiting watch or change stream and every request that has not started
+    // with Shutdown, or sends one Shutdown if there is nothing to answer, and closes the
+    // connection. A connection with a request in progress is stopped once it finished.
+    fn stop(&mut self, token: Token) {
+        let peer = match self.peers.get_mut(&token) {
+            Some(p) => p,
+            None => return,
+        };
+        let mut ids = Vec::new();
+        match std::mem::replace(&mut peer.state, State::Closing) {
+            State::Busy => {
+                peer.state = State::Busy;
+                return;
+            }
+            State::Closing => return,
+            State::Connecting => {
+                self.close(token);
+                return;
+            }
+            State::Watching { client, id, request, key, .. } => {
+                self.watchers.lock().unwrap().unregister(&client.db_path, &key, id);
+                ids.push(request);
+            }
+            State::Streaming(mut client) => {
+                client.end_subscription();
+                if let Some(stream) = peer.stream.take() {
+                    stream.active.store(false, Ordering::SeqCst);
+                    ids.push(stream.request);
+                }
+                ids.extend(self.channels.unsubscribe(token));
+            }
+            State::Idle(_) => (),
+        }
+        ids.extend(peer.pending.drain(..).filter_map(|frame| frame_id(&frame)));
+        if ids.is_empty() {
+            ids.push(0);
+        }
+        for id in ids {
+            if let Ok((buf, _)) = Message::with_id(id, OperateResult::Shutdown.downgrade(peer.version)).as_bytes() {
+                let _ = peer.conn.send(&buf);
+            }
+        }
+        self.flush(token);
     }
 
-    fn send_error(stream: &mut TcpStream, err: ConnectError) -> Result<()> {
-        let (buf, _) = Message::new(err).as_bytes()?;
-        stream.write(&buf.as_slice())?;
-        stream.shutdown(Shutdown::Both)?;
-        Ok(())
+    // Flushes every open datafile to disk and saves its index to a hint file.
+    fn close_stores(&mut self) {
+        let dbs = std::mem::take(&mut *self.dbs.lock().unwrap());
+        for (path, db) in dbs {
+            match db.lock().unwrap().write_hint() {
+                Ok(()) => output_prompt(format!("Closed '{}'", path)),
+                Err(e) => output_prompt(format!("Unable to close '{0}': {1}", path, e)),
+            }
+        }
     }
 
-    fn send_reply(stream: &mut TcpStream) -> Result<()> {
-        let (buf, _) = Message::new(ConnectReply::Success).as_bytes()?;
-        stream.write(&buf.as_slice())?;
+    // Scrapes are answered on threads of their own, reading the counters and the open
+    // datafiles while the event loop goes on.
+    fn serve_metrics(&self) -> Result<()> {
+        let listener = std::net::TcpListener::bind(&self.config.metrics_address)?;
+        let metrics = Arc::clone(&self.metrics);
+        let dbs = Arc::clone(&self.dbs);
+        let handler: http::Handler = Arc::new(move |request| {
+            if request.path != "/metrics" {
+                return Response::text(404, "Not found\n");
+            }
+            if request.method != "GET" {
+                return Response::text(405, "Only GET is allowed\n").with_header("Allow", "GET".to_string());
+            }
+            let open: Vec<(String, Arc<Mutex<DataStore>>)> = dbs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(path, db)| (path.clone(), Arc::clone(db)))
+                .collect();
+            let mut databases: Vec<DatabaseStats> = open
+                .into_iter()
+                .map(|(path, db)| {
+                    let db = db.lock().unwrap();
+                    DatabaseStats {
+                        path,
+                        keys: db.key_count(),
+                        size: db.position(),
+                        dead_bytes: db.dead_bytes(),
+                    }
+                })
+                .collect();
+            databases.sort_by(|a, b| a.path.cmp(&b.path));
+            Response::new(200, "text/plain; version=0.0.4; charset=utf-8", metrics.render(&databases))
+        });
+        http::serve(listener, METRICS_WORKERS, 0, handler);
         Ok(())
     }
 
-    fn open_new_db(&mut self, path: String) -> Result<Arc<Mutex<DataStore>>> {
-        let db = Arc::new(
-            Mutex::new(
-                DataStore::open(path.as_str())?
-            )
-        );
-        let arc_clone_db = Arc::clone(&db);
-        self.dbs.insert( path.clone(), db );
-        Ok(arc_clone_db)
+    // memcached clients cannot log in, they all act as memcached_user.
+    fn memcached_client(&self, address: SocketAddr) -> Result<Client> {
+        let (name, password) = match self.config.memcached_user.split_once('@') {
+            Some(u) => u,
+            None => return Err(RorError::ConfigError("memcached_user must be 'user@password'".to_string())),
+        };
+        let user = match User::login(name.to_string(), password.to_string()) {
+            Ok(u) => u,
+            Err(e) => return Err(RorError::ConfigError(format!("memcached_user cannot log in, {}", e))),
+        };
+        let db_path = resolve_db_path(&self.config, &self.config.memcached_database)?;
+        let mut client = Client::session(
+            name.to_string(),
+            user.level,
+            address,
+            db_path,
+            self.config.clone(),
+            Arc::clone(&self.dbs),
+            Arc::clone(&self.watchers),
+        )?;
+        client.raft = self.raft.clone();
+        client.audit = self.audit.clone();
+        Ok(client)
     }
 
-    fn compare(&mut self, path: String, stream: &mut TcpStream) -> Result<DataPath> {
-        for (key, db) in &self.dbs {
-            let exists = match is_same_file(&key, &path) {
-                Ok(b) => b,
+    fn accept(&mut self, listener: &TcpListener, poll: &Poll, accepted_times: &mut u32) {
+        loop {
+            let (mut stream, adr) = match listener.accept() {
+                Ok(r) => r,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
                 Err(e) => {
-                    Self::send_error(stream, ConnectError::ServerError)?;
-                    return Err(RorError::IOError(e));
+                    output_prompt(format!("Unable to accept connection from a client: {0}",e));
+                    return;
+                }
+            };
+            if let Err(e) = self.set_keepalive(&stream) {
+                output_prompt(format!("Unable to enable TCP keepalive for client [{0}]: {1}", adr, e));
+            }
+            let tls = match &self.tls {
+                Some(config) => match rustls::ServerConnection::new(Arc::clone(config)) {
+                    Ok(tls) => Some(tls),
+                    Err(e) => {
+                        output_prompt(format!("Unable to start TLS for client [{0}]: {1}", adr, e));
+                        continue;
+                    }
                 },
+                None => None,
             };
-            if exists {
-                let opened_db = Arc::clone(&db);
-                return Ok(DataPath::Exists(opened_db));
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                output_prompt(format!("Unable to accept connection from a client: {0}",e));
+                continue;
             }
+            output_prompt(format!("New connection: {}", adr));
+            *accepted_times += 1;
+            self.peers.insert(token, Peer {
+                conn: Connection::new(stream, adr, tls),
+                state: State::Connecting,
+                pending: VecDeque::new(),
+                paused: false,
+                stream: None,
+                last_active: Instant::now(),
+                user: String::new(),
+                db_path: String::new(),
+                connected_since: Local::now(),
+                last_command: None,
+                request_started: None,
+                slot: self.limiter.connect(adr.ip()),
+                version: MIN_PROTOCOL_VERSION,
+            });
+            self.metrics.connected(self.peers.len());
         }
-        return Ok(DataPath::None)
     }
 
-} 
+    fn set_keepalive(&self, stream: &mio::net::TcpStream) -> std::io::Result<()> {
+        let socket = SockRef::from(stream);
+        if self.config.keepalive == 0 {
+            return socket.set_keepalive(false);
+        }
+        let time = Duration::from_secs(self.config.keepalive);
+        let keepalive = TcpKeepalive::new().with_time(time);
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        let keepalive = keepalive.with_interval(KEEPALIVE_INTERVAL);
+        socket.set_tcp_keepalive(&keepalive)
+    }
 
-pub struct Client {
-    stream: TcpStream,
-    reader: BufReader<TcpStream>,
-    db: Arc<Mutex<DataStore>>,
-    level: String,
-    address: SocketAddr,
-    timeout: u64,
-    set_timeout: u64,
-}
+    fn receive(&mut self, token: Token) {
+        let max_bytes = self.config.max_frame_size as usize;
+        let peer = match self.peers.get_mut(&token) {
+            Some(p) => p,
+            None => return,
+        };
+        if peer.is_full(max_bytes) {
+            peer.paused = true;
+            return;
+        }
+        if peer.paused {
+            peer.conn.restart_read_deadline();
+        }
+        let queued: usize = peer.pending.iter().map(Vec::len).sum();
+        match peer.conn.read_frames(MAX_PENDING_REQUESTS - peer.pending.len(), max_bytes - queued) {
+            Ok(frames) => {
+                if !frames.is_empty() {
+                    peer.last_active = Instant::now();
+                }
+                peer.pending.extend(frames);
+                peer.paused = peer.is_full(max_bytes);
+            }
+            Err(e) => {
+                output_prompt(format!("An error occurred on client [{0}]. It may be fatal, the connection was forcibly terminated. {1}", peer.conn.address, e));
+                self.metrics.error("connection");
+                self.close(token);
+                return;
+            }
+        }
+        if peer.conn.closed && peer.pending.is_empty() {
+            output_prompt(format!("Client [{0}] disconnected", peer.conn.address));
+            self.close(token);
+        }
+    }
 
-impl Client {
-    fn handle_client(&mut self) {
+    // Starts the next pending request of a connection unless one is still running, or
+    // the client has yet to read the replies it got. Reading from a connection paused
+    // with a full queue resumes once a request has been taken from it.
+    fn dispatch(&mut self, token: Token, pool: &ThreadPool, notifier: &Notifier) {
+        let max_bytes = self.config.max_frame_size as usize;
         loop {
-            thread::sleep(time::Duration::from_secs(1 as u64));
-
-            if &self.timeout >= &self.set_timeout {
-                output_prompt(format!("Client [{0}] activity timeout", &self.address));
-                let _ = &self.stream.shutdown(Shutdown::Both);
-                break;
+            let peer = match self.peers.get_mut(&token) {
+                Some(p) => p,
+                None => return,
+            };
+            match peer.state {
+                State::Busy | State::Watching { .. } | State::Closing => return,
+                _ => (),
             }
-            match &&self.accept_request() {
-                Ok(()) => continue,
-                Err(RorError::Disconnect) => {
-                    output_prompt(format!("Client [{0}] disconnected", &self.address));
-                    let _ = &self.stream.shutdown(Shutdown::Both);
-                    break;
-                },
-                Err(RorError::KvError(e)) => output_prompt(format!("An error occurred on client [{0}], buffer flushed and error message sent. {1}",&self.address,e)),
-                Err(e) => {
-                    if let Err(_) = &self.stream.shutdown(Shutdown::Both) {
-                        output_prompt(format!("Client [{0}] unable to properly disconnect, thread has been forcibly closed",&self.address));
+            if self.shutdown.is_some() {
+                self.stop(token);
+                return;
+            }
+            if peer.conn.unsent() > MAX_UNSENT {
+                return;
+            }
+            if peer.paused && !peer.is_full(max_bytes) {
+                self.receive(token);
+                continue;
+            }
+            let frame = match peer.pending.pop_front() {
+                Some(f) => f,
+                None => {
+                    if peer.conn.closed {
+                        output_prompt(format!("Client [{0}] disconnected", peer.conn.address));
+                        self.close(token);
                     }
-                    output_prompt(format!("An error occurred on client [{0}]. It may be fatal, the connection was forcibly terminated. {1}",&self.address,e));
-                    break;
+                    return;
                 }
+            };
+            match std::mem::replace(&mut peer.state, State::Busy) {
+                State::Connecting => self.handshake(token, frame, pool, notifier),
+                State::Idle(client) => self.request(token, client, frame, pool, notifier),
+                State::Streaming(client) => self.end_stream(token, client, frame),
+                _ => unreachable!(),
             }
         }
     }
 
-    fn accept_request(&mut self) -> Result<()> {
-        let mut size_buffer = [0 as u8; USIZE_SIZE];
-        match self.reader.read_exact(&mut size_buffer) {
-            Ok(_) => self.timeout = 0,
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    self.timeout += 1;
-                    return Ok(());
+    fn handshake(&mut self, token: Token, frame: Vec<u8>, pool: &ThreadPool, notifier: &Notifier) {
+        let address = match self.peers.get(&token) {
+            Some(p) => p.conn.address,
+            None => return,
+        };
+        let request = frame_id(&frame).unwrap_or(0);
+        // The version is checked first, the rest of the request may have a different
+        // layout in other versions.
+        let head = match Message::<ProtocolVersion>::from_frame(&frame) {
+            Ok(Message { message: ProtocolVersion { version }, .. })
+                if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) => {
+                Err((
+                    ConnectError::UnsupportedVersion { min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION },
+                    format!("unsupported protocol version {}", version),
+                ))
+            }
+            _ => Message::<ConnectRequest>::from_frame(&frame)
+                .map(|m| m.message)
+                .map_err(|e| (ConnectError::RequestError, e.to_string())),
+        };
+        let refused = match self.peers.get_mut(&token) {
+            Some(peer) => {
+                if let Ok(h) = &head {
+                    peer.version = h.version;
                 }
-                return Err(RorError::IOError(e));
+                peer.slot.as_ref().err().cloned()
+            }
+            None => return,
+        };
+        let head = match head {
+            Ok(h) if refused.is_some() => {
+                self.refuse(token, request, address, &h.user_name, refused.unwrap_or_default());
+                return;
+            }
+            Ok(h) => h,
+            Err((err, reason)) => {
+                output_prompt(format!("Client [{0}], failed to login. reason: {1}", address, reason));
+                audit::login(&self.audit, "login", "", address, None, audit::refused(&err));
+                self.metrics.error("login");
+                self.set_state(token, State::Closing);
+                self.send(token, request, ConnectReply::Error(err));
+                return;
+            }
+        };
+        let config = self.config.clone();
+        let dbs = Arc::clone(&self.dbs);
+        let watchers = Arc::clone(&self.watchers);
+        let raft = self.raft.clone();
+        let audit = self.audit.clone();
+        let notifier = notifier.clone();
+        let user = head.user_name.clone();
+        pool.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                Client::login(head, address, config, dbs, watchers, raft, audit)
+            }));
+            let result = result.unwrap_or(Err((ConnectError::ServerError, RorError::ServerError)));
+            notifier.send(Event::Connected { token, request, user, result });
+        });
+    }
+
+    fn request(&mut self, token: Token, client: Client, frame: Vec<u8>, pool: &ThreadPool, notifier: &Notifier) {
+        let (id, request) = match Message::<OperateRequest>::from_frame(&frame) {
+            Ok(m) => (m.id, m.message),
+            Err(_) => {
+                self.metrics.error("protocol");
+                self.set_state(token, State::Idle(client));
+                self.send(token, frame_id(&frame).unwrap_or(0), OperateResult::Failure);
+                return;
             }
+        };
+        self.metrics.request(request.name());
+        if let Some(peer) = self.peers.get_mut(&token) {
+            peer.last_command = Some(request.name());
+            peer.request_started = Some(Instant::now());
         }
-        let body_size = usize::from_be_bytes(size_buffer);
-        let mut body_buffer = vec![0; body_size];
-        match self.reader.read(&mut body_buffer) {
-            Ok(_) => (),
-            Err(_) => return Ok(()),
+        if !matches!(request, OperateRequest::Quit) && !self.limiter.allow(&client.user) {
+            self.set_state(token, State::Idle(client));
+            self.reply(token, id, OperateResult::RateLimited);
+            return;
         }
-        let op: OperateRequest = match bincode::deserialize(&body_buffer) {
-            Ok(r) => r,
-            Err(_) => {
-                let msg = Message::new(OperateResult::Failure);
-                let (buf,_) = msg.as_bytes()?; 
-                self.stream.write(&buf)?;
-                self.stream.flush()?;
-                return Ok(());
+        match request {
+            OperateRequest::ListClients | OperateRequest::KillClient { .. } | OperateRequest::ServerInfo => {
+                let (name, subject) = (request.name(), audit::subject(&request, false));
+                let result = match is_admin(&client.level) {
+                    true => self.admin(client.address, request, pool),
+                    false => OperateResult::PermissionDenied,
+                };
+                if let Some(subject) = subject {
+                    client.audit(name, &subject, audit::outcome(&result));
+                }
+                self.set_state(token, State::Idle(client));
+                self.reply(token, id, result);
+            }
+            OperateRequest::Quit => {
+                output_prompt(format!("Client [{0}] disconnected", client.address));
+                self.close(token);
+            }
+            OperateRequest::Ping => {
+                self.set_state(token, State::Idle(client));
+                self.reply(token, id, OperateResult::Pong);
+            }
+            OperateRequest::Shutdown => {
+                let allowed = is_admin(&client.level);
+                let reason = format!("requested by client [{}]", client.address);
+                if let Some(subject) = audit::subject(&request, false) {
+                    let outcome = if allowed { "ok" } else { "permission_denied" };
+                    client.audit(request.name(), &subject, outcome);
+                }
+                self.set_state(token, State::Idle(client));
+                if !allowed {
+                    self.reply(token, id, OperateResult::PermissionDenied);
+                    return;
+                }
+                self.reply(token, id, OperateResult::Success);
+                self.begin_shutdown(&reason);
+            }
+            OperateRequest::Watch { key, timeout } => self.watch(token, id, client, key, timeout, notifier),
+            OperateRequest::Publish { channel, value } => {
+                let allowed = can_write(&client.level);
+                self.set_state(token, State::Idle(client));
+                let result = match allowed {
+                    true => self.publish(channel, value),
+                    false => OperateResult::PermissionDenied,
+                };
+                self.reply(token, id, result);
+            }
+            OperateRequest::SubscribeChannels { channels, patterns } => {
+                output_prompt(format!("Client [{0}] subscribed to channels {1:?} and patterns {2:?}", client.address, channels, patterns));
+                self.channels.subscribe(token, id, channels, patterns);
+                self.set_state(token, State::Streaming(client));
             }
+            OperateRequest::Subscribe { from_offset, prefix } => {
+                let feed = self.start_stream(token, id, false, notifier);
+                let handler = feed.handler();
+                Self::execute(token, id, client, pool, notifier, move |client| {
+                    let reply = client.subscribe(from_offset, prefix, handler);
+                    feed.live.store(true, Ordering::SeqCst);
+                    reply
+                });
+            }
+            OperateRequest::Replicate => {
+                let feed = self.start_stream(token, id, true, notifier);
+                let handler = feed.handler();
+                Self::execute(token, id, client, pool, notifier, move |client| {
+                    let replayed = |offset| {
+                        feed.push(OperateResult::Position { offset });
+                    };
+                    let reply = client.replicate(handler, replayed);
+                    feed.live.store(true, Ordering::SeqCst);
+                    reply
+                });
+            }
+            request => Self::execute(token, id, client, pool, notifier, move |client| {
+                client.match_command(request).map(Reply::Result)
+            }),
+        }
+    }
+
+    // Starts a change stream on the connection and returns the end of it that pushes
+    // events to the event loop.
+    fn start_stream(&mut self, token: Token, request: u64, replica: bool, notifier: &Notifier) -> Feed {
+        self.next_stream += 1;
+        let feed = Feed {
+            token,
+            stream: self.next_stream,
+            active: Arc::new(AtomicBool::new(true)),
+            backlog: Arc::new(AtomicUsize::new(0)),
+            live: Arc::new(AtomicBool::new(false)),
+            notifier: notifier.clone(),
         };
+        if let Some(peer) = self.peers.get_mut(&token) {
+            peer.stream = Some(Stream {
+                id: feed.stream,
+                request,
+                active: Arc::clone(&feed.active),
+                backlog: Arc::clone(&feed.backlog),
+                held: VecDeque::new(),
+                replica,
+            });
+        }
+        feed
+    }
 
-        match self.match_command(op) {
-            Ok(r) => {
-                let msg = Message::new(r);
-                let (buf,_) = msg.as_bytes()?; 
-                self.stream.write(&buf)?;
-                self.stream.flush()?;
-                return Ok(());
+    // Writes the held events of a stream until the client has MAX_UNSENT bytes to read.
+    fn drain_stream(&mut self, token: Token) {
+        loop {
+            let peer = match self.peers.get_mut(&token) {
+                Some(p) => p,
+                None => return,
+            };
+            if peer.conn.unsent() > MAX_UNSENT {
+                return;
             }
-            Err(e) => return Err(e),
+            let (request, result) = match peer.stream.as_mut() {
+                Some(stream) => match stream.held.pop_front() {
+                    Some(result) => {
+                        stream.backlog.fetch_sub(1, Ordering::SeqCst);
+                        (stream.request, result)
+                    }
+                    None => return,
+                },
+                None => return,
+            };
+            self.send(token, request, result);
         }
     }
 
-    fn match_command(&mut self, command: OperateRequest) -> Result<OperateResult> {
-        match command {
-            OperateRequest::Open { path } => {
-                todo!()
+    // Admin requests read and change the connections, which only the event loop owns,
+    // so they are answered in the loop.
+    fn admin(&mut self, address: SocketAddr, request: OperateRequest, pool: &ThreadPool) -> OperateResult {
+        match request {
+            OperateRequest::ListClients => {
+                // Tokens grow with every accepted connection, so this is the order the
+                // clients connected in.
+                let mut peers: Vec<(&Token, &Peer)> = self.peers.iter().collect();
+                peers.sort_by_key(|(token, _)| token.0);
+                let clients = peers
+                    .into_iter()
+                    .map(|(_, peer)| ClientInfo {
+                        address: peer.conn.address.to_string(),
+                        user: peer.user.clone(),
+                        database: peer.db_path.clone(),
+                        connected_since: peer.connected_since.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        last_command: peer.last_command.map(|c| c.to_string()),
+                    })
+                    .collect();
+                OperateResult::Clients(clients)
             }
-            OperateRequest::Get { key } => {
-                match self.db.lock().unwrap().get(key) {
-                    Ok(v) => {
-                        return Ok(OperateResult::Found(v));
+            OperateRequest::KillClient { address: target } => {
+                let token = self.peers
+                    .iter()
+                    .find(|(_, peer)| peer.conn.address.to_string() == target)
+                    .map(|(token, _)| *token);
+                match token {
+                    Some(token) => {
+                        output_prompt(format!("Client [{0}] was disconnected by client [{1}]", target, address));
+                        self.close(token);
+                        OperateResult::Success
                     }
-                    Err(KvError::KeyNotFound(_)) => return Ok(OperateResult::KeyNotFound),
-                    Err(e) => return Err(RorError::KvError(e)),
+                    None => OperateResult::Failure,
                 }
             }
-            OperateRequest::Delete { key } => {
-                if self.level != "2" && self.level != "3" {
-                    return Ok(OperateResult::PermissionDenied);
-                }
-                match self.db.lock().unwrap().delete(key) {
-                    Ok(_) => {
-                        return Ok(OperateResult::Success);
-                    }
-                    Err(KvError::KeyNotFound(_)) => return Ok(OperateResult::KeyNotFound),
-                    Err(e) => return Err(RorError::KvError(e)),
+            OperateRequest::ServerInfo => {
+                let mut databases: Vec<String> = self.dbs.lock().unwrap().keys().cloned().collect();
+                databases.sort();
+                let mut config = self.config.clone();
+                if let Some((user, _)) = config.local_user.split_once('@') {
+                    config.local_user = format!("{}@***", user);
                 }
-            }
-            OperateRequest::Add { key, value } => {
-                if self.level != "1" && self.level != "2" && self.level != "3" {
-                    return Ok(OperateResult::PermissionDenied);
+                if let Some((user, _)) = config.memcached_user.split_once('@') {
+                    config.memcached_user = format!("{}@***", user);
                 }
-                match self.db.lock().unwrap().add(key,value) {
-                    Ok(_) => {
-                        return Ok(OperateResult::Success);
-                    }
-                    Err(e) => return Err(RorError::KvError(e)),
+                if let Some((user, _)) = config.leader_user.split_once('@') {
+                    config.leader_user = format!("{}@***", user);
                 }
+                OperateResult::ServerInfo(ServerInfo {
+                    name: self.config.name.clone(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    protocol_version: PROTOCOL_VERSION,
+                    uptime: self.started.elapsed().as_secs(),
+                    clients: self.peers.len(),
+                    workers: pool.size(),
+                    databases,
+                    memory: resident_memory(),
+                    config: toml::to_string(&config).unwrap_or_default(),
+                    role: match &self.raft {
+                        Some(raft) => raft.role().to_string(),
+                        None => self.config.role.clone(),
+                    },
+                    replication: replica::report(&self.replication),
+                    cluster: self.raft.as_ref().map(|raft| Box::new(raft.info())),
+                })
             }
-            OperateRequest::CreateUser { name, password, level } => {
-                if self.level != "3" {
-                    return Ok(OperateResult::PermissionDenied);
-                }
+            _ => OperateResult::Failure,
+        }
+    }
 
-                match User::register(                    
-                    name,
-                    password,
-                    level
-                ) {
-                    Ok(_) => return Ok(OperateResult::Success),
-                    Err(e) => {
-                        output_prompt(format!("Unable to create new user for client [{0}], {1}", self.address, e));
-                        return Ok(OperateResult::Failure);
-                    }
-                }
-            },
-            OperateRequest::DeleteUser { name } => {
-                if self.level != "3" {
-                    return Ok(OperateResult::PermissionDenied);
-                }
+    fn execute<F>(token: Token, request: u64, mut client: Client, pool: &ThreadPool, notifier: &Notifier, job: F)
+    where
+        F: FnOnce(&mut Client) -> Result<Reply> + Send + 'static,
+    {
+        let notifier = notifier.clone();
+        pool.execute(move || {
+            let event = match panic::catch_unwind(AssertUnwindSafe(|| job(&mut client))) {
+                Ok(result) => Event::Done { token, request, client, result },
+                Err(_) => Event::Panicked { token },
+            };
+            notifier.send(event);
+        });
+    }
+
+    fn watch(&mut self, token: Token, request: u64, client: Client, key: String, timeout: u64, notifier: &Notifier) {
+        let notifier = notifier.clone();
+        let notify: Notify = Box::new(move |id, result| {
+            notifier.send(Event::Fired { token, id, result });
+        });
+        let id = self.watchers.lock().unwrap().register(&client.db_path, &key, notify);
+        let deadline = match timeout {
+            0 => None,
+            t => Some(Instant::now() + Duration::from_secs(t)),
+        };
+        if let Some(d) = deadline {
+            self.next_sweep = self.next_sweep.min(d);
+        }
+        self.set_state(token, State::Watching { client, id, request, key, deadline });
+    }
+
+    // Sends a message to every subscription of the channel. A subscriber that still has
+    // more than MAX_UNSENT bytes of messages to read is disconnected instead, so one
+    // that stops reading holds at most that much memory until the write deadline.
+    fn publish(&mut self, channel: String, value: Value) -> OperateResult {
+        let mut delivered = 0;
+        for (token, request, pattern) in self.channels.receivers(&channel) {
+            let peer = match self.peers.get(&token) {
+                Some(p) => p,
+                None => continue,
+            };
+            if peer.conn.unsent() > MAX_UNSENT {
+                output_prompt(format!("Client [{0}] fell behind the messages of its channels and was disconnected", peer.conn.address));
+                self.metrics.error("channel_overflow");
+                self.close(token);
+                continue;
+            }
+            let message = ChannelMessage {
+                channel: channel.clone(),
+                pattern,
+                value: value.clone(),
+            };
+            self.send(token, request, OperateResult::Message(message));
+            delivered += 1;
+        }
+        OperateResult::Published { receivers: delivered }
+    }
+
+    // Any request ends a change stream or a subscription to channels; the request
+    // itself is answered with Success.
+    fn end_stream(&mut self, token: Token, mut client: Client, frame: Vec<u8>) {
+        client.end_subscription();
+        if let Some(peer) = self.peers.get_mut(&token) {
+            if let Some(stream) = peer.stream.take() {
+                stream.active.store(false, Ordering::SeqCst);
+            }
+        }
+        self.channels.unsubscribe(token);
+        if let Ok(Message { message: OperateRequest::Quit, .. }) = Message::from_frame(&frame) {
+            output_prompt(format!("Client [{0}] disconnected", client.address));
+            self.close(token);
+            return;
+        }
+        self.set_state(token, State::Idle(client));
+        self.send(token, frame_id(&frame).unwrap_or(0), OperateResult::Success);
+    }
+
+    fn handle_event(&mut self, event: Event, pool: &ThreadPool, notifier: &Notifier) {
+        match event {
+            Event::Connected { token, request, user, result } => match result {
+                Ok(mut client) => {
+                    let counted = match self.peers.get_mut(&token).map(|p| &mut p.slot) {
+                        Some(Ok(slot)) => slot.login(&client.user),
+                        _ => Ok(()),
+                    };
+                    if let Err(reason) = counted {
+                        self.refuse(token, request, client.address, &user, reason);
+                        return;
+                    }
+                    client.audit_login();
+                    let max_frame_size = self.config.max_frame_size;
+                    let mut version = PROTOCOL_VERSION;
+                    if let Some(peer) = self.peers.get_mut(&token) {
+                        version = peer.version;
+                        peer.user = client.user.clone();
+                        peer.db_path = client.db_path.clone();
+                        peer.conn.set_max_frame_size(max_frame_size);
+                        if peer.conn.is_tls() {
+                            client.capabilities |= CAP_TLS;
+                        }
+                    }
+                    let capabilities = client.capabilities;
+                    self.set_state(token, State::Idle(client));
+                    self.send(token, request, ConnectReply::Success { version, capabilities, max_frame_size });
+                    self.dispatch(token, pool, notifier);
+                }
+                Err((err, e)) => {
+                    if let Some(peer) = self.peers.get(&token) {
+                        output_prompt(format!("Client [{0}], failed to login. reason: {1}", peer.conn.address, e));
+                        audit::login(&self.audit, "login", &user, peer.conn.address, None, audit::refused(&err));
+                    }
+                    self.metrics.error("login");
+                    self.set_state(token, State::Closing);
+                    self.send(token, request, ConnectReply::Error(err));
+                }
+            },
+            Event::Done { token, request, client, result } => {
+                if let Some(peer) = self.peers.get_mut(&token) {
+                    if peer.db_path != client.db_path {
+                        peer.db_path = client.db_path.clone();
+                    }
+                }
+                match result {
+                    Ok(Reply::Result(r)) => {
+                        if let Some(peer) = self.peers.get_mut(&token) {
+                            peer.stream = None;
+                        }
+                        self.set_state(token, State::Idle(client));
+                        self.reply(token, request, r);
+                    }
+                    Ok(Reply::Stream) => {
+                        output_prompt(format!("Client [{0}] subscribed to changes", client.address));
+                        self.set_state(token, State::Streaming(client));
+                    }
+                    Err(RorError::KvError(e)) => {
+                        output_prompt(format!("An error occurred on client [{0}], error message sent. {1}", client.address, e));
+                        self.set_state(token, State::Idle(client));
+                        self.reply(token, request, OperateResult::Failure);
+                    }
+                    Err(e) => {
+                        output_prompt(format!("An error occurred on client [{0}]. It may be fatal, the connection was forcibly terminated. {1}", client.address, e));
+                        self.metrics.error("internal");
+                        self.close(token);
+                    }
+                }
+                self.dispatch(token, pool, notifier);
+            }
+            Event::Push { token, stream, result } => {
+                match self.peers.get_mut(&token).and_then(|p| p.stream.as_mut()) {
+                    Some(s) if s.id == stream => s.held.push_back(result),
+                    _ => return,
+                }
+                self.drain_stream(token);
+            }
+            Event::Overflowed { token, stream } => {
+                let peer = match self.peers.get(&token) {
+                    Some(p) if p.stream.as_ref().is_some_and(|s| s.id == stream) => p,
+                    _ => return,
+                };
+                output_prompt(format!(
+                    "Client [{0}] fell {1} changes behind its change stream and was disconnected",
+                    peer.conn.address,
+                    MAX_STREAM_BACKLOG
+                ));
+                self.metrics.error("stream_overflow");
+                self.close(token);
+            }
+            Event::Fired { token, id, result } => {
+                let peer = match self.peers.get_mut(&token) {
+                    Some(p) => p,
+                    None => return,
+                };
+                if let State::Watching { id: watching, .. } = &peer.state {
+                    if *watching == id {
+                        if let State::Watching { client, request, .. } = std::mem::replace(&mut peer.state, State::Busy) {
+                            self.set_state(token, State::Idle(client));
+                            self.reply(token, request, result);
+                            self.dispatch(token, pool, notifier);
+                        }
+                    }
+                }
+            }
+            Event::Panicked { token } => {
+                if let Some(peer) = self.peers.get(&token) {
+                    output_prompt(format!("Client [{0}] request failed unexpectedly, the connection was forcibly terminated", peer.conn.address));
+                }
+                self.metrics.error("panic");
+                self.close(token);
+            }
+            Event::Signal(signal) => {
+                if self.shutdown.is_some() {
+                    output_prompt("Shutting down without waiting for the requests in progress");
+                    self.shutdown = Some(Instant::now());
+                    return;
+                }
+                self.begin_shutdown(&format!("signal {}", signal));
+            }
+        }
+    }
+
+    // Answers expired watches with Timeout and closes connections that have been idle
+    // longer than the configured timeout, or that stalled in the middle of sending a
+    // request or reading replies. Clients waiting on a watch or a change stream are not
+    // idle; dead ones among them are found by TCP keepalive or the write deadline.
+    fn sweep(&mut self, pool: &ThreadPool, notifier: &Notifier) {
+        let now = Instant::now();
+        let idle_limit = Duration::from_secs(self.config.timeout);
+        let read_limit = Duration::from_secs(self.config.read_timeout);
+        let write_limit = Duration::from_secs(self.config.write_timeout);
+        let mut next_sweep = now + SWEEP_INTERVAL;
+        let mut expired = Vec::new();
+        let mut idle = Vec::new();
+        let mut stalled = Vec::new();
+        let mut positions = Vec::new();
+        for (token, peer) in &self.peers {
+            if self.config.read_timeout > 0 && !peer.paused && peer.conn.read_waiting(now).is_some_and(|d| d >= read_limit) {
+                stalled.push((*token, "read"));
+                continue;
+            }
+            if self.config.write_timeout > 0 && peer.conn.write_waiting(now).is_some_and(|d| d >= write_limit) {
+                stalled.push((*token, "write"));
+                continue;
+            }
+            match &peer.state {
+                State::Watching { deadline: Some(d), .. } => {
+                    if *d <= now {
+                        expired.push(*token);
+                    } else {
+                        next_sweep = next_sweep.min(*d);
+                    }
+                }
+                // A datafile busy with a long write or compaction skips a beat rather
+                // than block the event loop.
+                State::Streaming(client) => {
+                    // A follower reading held changes hears of the position after them.
+                    if let Some(stream) = peer.stream.as_ref().filter(|s| s.replica && s.held.is_empty()) {
+                        if let Ok(db) = client.db.try_lock() {
+                            positions.push((*token, stream.request, db.position()));
+                        }
+                    }
+                }
+                State::Connecting | State::Idle(_)
+                    if self.config.timeout > 0
+                        && peer.pending.is_empty()
+                        && now.duration_since(peer.last_active) >= idle_limit => {
+                    idle.push(*token);
+                }
+                _ => (),
+            }
+        }
+        self.next_sweep = next_sweep;
+
+        for (token, request, offset) in positions {
+            self.send(token, request, OperateResult::Position { offset });
+        }
+        for token in expired {
+            let peer = match self.peers.get_mut(&token) {
+                Some(p) => p,
+                None => continue,
+            };
+            if let State::Watching { client, id, request, key, .. } = std::mem::replace(&mut peer.state, State::Busy) {
+                self.watchers.lock().unwrap().unregister(&client.db_path, &key, id);
+                self.set_state(token, State::Idle(client));
+                self.reply(token, request, OperateResult::Timeout);
+                self.dispatch(token, pool, notifier);
+            }
+        }
+        for token in idle {
+            if let Some(peer) = self.peers.get(&token) {
+                output_prompt(format!("Client [{0}] activity timeout", peer.conn.address));
+            }
+            self.metrics.error("idle_timeout");
+            self.close(token);
+        }
+        for (token, direction) in stalled {
+            if let Some(peer) = self.peers.get(&token) {
+                output_prompt(format!("Client [{0}] {1} timeout, the connection was terminated", peer.conn.address, direction));
+            }
+            self.metrics.error(match direction {
+                "read" => "read_timeout",
+                _ => "write_timeout",
+            });
+            self.close(token);
+        }
+    }
+
+    fn set_state(&mut self, token: Token, state: State) {
+        if let Some(peer) = self.peers.get_mut(&token) {
+            peer.state = state;
+        }
+    }
+
+    fn send<T: Serialize + serde::de::DeserializeOwned + Downgrade>(&mut self, token: Token, request: u64, message: T) {
+        let peer = match self.peers.get_mut(&token) {
+            Some(p) => p,
+            None => return,
+        };
+        let result = match Message::with_id(request, message.downgrade(peer.version)).as_bytes() {
+            Ok((buf, _)) => peer.conn.send(&buf),
+            Err(e) => {
+                output_prompt(format!("Unable to encode the reply to client [{0}]: {1}", peer.conn.address, e));
+                return;
+            }
+        };
+        if let Err(e) = result {
+            output_prompt(format!("An error occurred on client [{0}]. It may be fatal, the connection was forcibly terminated. {1}", peer.conn.address, e));
+            self.close(token);
+            return;
+        }
+        if let State::Closing = peer.state {
+            if peer.conn.is_flushed() {
+                self.close(token);
+            }
+        }
+    }
+
+    // Sends the result of a request and records its latency and outcome.
+    fn reply(&mut self, token: Token, request: u64, result: OperateResult) {
+        if let Some(started) = self.peers.get_mut(&token).and_then(|p| p.request_started.take()) {
+            if let Some(kind) = self.peers.get(&token).and_then(|p| p.last_command) {
+                self.metrics.observe(kind, started.elapsed());
+            }
+        }
+        self.metrics.result(&result);
+        self.send(token, request, result);
+    }
+
+    fn flush(&mut self, token: Token) {
+        let peer = match self.peers.get_mut(&token) {
+            Some(p) => p,
+            None => return,
+        };
+        if peer.conn.flush().is_err() || peer.conn.closed {
+            self.close(token);
+            return;
+        }
+        if let State::Closing = peer.state {
+            if peer.conn.is_flushed() {
+                self.close(token);
+            }
+        }
+    }
+
+    // Answers a handshake over one of the connection limits and closes the connection.
+    fn refuse(&mut self, token: Token, request: u64, address: SocketAddr, user: &str, reason: String) {
+        output_prompt(format!("Client [{0}], refused. reason: {1}", address, reason));
+        audit::login(&self.audit, "login", user, address, None, audit::refused(&ConnectError::TooManyConnections));
+        self.metrics.error("too_many_connections");
+        self.set_state(token, State::Closing);
+        self.send(token, request, ConnectReply::Error(ConnectError::TooManyConnections));
+    }
+
+    // Dropping the socket deregisters it from the poll.
+    fn close(&mut self, token: Token) {
+        let mut peer = match self.peers.remove(&token) {
+            Some(p) => p,
+            None => return,
+        };
+        if let Some(stream) = peer.stream.take() {
+            stream.active.store(false, Ordering::SeqCst);
+        }
+        match &mut peer.state {
+            State::Watching { client, id, key, .. } => self.watchers.lock().unwrap().unregister(&client.db_path, key, *id),
+            State::Streaming(client) => client.end_subscription(),
+            _ => (),
+        }
+        self.channels.unsubscribe(token);
+        let _ = peer.conn.stream.shutdown(std::net::Shutdown::Both);
+        self.metrics.disconnected(self.peers.len());
+    }
+
+    // Closes the datafiles no connected client is using anymore.
+    pub fn refresh(&mut self) -> Result<()> {
+        let mut dbs = self.dbs.lock().unwrap();
+        dbs.retain(|_, db| Arc::strong_count(db) > 1);
+        Ok(())
+    }
+}
+
+impl Peer {
+    // Whether the connection has as many requests queued as it may.
+    fn is_full(&self, max_bytes: usize) -> bool {
+        self.pending.len() >= MAX_PENDING_REQUESTS || self.pending.iter().map(Vec::len).sum::<usize>() >= max_bytes
+    }
+}
+
+impl Notifier {
+    // Returns false once the event loop is gone.
+    fn send(&self, event: Event) -> bool {
+        if self.sender.send(event).is_err() {
+            return false;
+        }
+        let _ = self.waker.wake();
+        true
+    }
+}
+
+impl Feed {
+    // The store handler of the stream.
+    fn handler(&self) -> impl FnMut(&ChangeEvent) -> bool + Send + 'static {
+        let feed = self.clone();
+        move |event: &ChangeEvent| feed.push(OperateResult::Change(event.clone()))
+    }
+
+    // Hands `result` to the event loop, and returns false once the stream has ended.
+    fn push(&self, result: OperateResult) -> bool {
+        while self.backlog.load(Ordering::SeqCst) >= MAX_STREAM_BACKLOG {
+            if !self.active.load(Ordering::SeqCst) {
+                return false;
+            }
+            if self.live.load(Ordering::SeqCst) {
+                self.active.store(false, Ordering::SeqCst);
+                self.notifier.send(Event::Overflowed { token: self.token, stream: self.stream });
+                return false;
+            }
+            thread::sleep(STREAM_WAIT);
+        }
+        self.backlog.fetch_add(1, Ordering::SeqCst);
+        self.active.load(Ordering::SeqCst)
+            && self.notifier.send(Event::Push { token: self.token, stream: self.stream, result })
+    }
+}
+
+pub struct Client {
+    // The capability flags agreed on in the handshake.
+    capabilities: u32,
+    user: String,
+    db: Arc<Mutex<DataStore>>,
+    level: String,
+    address: SocketAddr,
+    dbs: Databases,
+    watchers: Watchers,
+    db_path: String,
+    config: Config,
+    raft: Option<raft::Raft>,
+    audit: audit::Audit,
+    // The store handler of the client's change stream.
+    subscription: Option<u64>,
+}
+
+impl Client {
+    fn login(
+        head: ConnectRequest,
+        address: SocketAddr,
+        config: Config,
+        dbs: Databases,
+        watchers: Watchers,
+        raft: Option<raft::Raft>,
+        audit: audit::Audit,
+    ) -> std::result::Result<Self, (ConnectError, RorError)> {
+        let user = match User::login(head.user_name.clone(), head.password.clone()) {
+            Ok(u) => u,
+            Err(UserError::UserNotFound(n)) => {
+                return Err((ConnectError::UserNotFound, RorError::UserError(UserError::UserNotFound(n))));
+            },
+            Err(UserError::WrongPassWord) => {
+                return Err((ConnectError::PasswordError, RorError::UserError(UserError::WrongPassWord)));
+            },
+            Err(e) => return Err((ConnectError::ServerError, RorError::UserError(e))),
+        };
+        let db_path = match resolve_db_path(&config, &head.db_path) {
+            Ok(p) => p,
+            Err(e) => return Err((ConnectError::PathError, e)),
+        };
+        let opened_db = match open_db(&dbs, &db_path) {
+            Ok(db) => db,
+            Err(RorError::ServerError) => return Err((ConnectError::ServerError, RorError::ServerError)),
+            Err(e) => return Err((ConnectError::OpenFileError, e)),
+        };
+
+        Ok(Client {
+            capabilities: head.capabilities & CAPABILITIES,
+            user: head.user_name,
+            db: opened_db,
+            level: user.level,
+            address,
+            dbs,
+            watchers,
+            db_path,
+            config,
+            raft,
+            audit,
+            subscription: None,
+        })
+    }
+
+    // A client of a listener outside the event loop, logged in as `user` with the
+    // datafile at `db_path` open. Listeners that write through `match_command` set its
+    // Raft member, and listeners with an audit log its `audit`.
+    fn session(
+        user: String,
+        level: String,
+        address: SocketAddr,
+        db_path: String,
+        config: Config,
+        dbs: Databases,
+        watchers: Watchers,
+    ) -> Result<Self> {
+        let db = open_db(&dbs, &db_path)?;
+        Ok(Client {
+            capabilities: 0,
+            user,
+            db,
+            level,
+            address,
+            dbs,
+            watchers,
+            db_path,
+            config,
+            raft: None,
+            audit: None,
+            subscription: None,
+        })
+    }
+
+    // Runs a request of such a client and records it in the metrics.
+    fn execute(&mut self, request: OperateRequest, metrics: &Metrics) -> Result<OperateResult> {
+        let kind = request.name();
+        let started = Instant::now();
+        metrics.request(kind);
+        let result = self.match_command(request);
+        metrics.observe(kind, started.elapsed());
+        match &result {
+            Ok(r) => metrics.result(r),
+            Err(e) => {
+                output_prompt(format!("An error occurred on client [{0}]. {1}", self.address, e));
+                metrics.error("internal");
+            }
+        }
+        result
+    }
+
+    fn subscribe<F>(&mut self, from_offset: Option<u64>, prefix: Option<String>, mut handler: F) -> Result<Reply>
+    where
+        F: FnMut(&ChangeEvent) -> bool + Send + 'static,
+    {
+        let filtered = move |event: &ChangeEvent| {
+            let matched = match &prefix {
+                Some(p) => event.kind == ChangeKind::Compact || event.key.starts_with(p.as_str()),
+                None => true,
+            };
+            !matched || handler(event)
+        };
+        match self.db.lock().unwrap().subscribe_with(from_offset, filtered) {
+            Ok(id) => {
+                self.subscription = Some(id);
+                Ok(Reply::Stream)
+            }
+            Err(KvError::InvalidOffset(offset)) => {
+                output_prompt(format!("Client [{0}] cannot subscribe from offset {1}", self.address, offset));
+                Ok(Reply::Result(OperateResult::Failure))
+            }
+            Err(e) => Err(RorError::KvError(e)),
+        }
+    }
+
+    // Removes the store handler of the change stream as soon as the stream ends. The
+    // event loop does not wait for a datafile in use, the handler then goes with the
+    // next change, which it turns down.
+    fn end_subscription(&mut self) {
+        if let Some(id) = self.subscription.take() {
+            if let Ok(mut db) = self.db.try_lock() {
+                db.unsubscribe(id);
+            }
+        }
+    }
+
+    // Replays the whole log and registers `handler` for the changes after it. `replayed`
+    // gets the end of the log while the datafile is still locked, so no change can slip
+    // in between the replayed entries and it.
+    fn replicate<F, R>(&mut self, handler: F, replayed: R) -> Result<Reply>
+    where
+        F: FnMut(&ChangeEvent) -> bool + Send + 'static,
+        R: FnOnce(u64),
+    {
+        let mut db = self.db.lock().unwrap();
+        self.subscription = Some(db.subscribe_with(Some(0), handler)?);
+        replayed(db.position());
+        output_prompt(format!("Client [{0}] replicates '{1}'", self.address, self.db_path));
+        Ok(Reply::Stream)
+    }
+
+    fn match_command(&mut self, command: OperateRequest) -> Result<OperateResult> {
+        let subject = match self.audit {
+            Some(_) => audit::subject(&command, self.config.audit_writes),
+            None => None,
+        };
+        let name = command.name();
+        let result = self.run_command(command);
+        if let Some(subject) = subject {
+            let outcome = match &result {
+                Ok(r) => audit::outcome(r),
+                Err(_) => "error",
+            };
+            self.audit(name, &subject, outcome);
+        }
+        result
+    }
+
+    // Writes a line about a request of this client to the audit log, if there is one.
+    fn audit(&self, request: &str, subject: &audit::Subject, outcome: &str) {
+        if let Some(audit) = &self.audit {
+            let database = self.database_name();
+            audit.record(audit::Record {
+                event: subject.event,
+                user: &self.user,
+                address: self.address.to_string(),
+                database: Some(&database),
+                request,
+                key: subject.key.as_deref(),
+                target: subject.target.as_deref(),
+                outcome,
+            });
+        }
+    }
+
+    fn audit_login(&self) {
+        audit::login(&self.audit, "login", &self.user, self.address, Some(&self.database_name()), "ok");
+    }
+
+    // For listeners that write to the datafile themselves rather than through
+    // `match_command`.
+    fn audit_write(&self, request: &str, key: &str) {
+        if self.config.audit_writes {
+            let subject = audit::Subject { event: "write", key: Some(key.to_string()), target: None };
+            self.audit(request, &subject, "ok");
+        }
+    }
+
+    // The datafile by its path relative to data_path, as clients name it.
+    fn database_name(&self) -> String {
+        match Path::new(&self.db_path).strip_prefix(&self.config.data_path) {
+            Ok(name) => name.to_string_lossy().to_string(),
+            Err(_) => self.db_path.clone(),
+        }
+    }
+
+    fn run_command(&mut self, command: OperateRequest) -> Result<OperateResult> {
+        if self.config.is_follower() && is_write(&command) {
+            return Ok(OperateResult::ReadOnly);
+        }
+        if let Some(raft) = self.raft.clone() {
+            if is_write(&command) {
+                return self.replicate_write(&raft, command);
+            }
+        }
+        match command {
+            OperateRequest::Open { path } => {
+                let db_path = match resolve_db_path(&self.config, &path) {
+                    Ok(p) => p,
+                    Err(_) => return Ok(OperateResult::Failure),
+                };
+                match open_db(&self.dbs, &db_path) {
+                    Ok(db) => {
+                        output_prompt(format!("Client [{0}] opened '{1}'", self.address, db_path));
+                        self.db = db;
+                        self.db_path = db_path;
+                        Ok(OperateResult::Success)
+                    }
+                    Err(e) => {
+                        output_prompt(format!("Unable to open '{0}' for client [{1}], {2}", path, self.address, e));
+                        Ok(OperateResult::Failure)
+                    }
+                }
+            }
+            OperateRequest::List { cursor, limit } => {
+                self.scan("", cursor, limit)
+            }
+            OperateRequest::Scan { prefix, cursor, limit } => {
+                self.scan(&prefix, cursor, limit)
+            }
+            OperateRequest::ListDatabases => {
+                let data_path = Path::new(&self.config.data_path);
+                let mut names = Vec::new();
+                for file in data_files(data_path, Path::new(&self.config.backup_path))? {
+                    if let Ok(name) = file.strip_prefix(data_path) {
+                        names.push(name.to_string_lossy().to_string());
+                    }
+                }
+                names.sort();
+                Ok(OperateResult::Databases(names))
+            }
+            OperateRequest::CreateDatabase { path } => {
+                if !can_delete(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+                match self.create_db(&path) {
+                    Ok(()) => Ok(OperateResult::Success),
+                    Err(e) => {
+                        output_prompt(format!("Unable to create database '{0}' for client [{1}], {2}", path, self.address, e));
+                        Ok(OperateResult::Failure)
+                    }
+                }
+            }
+            OperateRequest::DropDatabase { path } => {
+                if !is_admin(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+                match self.drop_db(&path) {
+                    Ok(()) => Ok(OperateResult::Success),
+                    Err(e) => {
+                        output_prompt(format!("Unable to drop database '{0}' for client [{1}], {2}", path, self.address, e));
+                        Ok(OperateResult::Failure)
+                    }
+                }
+            }
+            OperateRequest::Get { key } => {
+                match self.db.lock().unwrap().get(key) {
+                    Ok(v) => {
+                        Ok(OperateResult::Found(v))
+                    }
+                    Err(KvError::KeyNotFound(_)) => Ok(OperateResult::KeyNotFound),
+                    Err(e) => Err(RorError::KvError(e)),
+                }
+            }
+            OperateRequest::Delete { key } => {
+                if !can_delete(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+                match self.db.lock().unwrap().delete(key.clone()) {
+                    Ok(_) => {
+                        self.watchers.lock().unwrap().notify(&self.db_path, &key, None);
+                        Ok(OperateResult::Success)
+                    }
+                    Err(KvError::KeyNotFound(_)) => Ok(OperateResult::KeyNotFound),
+                    Err(e) => Err(RorError::KvError(e)),
+                }
+            }
+            OperateRequest::Add { key, value } => {
+                if !can_write(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+                match self.db.lock().unwrap().add(key.clone(),value.clone()) {
+                    Ok(_) => {
+                        self.watchers.lock().unwrap().notify(&self.db_path, &key, Some(value));
+                        Ok(OperateResult::Success)
+                    }
+                    Err(e) => Err(RorError::KvError(e)),
+                }
+            }
+            OperateRequest::CreateUser { name, password, level } => {
+                if !is_admin(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+
+                match User::register(                    
+                    name,
+                    password,
+                    level
+                ) {
+                    Ok(_) => Ok(OperateResult::Success),
+                    Err(e) => {
+                        output_prompt(format!("Unable to create new user for client [{0}], {1}", self.address, e));
+                        Ok(OperateResult::Failure)
+                    }
+                }
+            },
+            OperateRequest::DeleteUser { name } => {
+                if !is_admin(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
 
                 match User::delete(name.clone()) {
                     Ok(_) => Ok(OperateResult::Success),
                     Err(e) => {
                         output_prompt(format!("Unable to delete user '{0}' for client [{1}], {2}", name, self.address, e));
-                        return Ok(OperateResult::Failure);
+                        Ok(OperateResult::Failure)
                     }
                 }
             }
             OperateRequest::GetType { key } => {
                 match self.db.lock().unwrap().get(key) {
                     Ok(v) => {
-                        return Ok(OperateResult::Type(DataStore::type_of(v)));
+                        Ok(OperateResult::Type(DataStore::type_of(v)))
                     }
-                    Err(KvError::KeyNotFound(_)) => return Ok(OperateResult::KeyNotFound),
-                    Err(e) => return Err(RorError::KvError(e)),
+                    Err(KvError::KeyNotFound(_)) => Ok(OperateResult::KeyNotFound),
+                    Err(e) => Err(RorError::KvError(e)),
                 }
             }
             OperateRequest::Compact => {
-                if self.level != "1" && self.level != "2" && self.level != "3" {
+                if !can_write(&self.level) {
                     return Ok(OperateResult::PermissionDenied);
                 }
                 match self.db.lock().unwrap().compact() {
                     Ok(_) => {
-                        return Ok(OperateResult::Success);
+                        Ok(OperateResult::Success)
                     }
-                    Err(e) => return Err(RorError::KvError(e)),
+                    Err(e) => Err(RorError::KvError(e)),
                 }
             },
-            OperateRequest::Quit => {
-                return Err(RorError::Disconnect);
+            OperateRequest::Backup { dest } => {
+                if !is_admin(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+                match self.backup(&dest) {
+                    Ok(manifest) => {
+                        output_prompt(format!("Client [{0}] created backup '{1}' of {2} datafiles", self.address, dest, manifest.files.len()));
+                        Ok(OperateResult::Success)
+                    }
+                    Err(e) => {
+                        output_prompt(format!("Unable to create backup '{0}' for client [{1}], {2}", dest, self.address, e));
+                        Ok(OperateResult::Failure)
+                    }
+                }
+            },
+            OperateRequest::Restore { snapshot, file, dest } => {
+                if !is_admin(&self.level) {
+                    return Ok(OperateResult::PermissionDenied);
+                }
+                match self.restore(&snapshot, &file, &dest) {
+                    Ok(()) => Ok(OperateResult::Success),
+                    Err(e) => {
+                        output_prompt(format!("Unable to restore '{0}' from backup '{1}' for client [{2}], {3}", file, snapshot, self.address, e));
+                        Ok(OperateResult::Failure)
+                    }
+                }
+            },
+            OperateRequest::AddMember { address } => Ok(self.change_members(address, true)),
+            OperateRequest::RemoveMember { address } => Ok(self.change_members(address, false)),
+            OperateRequest::Unsubscribe => Ok(OperateResult::Success),
+            // Handled by the event loop, which owns the connection.
+            OperateRequest::Subscribe { .. }
+            | OperateRequest::Replicate
+            | OperateRequest::Watch { .. }
+            | OperateRequest::Ping
+            | OperateRequest::Shutdown
+            | OperateRequest::ListClients
+            | OperateRequest::KillClient { .. }
+            | OperateRequest::ServerInfo
+            | OperateRequest::Quit => {
+                Ok(OperateResult::Failure)
+            },
+            // Only a proxy has shards.
+            OperateRequest::AddShard { .. }
+            | OperateRequest::RemoveShard { .. }
+            | OperateRequest::ListShards => {
+                Ok(OperateResult::Failure)
+            },
+            // Handled by the event loop, which owns the subscriptions.
+            OperateRequest::Publish { .. }
+            | OperateRequest::SubscribeChannels { .. } => {
+                Ok(OperateResult::Failure)
             },
         }
     }
+
+    // In a Raft cluster writes are appended to the replicated log and applied by every
+    // member, so only the permissions are checked here. Restore reads a backup of this
+    // server, which the other members do not have.
+    fn replicate_write(&self, raft: &raft::Raft, command: OperateRequest) -> Result<OperateResult> {
+        let allowed = match &command {
+            OperateRequest::Add { .. } => can_write(&self.level),
+            OperateRequest::Delete { .. } | OperateRequest::CreateDatabase { .. } => can_delete(&self.level),
+            _ => is_admin(&self.level),
+        };
+        if !allowed {
+            return Ok(OperateResult::PermissionDenied);
+        }
+        match &command {
+            OperateRequest::Restore { .. } => {
+                output_prompt(format!("Client [{0}] cannot restore a backup into a Raft cluster", self.address));
+                return Ok(OperateResult::Failure);
+            }
+            OperateRequest::DropDatabase { path } => {
+                let db_path = resolve_db_path(&self.config, path)?;
+                if self.find_open_db(Path::new(&db_path))?.is_some_and(|db| Arc::strong_count(&db) > 2) {
+                    output_prompt(format!("Unable to drop database '{0}' for client [{1}], {2}", path, self.address, RorError::DatabaseInUse(path.clone())));
+                    return Ok(OperateResult::Failure);
+                }
+            }
+            _ => (),
+        }
+        let db = match Path::new(&self.db_path).strip_prefix(&self.config.data_path) {
+            Ok(name) => name.to_string_lossy().to_string(),
+            Err(_) => return Err(RorError::PathError),
+        };
+        Ok(raft.write(db, command))
+    }
+
+    fn change_members(&self, address: String, add: bool) -> OperateResult {
+        if !is_admin(&self.level) {
+            return OperateResult::PermissionDenied;
+        }
+        match &self.raft {
+            Some(raft) => raft.change_members(address, add),
+            None => OperateResult::Failure,
+        }
+    }
+
+    // Copies every datafile under data_path into backup_path/[name]. Datafiles opened by
+    // clients are sealed under their lock and copied afterwards, so writers are only
+    // blocked while the pending writes are flushed.
+    fn backup(&self, name: &str) -> Result<Manifest> {
+        if !is_relative_path(name) || name.contains(['/', '\\']) {
+            return Err(RorError::PathError);
+        }
+        let root = Path::new(&self.config.backup_path);
+        let dir = root.join(name);
+        if dir.exists() {
+            return Err(RorError::KvError(KvError::FileExists(dir.to_string_lossy().to_string())));
+        }
+        fs::create_dir_all(&dir)?;
+        let previous = backup::latest_snapshot(root, name);
+
+        let data_path = Path::new(&self.config.data_path);
+        let mut files = Vec::new();
+        for path in data_files(data_path, root)? {
+            let relative = match path.strip_prefix(data_path) {
+                Ok(p) => p.to_string_lossy().to_string(),
+                Err(_) => return Err(RorError::PathError),
+            };
+            let checkpoint = match self.find_open_db(&path)? {
+                Some(db) => db.lock().unwrap().seal()?,
+                None => Checkpoint::from_path(&path)?,
+            };
+            let prev = previous.as_ref().and_then(|(p, m)| {
+                m.find(&relative).map(|f| (p.join(&relative), f))
+            });
+            let file = checkpoint.write_to(
+                &dir.join(&relative),
+                &relative,
+                prev.as_ref().map(|(p, f)| (p.as_path(), *f)),
+            )?;
+            files.push(file);
+        }
+        let sequence = previous.as_ref().map_or(1, |(_, m)| m.sequence + 1);
+        let manifest = Manifest::new(name.to_string(), sequence, files);
+        manifest.save(&dir)?;
+        Ok(manifest)
+    }
+
+    fn restore(&self, snapshot: &str, file: &str, dest: &str) -> Result<()> {
+        if !is_relative_path(snapshot) || !is_relative_path(dest) {
+            return Err(RorError::PathError);
+        }
+        let snapshot_dir = Path::new(&self.config.backup_path).join(snapshot);
+        let dest_path = Path::new(&self.config.data_path).join(dest);
+        backup::restore_snapshot(&snapshot_dir, file, &dest_path)?;
+        output_prompt(format!("Client [{0}] restored '{1}' from backup '{2}' into '{3}'", self.address, file, snapshot, dest));
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &str, cursor: Option<String>, limit: u32) -> Result<OperateResult> {
+        let limit = match limit {
+            0 => MAX_PAGE_SIZE,
+            n => n.min(MAX_PAGE_SIZE),
+        } as usize;
+        let (items, next_cursor) = self.db.lock().unwrap().scan(prefix, cursor.as_deref(), limit)?;
+        Ok(OperateResult::Entries { items, next_cursor })
+    }
+
+    fn create_db(&self, path: &str) -> Result<()> {
+        let db_path = resolve_db_path(&self.config, path)?;
+        if Path::new(&db_path).exists() {
+            return Err(RorError::KvError(KvError::FileExists(db_path)));
+        }
+        if let Some(parent) = Path::new(&db_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(&db_path)?;
+        output_prompt(format!("Client [{0}] created database '{1}'", self.address, db_path));
+        Ok(())
+    }
+
+    // A datafile can only be dropped while no client has it open. The list of open
+    // datafiles stays locked until the file is gone, so nobody can open it meanwhile.
+    fn drop_db(&self, path: &str) -> Result<()> {
+        let db_path = resolve_db_path(&self.config, path)?;
+        let mut dbs = self.dbs.lock().unwrap();
+        let mut open = None;
+        for (key, db) in dbs.iter() {
+            if is_same_file(key, &db_path)? {
+                if Arc::strong_count(db) > 1 {
+                    return Err(RorError::DatabaseInUse(path.to_string()));
+                }
+                open = Some(key.clone());
+                break;
+            }
+        }
+        if let Some(key) = open {
+            dbs.remove(&key);
+        }
+        fs::remove_file(&db_path)?;
+        let _ = fs::remove_file(hint_path(&db_path));
+        output_prompt(format!("Client [{0}] dropped database '{1}'", self.address, db_path));
+        Ok(())
+    }
+
+    fn find_open_db(&self, path: &Path) -> Result<Option<Arc<Mutex<DataStore>>>> {
+        for (key, db) in self.dbs.lock().unwrap().iter() {
+            if is_same_file(key, path)? {
+                return Ok(Some(Arc::clone(db)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl WatchRegistry {
+    fn register(&mut self, db_path: &str, key: &str, notify: Notify) -> u64 {
+        self.next_id += 1;
+        self.watchers
+            .entry((datafile_key(db_path), key.to_string()))
+            .or_default()
+            .push((self.next_id, notify));
+        self.next_id
+    }
+
+    fn unregister(&mut self, db_path: &str, key: &str, id: u64) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        let index = (datafile_key(db_path), key.to_string());
+        if let Some(list) = self.watchers.get_mut(&index) {
+            list.retain(|(i, _)| *i != id);
+            if list.is_empty() {
+                self.watchers.remove(&index);
+            }
+        }
+    }
+
+    fn notify(&mut self, db_path: &str, key: &str, value: Option<Value>) {
+        if self.watchers.is_empty() {
+            return;
+        }
+        if let Some(list) = self.watchers.remove(&(datafile_key(db_path), key.to_string())) {
+            for (id, notify) in list {
+                notify(id, OperateResult::KeyChanged { key: key.to_string(), value: value.clone() });
+            }
+        }
+    }
+}
+
+impl ChannelRegistry {
+    fn subscribe(&mut self, token: Token, request: u64, channels: Vec<String>, patterns: Vec<String>) {
+        let subscription = Subscription {
+            request,
+            channels: channels.into_iter().collect(),
+            patterns,
+        };
+        self.subscriptions.insert(token, subscription);
+    }
+
+    // Returns the id of the request that subscribed.
+    fn unsubscribe(&mut self, token: Token) -> Option<u64> {
+        self.subscriptions.remove(&token).map(|s| s.request)
+    }
+
+    // The subscriptions a message on `channel` goes to, with the pattern it matched. A
+    // client subscribed to the channel and to matching patterns gets it once for each.
+    fn receivers(&self, channel: &str) -> Vec<(Token, u64, Option<String>)> {
+        let mut receivers = Vec::new();
+        for (token, subscription) in &self.subscriptions {
+            if subscription.channels.contains(channel) {
+                receivers.push((*token, subscription.request, None));
+            }
+            for pattern in &subscription.patterns {
+                if redis::glob(pattern.as_bytes(), channel.as_bytes()) {
+                    receivers.push((*token, subscription.request, Some(pattern.clone())));
+                }
+            }
+        }
+        receivers
+    }
+}
+
+// Datafile paths sent by clients are relative to data_path and may not leave it.
+// Level 1 and up may write, 2 and up may also delete and create datafiles, and 3 may
+// manage users, backups and the server.
+fn can_write(level: &str) -> bool {
+    matches!(level, "1" | "2" | "3")
+}
+
+fn can_delete(level: &str) -> bool {
+    matches!(level, "2" | "3")
+}
+
+fn is_admin(level: &str) -> bool {
+    level == "3"
+}
+
+// Requests that change datafiles, which a follower refuses and a Raft cluster sends
+// through its log. Compact only rewrites the local log and is allowed.
+fn is_write(request: &OperateRequest) -> bool {
+    matches!(
+        request,
+        OperateRequest::Add { .. }
+            | OperateRequest::Delete { .. }
+            | OperateRequest::CreateDatabase { .. }
+            | OperateRequest::DropDatabase { .. }
+            | OperateRequest::Restore { .. }
+    )
+}
+
+fn resolve_db_path(config: &Config, path: &str) -> Result<String> {
+    let inside = Path::new(path).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || !inside {
+        return Err(RorError::PathError);
+    }
+    let mut db_path_buf = PathBuf::new();
+    db_path_buf.push(&config.data_path);
+    db_path_buf.push(path);
+    match db_path_buf.into_os_string().into_string() {
+        Ok(s) => Ok(s),
+        Err(_) => Err(RorError::PathError),
+    }
+}
+
+// The path watches and Redis expiry times are kept under, the same for every path that
+// names the datafile, such as `a.data` and `./a.data`. It stays as given only for a
+// datafile that does not exist (any more).
+fn datafile_key(db_path: &str) -> String {
+    fs::canonicalize(db_path)
+        .ok()
+        .and_then(|p| p.into_os_string().into_string().ok())
+        .unwrap_or_else(|| db_path.to_string())
+}
+
+// The datafile at `db_path`, opened if no client has it open yet. The lock on `dbs` is
+// held from the lookup to the insert, so clients opening the same datafile at once
+// share one DataStore instead of each getting a writer and index of its own.
+fn open_db(dbs: &Databases, db_path: &str) -> Result<Arc<Mutex<DataStore>>> {
+    let mut dbs = dbs.lock().unwrap();
+    fs::OpenOptions::new().create(true).append(true).open(db_path)?;
+    for (key, db) in dbs.iter() {
+        match is_same_file(key, db_path) {
+            Ok(true) => return Ok(Arc::clone(db)),
+            Ok(false) => (),
+            Err(_) => return Err(RorError::ServerError),
+        }
+    }
+    let db = Arc::new(Mutex::new(DataStore::open(db_path)?));
+    dbs.insert(db_path.to_string(), Arc::clone(&db));
+    Ok(db)
+}
+
+fn data_files(dir: &Path, skip: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if !is_same_file(&path, skip).unwrap_or(false) {
+                files.append(&mut data_files(&path, skip)?);
+            }
+        } else if !is_temporary_file(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+// The resident set size of the server process, from /proc on Linux.
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory() -> Option<u64> {
+    None
+}
+
+// Compaction output, hint files and a follower's copies in progress belong to a
+// datafile and are not datafiles themselves.
+fn is_temporary_file(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".compact") || name.ends_with(".hint") || name.ends_with(".hint.tmp") || name.ends_with(".sync")
+}
+
+fn is_relative_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| matches!(c, Component::Normal(_)))
 }
 
 #[derive(Deserialize,Serialize,Clone)]
@@ -490,6 +2241,109 @@ struct Config {
     local_user: String,
     default_db: String,
     auto_refresh: u32,
+    #[serde(default = "default_backup_path")]
+    backup_path: String,
+    // Worker threads executing requests, 0 starts one per CPU.
+    #[serde(default)]
+    workers: usize,
+    // Seconds before TCP keepalive probes an idle connection, 0 disables keepalive.
+    #[serde(default = "default_keepalive")]
+    keepalive: u64,
+    // Seconds a client may take to finish a request it started sending, and to start
+    // reading replies queued for it. 0 disables the deadline.
+    #[serde(default = "default_io_timeout")]
+    read_timeout: u64,
+    #[serde(default = "default_io_timeout")]
+    write_timeout: u64,
+    // Largest request in bytes a logged in client may send.
+    #[serde(default = "default_max_frame_size")]
+    max_frame_size: u32,
+    // PEM files of the server certificate chain and its private key. TLS is enabled
+    // when both are set, and with tls_client_ca clients must present a certificate
+    // signed by that CA.
+    #[serde(default)]
+    tls_cert: String,
+    #[serde(default)]
+    tls_key: String,
+    #[serde(default)]
+    tls_client_ca: String,
+    // Seconds a shutdown waits for the requests in progress before closing the datafiles.
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+    // Address of the HTTP listener serving Prometheus metrics at /metrics, empty
+    // disables it.
+    #[serde(default)]
+    metrics_address: String,
+    // Address of the HTTP listener serving the REST gateway, empty disables it.
+    #[serde(default)]
+    rest_address: String,
+    // Seconds a token from POST /auth/token stays valid.
+    #[serde(default = "default_rest_token_ttl")]
+    rest_token_ttl: u64,
+    // Address of the listener speaking the Redis protocol, empty disables it.
+    #[serde(default)]
+    redis_address: String,
+    // The datafiles Redis clients reach with SELECT 0, 1, ...
+    #[serde(default = "default_redis_databases")]
+    redis_databases: Vec<String>,
+    // Address of the listener speaking the memcached text protocol, empty disables it.
+    // Its clients use memcached_database as the user in memcached_user ("user@password").
+    #[serde(default)]
+    memcached_address: String,
+    #[serde(default = "default_memcached_database")]
+    memcached_database: String,
+    #[serde(default)]
+    memcached_user: String,
+    // "leader" serves reads and writes. A "follower" copies every datafile of the server
+    // at leader_address, logged in as leader_user ("user@password"), and only serves
+    // reads. With leader_tls_ca it connects over TLS.
+    #[serde(default = "default_role")]
+    role: String,
+    #[serde(default)]
+    leader_address: String,
+    #[serde(default)]
+    leader_user: String,
+    #[serde(default)]
+    leader_tls_ca: String,
+    // With role "raft" the server is a member of a Raft cluster, reached by the other
+    // members at raft_address. raft_peers are the Raft addresses of the members the
+    // cluster starts with, this server among them; a server joining later starts with
+    // none and waits to be added. The log is compacted into a snapshot in raft_path
+    // every raft_snapshot_entries entries, 0 never compacts it.
+    #[serde(default)]
+    raft_address: String,
+    #[serde(default)]
+    raft_peers: Vec<String>,
+    #[serde(default = "default_raft_path")]
+    raft_path: String,
+    #[serde(default = "default_raft_snapshot_entries")]
+    raft_snapshot_entries: u64,
+    // The most clients logged in at once, in total, as one user and from one IP
+    // address. Clients over a limit are refused with TooManyConnections. 0 is no limit.
+    #[serde(default)]
+    max_connections: usize,
+    #[serde(default)]
+    max_connections_per_user: usize,
+    #[serde(default)]
+    max_connections_per_ip: usize,
+    // The requests a second each user may make, over all its connections, and how many
+    // it may make at once after a pause, 0 being one second's worth. Requests over the
+    // limit are answered with RateLimited. A rate_limit of 0 turns it off.
+    #[serde(default)]
+    rate_limit: u32,
+    #[serde(default)]
+    rate_burst: u32,
+    // Where the audit log of logins and admin requests is written, empty disables it.
+    // With audit_writes it also records every Add and Delete. The file is rotated when
+    // it would grow past audit_max_size bytes, 0 never, and with audit_daily every day.
+    #[serde(default)]
+    audit_path: String,
+    #[serde(default)]
+    audit_writes: bool,
+    #[serde(default = "default_audit_max_size")]
+    audit_max_size: u64,
+    #[serde(default)]
+    audit_daily: bool,
 }
 
 impl Config {
@@ -504,8 +2358,84 @@ impl Config {
             local_user: String::new(),
             default_db: String::new(),
             auto_refresh: 20,
+            backup_path: default_backup_path(),
+            workers: 0,
+            keepalive: default_keepalive(),
+            read_timeout: default_io_timeout(),
+            write_timeout: default_io_timeout(),
+            max_frame_size: default_max_frame_size(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            tls_client_ca: String::new(),
+            shutdown_timeout: default_shutdown_timeout(),
+            metrics_address: String::new(),
+            rest_address: String::new(),
+            rest_token_ttl: default_rest_token_ttl(),
+            redis_address: String::new(),
+            redis_databases: default_redis_databases(),
+            memcached_address: String::new(),
+            memcached_database: default_memcached_database(),
+            memcached_user: String::new(),
+            role: default_role(),
+            leader_address: String::new(),
+            leader_user: String::new(),
+            leader_tls_ca: String::new(),
+            raft_address: String::new(),
+            raft_peers: Vec::new(),
+            raft_path: default_raft_path(),
+            raft_snapshot_entries: default_raft_snapshot_entries(),
+            max_connections: 0,
+            max_connections_per_user: 0,
+            max_connections_per_ip: 0,
+            rate_limit: 0,
+            rate_burst: 0,
+            audit_path: String::new(),
+            audit_writes: false,
+            audit_max_size: default_audit_max_size(),
+            audit_daily: false,
+        }
+    }
+
+    fn is_follower(&self) -> bool {
+        self.role == "follower"
+    }
+
+    fn is_raft(&self) -> bool {
+        self.role == "raft"
+    }
+
+    // Whether the listeners that write to the datafiles themselves may write. A follower
+    // refuses writes, and in a Raft cluster they must go through the replicated log.
+    fn writes_directly(&self) -> bool {
+        !self.is_follower() && !self.is_raft()
+    }
+
+    // The built-in REPL trusts the server's own certificate, and presents it as its
+    // client certificate when client certificates are required.
+    fn repl_tls(&self) -> Option<TlsOptions> {
+        if self.tls_cert.is_empty() {
+            return None;
+        }
+        let tls = TlsOptions::new(self.tls_cert.clone());
+        match self.tls_client_ca.is_empty() {
+            true => Some(tls),
+            false => Some(tls.with_client_cert(self.tls_cert.clone(), self.tls_key.clone())),
         }
     }
+
+    fn tls_config(&self) -> Result<Option<Arc<rustls::ServerConfig>>> {
+        if self.tls_cert.is_empty() && self.tls_key.is_empty() {
+            return Ok(None);
+        }
+        if self.tls_cert.is_empty() || self.tls_key.is_empty() {
+            return Err(RorError::TlsConfigError("both tls_cert and tls_key must be set".to_string()));
+        }
+        let client_ca = match self.tls_client_ca.as_str() {
+            "" => None,
+            ca => Some(ca),
+        };
+        Ok(Some(tls::server_config(&self.tls_cert, &self.tls_key, client_ca)?))
+    }
     pub fn get_server() -> Result<Self> {
         let mut file = File::open("config/server.toml")?;
         let mut c = String::new();
@@ -515,7 +2445,55 @@ impl Config {
     }
 }
 
-fn output_prompt<T: std::fmt::Display>(content: T) {
+fn default_backup_path() -> String {
+    "./backups/".to_string()
+}
+
+fn default_keepalive() -> u64 {
+    60
+}
+
+fn default_io_timeout() -> u64 {
+    30
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+fn default_rest_token_ttl() -> u64 {
+    3600
+}
+
+fn default_redis_databases() -> Vec<String> {
+    vec!["default.data".to_string()]
+}
+
+fn default_memcached_database() -> String {
+    "default.data".to_string()
+}
+
+fn default_role() -> String {
+    "leader".to_string()
+}
+
+fn default_raft_path() -> String {
+    "./raft/".to_string()
+}
+
+fn default_raft_snapshot_entries() -> u64 {
+    1000
+}
+
+fn default_audit_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+pub(crate) fn output_prompt<T: std::fmt::Display>(content: T) {
     let time = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
     println!("[{0}] {1}",time.yellow(),content);
 }
\ No newline at end of file
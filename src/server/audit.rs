@@ -0,0 +1,307 @@
+use std::fs::OpenOptions;
+use chrono::{NaiveDate, SecondsFormat};
+use super::*;
+
+// Set on every client when audit_path is, None otherwise.
+pub type Audit = Option<Arc<AuditLog>>;
+
+// An append-only log of who did what, one JSON object a line: logins and failed logins
+// on every listener, admin requests and, with audit_writes, every Add and Delete. When
+// the file would grow past audit_max_size, or with audit_daily on the first record of
+// a new day, it is renamed to `<audit_path>.<time>` and a new one is started. Old
+// files are never removed, that is left to whoever collects them.
+pub struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    daily: bool,
+    segment: Mutex<Segment>,
+}
+
+struct Segment {
+    file: File,
+    size: u64,
+    day: NaiveDate,
+}
+
+// One line of the audit log, without its time. Passwords and values are never written.
+#[derive(Serialize, Default)]
+pub struct Record<'a> {
+    // "login", "login_failed", "admin" or "write".
+    pub event: &'a str,
+    pub user: &'a str,
+    pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<&'a str>,
+    pub request: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<&'a str>,
+    // What an admin request acted on: a user, datafile, backup, client or member.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<&'a str>,
+    // "ok", or why the login or request failed.
+    pub outcome: &'a str,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    record: Record<'a>,
+}
+
+// What the audit log records of a request.
+pub struct Subject {
+    pub event: &'static str,
+    pub key: Option<String>,
+    pub target: Option<String>,
+}
+
+impl AuditLog {
+    pub fn open(config: &Config) -> Result<Audit> {
+        if config.audit_path.is_empty() {
+            return Ok(None);
+        }
+        let path = PathBuf::from(&config.audit_path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let segment = Self::create(&path)?;
+        Ok(Some(Arc::new(AuditLog {
+            path,
+            max_size: config.audit_max_size,
+            daily: config.audit_daily,
+            segment: Mutex::new(segment),
+        })))
+    }
+
+    fn create(path: &Path) -> std::io::Result<Segment> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Segment { file, size, day: Local::now().date_naive() })
+    }
+
+    // A record that cannot be written is reported on the console, the request it
+    // belongs to has already run.
+    pub fn record(&self, record: Record) {
+        let now = Local::now();
+        let line = Line { time: now.to_rfc3339_opts(SecondsFormat::Millis, false), record };
+        let mut bytes = match serde_json::to_vec(&line) {
+            Ok(b) => b,
+            Err(e) => {
+                output_prompt(format!("Unable to write the audit log, {}", e));
+                return;
+            }
+        };
+        bytes.push(b'\n');
+        let mut segment = self.segment.lock().unwrap();
+        let full = self.max_size > 0 && segment.size > 0 && segment.size + bytes.len() as u64 > self.max_size;
+        let new_day = self.daily && segment.day != now.date_naive();
+        if full || new_day {
+            match self.rotate() {
+                Ok(s) => *segment = s,
+                Err(e) => output_prompt(format!("Unable to rotate the audit log, {}", e)),
+            }
+        }
+        match segment.file.write_all(&bytes) {
+            Ok(()) => segment.size += bytes.len() as u64,
+            Err(e) => output_prompt(format!("Unable to write the audit log, {}", e)),
+        }
+    }
+
+    // Renames the current file after the time it is rotated at and starts a new one.
+    fn rotate(&self) -> std::io::Result<Segment> {
+        let stamp = Local::now().format("%Y-%m-%dT%H-%M-%S").to_string();
+        let mut rotated = PathBuf::from(format!("{0}.{1}", self.path.display(), stamp));
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{0}.{1}.{2}", self.path.display(), stamp, n));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        Self::create(&self.path)
+    }
+}
+
+// None for the requests the audit log leaves out, Add and Delete among them unless
+// `writes` is set.
+pub fn subject(request: &OperateRequest, writes: bool) -> Option<Subject> {
+    let (event, key, target) = match request {
+        OperateRequest::Add { key, .. } | OperateRequest::Delete { key } if writes => ("write", Some(key.clone()), None),
+        OperateRequest::CreateUser { name, .. } | OperateRequest::DeleteUser { name } => ("admin", None, Some(name.clone())),
+        OperateRequest::Compact => ("admin", None, None),
+        OperateRequest::Backup { dest } => ("admin", None, Some(dest.clone())),
+        OperateRequest::Restore { snapshot, .. } => ("admin", None, Some(snapshot.clone())),
+        OperateRequest::CreateDatabase { path } | OperateRequest::DropDatabase { path } => ("admin", None, Some(path.clone())),
+        OperateRequest::Shutdown => ("admin", None, None),
+        OperateRequest::KillClient { address } | OperateRequest::AddMember { address } | OperateRequest::RemoveMember { address } => {
+            ("admin", None, Some(address.clone()))
+        }
+        _ => return None,
+    };
+    Some(Subject { event, key, target })
+}
+
+// Records a login, or a failed one with why it failed as the outcome.
+pub fn login(audit: &Audit, request: &str, user: &str, address: SocketAddr, database: Option<&str>, outcome: &str) {
+    if let Some(audit) = audit {
+        audit.record(Record {
+            event: if outcome == "ok" { "login" } else { "login_failed" },
+            user,
+            address: address.to_string(),
+            database,
+            request,
+            outcome,
+            ..Default::default()
+        });
+    }
+}
+
+pub fn refused(err: &ConnectError) -> &'static str {
+    match err {
+        ConnectError::RequestError => "bad_request",
+        ConnectError::UserNotFound => "user_not_found",
+        ConnectError::PasswordError => "wrong_password",
+        ConnectError::OpenFileError => "open_failed",
+        ConnectError::PathError => "bad_path",
+        ConnectError::ServerError => "server_error",
+        ConnectError::UnsupportedVersion { .. } => "unsupported_version",
+        ConnectError::TooManyConnections => "too_many_connections",
+    }
+}
+
+pub fn outcome(result: &OperateResult) -> &'static str {
+    match result {
+        OperateResult::PermissionDenied => "permission_denied",
+        OperateResult::KeyNotFound => "key_not_found",
+        OperateResult::Failure => "failure",
+        OperateResult::ReadOnly => "read_only",
+        OperateResult::Redirect { .. } => "redirect",
+        OperateResult::RateLimited => "rate_limited",
+        OperateResult::Uncommitted { .. } => "uncommitted",
+        _ => "ok",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An audit log in a fresh directory, removed with it.
+    struct TestLog {
+        dir: PathBuf,
+        log: Arc<AuditLog>,
+    }
+
+    impl TestLog {
+        fn new(name: &str, max_size: u64, daily: bool) -> Self {
+            let dir = std::env::temp_dir().join(format!("rdb-audit-{0}-{1}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            let mut config = Config::default();
+            config.audit_path = dir.join("audit.log").to_string_lossy().into_owned();
+            config.audit_max_size = max_size;
+            config.audit_daily = daily;
+            let log = AuditLog::open(&config).unwrap().unwrap();
+            TestLog { dir, log }
+        }
+
+        fn write(&self, request: &OperateRequest) {
+            let subject = subject(request, true).unwrap();
+            self.log.record(Record {
+                event: subject.event,
+                user: "root",
+                address: "127.0.0.1:4000".to_string(),
+                database: Some("default.data"),
+                request: request.name(),
+                key: subject.key.as_deref(),
+                target: subject.target.as_deref(),
+                outcome: "ok",
+            });
+        }
+
+        // The files of the log, the current one last.
+        fn files(&self) -> Vec<String> {
+            let mut rotated: Vec<PathBuf> = fs::read_dir(&self.dir).unwrap().map(|e| e.unwrap().path()).collect();
+            rotated.retain(|p| p != &self.dir.join("audit.log"));
+            rotated.sort();
+            rotated.push(self.dir.join("audit.log"));
+            rotated.iter().map(|p| fs::read_to_string(p).unwrap()).collect()
+        }
+    }
+
+    impl Drop for TestLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn add(key: &str) -> OperateRequest {
+        OperateRequest::Add { key: key.to_string(), value: Value::String("secret value".to_string()) }
+    }
+
+    #[test]
+    fn records_leave_out_values_and_passwords() {
+        let log = TestLog::new("records", 0, false);
+        log.write(&add("user:1"));
+        log.write(&OperateRequest::CreateUser { name: "alice".to_string(), password: "hunter2".to_string(), level: "1".to_string() });
+        log.write(&OperateRequest::Delete { key: "user:1".to_string() });
+
+        let files = log.files();
+        assert_eq!(files.len(), 1);
+        let lines: Vec<serde_json::Value> = files[0].lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!((&lines[0]["event"], &lines[0]["request"], &lines[0]["key"]), (&"write".into(), &"add".into(), &"user:1".into()));
+        assert_eq!((&lines[1]["event"], &lines[1]["target"]), (&"admin".into(), &"alice".into()));
+        assert!(lines[1].get("key").is_none());
+        assert_eq!(lines[2]["request"], "delete");
+        assert!(lines.iter().all(|l| l["time"].is_string() && l["outcome"] == "ok"));
+        assert!(!files[0].contains("secret value") && !files[0].contains("hunter2"));
+    }
+
+    #[test]
+    fn writes_are_only_recorded_when_asked_for() {
+        assert!(subject(&add("key"), false).is_none());
+        assert!(subject(&OperateRequest::Get { key: "key".to_string() }, true).is_none());
+        assert_eq!(subject(&OperateRequest::Backup { dest: "nightly".to_string() }, false).unwrap().target.as_deref(), Some("nightly"));
+    }
+
+    #[test]
+    fn a_full_file_is_rotated() {
+        let log = TestLog::new("size", 400, false);
+        for i in 0..10 {
+            log.write(&add(&format!("key{}", i)));
+        }
+        let files = log.files();
+        assert!(files.len() > 2, "{} files", files.len());
+        assert!(files.iter().all(|f| !f.is_empty() && f.len() <= 400));
+        let keys: Vec<String> = files.iter().flat_map(|f| f.lines()).map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["key"].to_string()).collect();
+        assert_eq!(keys, (0..10).map(|i| format!("\"key{}\"", i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn a_record_bigger_than_the_limit_gets_a_file_of_its_own() {
+        let log = TestLog::new("big", 100, false);
+        log.write(&add(&"k".repeat(200)));
+        log.write(&add(&"k".repeat(200)));
+        let files = log.files();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().all(|f| f.lines().count() == 1));
+    }
+
+    #[test]
+    fn a_new_day_starts_a_new_file() {
+        let log = TestLog::new("daily", 0, true);
+        log.write(&add("yesterday"));
+        log.log.segment.lock().unwrap().day -= chrono::Duration::days(1);
+        log.write(&add("today"));
+        log.write(&add("today again"));
+        let files = log.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!((files[0].lines().count(), files[1].lines().count()), (1, 2));
+
+        let undated = TestLog::new("undated", 0, false);
+        undated.write(&add("yesterday"));
+        undated.log.segment.lock().unwrap().day -= chrono::Duration::days(1);
+        undated.write(&add("today"));
+        assert_eq!(undated.files().len(), 1);
+    }
+}
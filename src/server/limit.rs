@@ -0,0 +1,218 @@
+use std::net::IpAddr;
+use super::*;
+
+// The connection limits and request rate of server.toml, shared by every listener. A
+// connection holds a Slot while it is open, which counts it in the total and for its
+// address, and for its user once it has logged in. All connections of a user take
+// their requests from one bucket, which refills at rate_limit tokens a second up to
+// rate_burst.
+pub struct Limiter {
+    max_connections: usize,
+    max_per_user: usize,
+    max_per_ip: usize,
+    rate: f64,
+    burst: f64,
+    counts: Mutex<Counts>,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    ips: HashMap<IpAddr, usize>,
+    users: HashMap<String, usize>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+// Dropping the slot releases it.
+pub struct Slot {
+    limiter: Arc<Limiter>,
+    ip: IpAddr,
+    user: Option<String>,
+}
+
+impl Limiter {
+    pub fn new(config: &Config) -> Arc<Self> {
+        Arc::new(Limiter {
+            max_connections: config.max_connections,
+            max_per_user: config.max_connections_per_user,
+            max_per_ip: config.max_connections_per_ip,
+            rate: config.rate_limit as f64,
+            burst: match config.rate_burst {
+                0 => config.rate_limit as f64,
+                b => b as f64,
+            },
+            counts: Mutex::new(Counts::default()),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Counts a new connection from `ip`, or says which limit it is over.
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> std::result::Result<Slot, String> {
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_connections > 0 && counts.total >= self.max_connections {
+            return Err("the server is at max_connections".to_string());
+        }
+        let from_ip = counts.ips.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip > 0 && from_ip >= self.max_per_ip {
+            return Err(format!("{} is at max_connections_per_ip", ip));
+        }
+        counts.total += 1;
+        counts.ips.insert(ip, from_ip + 1);
+        Ok(Slot { limiter: Arc::clone(self), ip, user: None })
+    }
+
+    // Takes one request from the user's bucket, false if it is empty.
+    pub fn allow(&self, user: &str) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(user.to_string()).or_insert(TokenBucket { tokens: self.burst, refilled: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * self.rate).min(self.burst);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Slot {
+    // Counts the connection for `user`, instead of the user it was logged in as before.
+    pub fn login(&mut self, user: &str) -> std::result::Result<(), String> {
+        if self.user.as_deref() == Some(user) {
+            return Ok(());
+        }
+        let limiter = &self.limiter;
+        let mut counts = limiter.counts.lock().unwrap();
+        let of_user = counts.users.get(user).copied().unwrap_or(0);
+        if limiter.max_per_user > 0 && of_user >= limiter.max_per_user {
+            return Err(format!("user '{}' is at max_connections_per_user", user));
+        }
+        counts.users.insert(user.to_string(), of_user + 1);
+        if let Some(previous) = self.user.replace(user.to_string()) {
+            release(&mut counts.users, &previous);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total -= 1;
+        release(&mut counts.ips, &self.ip);
+        if let Some(user) = self.user.take() {
+            release(&mut counts.users, &user);
+        }
+    }
+}
+
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(n) = counts.get_mut(key) {
+        *n -= 1;
+        if *n == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(set: impl FnOnce(&mut Config)) -> Arc<Limiter> {
+        let mut config = Config::default();
+        set(&mut config);
+        Limiter::new(&config)
+    }
+
+    fn refused(limiter: &Arc<Limiter>, ip: IpAddr) -> String {
+        limiter.connect(ip).err().expect("the connection was counted")
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn connections_are_counted_in_total_and_by_address() {
+        let limiter = limiter(|c| {
+            c.max_connections = 3;
+            c.max_connections_per_ip = 2;
+        });
+        let first = limiter.connect(ip(1)).unwrap();
+        let _second = limiter.connect(ip(1)).unwrap();
+        assert!(refused(&limiter, ip(1)).contains("max_connections_per_ip"));
+        let _third = limiter.connect(ip(2)).unwrap();
+        assert_eq!(refused(&limiter, ip(3)), "the server is at max_connections");
+
+        // Dropping a slot frees it for its address and for the total.
+        drop(first);
+        let _again = limiter.connect(ip(1)).unwrap();
+        assert!(limiter.connect(ip(3)).is_err());
+    }
+
+    #[test]
+    fn slots_are_counted_for_the_user_they_logged_in_as() {
+        let limiter = limiter(|c| c.max_connections_per_user = 1);
+        let mut first = limiter.connect(ip(1)).unwrap();
+        first.login("alice").unwrap();
+        first.login("alice").unwrap();
+        let mut second = limiter.connect(ip(1)).unwrap();
+        assert!(second.login("alice").unwrap_err().contains("max_connections_per_user"));
+        second.login("bob").unwrap();
+
+        // Logging in as someone else releases the previous user.
+        first.login("carol").unwrap();
+        let mut third = limiter.connect(ip(2)).unwrap();
+        third.login("alice").unwrap();
+        drop(second);
+        first.login("bob").unwrap();
+        drop((first, third));
+        let counts = limiter.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.ips.is_empty() && counts.users.is_empty());
+    }
+
+    #[test]
+    fn zero_means_no_limit() {
+        let limiter = limiter(|_| ());
+        let slots: Vec<Slot> = (0..100).map(|_| limiter.connect(ip(1)).unwrap()).collect();
+        assert_eq!(slots.len(), 100);
+        assert!((0..1000).all(|_| limiter.allow("alice")));
+    }
+
+    #[test]
+    fn each_user_has_a_bucket_of_burst_requests() {
+        let limiter = limiter(|c| {
+            c.rate_limit = 10;
+            c.rate_burst = 3;
+        });
+        assert!((0..3).all(|_| limiter.allow("alice")));
+        assert!(!limiter.allow("alice"));
+        assert!((0..3).all(|_| limiter.allow("bob")));
+
+        // The bucket refills at rate_limit a second, but never past the burst.
+        limiter.buckets.lock().unwrap().get_mut("alice").unwrap().refilled -= Duration::from_millis(150);
+        assert!(limiter.allow("alice"));
+        assert!(!limiter.allow("alice"));
+        limiter.buckets.lock().unwrap().get_mut("alice").unwrap().refilled -= Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.allow("alice")));
+        assert!(!limiter.allow("alice"));
+    }
+
+    #[test]
+    fn the_burst_defaults_to_the_rate() {
+        let limiter = limiter(|c| c.rate_limit = 2);
+        assert!(limiter.allow("alice") && limiter.allow("alice"));
+        assert!(!limiter.allow("alice"));
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    io::{self, BufRead, BufReader, BufWriter},
+    net::TcpStream,
+    sync::atomic::{AtomicBool, AtomicU64},
+    time::SystemTime,
+};
+use super::*;
+
+// Longest command line, which bounds the number of keys of one get.
+const MAX_LINE: usize = 64 * 1024;
+const MAX_KEY_LENGTH: usize = 250;
+// Exptimes up to 30 days are relative to now, larger ones are Unix times.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+// Replies held back for pipelined commands are written once they reach this size.
+const MAX_PENDING_REPLIES: usize = 64 * 1024;
+// The reply to every command of a user whose requests are over rate_limit.
+const RATE_LIMITED: &str = "SERVER_ERROR rate limit exceeded";
+
+// A listener speaking the memcached text protocol. It has no login, every connection
+// acts as the user in `memcached_user` on the datafile in `memcached_database`.
+// An item is stored as an Array of its data (a String), flags, expiry time and cas
+// unique, so the flags and expiry time survive a restart.
+pub struct Memcached {
+    config: Config,
+    client: Client,
+    limiter: Arc<limit::Limiter>,
+    metrics: Arc<Metrics>,
+    stopping: Arc<AtomicBool>,
+    // Starts at the time the server started in microseconds, so cas uniques keep
+    // growing across restarts.
+    next_cas: AtomicU64,
+}
+
+struct Item {
+    data: String,
+    flags: u32,
+    // Unix time in seconds, 0 for items that do not expire.
+    expires: i64,
+    cas: u64,
+}
+
+enum Store {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+impl Memcached {
+    pub fn new(config: Config, client: Client, limiter: Arc<limit::Limiter>, metrics: Arc<Metrics>, stopping: Arc<AtomicBool>) -> Self {
+        let start = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(1);
+        Memcached {
+            config,
+            client,
+            limiter,
+            metrics,
+            stopping,
+            next_cas: AtomicU64::new(start),
+        }
+    }
+
+    pub fn serve(self: Arc<Self>, listener: std::net::TcpListener) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+                let memcached = Arc::clone(&self);
+                thread::spawn(move || memcached.run(stream));
+            }
+        });
+    }
+
+    fn run(&self, stream: TcpStream) {
+        let address = match stream.peer_addr() {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        if self.config.timeout > 0 {
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(self.config.timeout)));
+        }
+        let (read_half, write_half) = match stream.try_clone() {
+            Ok(s) => (s, stream),
+            Err(_) => return,
+        };
+        let mut reader = BufReader::new(read_half);
+        let mut writer = BufWriter::new(write_half);
+        // Every memcached client acts as memcached_user, so they all count for it.
+        let _slot = match self.limiter.connect(address.ip()).and_then(|mut s| s.login(&self.client.user).map(|_| s)) {
+            Ok(s) => s,
+            Err(reason) => {
+                output_prompt(format!("memcached client [{0}], refused. reason: {1}", address, reason));
+                self.metrics.error("too_many_connections");
+                let _ = writer.write_all(b"SERVER_ERROR too many connections\r\n").and_then(|_| writer.flush());
+                return;
+            }
+        };
+        output_prompt(format!("New memcached connection: {}", address));
+        let mut out = Vec::new();
+        loop {
+            let line = match read_line(&mut reader) {
+                Ok(Some(l)) => l,
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    self.metrics.error("protocol");
+                    out.extend_from_slice(format!("CLIENT_ERROR {}\r\n", e).as_bytes());
+                    break;
+                }
+                Err(_) => break,
+            };
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.is_empty() {
+                out.extend_from_slice(b"ERROR\r\n");
+            } else if args[0] == "quit" {
+                break;
+            } else if self.stopping.load(Ordering::SeqCst) {
+                out.extend_from_slice(b"SERVER_ERROR the server is shutting down\r\n");
+                break;
+            } else {
+                match self.command(&mut reader, &args, &mut out) {
+                    Ok(()) => (),
+                    // The data of a storage command could not be read, so the rest of
+                    // the stream cannot be parsed either.
+                    Err(e) => {
+                        out.extend_from_slice(format!("CLIENT_ERROR {}\r\n", e).as_bytes());
+                        break;
+                    }
+                }
+            }
+            // Replies to pipelined commands are written together.
+            if reader.buffer().is_empty() || out.len() >= MAX_PENDING_REPLIES {
+                let written = writer.write_all(&out).and_then(|_| writer.flush());
+                out.clear();
+                if written.is_err() {
+                    break;
+                }
+            }
+        }
+        // The replies to the commands before a quit, the end of the stream or an error.
+        if !out.is_empty() {
+            let _ = writer.write_all(&out).and_then(|_| writer.flush());
+        }
+        output_prompt(format!("memcached client [{0}] disconnected", address));
+    }
+
+    fn command(&self, reader: &mut BufReader<TcpStream>, args: &[&str], out: &mut Vec<u8>) -> io::Result<()> {
+        let kind = kind(args[0]);
+        let started = Instant::now();
+        self.metrics.request(kind);
+        let limited = !self.limiter.allow(&self.client.user);
+        if limited {
+            self.metrics.error("rate_limited");
+        }
+        let noreply = args.len() > 1 && args[args.len() - 1] == "noreply";
+        let reply = match (args[0], &args[1..]) {
+            ("get", keys) | ("gets", keys) if !keys.is_empty() && !limited => {
+                self.get(keys, args[0] == "gets", out);
+                self.metrics.observe(kind, started.elapsed());
+                return Ok(());
+            }
+            ("set", params) | ("add", params) | ("replace", params) | ("cas", params) => {
+                let command = match store_command(args[0], params) {
+                    Some(c) => c,
+                    None => {
+                        out.extend_from_slice(b"CLIENT_ERROR bad command line format\r\n");
+                        return Ok(());
+                    }
+                };
+                let (key, flags, exptime, length, store) = command;
+                if length > self.config.max_frame_size as usize {
+                    return Err(invalid("object too large"));
+                }
+                let mut data = vec![0; length + 2];
+                reader.read_exact(&mut data)?;
+                if !data.ends_with(b"\r\n") {
+                    return Err(invalid("bad data chunk"));
+                }
+                data.truncate(length);
+                // The data is read either way, so the next command can be parsed.
+                match String::from_utf8(data) {
+                    _ if limited => RATE_LIMITED.to_string(),
+                    Ok(data) => self.store(key, flags, exptime, data, store),
+                    Err(_) => "SERVER_ERROR data must be valid UTF-8".to_string(),
+                }
+            }
+            _ if limited => RATE_LIMITED.to_string(),
+            ("delete", [key]) | ("delete", [key, "noreply"]) => self.delete(key),
+            ("incr", [key, amount]) | ("incr", [key, amount, "noreply"]) => self.incr(key, amount, true),
+            ("decr", [key, amount]) | ("decr", [key, amount, "noreply"]) => self.incr(key, amount, false),
+            ("touch", [key, exptime]) | ("touch", [key, exptime, "noreply"]) => self.touch(key, exptime),
+            ("version", []) => format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+            _ => "ERROR".to_string(),
+        };
+        self.metrics.observe(kind, started.elapsed());
+        if !noreply {
+            out.extend_from_slice(reply.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Ok(())
+    }
+
+    fn get(&self, keys: &[&str], with_cas: bool, out: &mut Vec<u8>) {
+        let mut db = self.client.db.lock().unwrap();
+        for key in keys {
+            let item = match self.read(&mut db, key) {
+                Ok(Some(i)) => i,
+                Ok(None) => continue,
+                Err(e) => {
+                    out.extend_from_slice(format!("SERVER_ERROR {}\r\n", e).as_bytes());
+                    return;
+                }
+            };
+            let header = match with_cas {
+                true => format!("VALUE {0} {1} {2} {3}\r\n", key, item.flags, item.data.len(), item.cas),
+                false => format!("VALUE {0} {1} {2}\r\n", key, item.flags, item.data.len()),
+            };
+            out.extend_from_slice(header.as_bytes());
+            out.extend_from_slice(item.data.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"END\r\n");
+    }
+
+    fn store(&self, key: &str, flags: u32, exptime: i64, data: String, store: Store) -> String {
+        if !can_write(&self.client.level) {
+            return self.denied();
+        }
+        if !self.config.writes_directly() {
+            return self.read_only();
+        }
+        let mut db = self.client.db.lock().unwrap();
+        let current = match self.read(&mut db, key) {
+            Ok(i) => i,
+            Err(e) => return server_error(e),
+        };
+        let allowed = match (&store, &current) {
+            (Store::Set, _) => true,
+            (Store::Add, current) => current.is_none(),
+            (Store::Replace, current) => current.is_some(),
+            (Store::Cas(_), None) => return "NOT_FOUND".to_string(),
+            (Store::Cas(cas), Some(item)) => {
+                if *cas != item.cas {
+                    return "EXISTS".to_string();
+                }
+                true
+            }
+        };
+        if !allowed {
+            return "NOT_STORED".to_string();
+        }
+        let item = Item { data, flags, expires: expires_at(exptime), cas: 0 };
+        let request = match store {
+            Store::Set => "memcached set",
+            Store::Add => "memcached add",
+            Store::Replace => "memcached replace",
+            Store::Cas(_) => "memcached cas",
+        };
+        match self.write(&mut db, key, item, request) {
+            Ok(()) => "STORED".to_string(),
+            Err(e) => server_error(e),
+        }
+    }
+
+    fn delete(&self, key: &str) -> String {
+        if !can_delete(&self.client.level) {
+            return self.denied();
+        }
+        if !self.config.writes_directly() {
+            return self.read_only();
+        }
+        let mut db = self.client.db.lock().unwrap();
+        match self.read(&mut db, key) {
+            Ok(Some(_)) => (),
+            Ok(None) => return "NOT_FOUND".to_string(),
+            Err(e) => return server_error(e),
+        }
+        match db.delete(key.to_string()) {
+            Ok(()) => {
+                self.client.watchers.lock().unwrap().notify(&self.client.db_path, key, None);
+                self.client.audit_write("memcached delete", key);
+                "DELETED".to_string()
+            }
+            Err(e) => server_error(e),
+        }
+    }
+
+    // incr wraps around at 2^64, decr stops at 0.
+    fn incr(&self, key: &str, amount: &str, up: bool) -> String {
+        if !can_write(&self.client.level) {
+            return self.denied();
+        }
+        if !self.config.writes_directly() {
+            return self.read_only();
+        }
+        let amount = match amount.parse::<u64>() {
+            Ok(a) => a,
+            Err(_) => return "CLIENT_ERROR invalid numeric delta argument".to_string(),
+        };
+        let mut db = self.client.db.lock().unwrap();
+        let mut item = match self.read(&mut db, key) {
+            Ok(Some(i)) => i,
+            Ok(None) => return "NOT_FOUND".to_string(),
+            Err(e) => return server_error(e),
+        };
+        let number = match item.data.parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => return "CLIENT_ERROR cannot increment or decrement non-numeric value".to_string(),
+        };
+        let number = match up {
+            true => number.wrapping_add(amount),
+            false => number.saturating_sub(amount),
+        };
+        item.data = number.to_string();
+        let request = if up { "memcached incr" } else { "memcached decr" };
+        match self.write(&mut db, key, item, request) {
+            Ok(()) => number.to_string(),
+            Err(e) => server_error(e),
+        }
+    }
+
+    fn touch(&self, key: &str, exptime: &str) -> String {
+        if !can_write(&self.client.level) {
+            return self.denied();
+        }
+        if !self.config.writes_directly() {
+            return self.read_only();
+        }
+        let exptime = match exptime.parse::<i64>() {
+            Ok(e) => e,
+            Err(_) => return "CLIENT_ERROR invalid exptime argument".to_string(),
+        };
+        let mut db = self.client.db.lock().unwrap();
+        let mut item = match self.read(&mut db, key) {
+            Ok(Some(i)) => i,
+            Ok(None) => return "NOT_FOUND".to_string(),
+            Err(e) => return server_error(e),
+        };
+        item.expires = expires_at(exptime);
+        match self.write(&mut db, key, item, "memcached touch") {
+            Ok(()) => "TOUCHED".to_string(),
+            Err(e) => server_error(e),
+        }
+    }
+
+    // Reads an item, deleting it if it has expired. Values written by other clients
+    // are read as their text with flags and cas unique 0.
+    fn read(&self, db: &mut DataStore, key: &str) -> std::result::Result<Option<Item>, KvError> {
+        let value = match db.get(key.to_string()) {
+            Ok(v) => v,
+            Err(KvError::KeyNotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let item = match Item::from_value(&value) {
+            Some(i) => i,
+            None => return Ok(Some(Item { data: value.to_string(), flags: 0, expires: 0, cas: 0 })),
+        };
+        // A follower leaves expired items to the leader, whose delete it will copy, a Raft
+        // member leaves them in place, and a user who may not delete leaves them to one
+        // who may.
+        if item.expires != 0 && item.expires <= now() {
+            if self.config.writes_directly() && can_delete(&self.client.level) {
+                db.delete(key.to_string())?;
+                self.client.watchers.lock().unwrap().notify(&self.client.db_path, key, None);
+            }
+            return Ok(None);
+        }
+        Ok(Some(item))
+    }
+
+    // Writes an item with a new cas unique. `request` is the command, for the audit log.
+    fn write(&self, db: &mut DataStore, key: &str, mut item: Item, request: &str) -> std::result::Result<(), KvError> {
+        item.cas = self.next_cas.fetch_add(1, Ordering::Relaxed);
+        let value = item.into_value();
+        db.add(key.to_string(), value.clone())?;
+        self.client.watchers.lock().unwrap().notify(&self.client.db_path, key, Some(value));
+        self.client.audit_write(request, key);
+        Ok(())
+    }
+
+    fn denied(&self) -> String {
+        self.metrics.error("permission_denied");
+        format!("CLIENT_ERROR user {} has no permission to do this", self.client.user)
+    }
+
+    fn read_only(&self) -> String {
+        self.metrics.error("read_only");
+        match self.config.is_follower() {
+            true => "SERVER_ERROR the server is a read-only follower".to_string(),
+            false => "SERVER_ERROR writes to a Raft cluster go through the rdb protocol".to_string(),
+        }
+    }
+}
+
+impl Item {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Array(fields) => match fields.as_slice() {
+                [Value::String(data), Value::Int64(flags), Value::Int64(expires), Value::Int64(cas)] => Some(Item {
+                    data: data.clone(),
+                    flags: *flags as u32,
+                    expires: *expires,
+                    cas: *cas as u64,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::Array(Box::new(vec![
+            Value::String(self.data),
+            Value::Int64(self.flags as i64),
+            Value::Int64(self.expires),
+            Value::Int64(self.cas as i64),
+        ]))
+    }
+}
+
+// Parses `<key> <flags> <exptime> <bytes> [<cas unique>] [noreply]`.
+fn store_command<'a>(command: &str, params: &[&'a str]) -> Option<(&'a str, u32, i64, usize, Store)> {
+    let params = match params.last() {
+        Some(&"noreply") => &params[..params.len() - 1],
+        _ => params,
+    };
+    let (key, flags, exptime, length, store) = match (command, params) {
+        ("cas", [key, flags, exptime, length, cas]) => (*key, flags, exptime, length, Store::Cas(cas.parse().ok()?)),
+        ("set", [key, flags, exptime, length]) => (*key, flags, exptime, length, Store::Set),
+        ("add", [key, flags, exptime, length]) => (*key, flags, exptime, length, Store::Add),
+        ("replace", [key, flags, exptime, length]) => (*key, flags, exptime, length, Store::Replace),
+        _ => return None,
+    };
+    if key.len() > MAX_KEY_LENGTH {
+        return None;
+    }
+    Some((key, flags.parse().ok()?, exptime.parse().ok()?, length.parse().ok()?, store))
+}
+
+// 0 never expires, a negative exptime has expired already.
+fn expires_at(exptime: i64) -> i64 {
+    match exptime {
+        0 => 0,
+        e if e < 0 => 1,
+        e if e <= MAX_RELATIVE_EXPTIME => now() + e,
+        e => e,
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// A line without its line ending.
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(invalid("line too long"));
+    }
+    match String::from_utf8(line) {
+        Ok(l) => Ok(Some(l.trim_end_matches(['\r', '\n']).to_string())),
+        Err(_) => Err(invalid("the command line is not UTF-8")),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn server_error(e: KvError) -> String {
+    format!("SERVER_ERROR {}", e)
+}
+
+// The metrics label of a command.
+fn kind(name: &str) -> &'static str {
+    match name {
+        "get" => "memcached get",
+        "gets" => "memcached gets",
+        "set" => "memcached set",
+        "add" => "memcached add",
+        "replace" => "memcached replace",
+        "cas" => "memcached cas",
+        "delete" => "memcached delete",
+        "incr" => "memcached incr",
+        "decr" => "memcached decr",
+        "touch" => "memcached touch",
+        "version" => "memcached version",
+        _ => "memcached unknown",
+    }
+}
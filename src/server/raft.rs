@@ -0,0 +1,1000 @@
+use std::{
+    collections::{HashSet, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    io::{BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::{Condvar, MutexGuard, atomic::AtomicBool},
+    thread::JoinHandle,
+};
+use super::*;
+use storage::{Command, HardState, LogEntry, Snapshot, Storage};
+use transport::{Channel, Rpc, RpcReply};
+
+mod storage;
+mod transport;
+
+// The leader sends heartbeats this often. A follower that has not heard from a leader
+// for ELECTION_TIMEOUT, plus a random part of it so servers do not all stand at once,
+// starts an election.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(1000);
+// How often the timers are checked.
+const TICK: Duration = Duration::from_millis(20);
+// How long a call to another server may take. Snapshots carry every datafile and get
+// longer.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+// The most entries one Append carries.
+const MAX_BATCH: usize = 256;
+// Connections from other servers are closed after being silent this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type Raft = Arc<Node>;
+
+// A member of a Raft cluster. Writes are appended to the log of the leader, copied to
+// the other members and applied to the datafiles of every member once a majority has
+// them, so a write that was acknowledged survives the loss of any minority.
+pub struct Node {
+    config: Config,
+    // The Raft address of this server, and the address its clients connect to.
+    address: String,
+    client_address: String,
+    dbs: Databases,
+    watchers: Watchers,
+    stopping: Arc<AtomicBool>,
+    state: Mutex<RaftState>,
+    // Signalled whenever the log, the commit index, the applied index or the role
+    // changes.
+    changed: Condvar,
+    applier: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct RaftState {
+    storage: Storage,
+    term: u64,
+    voted_for: Option<String>,
+    role: Role,
+    // The Raft and the client address of the leader of `term`, once known.
+    leader: Option<(String, String)>,
+    // The log holds the entries after the snapshot, which replaced those up to
+    // snapshot_index.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: Vec<String>,
+    entries: Vec<LogEntry>,
+    // The members as of the last entry of the log. A change takes effect as soon as it
+    // is in the log, before it is committed.
+    members: Vec<String>,
+    commit: u64,
+    applied: u64,
+    // A snapshot the applier has yet to load into the datafiles.
+    pending_snapshot: Option<Snapshot>,
+    // The results of the writes clients are waiting for, None until applied.
+    results: HashMap<u64, Option<OperateResult>>,
+    // When a follower last heard from its leader.
+    last_heard: Instant,
+    election_deadline: Instant,
+    votes: HashSet<String>,
+    // The other members as the leader sees them.
+    progress: HashMap<String, Progress>,
+    leader_since: Instant,
+}
+
+struct Progress {
+    // The next entry to send, and the last one the member is known to have.
+    next: u64,
+    matched: u64,
+    last_contact: Option<Instant>,
+}
+
+enum Work {
+    Snapshot(Snapshot),
+    Entries(Vec<LogEntry>),
+}
+
+impl Node {
+    // Loads the Raft state from raft_path and starts the listener for the other
+    // members, the election timer and the applier. The datafiles are brought to the
+    // snapshot first, since a crash may have left them anywhere after it.
+    pub fn start(config: Config, dbs: Databases, watchers: Watchers, stopping: Arc<AtomicBool>) -> Result<Raft> {
+        fs::create_dir_all(&config.raft_path)?;
+        let raft_path = fs::canonicalize(&config.raft_path)?;
+        if raft_path.starts_with(fs::canonicalize(&config.data_path)?) {
+            return Err(RorError::ConfigError("raft_path may not be inside data_path".to_string()));
+        }
+        let listener = TcpListener::bind(&config.raft_address)?;
+        let (storage, hard_state, snapshot, entries) = Storage::open(&config.raft_path)?;
+        let (snapshot_index, snapshot_term, snapshot_members) = match &snapshot {
+            Some(s) => (s.index, s.term, s.members.clone()),
+            None => (0, 0, config.raft_peers.clone()),
+        };
+        let now = Instant::now();
+        let mut state = RaftState {
+            storage,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            role: Role::Follower,
+            leader: None,
+            snapshot_index,
+            snapshot_term,
+            snapshot_members,
+            entries,
+            members: Vec::new(),
+            commit: snapshot_index,
+            applied: 0,
+            pending_snapshot: snapshot,
+            results: HashMap::new(),
+            last_heard: now,
+            election_deadline: now,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            leader_since: now,
+        };
+        state.members = state.members_at(state.last_index());
+        state.reset_election();
+
+        let node = Arc::new(Node {
+            address: config.raft_address.clone(),
+            client_address: format!("{0}:{1}", config.ip, config.port),
+            config,
+            dbs,
+            watchers,
+            stopping,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            applier: Mutex::new(None),
+        });
+        let listening = Arc::clone(&node);
+        thread::spawn(move || listening.listen(listener));
+        let ticking = Arc::clone(&node);
+        thread::spawn(move || ticking.tick());
+        let applying = Arc::clone(&node);
+        *node.applier.lock().unwrap() = Some(thread::spawn(move || applying.apply_committed()));
+        Ok(node)
+    }
+
+    // Waits for the applier to finish what it is applying, so no datafile is written
+    // after the server closed it.
+    pub fn stop(&self) {
+        self.changed.notify_all();
+        if let Some(applier) = self.applier.lock().unwrap().take() {
+            let _ = applier.join();
+        }
+    }
+
+    // Appends a write to the datafile at `db`, relative to data_path, and waits until it
+    // is applied. A server that is not the leader answers Redirect.
+    pub fn write(self: &Arc<Self>, db: String, request: OperateRequest) -> OperateResult {
+        let state = self.state.lock().unwrap();
+        self.submit(state, Command::Write { db, request })
+    }
+
+    // Adds or removes one member at a time, and only once the previous change is
+    // committed, so the majorities of the old and the new members always overlap.
+    pub fn change_members(self: &Arc<Self>, address: String, add: bool) -> OperateResult {
+        let state = self.state.lock().unwrap();
+        if state.role != Role::Leader {
+            return self.redirect(&state);
+        }
+        if state.members_index() > state.commit {
+            output_prompt("Raft: the previous membership change is not committed yet");
+            return OperateResult::Failure;
+        }
+        let mut members = state.members.clone();
+        match (add, members.contains(&address)) {
+            (true, false) => members.push(address.clone()),
+            (false, true) => members.retain(|m| *m != address),
+            _ => return OperateResult::Failure,
+        }
+        if members.is_empty() {
+            return OperateResult::Failure;
+        }
+        output_prompt(format!("Raft: {0} member {1}", if add { "adding" } else { "removing" }, address));
+        self.submit(state, Command::Members(members))
+    }
+
+    pub fn role(&self) -> &'static str {
+        self.state.lock().unwrap().role.name()
+    }
+
+    // Only the leader knows how far the others are. A follower reports when it last
+    // heard from the leader.
+    pub fn info(&self) -> ClusterInfo {
+        let s = self.state.lock().unwrap();
+        let members = s.members
+            .iter()
+            .map(|member| {
+                let is_leader = s.leader.as_ref().is_some_and(|(l, _)| l == member);
+                let progress = s.progress.get(member);
+                let contact = match s.role {
+                    Role::Leader => progress.and_then(|p| p.last_contact),
+                    _ if is_leader => Some(s.last_heard),
+                    _ => None,
+                };
+                let state = match (*member == self.address, is_leader, s.role) {
+                    (true, _, role) => role.name(),
+                    (false, true, _) => "leader",
+                    (false, false, Role::Leader) if contact.is_some_and(|t| t.elapsed() < ELECTION_TIMEOUT) => "follower",
+                    (false, false, Role::Leader) => "unreachable",
+                    (false, false, _) => "follower",
+                };
+                MemberInfo {
+                    address: member.clone(),
+                    state: state.to_string(),
+                    matched: progress.map(|p| p.matched),
+                    last_contact: contact.map(|t| t.elapsed().as_secs()),
+                }
+            })
+            .collect();
+        ClusterInfo {
+            term: s.term,
+            leader: s.leader.as_ref().map(|(l, _)| l.clone()),
+            last_index: s.last_index(),
+            commit: s.commit,
+            applied: s.applied,
+            snapshot: s.snapshot_index,
+            members,
+        }
+    }
+
+    // Appends `command` to the log and waits until it is applied. A server that is not
+    // the leader sends the client to the leader with Redirect. If this server loses the
+    // lead after appending the entry but before it is committed, the new leader may still
+    // commit it, so the client gets Uncommitted rather than a Redirect it would follow by
+    // sending the write a second time.
+    fn submit(self: &Arc<Self>, mut s: MutexGuard<RaftState>, command: Command) -> OperateResult {
+        if s.role != Role::Leader {
+            return self.redirect(&s);
+        }
+        let term = s.term;
+        let index = match self.propose(&mut s, command) {
+            Ok(i) => i,
+            Err(e) => {
+                output_prompt(format!("Raft: unable to append to the log, {}", e));
+                return OperateResult::Failure;
+            }
+        };
+        s.results.insert(index, None);
+        loop {
+            if let Some(Some(_)) = s.results.get(&index) {
+                return s.results.remove(&index).flatten().unwrap_or(OperateResult::Failure);
+            }
+            if self.stopping.load(Ordering::SeqCst) {
+                s.results.remove(&index);
+                return OperateResult::Shutdown;
+            }
+            let committed = s.commit >= index && s.term_at(index) == Some(term);
+            if !committed && (s.role != Role::Leader || s.term != term) {
+                s.results.remove(&index);
+                return OperateResult::Uncommitted { leader: self.leader(&s) };
+            }
+            s = self.changed.wait_timeout(s, TICK).unwrap().0;
+        }
+    }
+
+    fn propose(self: &Arc<Self>, s: &mut RaftState, command: Command) -> Result<u64> {
+        let entry = LogEntry {
+            index: s.last_index() + 1,
+            term: s.term,
+            command,
+        };
+        s.append(vec![entry])?;
+        for member in s.members.clone() {
+            if member != self.address {
+                self.replicate_to(s, member);
+            }
+        }
+        self.advance_commit(s);
+        self.changed.notify_all();
+        Ok(s.last_index())
+    }
+
+    fn redirect(&self, s: &RaftState) -> OperateResult {
+        OperateResult::Redirect { leader: self.leader(s) }
+    }
+
+    // The address clients reach the leader at, unless that is this server.
+    fn leader(&self, s: &RaftState) -> Option<String> {
+        s.leader
+            .as_ref()
+            .filter(|(l, _)| *l != self.address)
+            .map(|(_, client)| client.clone())
+    }
+
+    fn listen(self: Arc<Self>, listener: TcpListener) {
+        let members = self.state.lock().unwrap().members.join(", ");
+        match members.is_empty() {
+            true => output_prompt(format!("Raft: listening on {}, waiting to be added to a cluster", self.address)),
+            false => output_prompt(format!("Raft: listening on {0}, members: {1}", self.address, members)),
+        }
+        for stream in listener.incoming() {
+            if self.stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let node = Arc::clone(&self);
+            thread::spawn(move || node.serve(stream));
+        }
+    }
+
+    // Answers the calls of another member until it disconnects.
+    fn serve(&self, stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+        let _ = stream.set_nodelay(true);
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        while !self.stopping.load(Ordering::SeqCst) {
+            let rpc: Rpc = match transport::read_message(&mut reader) {
+                Ok(r) => r,
+                Err(_) => return,
+            };
+            let reply = match self.handle(rpc) {
+                Ok(r) => r,
+                Err(e) => {
+                    output_prompt(format!("Raft: unable to answer {0}, {1}", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default(), e));
+                    return;
+                }
+            };
+            if transport::write_message(&mut writer, &reply).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn handle(&self, rpc: Rpc) -> Result<RpcReply> {
+        let mut guard = self.state.lock().unwrap();
+        let s = &mut *guard;
+        match rpc {
+            Rpc::Vote { term, candidate, last_index, last_term } => {
+                // While there is a leader its followers ignore candidates, so a server
+                // removed from the cluster cannot disrupt it.
+                let led = s.role == Role::Leader
+                    || (s.role == Role::Follower && s.leader.is_some() && s.last_heard.elapsed() < ELECTION_TIMEOUT);
+                if led {
+                    return Ok(RpcReply::Vote { term: s.term, granted: false });
+                }
+                if term > s.term {
+                    self.become_follower(s, term)?;
+                }
+                let up_to_date = (last_term, last_index) >= (s.last_term(), s.last_index());
+                let granted = term == s.term && up_to_date && s.voted_for.as_ref().is_none_or(|v| *v == candidate);
+                if granted && s.voted_for.is_none() {
+                    s.voted_for = Some(candidate);
+                    s.save_state()?;
+                    s.reset_election();
+                }
+                Ok(RpcReply::Vote { term: s.term, granted })
+            }
+            Rpc::Append { term, leader, leader_client, prev_index, prev_term, entries, commit } => {
+                if term < s.term {
+                    return Ok(RpcReply::Append { term: s.term, success: false, last_index: s.last_index() });
+                }
+                self.follow(s, term, leader, leader_client)?;
+                if prev_index > s.last_index() {
+                    return Ok(RpcReply::Append { term: s.term, success: false, last_index: s.last_index() });
+                }
+                // The entry before the new ones must be the leader's, or the follower's
+                // log differs from there on.
+                if prev_index > s.snapshot_index && s.term_at(prev_index) != Some(prev_term) {
+                    return Ok(RpcReply::Append { term: s.term, success: false, last_index: prev_index - 1 });
+                }
+                let last = prev_index + entries.len() as u64;
+                let mut new = Vec::new();
+                for entry in entries {
+                    if entry.index <= s.snapshot_index {
+                        continue;
+                    }
+                    if new.is_empty() {
+                        match s.term_at(entry.index) {
+                            Some(t) if t == entry.term => continue,
+                            Some(_) => s.truncate(entry.index)?,
+                            None => (),
+                        }
+                    }
+                    new.push(entry);
+                }
+                s.append(new)?;
+                if commit.min(last) > s.commit {
+                    s.commit = commit.min(last);
+                    self.changed.notify_all();
+                }
+                Ok(RpcReply::Append { term: s.term, success: true, last_index: last })
+            }
+            Rpc::Snapshot { term, leader, leader_client, snapshot } => {
+                if term < s.term {
+                    return Ok(RpcReply::Snapshot { term: s.term });
+                }
+                self.follow(s, term, leader, leader_client)?;
+                if snapshot.index <= s.snapshot_index {
+                    return Ok(RpcReply::Snapshot { term: s.term });
+                }
+                s.storage.save_snapshot(&snapshot)?;
+                // Entries after the snapshot stay if the log agrees with it.
+                if s.term_at(snapshot.index) == Some(snapshot.term) {
+                    s.entries.retain(|e| e.index > snapshot.index);
+                } else {
+                    s.entries.clear();
+                }
+                s.storage.rewrite(&s.entries)?;
+                s.snapshot_index = snapshot.index;
+                s.snapshot_term = snapshot.term;
+                s.snapshot_members = snapshot.members.clone();
+                s.members = s.members_at(s.last_index());
+                s.commit = s.commit.max(snapshot.index);
+                output_prompt(format!("Raft: received the snapshot of entry {0} from {1}", snapshot.index, s.leader.as_ref().map(|(l, _)| l.as_str()).unwrap_or("-")));
+                s.pending_snapshot = Some(snapshot);
+                self.changed.notify_all();
+                Ok(RpcReply::Snapshot { term: s.term })
+            }
+        }
+    }
+
+    // Makes this server a follower of `leader` in `term`.
+    fn follow(&self, s: &mut RaftState, term: u64, leader: String, leader_client: String) -> Result<()> {
+        if term > s.term || s.role != Role::Follower {
+            self.become_follower(s, term)?;
+        }
+        if s.leader.as_ref().map(|(l, _)| l) != Some(&leader) {
+            output_prompt(format!("Raft: following leader {0} in term {1}", leader, term));
+        }
+        s.leader = Some((leader, leader_client));
+        s.last_heard = Instant::now();
+        s.reset_election();
+        Ok(())
+    }
+
+    fn become_follower(&self, s: &mut RaftState, term: u64) -> Result<()> {
+        if term > s.term {
+            s.term = term;
+            s.voted_for = None;
+            s.leader = None;
+            s.save_state()?;
+        }
+        if s.role == Role::Leader {
+            output_prompt(format!("Raft: no longer the leader in term {}", s.term));
+        }
+        s.role = Role::Follower;
+        s.votes.clear();
+        s.progress.clear();
+        s.reset_election();
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // Starts elections on followers and candidates whose timer ran out, and makes a
+    // leader step down once it has not heard from a majority for an election timeout,
+    // since the others may have elected a new leader meanwhile. A server only stands
+    // while it is a member, so a new server waits until the leader adds it.
+    fn tick(self: Arc<Self>) {
+        while !self.stopping.load(Ordering::SeqCst) {
+            thread::sleep(TICK);
+            let mut guard = self.state.lock().unwrap();
+            let s = &mut *guard;
+            let result = match s.role {
+                Role::Leader if !self.has_quorum(s) => {
+                    output_prompt("Raft: lost contact with the majority of the members");
+                    s.leader = None;
+                    self.become_follower(s, s.term)
+                }
+                Role::Leader => Ok(()),
+                _ if Instant::now() >= s.election_deadline && s.members.contains(&self.address) => self.campaign(s),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                output_prompt(format!("Raft: unable to save the state, {}", e));
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    fn has_quorum(&self, s: &RaftState) -> bool {
+        if s.leader_since.elapsed() < ELECTION_TIMEOUT {
+            return true;
+        }
+        let reached = s.members
+            .iter()
+            .filter(|m| {
+                **m == self.address
+                    || s.progress.get(*m).and_then(|p| p.last_contact).is_some_and(|t| t.elapsed() < ELECTION_TIMEOUT)
+            })
+            .count();
+        reached * 2 > s.members.len()
+    }
+
+    fn campaign(self: &Arc<Self>, s: &mut RaftState) -> Result<()> {
+        s.term += 1;
+        s.role = Role::Candidate;
+        s.leader = None;
+        s.voted_for = Some(self.address.clone());
+        s.save_state()?;
+        s.reset_election();
+        s.votes = HashSet::from([self.address.clone()]);
+        output_prompt(format!("Raft: starting an election for term {}", s.term));
+        if s.votes.len() * 2 > s.members.len() {
+            return self.become_leader(s);
+        }
+        for member in s.members.iter().filter(|m| **m != self.address) {
+            let rpc = Rpc::Vote {
+                term: s.term,
+                candidate: self.address.clone(),
+                last_index: s.last_index(),
+                last_term: s.last_term(),
+            };
+            let node = Arc::clone(self);
+            let (term, member) = (s.term, member.clone());
+            thread::spawn(move || {
+                if let Ok(reply) = Channel::new(member.clone()).call(&rpc, RPC_TIMEOUT) {
+                    node.count_vote(term, member, reply);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn count_vote(self: &Arc<Self>, term: u64, voter: String, reply: RpcReply) {
+        let mut guard = self.state.lock().unwrap();
+        let s = &mut *guard;
+        let (reply_term, granted) = match reply {
+            RpcReply::Vote { term, granted } => (term, granted),
+            _ => return,
+        };
+        let result = if reply_term > s.term {
+            self.become_follower(s, reply_term)
+        } else if s.role == Role::Candidate && s.term == term && granted {
+            s.votes.insert(voter);
+            let votes = s.members.iter().filter(|m| s.votes.contains(*m)).count();
+            match votes * 2 > s.members.len() {
+                true => self.become_leader(s),
+                false => Ok(()),
+            }
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            output_prompt(format!("Raft: unable to save the state, {}", e));
+        }
+    }
+
+    // A new leader appends an empty entry, since entries of earlier terms only count as
+    // committed once an entry of its own term is.
+    fn become_leader(self: &Arc<Self>, s: &mut RaftState) -> Result<()> {
+        output_prompt(format!("Raft: elected leader for term {}", s.term));
+        s.role = Role::Leader;
+        s.leader = Some((self.address.clone(), self.client_address.clone()));
+        s.leader_since = Instant::now();
+        s.votes.clear();
+        s.progress.clear();
+        self.propose(s, Command::Noop)?;
+        Ok(())
+    }
+
+    // Starts the thread that replicates the log to `member` unless it runs already.
+    fn replicate_to(self: &Arc<Self>, s: &mut RaftState, member: String) {
+        if s.progress.contains_key(&member) {
+            return;
+        }
+        s.progress.insert(member.clone(), Progress {
+            next: s.last_index() + 1,
+            matched: 0,
+            last_contact: None,
+        });
+        let node = Arc::clone(self);
+        let term = s.term;
+        thread::spawn(move || node.replicate(term, member));
+    }
+
+    // Sends the log to `member` while this server leads `term`: new entries as soon as
+    // they are appended, a heartbeat when there are none, and the snapshot when the
+    // member needs entries the log no longer has. A removed member still gets the entry
+    // that removed it, so it stops standing for election.
+    fn replicate(self: Arc<Self>, term: u64, member: String) {
+        let mut channel = Channel::new(member.clone());
+        let mut last_sent: Option<Instant> = None;
+        let mut failed = false;
+        loop {
+            let rpc = {
+                let mut guard = self.state.lock().unwrap();
+                loop {
+                    let s = &mut *guard;
+                    if self.stopping.load(Ordering::SeqCst) || s.role != Role::Leader || s.term != term {
+                        return;
+                    }
+                    let next = match s.progress.get(&member) {
+                        Some(p) if s.members.contains(&member) || (p.matched < s.members_index() && !failed) => p.next,
+                        _ => {
+                            s.progress.remove(&member);
+                            return;
+                        }
+                    };
+                    let due = last_sent.is_none_or(|t| t.elapsed() >= HEARTBEAT_INTERVAL);
+                    if due || (!failed && next <= s.last_index()) {
+                        break;
+                    }
+                    guard = self.changed.wait_timeout(guard, TICK).unwrap().0;
+                }
+                match self.message(&guard, &member) {
+                    Ok(rpc) => rpc,
+                    Err(e) => {
+                        output_prompt(format!("Raft: unable to read the snapshot for {0}, {1}", member, e));
+                        last_sent = Some(Instant::now());
+                        failed = true;
+                        continue;
+                    }
+                }
+            };
+            last_sent = Some(Instant::now());
+            let timeout = match rpc {
+                Rpc::Snapshot { .. } => SNAPSHOT_TIMEOUT,
+                _ => RPC_TIMEOUT,
+            };
+            let reply = channel.call(&rpc, timeout);
+            failed = reply.is_err();
+            if let Ok(reply) = reply {
+                let mut guard = self.state.lock().unwrap();
+                if let Err(e) = self.handle_reply(&mut guard, term, &member, rpc, reply) {
+                    output_prompt(format!("Raft: unable to save the state, {}", e));
+                }
+            }
+        }
+    }
+
+    fn message(&self, s: &RaftState, member: &str) -> Result<Rpc> {
+        let next = s.progress.get(member).map_or(s.last_index() + 1, |p| p.next);
+        let (leader, leader_client) = (self.address.clone(), self.client_address.clone());
+        if next <= s.snapshot_index {
+            return match s.storage.load_snapshot()? {
+                Some(snapshot) => Ok(Rpc::Snapshot { term: s.term, leader, leader_client, snapshot }),
+                None => Err(RorError::ReplicationError("the snapshot is missing".to_string())),
+            };
+        }
+        let entries = s.entries
+            .iter()
+            .skip((next - s.snapshot_index - 1) as usize)
+            .take(MAX_BATCH)
+            .cloned()
+            .collect();
+        Ok(Rpc::Append {
+            term: s.term,
+            leader,
+            leader_client,
+            prev_index: next - 1,
+            prev_term: s.term_at(next - 1).unwrap_or(0),
+            entries,
+            commit: s.commit,
+        })
+    }
+
+    fn handle_reply(&self, s: &mut RaftState, term: u64, member: &str, rpc: Rpc, reply: RpcReply) -> Result<()> {
+        let reply_term = match reply {
+            RpcReply::Vote { term, .. } | RpcReply::Append { term, .. } | RpcReply::Snapshot { term } => term,
+        };
+        if reply_term > s.term {
+            return self.become_follower(s, reply_term);
+        }
+        if s.role != Role::Leader || s.term != term {
+            return Ok(());
+        }
+        let progress = match s.progress.get_mut(member) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        progress.last_contact = Some(Instant::now());
+        match (rpc, reply) {
+            (Rpc::Append { .. }, RpcReply::Append { success: true, last_index, .. }) => {
+                progress.matched = progress.matched.max(last_index);
+                progress.next = progress.matched + 1;
+            }
+            (Rpc::Append { .. }, RpcReply::Append { success: false, last_index, .. }) => {
+                progress.next = progress.next.saturating_sub(1).min(last_index + 1).max(1);
+            }
+            (Rpc::Snapshot { snapshot, .. }, RpcReply::Snapshot { .. }) => {
+                progress.matched = progress.matched.max(snapshot.index);
+                progress.next = progress.matched + 1;
+            }
+            _ => (),
+        }
+        self.advance_commit(s);
+        Ok(())
+    }
+
+    // Commits the entries of the current term that a majority of the members has. A
+    // leader that removed itself steps down once the change is committed.
+    fn advance_commit(&self, s: &mut RaftState) {
+        let mut index = s.last_index();
+        while index > s.commit && s.term_at(index) == Some(s.term) {
+            let count = s.members
+                .iter()
+                .filter(|m| **m == self.address || s.progress.get(*m).is_some_and(|p| p.matched >= index))
+                .count();
+            if count * 2 > s.members.len() {
+                s.commit = index;
+                self.changed.notify_all();
+                break;
+            }
+            index -= 1;
+        }
+        if s.role == Role::Leader && !s.members.contains(&self.address) && s.commit >= s.members_index() {
+            output_prompt("Raft: removed from the cluster");
+            s.leader = None;
+            let _ = self.become_follower(s, s.term);
+        }
+    }
+
+    // Applies the committed entries to the datafiles in log order and loads the
+    // snapshots the leader sends. It is the only writer of the datafiles, so between
+    // two entries they hold the state as of `applied`.
+    fn apply_committed(self: Arc<Self>) {
+        loop {
+            let work = {
+                let mut s = self.state.lock().unwrap();
+                loop {
+                    if self.stopping.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    // A snapshot that arrived while the entries it covers were being
+                    // applied is not needed anymore.
+                    if let Some(snapshot) = s.pending_snapshot.take() {
+                        if snapshot.index > s.applied {
+                            break Work::Snapshot(snapshot);
+                        }
+                        continue;
+                    }
+                    if s.applied < s.commit {
+                        let (applied, commit) = (s.applied, s.commit);
+                        break Work::Entries(s.entries.iter().filter(|e| e.index > applied && e.index <= commit).cloned().collect());
+                    }
+                    s = self.changed.wait_timeout(s, TICK).unwrap().0;
+                }
+            };
+            match work {
+                Work::Snapshot(snapshot) => {
+                    if let Err(e) = self.load(&snapshot) {
+                        output_prompt(format!("Raft: unable to load the snapshot of entry {0}, {1}", snapshot.index, e));
+                    }
+                    let mut s = self.state.lock().unwrap();
+                    s.applied = s.applied.max(snapshot.index);
+                    self.changed.notify_all();
+                }
+                Work::Entries(entries) => {
+                    for entry in entries {
+                        let result = match entry.command {
+                            Command::Write { db, request } => self.apply(&db, request).unwrap_or_else(|e| {
+                                output_prompt(format!("Raft: unable to apply entry {0}, {1}", entry.index, e));
+                                OperateResult::Failure
+                            }),
+                            Command::Noop | Command::Members(_) => OperateResult::Success,
+                        };
+                        let mut s = self.state.lock().unwrap();
+                        s.applied = entry.index;
+                        if let Some(slot) = s.results.get_mut(&entry.index) {
+                            *slot = Some(result);
+                        }
+                        self.changed.notify_all();
+                    }
+                }
+            }
+            if let Err(e) = self.compact() {
+                output_prompt(format!("Raft: unable to compact the log, {}", e));
+            }
+        }
+    }
+
+    // Permissions were checked by the leader before the write was appended.
+    fn apply(&self, db: &str, request: OperateRequest) -> Result<OperateResult> {
+        match request {
+            OperateRequest::Add { key, value } => {
+                let db_path = resolve_db_path(&self.config, db)?;
+                open_db(&self.dbs, &db_path)?.lock().unwrap().add(key.clone(), value.clone())?;
+                self.watchers.lock().unwrap().notify(&db_path, &key, Some(value));
+                Ok(OperateResult::Success)
+            }
+            OperateRequest::Delete { key } => {
+                let db_path = resolve_db_path(&self.config, db)?;
+                match open_db(&self.dbs, &db_path)?.lock().unwrap().delete(key.clone()) {
+                    Ok(()) => (),
+                    Err(KvError::KeyNotFound(_)) => return Ok(OperateResult::KeyNotFound),
+                    Err(e) => return Err(RorError::KvError(e)),
+                }
+                self.watchers.lock().unwrap().notify(&db_path, &key, None);
+                Ok(OperateResult::Success)
+            }
+            OperateRequest::CreateDatabase { path } => {
+                let db_path = resolve_db_path(&self.config, &path)?;
+                if Path::new(&db_path).exists() {
+                    return Ok(OperateResult::Failure);
+                }
+                if let Some(parent) = Path::new(&db_path).parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                File::create(&db_path)?;
+                output_prompt(format!("Raft: created database '{}'", db_path));
+                Ok(OperateResult::Success)
+            }
+            OperateRequest::DropDatabase { path } => {
+                let db_path = resolve_db_path(&self.config, &path)?;
+                if !Path::new(&db_path).exists() {
+                    return Ok(OperateResult::Failure);
+                }
+                self.drop_db(&db_path)?;
+                output_prompt(format!("Raft: dropped database '{}'", db_path));
+                Ok(OperateResult::Success)
+            }
+            _ => Ok(OperateResult::Failure),
+        }
+    }
+
+    // Clients that still have the datafile open keep their handle to the removed file.
+    fn drop_db(&self, db_path: &str) -> Result<()> {
+        self.dbs.lock().unwrap().retain(|key, _| !is_same_file(key, db_path).unwrap_or(false));
+        fs::remove_file(db_path)?;
+        let _ = fs::remove_file(hint_path(db_path));
+        Ok(())
+    }
+
+    // Makes the datafiles hold what the snapshot holds. Datafiles the snapshot does not
+    // have were dropped on the leader.
+    fn load(&self, snapshot: &Snapshot) -> Result<()> {
+        let mut names = HashSet::new();
+        for (name, entries) in &snapshot.data {
+            let db_path = resolve_db_path(&self.config, name)?;
+            if let Some(parent) = Path::new(&db_path).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let db = open_db(&self.dbs, &db_path)?;
+            let changed = replica::replace(&mut db.lock().unwrap(), entries.iter().cloned().collect())?;
+            let mut watchers = self.watchers.lock().unwrap();
+            for (key, value) in changed {
+                watchers.notify(&db_path, &key, value);
+            }
+            names.insert(name.clone());
+        }
+        let data_path = Path::new(&self.config.data_path);
+        for path in data_files(data_path, Path::new(&self.config.backup_path))? {
+            if let Ok(name) = path.strip_prefix(data_path) {
+                if !names.contains(name.to_string_lossy().as_ref()) {
+                    self.drop_db(&path.to_string_lossy())?;
+                }
+            }
+        }
+        output_prompt(format!("Raft: loaded the snapshot of entry {0} with {1} datafiles", snapshot.index, snapshot.data.len()));
+        Ok(())
+    }
+
+    // Replaces the applied part of the log with a snapshot of the datafiles once it
+    // holds raft_snapshot_entries entries.
+    fn compact(&self) -> Result<()> {
+        let (index, term, members) = {
+            let s = self.state.lock().unwrap();
+            if self.config.raft_snapshot_entries == 0 || s.applied < s.snapshot_index + self.config.raft_snapshot_entries {
+                return Ok(());
+            }
+            match s.term_at(s.applied) {
+                Some(term) => (s.applied, term, s.members_at(s.applied)),
+                None => return Ok(()),
+            }
+        };
+        let data_path = Path::new(&self.config.data_path);
+        let mut data = Vec::new();
+        for path in data_files(data_path, Path::new(&self.config.backup_path))? {
+            let name = match path.strip_prefix(data_path) {
+                Ok(n) => n.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+            let db = open_db(&self.dbs, &path.to_string_lossy())?;
+            let entries = db.lock().unwrap().get_all_entry()?.into_iter().map(|e| (e.key, e.value)).collect();
+            data.push((name, entries));
+        }
+        let snapshot = Snapshot { index, term, members, data };
+
+        let mut guard = self.state.lock().unwrap();
+        let s = &mut *guard;
+        if s.snapshot_index >= index {
+            return Ok(());
+        }
+        s.storage.save_snapshot(&snapshot)?;
+        s.entries.retain(|e| e.index > index);
+        s.storage.rewrite(&s.entries)?;
+        s.snapshot_index = index;
+        s.snapshot_term = term;
+        s.snapshot_members = snapshot.members;
+        output_prompt(format!("Raft: compacted the log up to entry {}", index));
+        Ok(())
+    }
+}
+
+impl RaftState {
+    fn last_index(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot_index, |e| e.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    // None for entries the snapshot replaced and entries past the end of the log.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot_index - 1) as usize).map(|e| e.term)
+    }
+
+    fn members_at(&self, index: u64) -> Vec<String> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|e| e.index <= index)
+            .find_map(|e| match &e.command {
+                Command::Members(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_members.clone())
+    }
+
+    // The index of the entry the current members come from, 0 if from the snapshot or
+    // the configuration.
+    fn members_index(&self) -> u64 {
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| matches!(e.command, Command::Members(_)))
+            .map_or(0, |e| e.index)
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.storage.append(&entries)?;
+        let members = entries.iter().any(|e| matches!(e.command, Command::Members(_)));
+        self.entries.extend(entries);
+        if members {
+            self.members = self.members_at(self.last_index());
+        }
+        Ok(())
+    }
+
+    // Drops the entries from `index` on, which conflict with the leader's log.
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.retain(|e| e.index < index);
+        self.storage.rewrite(&self.entries)?;
+        self.members = self.members_at(self.last_index());
+        Ok(())
+    }
+
+    fn save_state(&self) -> Result<()> {
+        self.storage.save_state(&HardState {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+        })
+    }
+
+    fn reset_election(&mut self) {
+        let timeout = ELECTION_TIMEOUT.as_millis() as u64;
+        let jitter = RandomState::new().build_hasher().finish() % timeout;
+        self.election_deadline = Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter);
+    }
+}
+
+impl Role {
+    fn name(&self) -> &'static str {
+        match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use serde::{Serialize, Deserialize};
+use crate::{
+    error::Result,
+    request::OperateRequest,
+    store::kv::Value,
+};
+
+// What a log entry does once it is committed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Command {
+    // Appended by a new leader, so the entries of earlier terms get committed.
+    Noop,
+    // A write to the datafile at `db`, relative to data_path.
+    Write { db: String, request: OperateRequest },
+    // The members of the cluster from this entry on, by Raft address.
+    Members(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+// The term and vote must survive a restart, or a server could vote twice in a term.
+#[derive(Serialize, Deserialize, Default)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+}
+
+// Every datafile of the cluster after the entry at `index` was applied. It replaces
+// the log up to that entry.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub members: Vec<String>,
+    // The live entries of each datafile by its path relative to data_path.
+    pub data: Vec<(String, Vec<(String, Value)>)>,
+}
+
+// The Raft state of a server in raft_path: the hard state and the snapshot are
+// replaced as a whole, the log is appended to and rewritten when entries are dropped
+// from either end. Every write is synced before it returns.
+pub struct Storage {
+    dir: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    pub fn open(dir: &str) -> Result<(Storage, HardState, Option<Snapshot>, Vec<LogEntry>)> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        let state: HardState = read_file(&dir.join("state"))?.unwrap_or_default();
+        let snapshot: Option<Snapshot> = read_file(&dir.join("snapshot"))?;
+        let mut entries = read_log(&dir.join("log"))?;
+        // A crash between saving a snapshot and rewriting the log leaves entries the
+        // snapshot already covers.
+        if let Some(s) = &snapshot {
+            entries.retain(|e| e.index > s.index);
+        }
+        let log = BufWriter::new(OpenOptions::new().append(true).create(true).open(dir.join("log"))?);
+        let mut storage = Storage { dir, log };
+        storage.rewrite(&entries)?;
+        Ok((storage, state, snapshot, entries))
+    }
+
+    pub fn save_state(&self, state: &HardState) -> Result<()> {
+        write_file(&self.dir.join("state"), state)
+    }
+
+    pub fn load_snapshot(&self) -> Result<Option<Snapshot>> {
+        read_file(&self.dir.join("snapshot"))
+    }
+
+    pub fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        write_file(&self.dir.join("snapshot"), snapshot)
+    }
+
+    pub fn append(&mut self, entries: &[LogEntry]) -> Result<()> {
+        for entry in entries {
+            let body = bincode::serialize(entry)?;
+            self.log.write_all(&(body.len() as u32).to_be_bytes())?;
+            self.log.write_all(&body)?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    // Replaces the log with `entries`.
+    pub fn rewrite(&mut self, entries: &[LogEntry]) -> Result<()> {
+        let path = self.dir.join("log");
+        let tmp = self.dir.join("log.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in entries {
+            let body = bincode::serialize(entry)?;
+            writer.write_all(&(body.len() as u32).to_be_bytes())?;
+            writer.write_all(&body)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, &path)?;
+        self.log = BufWriter::new(OpenOptions::new().append(true).open(&path)?);
+        Ok(())
+    }
+}
+
+// An entry cut off by a crash ends the log.
+fn read_log(path: &Path) -> Result<Vec<LogEntry>> {
+    let mut entries = Vec::new();
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Ok(entries),
+    };
+    let mut reader = BufReader::new(file);
+    loop {
+        let mut len = [0; 4];
+        if reader.read_exact(&mut len).is_err() {
+            break;
+        }
+        let mut body = vec![0; u32::from_be_bytes(len) as usize];
+        if reader.read_exact(&mut body).is_err() {
+            break;
+        }
+        match bincode::deserialize(&body) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+    Ok(entries)
+}
+
+fn read_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    Ok(Some(bincode::deserialize_from(reader)?))
+}
+
+fn write_file<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    bincode::serialize_into(&mut writer, value)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::{
+    io::{BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use crate::error::{RorError, Result};
+use super::storage::{LogEntry, Snapshot};
+
+// Snapshots hold every datafile of the cluster, so messages may be a lot larger than
+// client frames.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024;
+
+// The messages servers of a cluster send each other, each answered by the RpcReply of
+// the same kind. Addresses are Raft addresses, except `leader_client`, the address
+// clients reach the leader at.
+#[derive(Serialize, Deserialize)]
+pub enum Rpc {
+    Vote {
+        term: u64,
+        candidate: String,
+        last_index: u64,
+        last_term: u64,
+    },
+    Append {
+        term: u64,
+        leader: String,
+        leader_client: String,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+    },
+    Snapshot {
+        term: u64,
+        leader: String,
+        leader_client: String,
+        snapshot: Snapshot,
+    },
+}
+
+// A failed Append carries the last index of the follower's log, so the leader can skip
+// back to it instead of one entry at a time.
+#[derive(Serialize, Deserialize)]
+pub enum RpcReply {
+    Vote { term: u64, granted: bool },
+    Append { term: u64, success: bool, last_index: u64 },
+    Snapshot { term: u64 },
+}
+
+// A connection to another server, opened on the first call and again after a failed
+// one.
+pub struct Channel {
+    address: String,
+    stream: Option<TcpStream>,
+}
+
+impl Channel {
+    pub fn new(address: String) -> Self {
+        Channel {
+            address,
+            stream: None,
+        }
+    }
+
+    // Sends `rpc` and waits up to `timeout` for the reply.
+    pub fn call(&mut self, rpc: &Rpc, timeout: Duration) -> Result<RpcReply> {
+        let result = self.try_call(rpc, timeout);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn try_call(&mut self, rpc: &Rpc, timeout: Duration) -> Result<RpcReply> {
+        if self.stream.is_none() {
+            let address = match self.address.to_socket_addrs()?.next() {
+                Some(a) => a,
+                None => return Err(RorError::ConfigError(format!("cannot resolve '{}'", self.address))),
+            };
+            let stream = TcpStream::connect_timeout(&address, timeout)?;
+            stream.set_nodelay(true)?;
+            self.stream = Some(stream);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write_message(&mut BufWriter::new(&*stream), rpc)?;
+        read_message(&mut BufReader::new(&*stream))
+    }
+}
+
+// A message is its length as a big-endian u32 followed by its bincode encoding.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let body = bincode::serialize(message)?;
+    if body.len() > MAX_MESSAGE_SIZE {
+        return Err(RorError::FrameTooLarge(body.len(), MAX_MESSAGE_SIZE));
+    }
+    writer.write_all(&(body.len() as u32).to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(RorError::FrameTooLarge(len, MAX_MESSAGE_SIZE));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(bincode::deserialize(&body)?)
+}
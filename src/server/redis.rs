@@ -0,0 +1,901 @@
+use std::{
+    io::{self, BufRead, BufReader, BufWriter},
+    net::TcpStream,
+    sync::{Weak, atomic::{AtomicBool, AtomicU64}},
+};
+use super::*;
+
+// Longest line of an inline command or a RESP header.
+const MAX_LINE: usize = 64 * 1024;
+// Most arguments one command may have.
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+// Room reserved up front for the arguments of a command and for one bulk string. The
+// lengths come from the client, before it has logged in, so anything beyond this is
+// only allocated as the data arrives.
+const PREALLOCATE_ARGUMENTS: usize = 64;
+const PREALLOCATE_BULK: usize = 64 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+// SCAN cursors a connection keeps, the oldest is forgotten when there are more.
+const MAX_CURSORS: usize = 1024;
+// Expired keys nobody reads are deleted at this interval.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+// A listener speaking the Redis protocol (RESP2, and RESP3 after HELLO 3), so Redis
+// clients and tools can use the datafiles. Every connection has a thread of its own.
+// Values are written as Strings and read as their text, and SELECT switches between
+// the datafiles listed in `redis_databases`.
+pub struct Redis {
+    config: Config,
+    dbs: Databases,
+    watchers: Watchers,
+    audit: audit::Audit,
+    limiter: Arc<limit::Limiter>,
+    metrics: Arc<Metrics>,
+    stopping: Arc<AtomicBool>,
+    // Deadlines set with EXPIRE or SET EX, by `datafile_key` and key, with the datafile
+    // to delete the key from. They are kept in memory, so they do not survive a restart.
+    expires: Mutex<HashMap<(String, String), Deadline>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Clone)]
+struct Deadline {
+    at: Instant,
+    db: Weak<Mutex<DataStore>>,
+}
+
+struct Session {
+    id: u64,
+    address: SocketAddr,
+    slot: limit::Slot,
+    // Set once the connection has logged in with AUTH or HELLO.
+    client: Option<Client>,
+    database: usize,
+    protocol: u8,
+    cursors: HashMap<u64, String>,
+    next_cursor: u64,
+    quit: bool,
+}
+
+enum Resp {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Resp>),
+    Map(Vec<(Resp, Resp)>),
+}
+
+impl Redis {
+    pub fn new(
+        config: Config,
+        dbs: Databases,
+        watchers: Watchers,
+        audit: audit::Audit,
+        limiter: Arc<limit::Limiter>,
+        metrics: Arc<Metrics>,
+        stopping: Arc<AtomicBool>,
+    ) -> Self {
+        Redis {
+            config,
+            dbs,
+            watchers,
+            audit,
+            limiter,
+            metrics,
+            stopping,
+            expires: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn serve(self: Arc<Self>, listener: std::net::TcpListener) {
+        let redis = Arc::clone(&self);
+        thread::spawn(move || loop {
+            thread::sleep(EXPIRE_INTERVAL);
+            redis.expire_all();
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+                let redis = Arc::clone(&self);
+                thread::spawn(move || redis.run(stream));
+            }
+        });
+    }
+
+    fn run(&self, stream: TcpStream) {
+        let address = match stream.peer_addr() {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        if self.config.timeout > 0 {
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(self.config.timeout)));
+        }
+        let (read_half, write_half) = match stream.try_clone() {
+            Ok(s) => (s, stream),
+            Err(_) => return,
+        };
+        let mut reader = BufReader::new(read_half);
+        let mut writer = BufWriter::new(write_half);
+        let slot = match self.limiter.connect(address.ip()) {
+            Ok(s) => s,
+            Err(reason) => {
+                output_prompt(format!("Redis client [{0}], refused. reason: {1}", address, reason));
+                self.metrics.error("too_many_connections");
+                let _ = writer.write_all(b"-ERR max number of clients reached\r\n").and_then(|_| writer.flush());
+                return;
+            }
+        };
+        output_prompt(format!("New Redis connection: {}", address));
+        let mut session = Session {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            address,
+            slot,
+            client: None,
+            database: 0,
+            protocol: 2,
+            cursors: HashMap::new(),
+            next_cursor: 1,
+            quit: false,
+        };
+        let mut out = Vec::new();
+        loop {
+            let args = match read_command(&mut reader, self.config.max_frame_size as usize) {
+                Ok(Some(a)) => a,
+                Ok(None) => break,
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    self.metrics.error("protocol");
+                    encode(&Resp::Error(format!("ERR Protocol error: {}", e)), session.protocol, &mut out);
+                    session.quit = true;
+                    Vec::new()
+                }
+                Err(_) => break,
+            };
+            if !args.is_empty() {
+                let reply = match self.stopping.load(Ordering::SeqCst) {
+                    true => {
+                        session.quit = true;
+                        Resp::Error("ERR The server is shutting down".to_string())
+                    }
+                    false => self.command(&mut session, args),
+                };
+                encode(&reply, session.protocol, &mut out);
+            }
+            // Replies to pipelined commands are written together.
+            if reader.buffer().is_empty() || session.quit {
+                if writer.write_all(&out).and_then(|_| writer.flush()).is_err() {
+                    break;
+                }
+                out.clear();
+            }
+            if session.quit {
+                break;
+            }
+        }
+        output_prompt(format!("Redis client [{0}] disconnected", address));
+    }
+
+    fn command(&self, session: &mut Session, args: Vec<Vec<u8>>) -> Resp {
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let kind = kind(&name);
+        let started = Instant::now();
+        self.metrics.request(kind);
+        let reply = self.execute(session, &name, &args[1..]);
+        self.metrics.observe(kind, started.elapsed());
+        reply
+    }
+
+    fn execute(&self, session: &mut Session, name: &str, args: &[Vec<u8>]) -> Resp {
+        match name {
+            "QUIT" => {
+                session.quit = true;
+                return Resp::Simple("OK");
+            }
+            "AUTH" => {
+                return match args {
+                    [password] => self.auth(session, b"default", password),
+                    [user, password] => self.auth(session, user, password),
+                    _ => arity(name),
+                };
+            }
+            "HELLO" => return self.hello(session, args),
+            _ => (),
+        }
+        let client = match session.client.as_mut() {
+            Some(c) => c,
+            None if kind(name) == "redis unknown" => return unknown(name, args),
+            None => return Resp::Error("NOAUTH Authentication required.".to_string()),
+        };
+        if !self.limiter.allow(&client.user) {
+            self.metrics.error("rate_limited");
+            return Resp::Error("ERR rate limit exceeded, try again later".to_string());
+        }
+        if !self.config.writes_directly() && matches!(name, "SET" | "DEL" | "INCR" | "EXPIRE") {
+            self.metrics.error("read_only");
+            return Resp::Error(match self.config.is_follower() {
+                true => "READONLY You can't write against a read only replica.".to_string(),
+                false => "READONLY Writes to a Raft cluster go through the rdb protocol.".to_string(),
+            });
+        }
+        let args: Vec<String> = match args.iter().map(|a| String::from_utf8(a.clone())).collect() {
+            Ok(a) => a,
+            Err(_) => return Resp::Error("ERR keys and values must be valid UTF-8".to_string()),
+        };
+        match (name, args.as_slice()) {
+            ("PING", []) => Resp::Simple("PONG"),
+            ("PING", [message]) | ("ECHO", [message]) => Resp::Bulk(message.clone().into_bytes()),
+            ("GET", [key]) => self.get(client, key),
+            ("SET", [key, value, options @ ..]) => self.set(client, key, value, options),
+            ("DEL", keys) if !keys.is_empty() => self.del(client, keys),
+            ("EXISTS", keys) if !keys.is_empty() => self.exists(client, keys),
+            ("INCR", [key]) => self.incr(client, key),
+            ("EXPIRE", [key, seconds]) => self.expire(client, key, seconds),
+            ("TYPE", [key]) => self.type_of(client, key),
+            ("SELECT", [index]) => self.select(session, index),
+            ("SCAN", [cursor, options @ ..]) => self.scan(session, cursor, options),
+            ("PING", _) | ("ECHO", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("INCR", _)
+            | ("EXPIRE", _) | ("TYPE", _) | ("SELECT", _) | ("SCAN", _) => arity(name),
+            _ => unknown(name, &args.into_iter().map(|a| a.into_bytes()).collect::<Vec<_>>()),
+        }
+    }
+
+    // AUTH with one argument logs in as the user named "default".
+    fn auth(&self, session: &mut Session, user: &[u8], password: &[u8]) -> Resp {
+        let user = String::from_utf8_lossy(user).to_string();
+        let password = String::from_utf8_lossy(password).to_string();
+        let level = match User::login(user.clone(), password) {
+            Ok(u) => u.level,
+            Err(e @ UserError::UserNotFound(_)) | Err(e @ UserError::WrongPassWord) => {
+                output_prompt(format!("Redis client [{0}], failed to login as '{1}'", session.address, user));
+                let outcome = match e {
+                    UserError::UserNotFound(_) => "user_not_found",
+                    _ => "wrong_password",
+                };
+                audit::login(&self.audit, "redis auth", &user, session.address, None, outcome);
+                self.metrics.error("login");
+                return Resp::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string());
+            }
+            Err(e) => {
+                output_prompt(format!("Redis client [{0}], failed to login. reason: {1}", session.address, e));
+                return Resp::Error("ERR unable to read the users".to_string());
+            }
+        };
+        match self.open(session.address, user, level, session.database) {
+            Ok(client) => {
+                if let Err(reason) = session.slot.login(&client.user) {
+                    output_prompt(format!("Redis client [{0}], refused. reason: {1}", session.address, reason));
+                    self.metrics.error("too_many_connections");
+                    return Resp::Error("ERR max number of clients reached".to_string());
+                }
+                audit::login(&self.audit, "redis auth", &client.user, session.address, Some(&client.database_name()), "ok");
+                session.client = Some(client);
+                Resp::Simple("OK")
+            }
+            Err(e) => e,
+        }
+    }
+
+    // HELLO [protover [AUTH user password] [SETNAME name]]
+    fn hello(&self, session: &mut Session, args: &[Vec<u8>]) -> Resp {
+        let protocol = match args.first().map(|p| String::from_utf8_lossy(p).parse::<u8>()) {
+            None => session.protocol,
+            Some(Ok(p)) if p == 2 || p == 3 => p,
+            Some(_) => return Resp::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+        let mut options = args.iter().skip(1);
+        while let Some(option) = options.next() {
+            match String::from_utf8_lossy(option).to_uppercase().as_str() {
+                "AUTH" => match (options.next(), options.next()) {
+                    (Some(user), Some(password)) => {
+                        if let Resp::Error(e) = self.auth(session, user, password) {
+                            return Resp::Error(e);
+                        }
+                    }
+                    _ => return Resp::Error("ERR syntax error".to_string()),
+                },
+                "SETNAME" if options.next().is_some() => (),
+                _ => return Resp::Error("ERR syntax error".to_string()),
+            }
+        }
+        if session.client.is_none() {
+            return Resp::Error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".to_string());
+        }
+        session.protocol = protocol;
+        Resp::Map(vec![
+            (bulk("server"), bulk("rdb")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), Resp::Integer(protocol as i64)),
+            (bulk("id"), Resp::Integer(session.id as i64)),
+            (bulk("mode"), bulk("standalone")),
+            (bulk("role"), bulk(match self.config.is_follower() {
+                true => "replica",
+                false => "master",
+            })),
+            (bulk("modules"), Resp::Array(Vec::new())),
+        ])
+    }
+
+    fn select(&self, session: &mut Session, index: &str) -> Resp {
+        let index = match index.parse::<usize>() {
+            Ok(i) if i < self.config.redis_databases.len() => i,
+            Ok(_) => return Resp::Error("ERR DB index is out of range".to_string()),
+            Err(_) => return not_integer(),
+        };
+        let (user, level) = match &session.client {
+            Some(c) => (c.user.clone(), c.level.clone()),
+            None => return Resp::Error("NOAUTH Authentication required.".to_string()),
+        };
+        match self.open(session.address, user, level, index) {
+            Ok(client) => {
+                session.client = Some(client);
+                session.database = index;
+                session.cursors.clear();
+                Resp::Simple("OK")
+            }
+            Err(e) => e,
+        }
+    }
+
+    fn open(&self, address: SocketAddr, user: String, level: String, index: usize) -> std::result::Result<Client, Resp> {
+        let name = match self.config.redis_databases.get(index) {
+            Some(n) => n,
+            None => return Err(Resp::Error("ERR DB index is out of range".to_string())),
+        };
+        let session = resolve_db_path(&self.config, name).and_then(|db_path| {
+            Client::session(
+                user,
+                level,
+                address,
+                db_path,
+                self.config.clone(),
+                Arc::clone(&self.dbs),
+                Arc::clone(&self.watchers),
+            )
+        });
+        session.map(|mut client| {
+            client.audit = self.audit.clone();
+            client
+        }).map_err(|e| {
+            output_prompt(format!("Unable to open '{0}' for Redis client [{1}], {2}", name, address, e));
+            Resp::Error("ERR unable to open the database".to_string())
+        })
+    }
+
+    fn get(&self, client: &Client, key: &str) -> Resp {
+        let mut db = client.db.lock().unwrap();
+        self.expire_due(client, &mut db, key);
+        match db.get(key.to_string()) {
+            Ok(Value::Array(_)) => wrong_type(),
+            Ok(v) => Resp::Bulk(v.to_string().into_bytes()),
+            Err(KvError::KeyNotFound(_)) => Resp::Nil,
+            Err(e) => internal(client, e),
+        }
+    }
+
+    // SET key value [EX seconds | PX milliseconds | KEEPTTL] [NX | XX]
+    fn set(&self, client: &Client, key: &str, value: &str, options: &[String]) -> Resp {
+        if !can_write(&client.level) {
+            return self.denied(client, "set");
+        }
+        let mut ttl = None;
+        let mut keep_ttl = false;
+        let mut condition = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.to_uppercase().as_str() {
+                unit @ ("EX" | "PX") if ttl.is_none() && !keep_ttl => {
+                    let amount = match options.next().map(|a| a.parse::<u64>()) {
+                        Some(Ok(a)) if a > 0 => a,
+                        Some(Ok(_)) => return Resp::Error("ERR invalid expire time in 'set' command".to_string()),
+                        _ => return not_integer(),
+                    };
+                    ttl = Some(match unit {
+                        "EX" => Duration::from_secs(amount),
+                        _ => Duration::from_millis(amount),
+                    });
+                }
+                "KEEPTTL" if ttl.is_none() => keep_ttl = true,
+                c @ ("NX" | "XX") if condition.is_none() => condition = Some(c == "NX"),
+                _ => return Resp::Error("ERR syntax error".to_string()),
+            }
+        }
+        let mut db = client.db.lock().unwrap();
+        self.expire_due(client, &mut db, key);
+        if let Some(absent) = condition {
+            let exists = match db.get(key.to_string()) {
+                Ok(_) => true,
+                Err(KvError::KeyNotFound(_)) => false,
+                Err(e) => return internal(client, e),
+            };
+            if exists == absent {
+                return Resp::Nil;
+            }
+        }
+        let value = Value::String(value.to_string());
+        if let Err(e) = db.add(key.to_string(), value.clone()) {
+            return internal(client, e);
+        }
+        let entry = (datafile_key(&client.db_path), key.to_string());
+        match ttl {
+            Some(ttl) => {
+                self.expires.lock().unwrap().insert(entry, Deadline { at: Instant::now() + ttl, db: Arc::downgrade(&client.db) });
+            }
+            None if !keep_ttl => {
+                self.expires.lock().unwrap().remove(&entry);
+            }
+            None => (),
+        }
+        self.watchers.lock().unwrap().notify(&client.db_path, key, Some(value));
+        client.audit_write("redis set", key);
+        Resp::Simple("OK")
+    }
+
+    fn del(&self, client: &Client, keys: &[String]) -> Resp {
+        if !can_delete(&client.level) {
+            return self.denied(client, "del");
+        }
+        let mut db = client.db.lock().unwrap();
+        let mut deleted = 0;
+        for key in keys {
+            if self.expire_due(client, &mut db, key) {
+                continue;
+            }
+            match db.delete(key.clone()) {
+                Ok(()) => {
+                    deleted += 1;
+                    self.expires.lock().unwrap().remove(&(datafile_key(&client.db_path), key.clone()));
+                    self.watchers.lock().unwrap().notify(&client.db_path, key, None);
+                    client.audit_write("redis del", key);
+                }
+                Err(KvError::KeyNotFound(_)) => (),
+                Err(e) => return internal(client, e),
+            }
+        }
+        Resp::Integer(deleted)
+    }
+
+    fn exists(&self, client: &Client, keys: &[String]) -> Resp {
+        let mut db = client.db.lock().unwrap();
+        let mut found = 0;
+        for key in keys {
+            self.expire_due(client, &mut db, key);
+            match db.get(key.clone()) {
+                Ok(_) => found += 1,
+                Err(KvError::KeyNotFound(_)) => (),
+                Err(e) => return internal(client, e),
+            }
+        }
+        Resp::Integer(found)
+    }
+
+    // Integers stay Int32 or Int64, and Strings holding a number stay Strings.
+    fn incr(&self, client: &Client, key: &str) -> Resp {
+        if !can_write(&client.level) {
+            return self.denied(client, "incr");
+        }
+        let mut db = client.db.lock().unwrap();
+        self.expire_due(client, &mut db, key);
+        let value = match db.get(key.to_string()) {
+            Ok(Value::Int32(n)) => n.checked_add(1).map(Value::Int32),
+            Ok(Value::Int64(n)) => n.checked_add(1).map(Value::Int64),
+            Ok(Value::String(s)) => s.parse::<i64>().ok().and_then(|n| n.checked_add(1)).map(|n| Value::String(n.to_string())),
+            Ok(_) => None,
+            Err(KvError::KeyNotFound(_)) => Some(Value::String("1".to_string())),
+            Err(e) => return internal(client, e),
+        };
+        let value = match value {
+            Some(v) => v,
+            None => return not_integer(),
+        };
+        if let Err(e) = db.add(key.to_string(), value.clone()) {
+            return internal(client, e);
+        }
+        let number = value.to_string().parse::<i64>().unwrap_or_default();
+        self.watchers.lock().unwrap().notify(&client.db_path, key, Some(value));
+        client.audit_write("redis incr", key);
+        Resp::Integer(number)
+    }
+
+    // Expiring deletes the key, so it takes the level needed to delete.
+    fn expire(&self, client: &Client, key: &str, seconds: &str) -> Resp {
+        if !can_delete(&client.level) {
+            return self.denied(client, "expire");
+        }
+        let seconds = match seconds.parse::<i64>() {
+            Ok(s) => s,
+            Err(_) => return not_integer(),
+        };
+        let mut db = client.db.lock().unwrap();
+        self.expire_due(client, &mut db, key);
+        match db.get(key.to_string()) {
+            Ok(_) => (),
+            Err(KvError::KeyNotFound(_)) => return Resp::Integer(0),
+            Err(e) => return internal(client, e),
+        }
+        let entry = (datafile_key(&client.db_path), key.to_string());
+        if seconds <= 0 {
+            self.expires.lock().unwrap().remove(&entry);
+            if let Err(e) = db.delete(key.to_string()) {
+                return internal(client, e);
+            }
+            self.watchers.lock().unwrap().notify(&client.db_path, key, None);
+            client.audit_write("redis expire", key);
+            return Resp::Integer(1);
+        }
+        let deadline = Deadline { at: Instant::now() + Duration::from_secs(seconds as u64), db: Arc::downgrade(&client.db) };
+        self.expires.lock().unwrap().insert(entry, deadline);
+        client.audit_write("redis expire", key);
+        Resp::Integer(1)
+    }
+
+    fn type_of(&self, client: &Client, key: &str) -> Resp {
+        let mut db = client.db.lock().unwrap();
+        self.expire_due(client, &mut db, key);
+        match db.get(key.to_string()) {
+            Ok(Value::Array(_)) => Resp::Simple("list"),
+            Ok(_) => Resp::Simple("string"),
+            Err(KvError::KeyNotFound(_)) => Resp::Simple("none"),
+            Err(e) => internal(client, e),
+        }
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]. Cursors are numbers as
+    // Redis clients expect, each standing for the key the previous page ended at.
+    fn scan(&self, session: &mut Session, cursor: &str, options: &[String]) -> Resp {
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut kind = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = match options.next() {
+                Some(v) => v,
+                None => return Resp::Error("ERR syntax error".to_string()),
+            };
+            match option.to_uppercase().as_str() {
+                "MATCH" => pattern = Some(value.clone()),
+                "COUNT" => match value.parse::<usize>() {
+                    Ok(c) if c > 0 => count = c.min(MAX_PAGE_SIZE as usize),
+                    Ok(_) => return Resp::Error("ERR syntax error".to_string()),
+                    Err(_) => return not_integer(),
+                },
+                "TYPE" => kind = Some(value.to_lowercase()),
+                _ => return Resp::Error("ERR syntax error".to_string()),
+            }
+        }
+        let after = match cursor.parse::<u64>() {
+            Ok(0) => None,
+            Ok(c) => match session.cursors.remove(&c) {
+                Some(key) => Some(key),
+                None => return Resp::Error("ERR invalid cursor".to_string()),
+            },
+            Err(_) => return Resp::Error("ERR invalid cursor".to_string()),
+        };
+        let client = match &session.client {
+            Some(c) => c,
+            None => return Resp::Error("NOAUTH Authentication required.".to_string()),
+        };
+        let prefix = pattern.as_deref().map_or("", literal_prefix);
+        let page = client.db.lock().unwrap().scan(prefix, after.as_deref(), count);
+        let (items, next) = match page {
+            Ok(p) => p,
+            Err(e) => return internal(client, e),
+        };
+        let now = Instant::now();
+        let db_key = datafile_key(&client.db_path);
+        let expires = self.expires.lock().unwrap();
+        let keys = items
+            .into_iter()
+            .filter(|(key, _)| expires.get(&(db_key.clone(), key.clone())).is_none_or(|d| d.at > now))
+            .filter(|(key, _)| pattern.as_deref().is_none_or(|p| glob(p.as_bytes(), key.as_bytes())))
+            .filter(|(_, value)| {
+                let name = match value {
+                    Value::Array(_) => "list",
+                    _ => "string",
+                };
+                kind.as_deref().is_none_or(|k| k == name)
+            })
+            .map(|(key, _)| Resp::Bulk(key.into_bytes()))
+            .collect();
+        drop(expires);
+        let cursor = match next {
+            Some(key) => {
+                if session.cursors.len() >= MAX_CURSORS {
+                    if let Some(oldest) = session.cursors.keys().min().copied() {
+                        session.cursors.remove(&oldest);
+                    }
+                }
+                let id = session.next_cursor;
+                session.next_cursor += 1;
+                session.cursors.insert(id, key);
+                id
+            }
+            None => 0,
+        };
+        Resp::Array(vec![Resp::Bulk(cursor.to_string().into_bytes()), Resp::Array(keys)])
+    }
+
+    // Deletes the key if its deadline has passed, and tells whether it did. Called
+    // with the datafile locked.
+    fn expire_due(&self, client: &Client, db: &mut DataStore, key: &str) -> bool {
+        {
+            let mut expires = self.expires.lock().unwrap();
+            if expires.is_empty() {
+                return false;
+            }
+            let entry = (datafile_key(&client.db_path), key.to_string());
+            match expires.get(&entry) {
+                Some(deadline) if deadline.at <= Instant::now() => expires.remove(&entry),
+                _ => return false,
+            };
+        }
+        if db.delete(key.to_string()).is_ok() {
+            self.watchers.lock().unwrap().notify(&client.db_path, key, None);
+        }
+        true
+    }
+
+    // Deletes the expired keys of the open datafiles, and forgets the ones of datafiles
+    // no client has open any more.
+    fn expire_all(&self) {
+        let now = Instant::now();
+        let due: Vec<((String, String), Deadline)> = self.expires
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, deadline)| deadline.at <= now)
+            .map(|(entry, deadline)| (entry.clone(), deadline.clone()))
+            .collect();
+        for (entry, deadline) in due {
+            let db = match deadline.db.upgrade() {
+                Some(db) => db,
+                None => {
+                    self.expires.lock().unwrap().remove(&entry);
+                    continue;
+                }
+            };
+            let mut db = db.lock().unwrap();
+            {
+                let mut expires = self.expires.lock().unwrap();
+                match expires.get(&entry) {
+                    Some(deadline) if deadline.at <= Instant::now() => expires.remove(&entry),
+                    _ => continue,
+                };
+            }
+            if db.delete(entry.1.clone()).is_ok() {
+                self.watchers.lock().unwrap().notify(&entry.0, &entry.1, None);
+            }
+        }
+    }
+
+    fn denied(&self, client: &Client, command: &str) -> Resp {
+        self.metrics.error("permission_denied");
+        Resp::Error(format!("NOPERM User {0} has no permissions to run the '{1}' command", client.user, command))
+    }
+}
+
+// Reads one command, as a RESP array of bulk strings or as an inline command. None at
+// the end of the stream, InvalidData for a malformed command.
+fn read_command(reader: &mut BufReader<TcpStream>, max_bulk: usize) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|a| !a.is_empty())
+            .map(|a| a.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = parse_length(&line[1..])?;
+    if count > MAX_ARGUMENTS as i64 {
+        return Err(invalid("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity((count.max(0) as usize).min(PREALLOCATE_ARGUMENTS));
+    for _ in 0..count {
+        let header = match read_line(reader)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        if header.first() != Some(&b'$') {
+            return Err(invalid("expected '$'"));
+        }
+        let length = parse_length(&header[1..])?;
+        if length < 0 || length as usize > max_bulk {
+            return Err(invalid("invalid bulk length"));
+        }
+        let mut arg = Vec::with_capacity((length as usize + 2).min(PREALLOCATE_BULK));
+        reader.by_ref().take(length as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < length as usize + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(invalid("expected CRLF after bulk string"));
+        }
+        arg.truncate(length as usize);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// A line without its line ending.
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(invalid("line too long or incomplete"));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(s: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn encode(reply: &Resp, protocol: u8, out: &mut Vec<u8>) {
+    match reply {
+        Resp::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+        Resp::Error(e) => out.extend_from_slice(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes()),
+        Resp::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+        Resp::Bulk(b) => {
+            out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+            out.extend_from_slice(b);
+            out.extend_from_slice(b"\r\n");
+        }
+        Resp::Nil if protocol == 3 => out.extend_from_slice(b"_\r\n"),
+        Resp::Nil => out.extend_from_slice(b"$-1\r\n"),
+        Resp::Array(items) => {
+            out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode(item, protocol, out);
+            }
+        }
+        // RESP2 has no maps, they are sent as arrays of alternating keys and values.
+        Resp::Map(pairs) => {
+            match protocol {
+                3 => out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes()),
+                _ => out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes()),
+            }
+            for (key, value) in pairs {
+                encode(key, protocol, out);
+                encode(value, protocol, out);
+            }
+        }
+    }
+}
+
+// The metrics label of a command.
+fn kind(name: &str) -> &'static str {
+    match name {
+        "QUIT" => "redis quit",
+        "AUTH" => "redis auth",
+        "HELLO" => "redis hello",
+        "PING" => "redis ping",
+        "ECHO" => "redis echo",
+        "GET" => "redis get",
+        "SET" => "redis set",
+        "DEL" => "redis del",
+        "EXISTS" => "redis exists",
+        "INCR" => "redis incr",
+        "EXPIRE" => "redis expire",
+        "TYPE" => "redis type",
+        "SELECT" => "redis select",
+        "SCAN" => "redis scan",
+        _ => "redis unknown",
+    }
+}
+
+// The part of a glob pattern before its first special character.
+fn literal_prefix(pattern: &str) -> &str {
+    let end = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+// Redis glob-style matching: `*`, `?`, `[abc]`, `[^a]`, `[a-z]` and `\` escapes.
+pub(super) fn glob(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where to resume after the last `*` if the rest does not match.
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, i));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => class(pattern, p, s[i]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == s[i]).then_some(p + 2),
+            Some(c) => (*c == s[i]).then_some(p + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                i += 1;
+            }
+            (None, Some((after_star, matched))) => {
+                p = after_star;
+                i = matched + 1;
+                star = Some((after_star, matched + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+// Matches `c` against the class starting at `pattern[start]`, which is '['. Returns
+// the position after the class if it matches.
+fn class(pattern: &[u8], start: usize, c: u8) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+            matched |= (low..=high).contains(&c);
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    // An unclosed class matches nothing.
+    if p >= pattern.len() || matched == negate {
+        return None;
+    }
+    Some(p + 1)
+}
+
+fn bulk(s: &str) -> Resp {
+    Resp::Bulk(s.as_bytes().to_vec())
+}
+
+fn arity(name: &str) -> Resp {
+    Resp::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+fn unknown(name: &str, args: &[Vec<u8>]) -> Resp {
+    let args: String = args.iter().take(3).map(|a| format!("'{}' ", String::from_utf8_lossy(a))).collect();
+    Resp::Error(format!("ERR unknown command '{0}', with args beginning with: {1}", name.to_lowercase(), args))
+}
+
+fn not_integer() -> Resp {
+    Resp::Error("ERR value is not an integer or out of range".to_string())
+}
+
+fn wrong_type() -> Resp {
+    Resp::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+}
+
+fn internal(client: &Client, e: KvError) -> Resp {
+    output_prompt(format!("An error occurred on Redis client [{0}], error message sent. {1}", client.address, e));
+    Resp::Error(format!("ERR {}", e))
+}
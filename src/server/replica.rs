@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::AtomicBool,
+};
+use super::*;
+use crate::client::Client as Leader;
+
+// Time a follower waits before connecting to the leader again after the replication of
+// a datafile stopped.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+// The leader sends its position every second, so a stream that stays silent this long
+// is taken for dead.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+// How often the follower looks for datafiles created on the leader.
+const LIST_INTERVAL: Duration = Duration::from_secs(10);
+// The datafile the follower opens on the leader to list the others. `rdb init` creates
+// it on every server.
+const LIST_DATABASE: &str = "default.data";
+
+// The replication of each datafile by its name relative to data_path.
+pub type Status = Arc<Mutex<BTreeMap<String, Progress>>>;
+
+pub struct Progress {
+    state: &'static str,
+    // How far the log of the leader has been applied, and where it ends.
+    offset: u64,
+    leader_offset: u64,
+    // The last time the follower had applied everything the leader had.
+    caught_up: Option<Instant>,
+}
+
+// A follower copies every datafile of its leader, each on a thread of its own. The
+// thread replays the whole log of the leader into a scratch datafile, applies the
+// difference to the local datafile and then every change the leader streams. After a
+// lost connection it starts over with a full copy, since the leader may have compacted
+// its log meanwhile and the old offsets mean nothing anymore.
+pub struct Replica {
+    config: Config,
+    dbs: Databases,
+    watchers: Watchers,
+    stopping: Arc<AtomicBool>,
+    status: Status,
+}
+
+impl Replica {
+    pub fn new(config: Config, dbs: Databases, watchers: Watchers, stopping: Arc<AtomicBool>, status: Status) -> Self {
+        Replica {
+            config,
+            dbs,
+            watchers,
+            stopping,
+            status,
+        }
+    }
+
+    pub fn start(self: Arc<Self>) {
+        thread::spawn(move || {
+            let mut failing = false;
+            while !self.stopping.load(Ordering::SeqCst) {
+                match self.list() {
+                    Ok(names) => {
+                        failing = false;
+                        for name in names {
+                            let mut status = self.status.lock().unwrap();
+                            if status.contains_key(&name) {
+                                continue;
+                            }
+                            status.insert(name.clone(), Progress::new());
+                            let replica = Arc::clone(&self);
+                            thread::spawn(move || replica.follow(name));
+                        }
+                    }
+                    Err(e) => {
+                        if !failing {
+                            output_prompt(format!("Unable to list the databases of leader {0}: {1}", self.config.leader_address, e));
+                        }
+                        failing = true;
+                    }
+                }
+                self.pause(LIST_INTERVAL);
+            }
+        });
+    }
+
+    // Follows one datafile until the server stops or the leader dropped it.
+    fn follow(&self, name: String) {
+        output_prompt(format!("Replicating '{0}' from {1}", name, self.config.leader_address));
+        while !self.stopping.load(Ordering::SeqCst) {
+            let e = match self.sync(&name) {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+            self.update(&name, |p| p.state = "disconnected");
+            if let Ok(names) = self.list() {
+                if !names.contains(&name) {
+                    output_prompt(format!("'{}' is gone from the leader and no longer replicated", name));
+                    self.status.lock().unwrap().remove(&name);
+                    return;
+                }
+            }
+            output_prompt(format!("Replication of '{0}' from {1} stopped, retrying in {2} seconds. {3}", name, self.config.leader_address, RETRY_INTERVAL.as_secs(), e));
+            self.pause(RETRY_INTERVAL);
+        }
+    }
+
+    // Copies the datafile from the leader and then applies the changes it streams,
+    // until the server stops or the connection fails.
+    fn sync(&self, name: &str) -> Result<()> {
+        let db_path = resolve_db_path(&self.config, name)?;
+        if let Some(parent) = Path::new(&db_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let db = open_db(&self.dbs, &db_path)?;
+        let mut leader = self.connect(name)?;
+        leader.set_timeout(Some(STREAM_TIMEOUT))?;
+        leader.replicate()?;
+        self.update(name, |p| p.state = "copying");
+
+        let copy_path = format!("{}.sync", db_path);
+        let _ = fs::remove_file(&copy_path);
+        let mut copy = DataStore::open(&copy_path)?;
+        let offset = loop {
+            match leader.next_replicated()? {
+                OperateResult::Change(event) => {
+                    apply(&mut copy, &event)?;
+                    self.update(name, |p| p.offset = event.next_offset);
+                }
+                OperateResult::Position { offset } => break offset,
+                _ => return Err(RorError::ReplicationError("unexpected message from the leader".to_string())),
+            }
+        };
+        let changed = {
+            let mut db = db.lock().unwrap();
+            let wanted = copy.get_all_entry()?.into_iter().map(|entry| (entry.key, entry.value)).collect();
+            let changed = replace(&mut db, wanted)?;
+            let mut watchers = self.watchers.lock().unwrap();
+            for (key, value) in &changed {
+                watchers.notify(&db_path, key, value.clone());
+            }
+            changed.len()
+        };
+        drop(copy);
+        fs::remove_file(&copy_path)?;
+        output_prompt(format!("Copied '{0}' from the leader, {1} keys changed", name, changed));
+        self.update(name, |p| {
+            p.state = "streaming";
+            p.leader_offset = offset;
+            p.applied(offset);
+        });
+
+        while !self.stopping.load(Ordering::SeqCst) {
+            match leader.next_replicated()? {
+                OperateResult::Change(event) => {
+                    let mut db = db.lock().unwrap();
+                    apply(&mut db, &event)?;
+                    match event.kind {
+                        ChangeKind::Add => self.watchers.lock().unwrap().notify(&db_path, &event.key, Some(event.value.clone())),
+                        ChangeKind::Delete => self.watchers.lock().unwrap().notify(&db_path, &event.key, None),
+                        ChangeKind::Compact => (),
+                    }
+                    drop(db);
+                    self.update(name, |p| {
+                        // Compaction moves the end of the leader's log back.
+                        if event.kind == ChangeKind::Compact {
+                            p.leader_offset = event.next_offset;
+                        }
+                        p.applied(event.next_offset);
+                    });
+                }
+                OperateResult::Position { offset } => self.update(name, |p| p.leader(offset)),
+                _ => return Err(RorError::ReplicationError("unexpected message from the leader".to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut leader = self.connect(LIST_DATABASE)?;
+        leader.set_timeout(Some(STREAM_TIMEOUT))?;
+        match leader.operate(OperateRequest::ListDatabases)? {
+            OperateResult::Databases(names) => Ok(names),
+            _ => Err(RorError::ReplicationError("the leader did not list its databases".to_string())),
+        }
+    }
+
+    // Logs in to the leader as leader_user. With leader_tls_ca the connection uses TLS,
+    // and the follower presents its own certificate if it has one.
+    fn connect(&self, db_path: &str) -> Result<Leader> {
+        let (user, password) = match self.config.leader_user.split_once('@') {
+            Some(u) => u,
+            None => return Err(RorError::ConfigError("leader_user must be 'user@password'".to_string())),
+        };
+        let (ip, port) = match self.config.leader_address.rsplit_once(':') {
+            Some(a) => a,
+            None => return Err(RorError::ConfigError("leader_address must be 'host:port'".to_string())),
+        };
+        let (ip, port, user, password, db_path) = (ip.to_string(), port.to_string(), user.to_string(), password.to_string(), db_path.to_string());
+        if self.config.leader_tls_ca.is_empty() {
+            return Leader::connect(ip, port, user, password, db_path);
+        }
+        let tls = TlsOptions::new(self.config.leader_tls_ca.clone());
+        let tls = match self.config.tls_cert.is_empty() {
+            true => tls,
+            false => tls.with_client_cert(self.config.tls_cert.clone(), self.config.tls_key.clone()),
+        };
+        Leader::connect_tls(ip, port, user, password, db_path, &tls)
+    }
+
+    fn update<F: FnOnce(&mut Progress)>(&self, name: &str, f: F) {
+        if let Some(progress) = self.status.lock().unwrap().get_mut(name) {
+            f(progress);
+        }
+    }
+
+    // Sleeps for `duration`, or until the server is stopping.
+    fn pause(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        while !self.stopping.load(Ordering::SeqCst) && Instant::now() < until {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Progress {
+    fn new() -> Self {
+        Progress {
+            state: "connecting",
+            offset: 0,
+            leader_offset: 0,
+            caught_up: None,
+        }
+    }
+
+    fn applied(&mut self, offset: u64) {
+        self.offset = offset;
+        if self.offset >= self.leader_offset {
+            self.caught_up = Some(Instant::now());
+        }
+    }
+
+    fn leader(&mut self, offset: u64) {
+        self.leader_offset = offset;
+        if self.offset >= self.leader_offset {
+            self.caught_up = Some(Instant::now());
+        }
+    }
+}
+
+pub fn report(status: &Status) -> Vec<ReplicationInfo> {
+    status
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, p)| ReplicationInfo {
+            database: name.clone(),
+            state: p.state.to_string(),
+            offset: p.offset,
+            leader_offset: p.leader_offset,
+            lag: p.caught_up.map(|t| match p.state == "streaming" && p.offset >= p.leader_offset {
+                true => 0,
+                false => t.elapsed().as_secs(),
+            }),
+        })
+        .collect()
+}
+
+fn apply(db: &mut DataStore, event: &ChangeEvent) -> Result<()> {
+    match event.kind {
+        ChangeKind::Add => db.add(event.key.clone(), event.value.clone())?,
+        ChangeKind::Delete => match db.delete(event.key.clone()) {
+            Ok(()) | Err(KvError::KeyNotFound(_)) => (),
+            Err(e) => return Err(RorError::KvError(e)),
+        },
+        ChangeKind::Compact => (),
+    }
+    Ok(())
+}
+
+// Makes `db` hold exactly the `wanted` entries and returns the keys that changed, with
+// None for the deleted ones. Going through add and delete keeps the change streams and
+// watches working across a copy.
+pub fn replace(db: &mut DataStore, mut wanted: HashMap<String, Value>) -> Result<Vec<(String, Option<Value>)>> {
+    let mut changed = Vec::new();
+    for entry in db.get_all_entry()? {
+        match wanted.remove(&entry.key) {
+            Some(value) if value == entry.value => (),
+            Some(value) => {
+                db.add(entry.key.clone(), value.clone())?;
+                changed.push((entry.key, Some(value)));
+            }
+            None => {
+                db.delete(entry.key.clone())?;
+                changed.push((entry.key, None));
+            }
+        }
+    }
+    for (key, value) in wanted {
+        db.add(key.clone(), value.clone())?;
+        changed.push((key, Some(value)));
+    }
+    Ok(changed)
+}
@@ -0,0 +1,387 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::AtomicBool,
+};
+use base64::{Engine as _, engine::general_purpose};
+use super::*;
+use super::http::Request;
+
+// The REST gateway answers HTTP requests on the threads of `http::serve`. Every request
+// logs in like a client does and runs through `Client::match_command`, so the levels
+// allowed to read and write are the same as over the binary protocol.
+pub struct Gateway {
+    config: Config,
+    dbs: Databases,
+    watchers: Watchers,
+    raft: Option<raft::Raft>,
+    audit: audit::Audit,
+    limiter: Arc<limit::Limiter>,
+    metrics: Arc<Metrics>,
+    stopping: Arc<AtomicBool>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+// A user logged in with POST /auth/token, until `expires`.
+struct Session {
+    user: String,
+    level: String,
+    expires: Instant,
+}
+
+// The user a request is made as.
+struct Identity {
+    user: String,
+    level: String,
+}
+
+#[derive(Deserialize)]
+struct Batch {
+    operations: Vec<Operation>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Get { key: String },
+    Put { key: String, value: Value },
+    Delete { key: String },
+}
+
+#[derive(Serialize)]
+struct Entry {
+    key: String,
+    value: Value,
+}
+
+#[derive(Serialize)]
+struct Page {
+    items: Vec<Entry>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Gateway {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: Config,
+        dbs: Databases,
+        watchers: Watchers,
+        raft: Option<raft::Raft>,
+        audit: audit::Audit,
+        limiter: Arc<limit::Limiter>,
+        metrics: Arc<Metrics>,
+        stopping: Arc<AtomicBool>,
+    ) -> Self {
+        Gateway {
+            config,
+            dbs,
+            watchers,
+            raft,
+            audit,
+            limiter,
+            metrics,
+            stopping,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn handle(&self, request: Request) -> Response {
+        if self.stopping.load(Ordering::SeqCst) {
+            return error(503, "The server is shutting down");
+        }
+        // Every request is a connection of its own, counted while it runs.
+        let mut slot = match self.limiter.connect(request.address.ip()) {
+            Ok(s) => s,
+            Err(reason) => return self.refuse(&request, reason),
+        };
+        let segments = match request.segments() {
+            Some(s) => s,
+            None => return error(400, "Invalid path"),
+        };
+        let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["auth", "token"]) => self.issue_token(&request),
+            ("DELETE", ["auth", "token"]) => self.revoke_token(&request),
+            (_, ["auth", "token"]) => not_allowed("POST, DELETE"),
+            ("GET", ["db", name, "keys"]) => {
+                let prefix = request.query("prefix").unwrap_or("").to_string();
+                let cursor = request.query("cursor").map(|c| c.to_string());
+                let limit = match request.query("limit").map(|l| l.parse::<u32>()) {
+                    Some(Ok(l)) => l,
+                    Some(Err(_)) => return error(400, "limit must be a number"),
+                    None => 0,
+                };
+                self.run(&request, &mut slot, name, |client| {
+                    let result = client.execute(OperateRequest::Scan { prefix, cursor, limit }, &self.metrics);
+                    match result {
+                        Ok(OperateResult::Entries { items, next_cursor }) => {
+                            let items = items.into_iter().map(|(key, value)| Entry { key, value }).collect();
+                            json(200, &Page { items, next_cursor })
+                        }
+                        other => response(other),
+                    }
+                })
+            }
+            (_, ["db", _, "keys"]) => not_allowed("GET"),
+            ("GET", ["db", name, "keys", key]) => self.run(&request, &mut slot, name, |client| {
+                match client.execute(OperateRequest::Get { key: key.to_string() }, &self.metrics) {
+                    Ok(OperateResult::Found(value)) => json(200, &Entry { key: key.to_string(), value }),
+                    other => response(other),
+                }
+            }),
+            ("PUT", ["db", name, "keys", key]) => {
+                let value: Value = match serde_json::from_slice(&request.body) {
+                    Ok(v) => v,
+                    Err(e) => return error(400, &format!("Invalid value: {}", e)),
+                };
+                self.run(&request, &mut slot, name, |client| {
+                    response(client.execute(OperateRequest::Add { key: key.to_string(), value }, &self.metrics))
+                })
+            }
+            ("DELETE", ["db", name, "keys", key]) => self.run(&request, &mut slot, name, |client| {
+                response(client.execute(OperateRequest::Delete { key: key.to_string() }, &self.metrics))
+            }),
+            (_, ["db", _, "keys", _]) => not_allowed("GET, PUT, DELETE"),
+            ("POST", ["db", name, "batch"]) => {
+                let batch: Batch = match serde_json::from_slice(&request.body) {
+                    Ok(b) => b,
+                    Err(e) => return error(400, &format!("Invalid batch: {}", e)),
+                };
+                self.run(&request, &mut slot, name, |client| {
+                    let results: Vec<BatchResult> = batch.operations
+                        .into_iter()
+                        .map(|operation| self.batch_operation(client, operation))
+                        .collect();
+                    json(200, &serde_json::json!({ "results": results }))
+                })
+            }
+            (_, ["db", _, "batch"]) => not_allowed("POST"),
+            _ => error(404, "Not found"),
+        }
+    }
+
+    fn batch_operation(&self, client: &mut Client, operation: Operation) -> BatchResult {
+        let request = match operation {
+            Operation::Get { key } => OperateRequest::Get { key },
+            Operation::Put { key, value } => OperateRequest::Add { key, value },
+            Operation::Delete { key } => OperateRequest::Delete { key },
+        };
+        let result = client.execute(request, &self.metrics);
+        let status = status(&result);
+        match result {
+            Ok(OperateResult::Found(value)) => BatchResult { status, value: Some(value), error: None },
+            _ if status < 300 => BatchResult { status, value: None, error: None },
+            Ok(_) => BatchResult { status, value: None, error: Some(message(status).to_string()) },
+            Err(e) => BatchResult { status, value: None, error: Some(e.to_string()) },
+        }
+    }
+
+    // Logs the request in and runs `job` with a client that has the database open.
+    fn run<F>(&self, request: &Request, slot: &mut limit::Slot, name: &str, job: F) -> Response
+    where
+        F: FnOnce(&mut Client) -> Response,
+    {
+        let identity = match self.authenticate(request) {
+            Ok(i) => i,
+            Err(response) => return response,
+        };
+        if let Err(reason) = slot.login(&identity.user) {
+            return self.refuse(request, reason);
+        }
+        if !self.limiter.allow(&identity.user) {
+            self.metrics.error("rate_limited");
+            return error(429, "Too many requests");
+        }
+        let db_path = match resolve_db_path(&self.config, name) {
+            Ok(p) => p,
+            Err(_) => return error(400, "Invalid database path"),
+        };
+        // Unlike Open, the gateway does not create missing datafiles.
+        if !Path::new(&db_path).is_file() {
+            return error(404, "Database not found");
+        }
+        let session = Client::session(
+            identity.user,
+            identity.level,
+            request.address,
+            db_path.clone(),
+            self.config.clone(),
+            Arc::clone(&self.dbs),
+            Arc::clone(&self.watchers),
+        );
+        match session {
+            Ok(mut client) => {
+                client.raft = self.raft.clone();
+                client.audit = self.audit.clone();
+                job(&mut client)
+            }
+            Err(e) => {
+                output_prompt(format!("Unable to open '{0}' for REST client [{1}], {2}", db_path, request.address, e));
+                error(500, "Unable to open the database")
+            }
+        }
+    }
+
+    fn refuse(&self, request: &Request, reason: String) -> Response {
+        output_prompt(format!("REST client [{0}], refused. reason: {1}", request.address, reason));
+        self.metrics.error("too_many_connections");
+        error(503, "Too many connections")
+    }
+
+    // Basic auth with a user name and password, or a bearer token from POST /auth/token.
+    fn authenticate(&self, request: &Request) -> std::result::Result<Identity, Response> {
+        let header = request.header("authorization").unwrap_or("");
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            let now = Instant::now();
+            let mut sessions = self.sessions.lock().unwrap();
+            let found = sessions.get(token.trim()).map(|s| (s.expires > now, Identity { user: s.user.clone(), level: s.level.clone() }));
+            sessions.retain(|_, s| s.expires > now);
+            return match found {
+                Some((true, identity)) => Ok(identity),
+                Some((false, _)) => Err(unauthorized("The token has expired")),
+                None => Err(unauthorized("Unknown token")),
+            };
+        }
+        self.login(request)
+    }
+
+    fn login(&self, request: &Request) -> std::result::Result<Identity, Response> {
+        let encoded = match request.header("authorization").and_then(|h| h.strip_prefix("Basic ")) {
+            Some(e) => e.trim(),
+            None => return Err(unauthorized("Authentication required")),
+        };
+        let decoded = general_purpose::STANDARD
+            .decode(encoded)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        let (name, password) = match decoded.as_deref().and_then(|d| d.split_once(':')) {
+            Some((n, p)) => (n.to_string(), p.to_string()),
+            None => return Err(unauthorized("Invalid basic credentials")),
+        };
+        match User::login(name.clone(), password) {
+            Ok(user) => Ok(Identity { user: name, level: user.level }),
+            Err(e @ UserError::UserNotFound(_)) | Err(e @ UserError::WrongPassWord) => {
+                output_prompt(format!("REST client [{0}], failed to login as '{1}'", request.address, name));
+                let outcome = match e {
+                    UserError::UserNotFound(_) => "user_not_found",
+                    _ => "wrong_password",
+                };
+                audit::login(&self.audit, "rest login", &name, request.address, None, outcome);
+                self.metrics.error("login");
+                Err(unauthorized("Wrong user name or password"))
+            }
+            Err(e) => {
+                output_prompt(format!("REST client [{0}], failed to login. reason: {1}", request.address, e));
+                Err(error(500, "Unable to read the users"))
+            }
+        }
+    }
+
+    fn issue_token(&self, request: &Request) -> Response {
+        let identity = match self.login(request) {
+            Ok(i) => i,
+            Err(response) => return response,
+        };
+        audit::login(&self.audit, "rest login", &identity.user, request.address, None, "ok");
+        let token = match new_token() {
+            Ok(t) => t,
+            Err(e) => {
+                output_prompt(format!("REST client [{0}], unable to issue a token. reason: {1}", request.address, e));
+                return error(500, "Unable to issue a token");
+            }
+        };
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.expires > now);
+        sessions.insert(token.clone(), Session {
+            user: identity.user,
+            level: identity.level,
+            expires: now + Duration::from_secs(self.config.rest_token_ttl),
+        });
+        json(200, &serde_json::json!({ "token": token, "expires_in": self.config.rest_token_ttl }))
+    }
+
+    // Ends the session of the bearer token the request is made with.
+    fn revoke_token(&self, request: &Request) -> Response {
+        let token = match request.header("authorization").and_then(|h| h.strip_prefix("Bearer ")) {
+            Some(t) => t.trim(),
+            None => return unauthorized("A bearer token is required"),
+        };
+        match self.sessions.lock().unwrap().remove(token) {
+            Some(_) => Response::new(204, "application/json", Vec::new()),
+            None => unauthorized("Unknown token"),
+        }
+    }
+}
+
+// 256 bits from the operating system's random number generator, as hex.
+fn new_token() -> std::result::Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn status(result: &Result<OperateResult>) -> u16 {
+    match result {
+        Ok(OperateResult::Found(_)) | Ok(OperateResult::Entries { .. }) => 200,
+        Ok(OperateResult::Success) => 204,
+        Ok(OperateResult::PermissionDenied) => 403,
+        Ok(OperateResult::KeyNotFound) => 404,
+        Ok(OperateResult::ReadOnly) => 409,
+        Ok(OperateResult::Redirect { .. }) => 421,
+        Ok(OperateResult::Uncommitted { .. }) => 503,
+        Ok(OperateResult::RateLimited) => 429,
+        Ok(OperateResult::Failure) => 500,
+        Ok(_) => 400,
+        Err(_) => 500,
+    }
+}
+
+fn message(status: u16) -> &'static str {
+    match status {
+        403 => "Permission denied",
+        404 => "Key not found",
+        409 => "The server is a read-only follower",
+        421 => "The server is not the leader of its Raft cluster",
+        429 => "Too many requests",
+        503 => "The server lost the lead of its Raft cluster, the write may or may not have been applied",
+        500 => "Internal error",
+        _ => "Request failed",
+    }
+}
+
+fn response(result: Result<OperateResult>) -> Response {
+    match status(&result) {
+        204 => Response::new(204, "application/json", Vec::new()),
+        status => error(status, message(status)),
+    }
+}
+
+fn json<T: Serialize>(status: u16, body: &T) -> Response {
+    match serde_json::to_vec(body) {
+        Ok(body) => Response::new(status, "application/json", body),
+        Err(_) => error(500, "Unable to encode the response"),
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    let body = serde_json::json!({ "error": message }).to_string();
+    Response::new(status, "application/json", body)
+}
+
+fn unauthorized(message: &str) -> Response {
+    error(401, message).with_header("WWW-Authenticate", "Basic realm=\"rdb\"".to_string())
+}
+
+fn not_allowed(allow: &'static str) -> Response {
+    error(405, "Method not allowed").with_header("Allow", allow.to_string())
+}
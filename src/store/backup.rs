@@ -0,0 +1,320 @@
+use std::{
+    io::{self, Read, Write, BufWriter},
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+use serde::{Serialize,Deserialize};
+use chrono::prelude::Local;
+use super::{
+    kv::{DataStore, hint_path},
+    kv_error::{KvError, Result},
+};
+
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+// A consistent view of a datafile: the file handle stays valid even if the store
+// compacts and renames a new file over the path, and the data is append-only, so
+// the first `len` bytes never change while the copy is taken.
+pub struct Checkpoint {
+    file: File,
+    len: u64,
+    keys: Option<usize>,
+    source_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub name: String,
+    // One more than the latest snapshot beside it when it was taken. `created` only has
+    // seconds and follows the clock, so snapshots are ordered by this instead; those
+    // written before it existed read as 0.
+    #[serde(default)]
+    pub sequence: u64,
+    pub created: String,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    pub keys: Option<usize>,
+    pub source_id: Option<String>,
+    pub linked: bool,
+}
+
+impl DataStore {
+    // Flushes pending writes and captures the datafile up to the last complete entry.
+    // Only this step needs exclusive access; copying the checkpoint does not.
+    pub fn seal(&mut self) -> Result<Checkpoint> {
+        self.flush()?;
+        let file = File::open(&self.path)?;
+        let source_id = file_id(&file)?;
+        Ok(Checkpoint {
+            file,
+            len: self.position(),
+            keys: Some(self.key_count()),
+            source_id,
+        })
+    }
+
+    pub fn checkpoint(&mut self, dir: &str) -> Result<Manifest> {
+        let dir = Path::new(dir);
+        let name = match Path::new(&self.path).file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => return Err(KvError::InvalidPath(self.path.clone())),
+        };
+        let dir_name = dir.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let root = match dir.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let sequence = next_sequence(root, &dir_name);
+        fs::create_dir_all(dir)?;
+        let file = self.seal()?.write_to(&dir.join(&name), &name, None)?;
+        let manifest = Manifest::new(dir_name, sequence, vec![file]);
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+}
+
+impl Checkpoint {
+    // For datafiles that are not opened by any store, so nothing is appending to them.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let source_id = file_id(&file)?;
+        Ok(Checkpoint { file, len, keys: None, source_id })
+    }
+
+    // Writes the checkpoint to `dest`. If `previous` holds the same sealed data from an
+    // earlier snapshot, it is hard-linked instead of copied.
+    pub fn write_to(self, dest: &Path, name: &str, previous: Option<(&Path, &ManifestFile)>) -> Result<ManifestFile> {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut linked = false;
+        if let Some((prev_path, prev)) = previous {
+            if self.source_id.is_some() && prev.source_id == self.source_id && prev.size == self.len {
+                linked = fs::hard_link(prev_path, dest).is_ok();
+            }
+        }
+        if !linked {
+            let mut writer = BufWriter::new(File::create(dest)?);
+            let copied = io::copy(&mut (&self.file).take(self.len), &mut writer)?;
+            if copied != self.len {
+                return Err(KvError::IOError(io::ErrorKind::UnexpectedEof.into()));
+            }
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        Ok(ManifestFile {
+            path: name.to_string(),
+            size: self.len,
+            keys: self.keys,
+            source_id: self.source_id,
+            linked,
+        })
+    }
+}
+
+impl Manifest {
+    pub fn new(name: String, sequence: u64, files: Vec<ManifestFile>) -> Self {
+        Manifest {
+            name,
+            sequence,
+            created: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            files,
+        }
+    }
+
+    pub fn load(dir: &Path) -> Result<Self> {
+        let content = fs::read_to_string(dir.join(MANIFEST_NAME))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    // The manifest is written last, so a snapshot without one is incomplete.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_NAME));
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, dir.join(MANIFEST_NAME))?;
+        Ok(())
+    }
+
+    pub fn find(&self, path: &str) -> Option<&ManifestFile> {
+        self.files.iter().find(|f| f.path == path)
+    }
+}
+
+// Returns the most recent complete snapshot in `root`, skipping `exclude`.
+pub fn latest_snapshot(root: &Path, exclude: &str) -> Option<(PathBuf, Manifest)> {
+    let mut latest: Option<(PathBuf, Manifest)> = None;
+    for dir in fs::read_dir(root).ok()?.flatten() {
+        if dir.file_name().to_string_lossy() == exclude {
+            continue;
+        }
+        if let Ok(manifest) = Manifest::load(&dir.path()) {
+            match &latest {
+                Some((_, m)) if (m.sequence, &m.created) >= (manifest.sequence, &manifest.created) => (),
+                _ => latest = Some((dir.path(), manifest)),
+            }
+        }
+    }
+    latest
+}
+
+// The sequence number of a new snapshot in `root`.
+pub fn next_sequence(root: &Path, exclude: &str) -> u64 {
+    latest_snapshot(root, exclude).map_or(1, |(_, m)| m.sequence + 1)
+}
+
+// Copies one datafile out of a snapshot into a new datafile and checks that it opens.
+pub fn restore_snapshot(snapshot: &Path, file: &str, dest: &Path) -> Result<()> {
+    let manifest = Manifest::load(snapshot)?;
+    if manifest.find(file).is_none() {
+        return Err(KvError::InvalidPath(file.to_string()));
+    }
+    if dest.exists() {
+        return Err(KvError::FileExists(dest.to_string_lossy().to_string()));
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(snapshot.join(file), dest)?;
+    let _ = fs::remove_file(hint_path(&dest.to_string_lossy()));
+    if let Err(e) = DataStore::open(&dest.to_string_lossy()) {
+        let _ = fs::remove_file(dest);
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_id(file: &File) -> Result<Option<String>> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = file.metadata()?;
+    Ok(Some(format!("{}:{}", meta.dev(), meta.ino())))
+}
+
+#[cfg(not(unix))]
+fn file_id(_file: &File) -> Result<Option<String>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::kv::Value;
+
+    // A fresh directory under the system temp directory, removed when it is dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("rdb-backup-{0}-{1}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn snapshot(root: &Path, name: &str, manifest: &str) {
+        fs::create_dir_all(root.join(name)).unwrap();
+        fs::write(root.join(name).join(MANIFEST_NAME), manifest).unwrap();
+    }
+
+    #[test]
+    fn the_latest_snapshot_has_the_highest_sequence() {
+        let root = TempDir::new("latest");
+        let manifest = |sequence: u64, created: &str| {
+            format!("{{\"name\":\"s\",\"sequence\":{0},\"created\":\"{1}\",\"files\":[]}}", sequence, created)
+        };
+        // Created in the same second, and one after the clock was set back.
+        snapshot(&root.0, "first", &manifest(1, "2030-01-01 00:00:00"));
+        snapshot(&root.0, "second", &manifest(2, "2030-01-01 00:00:00"));
+        snapshot(&root.0, "third", &manifest(3, "2020-01-01 00:00:00"));
+        snapshot(&root.0, "excluded", &manifest(9, "2040-01-01 00:00:00"));
+        fs::create_dir_all(root.0.join("incomplete")).unwrap();
+
+        let (dir, latest) = latest_snapshot(&root.0, "excluded").unwrap();
+        assert_eq!(dir, root.0.join("third"));
+        assert_eq!(latest.sequence, 3);
+        assert_eq!(next_sequence(&root.0, "excluded"), 4);
+        assert_eq!(next_sequence(&root.0, "none"), 10);
+        assert_eq!(next_sequence(&root.0.join("incomplete"), ""), 1);
+    }
+
+    #[test]
+    fn snapshots_without_a_sequence_come_first() {
+        let root = TempDir::new("legacy");
+        snapshot(&root.0, "old", "{\"name\":\"old\",\"created\":\"2030-01-01 00:00:00\",\"files\":[]}");
+        snapshot(&root.0, "older", "{\"name\":\"older\",\"created\":\"2029-01-01 00:00:00\",\"files\":[]}");
+        assert_eq!(latest_snapshot(&root.0, "").unwrap().0, root.0.join("old"));
+        snapshot(&root.0, "new", "{\"name\":\"new\",\"sequence\":1,\"created\":\"2000-01-01 00:00:00\",\"files\":[]}");
+        assert_eq!(latest_snapshot(&root.0, "").unwrap().0, root.0.join("new"));
+    }
+
+    #[test]
+    fn a_checkpoint_restores_into_a_new_datafile() {
+        let root = TempDir::new("restore");
+        let mut db = DataStore::open(&root.path("src.data")).unwrap();
+        for key in ["a", "b", "c"] {
+            db.add(key.to_string(), Value::String(key.to_string())).unwrap();
+        }
+        let first = db.checkpoint(&root.path("first")).unwrap();
+        assert_eq!((first.sequence, first.files[0].keys), (1, Some(3)));
+        db.add("d".to_string(), Value::Int32(4)).unwrap();
+        db.delete("a".to_string()).unwrap();
+        assert_eq!(db.checkpoint(&root.path("second")).unwrap().sequence, 2);
+
+        let first_dir = root.0.join("first");
+        let dest = root.0.join("restored.data");
+        restore_snapshot(&first_dir, "src.data", &dest).unwrap();
+        let mut restored = DataStore::open(&dest.to_string_lossy()).unwrap();
+        assert_eq!(restored.key_count(), 3);
+        assert_eq!(restored.get("a".to_string()).unwrap(), Value::String("a".to_string()));
+        assert!(restored.get("d".to_string()).is_err());
+
+        assert!(matches!(restore_snapshot(&first_dir, "src.data", &dest), Err(KvError::FileExists(_))));
+        assert!(matches!(restore_snapshot(&first_dir, "other.data", &root.0.join("x.data")), Err(KvError::InvalidPath(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unchanged_data_is_linked_to_the_previous_snapshot() {
+        let root = TempDir::new("link");
+        let source = root.0.join("src.data");
+        let mut db = DataStore::open(&source.to_string_lossy()).unwrap();
+        db.add("a".to_string(), Value::Int32(1)).unwrap();
+        db.flush().unwrap();
+
+        let first = root.0.join("first.data");
+        let copied = Checkpoint::from_path(&source).unwrap().write_to(&first, "src.data", None).unwrap();
+        assert!(!copied.linked);
+        let linked = Checkpoint::from_path(&source).unwrap()
+            .write_to(&root.0.join("second.data"), "src.data", Some((&first, &copied)))
+            .unwrap();
+        assert!(linked.linked);
+
+        // Once the datafile has grown it is copied again, up to its new end.
+        db.add("b".to_string(), Value::Int32(2)).unwrap();
+        let grown = db.seal().unwrap()
+            .write_to(&root.0.join("third.data"), "src.data", Some((&first, &copied)))
+            .unwrap();
+        assert!(!grown.linked);
+        assert_eq!(grown.size, fs::metadata(root.0.join("third.data")).unwrap().len());
+        assert!(grown.size > copied.size);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use serde::{Serialize,Deserialize};
+use super::kv::{Command, Entry, Value};
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ChangeKind {
+    Add,
+    Delete,
+    // The log was rewritten by compaction, offsets before `next_offset` are no longer valid.
+    Compact,
+}
+
+// `next_offset` is the offset to resume from after this event has been handled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeEvent {
+    pub offset: u64,
+    pub next_offset: u64,
+    pub kind: ChangeKind,
+    pub key: String,
+    pub value: Value,
+}
+
+// A handler returns false once it no longer wants events and is then dropped.
+pub type Handler = Box<dyn FnMut(&ChangeEvent) -> bool + Send>;
+
+// Handlers by the id `add` returned for them.
+#[derive(Default)]
+pub struct Subscribers {
+    handlers: Vec<(u64, Handler)>,
+    next_id: u64,
+}
+
+impl ChangeEvent {
+    pub fn from_entry(offset: u64, entry: &Entry) -> Self {
+        let kind = match entry.meta.command {
+            Command::Add => ChangeKind::Add,
+            Command::Delete => ChangeKind::Delete,
+        };
+        ChangeEvent {
+            offset,
+            next_offset: offset + entry.size() as u64,
+            kind,
+            key: entry.key.clone(),
+            value: entry.value.clone(),
+        }
+    }
+
+    pub fn compact(position: u64) -> Self {
+        ChangeEvent {
+            offset: position,
+            next_offset: position,
+            kind: ChangeKind::Compact,
+            key: String::new(),
+            value: Value::Null,
+        }
+    }
+}
+
+impl Subscribers {
+    pub fn add(&mut self, handler: Handler) -> u64 {
+        self.next_id += 1;
+        self.handlers.push((self.next_id, handler));
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.handlers.retain(|(i, _)| *i != id);
+    }
+
+    pub fn notify(&mut self, event: ChangeEvent) {
+        self.handlers.retain_mut(|(_, h)| h(&event));
+    }
+
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+}
+
+impl fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Subscribers({})", self.handlers.len())
+    }
+}
+
+impl fmt::Display for ChangeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ChangeKind::Add => write!(f, "[{}] add {} : {}", self.offset, self.key, self.value),
+            ChangeKind::Delete => write!(f, "[{}] delete {}", self.offset, self.key),
+            ChangeKind::Compact => write!(f, "[{}] compact", self.offset),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::{BufRead, Write};
+use serde::{Serialize,Deserialize};
+use super::{
+    kv::{DataStore, Value},
+    kv_error::{KvError, Result},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    Json,
+    Csv,
+}
+
+// Entries are read from the datafile this many at a time.
+const PAGE_SIZE: usize = 1000;
+
+struct Record {
+    key: String,
+    value: Value,
+}
+
+// A line of a JSON dump. The value is Value as serde encodes it, except for NaN and the
+// infinities, which JSON has no numbers for and are written as strings.
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    key: String,
+    value: serde_json::Value,
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "json" | "jsonl" | "ndjson" => Ok(DumpFormat::Json),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(KvError::UnknownFormat(name.to_string())),
+        }
+    }
+
+    // Anything that is not a .csv file is treated as JSON Lines.
+    pub fn from_path(path: &str) -> Self {
+        if path.to_lowercase().ends_with(".csv") {
+            return DumpFormat::Csv;
+        }
+        DumpFormat::Json
+    }
+}
+
+pub fn dump<W: Write>(db: &mut DataStore, format: DumpFormat, writer: &mut W) -> Result<usize> {
+    if format == DumpFormat::Csv {
+        writeln!(writer, "key,type,value")?;
+    }
+    let mut count = 0;
+    let mut cursor = None;
+    loop {
+        let (entries, next) = db.scan("", cursor.as_deref(), PAGE_SIZE)?;
+        for (key, value) in &entries {
+            match format {
+                DumpFormat::Json => {
+                    let record = JsonRecord { key: key.clone(), value: to_json(value)? };
+                    writeln!(writer, "{}", serde_json::to_string(&record)?)?;
+                }
+                DumpFormat::Csv => {
+                    let (datatype, value) = csv_value(key, value)?;
+                    writeln!(writer, "{},{},{}", csv_field(key), datatype, csv_field(&value))?;
+                }
+            }
+        }
+        count += entries.len();
+        match next {
+            Some(key) => cursor = Some(key),
+            None => break,
+        }
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+pub fn restore<R: BufRead>(db: &mut DataStore, format: DumpFormat, reader: &mut R) -> Result<usize> {
+    let records = match format {
+        DumpFormat::Json => read_json(reader)?,
+        DumpFormat::Csv => read_csv(reader)?,
+    };
+    let count = records.len();
+    for record in records {
+        db.add(record.key, record.value)?;
+    }
+    Ok(count)
+}
+
+// Every record is parsed before anything is written, so a malformed file leaves the
+// database untouched.
+fn read_json<R: BufRead>(reader: &mut R) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<JsonRecord>(&line).and_then(|r| Ok(Record { key: r.key, value: from_json(&r.value)? })) {
+            Ok(r) => records.push(r),
+            Err(e) => return Err(KvError::InvalidRecord(i + 1, e.to_string())),
+        }
+    }
+    Ok(records)
+}
+
+fn read_csv<R: BufRead>(reader: &mut R) -> Result<Vec<Record>> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    let mut records = Vec::new();
+    for (i, (line, row)) in parse_csv(&content)?.into_iter().enumerate() {
+        if i == 0 && row.len() == 3 && row[0] == "key" && row[1] == "type" && row[2] == "value" {
+            continue;
+        }
+        if row.len() != 3 {
+            return Err(KvError::InvalidRecord(line, format!("expected 3 fields, found {}", row.len())));
+        }
+        let value = match parse_csv_value(&row[1], &row[2]) {
+            Ok(v) => v,
+            Err(e) => return Err(KvError::InvalidRecord(line, e.to_string())),
+        };
+        records.push(Record { key: row[0].clone(), value });
+    }
+    Ok(records)
+}
+
+fn to_json(value: &Value) -> Result<serde_json::Value> {
+    let json = match value {
+        Value::Float32(f) if !f.is_finite() => serde_json::json!({ "Float32": f.to_string() }),
+        Value::Float64(f) if !f.is_finite() => serde_json::json!({ "Float64": f.to_string() }),
+        Value::Array(items) => {
+            let items = items.iter().map(to_json).collect::<Result<Vec<_>>>()?;
+            serde_json::json!({ "Array": items })
+        }
+        _ => serde_json::to_value(value)?,
+    };
+    Ok(json)
+}
+
+fn from_json(json: &serde_json::Value) -> serde_json::Result<Value> {
+    let not_a_float = |s: &str| serde::de::Error::custom(format!("'{}' is not a float", s));
+    let (variant, inner) = match json {
+        serde_json::Value::Object(fields) if fields.len() == 1 => fields.iter().next().unwrap(),
+        _ => return Value::deserialize(json),
+    };
+    match (variant.as_str(), inner) {
+        ("Float32", serde_json::Value::String(s)) => s.parse().map(Value::Float32).map_err(|_| not_a_float(s)),
+        ("Float64", serde_json::Value::String(s)) => s.parse().map(Value::Float64).map_err(|_| not_a_float(s)),
+        ("Array", serde_json::Value::Array(items)) => {
+            Ok(Value::Array(Box::new(items.iter().map(from_json).collect::<serde_json::Result<_>>()?)))
+        }
+        _ => Value::deserialize(json),
+    }
+}
+
+fn csv_value(key: &str, value: &Value) -> Result<(String, String)> {
+    if let Value::Array(_) = value {
+        return Err(KvError::UnsupportedValue(key.to_string(), "CSV".to_string()));
+    }
+    Ok((DataStore::type_of(value.clone()), value.to_string()))
+}
+
+fn parse_csv_value(datatype: &str, s: &str) -> Result<Value> {
+    let convert_error = || KvError::ConvertError(s.to_string(), datatype.to_string());
+    let value = match datatype {
+        "Null" => Value::Null,
+        "Bool" => Value::Bool(s.parse().map_err(|_| convert_error())?),
+        "Int" => Value::Int32(s.parse().map_err(|_| convert_error())?),
+        "Long" => Value::Int64(s.parse().map_err(|_| convert_error())?),
+        "Float" => Value::Float32(s.parse().map_err(|_| convert_error())?),
+        "Double" => Value::Float64(s.parse().map_err(|_| convert_error())?),
+        "Char" => Value::Char(s.parse().map_err(|_| convert_error())?),
+        "String" => Value::String(s.to_string()),
+        _ => return Err(KvError::UnknownType(datatype.to_string())),
+    };
+    Ok(value)
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) || s.starts_with(' ') || s.ends_with(' ') {
+        return format!("\"{}\"", s.replace('"', "\"\""));
+    }
+    s.to_string()
+}
+
+// Splits RFC 4180 style CSV into rows, returning each row with the line it starts on.
+fn parse_csv(content: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut rows = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                _ => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' => (),
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                if !(row.len() == 1 && row[0].is_empty()) {
+                    rows.push((row_line, std::mem::take(&mut row)));
+                }
+                row.clear();
+                line += 1;
+                row_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(KvError::InvalidRecord(row_line, "unterminated quoted field".to_string()));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push((row_line, row));
+    }
+    Ok(rows)
+}
@@ -7,19 +7,43 @@ use std::{
         Read,
         Seek,
         SeekFrom,
+        ErrorKind,
     },
     string::String,
-    collections::HashMap,
+    collections::BTreeMap,
+    ops::Bound,
     fs::{self, File,OpenOptions},
+    sync::mpsc::{self, Receiver},
 };
 use bincode;
 use serde::{Serialize,Deserialize};
-use super::kv_error::{KvError,Result};
+use super::{
+    kv_error::{KvError,Result},
+    cdc::{ChangeEvent, Subscribers},
+    verify::{probe, Probe},
+};
 
 const USIZE_SIZE: usize = std::mem::size_of::<usize>();
-const ENTRY_META_SIZE: usize = USIZE_SIZE * 2 + 4;
+pub const ENTRY_META_SIZE: usize = USIZE_SIZE * 2 + 4;
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
+// A hint file stores the index of a datafile when it is closed, so the next open does
+// not have to read every entry. It is only used while the datafile still has the size
+// recorded in it, and removed once it has been read.
+#[derive(Serialize, Deserialize)]
+struct Hint<I> {
+    position: u64,
+    uncompacted: u64,
+    index: I,
+}
+
+pub fn hint_path(path: &str) -> String {
+    format!("{}.hint", path)
+}
+
+// A page of entries returned by `scan` and the key the next page starts after.
+pub type Page = (Vec<(String, Value)>, Option<String>);
+
 #[derive(Serialize, Deserialize, PartialEq,Debug, Clone)]
 pub enum Value {
     Null,
@@ -33,7 +57,7 @@ pub enum Value {
     Array(Box<Vec<Value>>)
 }
 
-#[derive(Serialize, Deserialize, PartialEq,Debug)]
+#[derive(Serialize, Deserialize, PartialEq,Debug, Clone)]
 pub enum Command {
     Add,
     Delete,
@@ -41,16 +65,16 @@ pub enum Command {
 
 #[derive(Serialize, Deserialize,Debug)]
 pub struct Entry {
-    meta: Meta, 
-    key: String, 
-    value: Value,
+    pub meta: Meta, 
+    pub key: String, 
+    pub value: Value,
 }
 
 #[derive(Serialize, Deserialize,Debug)]
 pub struct Meta {
-    command: Command,
-    key_size: usize,
-    value_size: usize,
+    pub command: Command,
+    pub key_size: usize,
+    pub value_size: usize,
 }
 
 impl Entry { 
@@ -58,18 +82,18 @@ impl Entry {
         Entry {
             meta: Meta {
                 command: Command::Add,
-                key_size: key.as_bytes().len(),
-                value_size: value_size
+                key_size: key.len(),
+                value_size
             },
             key,
-            value: value
+            value
         }
     }   
     pub fn delete(key: String) -> Entry {
         Entry {
             meta: Meta {
                 command: Command::Delete,
-                key_size: key.as_bytes().len(),
+                key_size: key.len(),
                 value_size: 4,
             },
             key,
@@ -107,64 +131,115 @@ pub struct DataStore {
     pub path: String,
     file_reader: BufReader<File>,
     file_writer: BufWriter<File>,
-    index: HashMap<String, u64>,
+    index: BTreeMap<String, u64>,
     position: u64,
     uncompacted: u64,
+    subscribers: Subscribers,
 }
 
 impl DataStore {
     pub fn open(path: &str) -> Result<DataStore> {
-        let file_writer = BufWriter::new(OpenOptions::new().write(true).append(true).create(true).open(path)?);
+        let file_writer = BufWriter::new(OpenOptions::new().append(true).create(true).open(path)?);
         let file_reader = BufReader::new(File::open(path)?);
         let mut result = DataStore {
             path: path.to_string(),
             file_reader,
             file_writer,
-            index: HashMap::new(),
+            index: BTreeMap::new(),
             position: 0,
             uncompacted: 0,
+            subscribers: Subscribers::default(),
+        };
+        (result.index, result.uncompacted, result.position) = match result.load_hint() {
+            Some(loaded) => loaded,
+            None => result.load_hashmap()?,
         };
-        (result.index, result.uncompacted) = result.load_hashmap()?;
+        let _ = fs::remove_file(hint_path(path));
         Ok(result)
     }
 
+    // Flushes the datafile and saves its index to the hint file.
+    pub fn write_hint(&mut self) -> Result<()> {
+        self.flush()?;
+        let hint = Hint {
+            position: self.position,
+            uncompacted: self.uncompacted,
+            index: &self.index,
+        };
+        let path = hint_path(&self.path);
+        let tmp = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        bincode::serialize_into(&mut writer, &hint)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
     pub fn get(&mut self, key: String) -> Result<Value> {
         match self.read(&key) {
             Ok(entry) => {
-                return Ok(entry.value);
+                Ok(entry.value)
             },
             Err(KvError::KeyNotFound(key)) => Err(KvError::KeyNotFound(key)),
-            Err(e) => return Err(e),
+            Err(e) => Err(e),
         }
     }
 
     pub fn get_all_value(&mut self) -> Result<Vec<Value>> {
         let mut data: Vec<Value> = Vec::new();
         let mut offset_vec: Vec<u64> = Vec::new();
-        for (_, offset) in &self.index {
+        for offset in self.index.values() {
             offset_vec.push(*offset);
         }
         for offset in offset_vec {
-            let value = match self.read_with_offset(offset.clone()) {
+            let value = match self.read_with_offset(offset) {
                 Ok(entry) => entry.value,
                 Err(e) => return Err(e),
             };
             data.push(value);
         }
-        return Ok(data);
+        Ok(data)
     }
 
     pub fn get_all_entry(&mut self) -> Result<Vec<Entry>> {
         let mut data: Vec<Entry> = Vec::new();
         let mut offset_vec: Vec<u64> = Vec::new();
-        for (_, offset) in &self.index {
+        for offset in self.index.values() {
             offset_vec.push(*offset);
         }
         for offset in offset_vec {
-            let entry = self.read_with_offset(offset.clone())?;
+            let entry = self.read_with_offset(offset)?;
             data.push(entry);
         }
-        return Ok(data);
+        Ok(data)
+    }
+
+    // Returns up to `limit` entries whose key starts with `prefix`, in key order and
+    // after the key `after`, and the key to continue from if there are more. The index
+    // is sorted, so a page starts at the later of the prefix and the cursor.
+    pub fn scan(&mut self, prefix: &str, after: Option<&str>, limit: usize) -> Result<Page> {
+        let start = match after {
+            Some(a) if a >= prefix => Bound::Excluded(a),
+            _ => Bound::Included(prefix),
+        };
+        let mut page: Vec<(String, u64)> = self.index
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .take(limit + 1)
+            .map(|(k, offset)| (k.clone(), *offset))
+            .collect();
+        let more = page.len() > limit;
+        page.truncate(limit);
+        let mut items = Vec::with_capacity(page.len());
+        for (key, offset) in page {
+            items.push((key, self.read_with_offset(offset)?.value));
+        }
+        let next = match more {
+            true => items.last().map(|(k, _)| k.clone()),
+            false => None,
+        };
+        Ok((items, next))
     }
 
     pub fn add(&mut self, key: String, value: Value) -> Result<()> {
@@ -176,7 +251,9 @@ impl DataStore {
             let last_invalid_entry = self.read_with_offset(*pos)?;
             self.uncompacted += last_invalid_entry.size() as u64;
         }
-        self.index.insert(key, self.position - size);
+        let offset = self.position - size;
+        self.index.insert(key, offset);
+        self.notify(offset, &entry);
         Ok(())
     }
     
@@ -189,6 +266,7 @@ impl DataStore {
             self.file_writer.flush()?;
             self.uncompacted += size;
             self.uncompacted += invalid_add_entry.size() as u64;
+            self.notify(self.position - size, &entry);
 
             return Ok(());
         }
@@ -197,10 +275,10 @@ impl DataStore {
 
     pub fn compact(&mut self) -> Result<()> {
         let new_filename = self.path.clone() + ".compact";
-        let mut new_file_writer = BufWriter::new(OpenOptions::new().write(true).create(true).open(new_filename.clone())?);
+        let mut new_file_writer = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(new_filename.clone())?);
         let mut new_position = 0;
         let mut offset = 0;
-        let mut new_hashmap: HashMap<String, u64> = HashMap::new();
+        let mut new_hashmap: BTreeMap<String, u64> = BTreeMap::new();
         loop {
             match self.read_with_offset(offset) {
                 Ok(entry) => {
@@ -209,13 +287,13 @@ impl DataStore {
                         if entry.meta.command == Command::Add && *pos == offset {
                             new_hashmap.insert(entry.key.clone(),new_position);
                             let buf = entry.encode()?; 
-                            new_file_writer.write(&buf)?;
+                            new_file_writer.write_all(&buf)?;
                             new_position += size;
                         }
                     }
                     offset += size;
                 },
-                Err(KvError::EOF) => break,
+                Err(KvError::Eof) => break,
                 Err(e) => return Err(e),
             }
         }
@@ -226,11 +304,86 @@ impl DataStore {
         self.position = new_position;
         self.uncompacted = 0;
         self.index = new_hashmap;
+        if !self.subscribers.is_empty() {
+            self.subscribers.notify(ChangeEvent::compact(new_position));
+        }
+        Ok(())
+    }
+
+    // Every committed add and delete is sent to the returned receiver together with its
+    // log offset. With `from_offset`, the entries already in the log from that offset on
+    // are replayed first; it must be the start of an entry, e.g. a `next_offset` from an
+    // earlier event, or the end of the log.
+    pub fn subscribe(&mut self, from_offset: Option<u64>) -> Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribe_with(from_offset, move |event| sender.send(event.clone()).is_ok())?;
+        Ok(receiver)
+    }
+
+    // Like `subscribe`, but events are handed to `handler` on the writing thread while
+    // the store is borrowed, so it must not block. Returning false unsubscribes, and so
+    // does `unsubscribe` with the returned id, which is 0 if the handler already did so
+    // during the replay. The replay starts at `from_offset` once the entry there checks
+    // out like in `verify`, rather than reading the log before it.
+    pub fn subscribe_with<F>(&mut self, from_offset: Option<u64>, mut handler: F) -> Result<u64>
+    where
+        F: FnMut(&ChangeEvent) -> bool + Send + 'static,
+    {
+        if let Some(from) = from_offset {
+            if from > self.position {
+                return Err(KvError::InvalidOffset(from));
+            }
+            self.file_writer.flush()?;
+            if from < self.position && !matches!(probe(&mut self.file_reader, from, self.position)?, Probe::Valid(_)) {
+                return Err(KvError::InvalidOffset(from));
+            }
+            let mut offset = from;
+            while offset < self.position {
+                let entry = self.read_with_offset(offset)?;
+                if !handler(&ChangeEvent::from_entry(offset, &entry)) {
+                    return Ok(0);
+                }
+                offset += entry.size() as u64;
+            }
+        }
+        Ok(self.subscribers.add(Box::new(handler)))
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) {
+        self.subscribers.remove(id);
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    fn notify(&mut self, offset: u64, entry: &Entry) {
+        if !self.subscribers.is_empty() {
+            self.subscribers.notify(ChangeEvent::from_entry(offset, entry));
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file_writer.flush()?;
+        self.file_writer.get_ref().sync_data()?;
         Ok(())
     }
 
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.index.len()
+    }
+
+    // Bytes of overwritten and deleted entries that a compaction would reclaim.
+    pub fn dead_bytes(&self) -> u64 {
+        self.uncompacted
+    }
+
     pub fn type_of(value: Value) -> String {
-        return match value {
+        match value {
             Value::Null => "Null".to_string(),
             Value::Bool(_) => "Bool".to_string(),
             Value::Int32(_) => "Int".to_string(),
@@ -243,9 +396,19 @@ impl DataStore {
         }
     }
 
-    fn load_hashmap(&mut self) -> Result<(HashMap<String, u64>, u64)> {
+    fn load_hint(&self) -> Option<(BTreeMap<String, u64>, u64, u64)> {
+        let file = File::open(hint_path(&self.path)).ok()?;
+        let hint: Hint<BTreeMap<String, u64>> = bincode::deserialize_from(BufReader::new(file)).ok()?;
+        let size = self.file_reader.get_ref().metadata().ok()?.len();
+        if size != hint.position {
+            return None;
+        }
+        Some((hint.index, hint.uncompacted, hint.position))
+    }
+
+    fn load_hashmap(&mut self) -> Result<(BTreeMap<String, u64>, u64, u64)> {
         let mut offset = 0;
-        let mut new_hashmap: HashMap<String, u64> = HashMap::new();
+        let mut new_hashmap: BTreeMap<String, u64> = BTreeMap::new();
         let mut uncompacted: u64 = 0;
         loop {
             match self.read_with_offset(offset) {
@@ -264,11 +427,11 @@ impl DataStore {
                     }
                     offset += size;
                 },
-                Err(KvError::EOF) => {break;}
+                Err(KvError::Eof) => {break;}
                 Err(e) => return Err(e),
             }
         }
-        Ok((new_hashmap,uncompacted))
+        Ok((new_hashmap,uncompacted,offset))
     }
 
     fn write(&mut self, entry: &Entry) -> Result<u64> {
@@ -278,7 +441,7 @@ impl DataStore {
         let buf = entry.encode()?; 
         let size = buf.len() as u64;
         self.position += size;
-        self.file_writer.write(&buf)?;
+        self.file_writer.write_all(&buf)?;
         Ok(size)
     }
 
@@ -290,49 +453,62 @@ impl DataStore {
     }
 
     fn read_with_offset(&mut self, offset: u64) -> Result<Entry> {
-        self.file_reader.seek(SeekFrom::Start(offset))?;
-        let mut entry_buf: [u8; ENTRY_META_SIZE] = [0; ENTRY_META_SIZE];
-        let len = self.file_reader.read(&mut entry_buf)?;
-        if len == 0 {
-            return Err(KvError::EOF);
+        read_entry(&mut self.file_reader, offset)
+    }
+}
+
+pub fn read_entry<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<Entry> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut entry_buf: [u8; ENTRY_META_SIZE] = [0; ENTRY_META_SIZE];
+    let mut len = 0;
+    while len < ENTRY_META_SIZE {
+        match reader.read(&mut entry_buf[len..])? {
+            0 => break,
+            n => len += n,
         }
-        return match Entry::decode(&entry_buf) {
-            Ok(entry_meta) => {
-                let mut key_buf = vec![0; entry_meta.key_size];
-                self.file_reader.read_exact(key_buf.as_mut_slice())?;
-                let key = String::from_utf8(key_buf)?;
-                
-                let mut value_buf = vec![0; entry_meta.value_size];
-                self.file_reader.read_exact(value_buf.as_mut_slice())?;
-                let value: Value = bincode::deserialize(&value_buf.as_mut_slice())?;
-                let result: Entry = match entry_meta.command {
-                    Command::Add => {
-                        Entry {
-                            meta: Meta {
-                                command: Command::Add,
-                                key_size: entry_meta.key_size,
-                                value_size: entry_meta.value_size,
-                            },
-                            key: key,
-                            value: value,
-                        }
+    }
+    if len == 0 {
+        return Err(KvError::Eof);
+    }
+    if len < ENTRY_META_SIZE {
+        return Err(KvError::IOError(ErrorKind::UnexpectedEof.into()));
+    }
+    match Entry::decode(&entry_buf) {
+        Ok(entry_meta) => {
+            let mut key_buf = vec![0; entry_meta.key_size];
+            reader.read_exact(key_buf.as_mut_slice())?;
+            let key = String::from_utf8(key_buf)?;
+            
+            let mut value_buf = vec![0; entry_meta.value_size];
+            reader.read_exact(value_buf.as_mut_slice())?;
+            let value: Value = bincode::deserialize(value_buf.as_mut_slice())?;
+            let result: Entry = match entry_meta.command {
+                Command::Add => {
+                    Entry {
+                        meta: Meta {
+                            command: Command::Add,
+                            key_size: entry_meta.key_size,
+                            value_size: entry_meta.value_size,
+                        },
+                        key,
+                        value,
                     }
-                    Command::Delete => {
-                        Entry {
-                            meta: Meta {
-                                command: Command::Delete,
-                                key_size: entry_meta.key_size,
-                                value_size: entry_meta.value_size,
-                            },
-                            key: key,
-                            value: Value::Null,
-                        }
+                }
+                Command::Delete => {
+                    Entry {
+                        meta: Meta {
+                            command: Command::Delete,
+                            key_size: entry_meta.key_size,
+                            value_size: entry_meta.value_size,
+                        },
+                        key,
+                        value: Value::Null,
                     }
-                };
-                Ok(result)
-            },
-            Err(e) => Err(e),
-        };
+                }
+            };
+            Ok(result)
+        },
+        Err(e) => Err(e),
     }
 }
 
@@ -12,6 +12,8 @@ pub enum KvError {
     IsDir(String),
     #[error("Invalid Path '{0}'")]
     InvalidPath(String),
+    #[error("File '{0}' already exists")]
+    FileExists(String),
     #[error("Key not found: \"{0}\"")]
     KeyNotFound(String),
     #[error("{0}")]
@@ -24,6 +26,8 @@ pub enum KvError {
     TomlDeError(#[from] toml::de::Error),
     #[error("{0}")]
     TomlSeError(#[from] toml::ser::Error),
+    #[error("{0}")]
+    SerdeJsonError(#[from] serde_json::Error),
     #[error("Cannot convert '{0}' to {1}")]
     ConvertError(String,String),
     #[error("Incorrect argument to command '{0}'")]
@@ -32,8 +36,16 @@ pub enum KvError {
     UnknownType(String),
     #[error("Unknown command '{0}'")]
     UnknownCommand(String),
+    #[error("Unknown format '{0}'")]
+    UnknownFormat(String),
+    #[error("Invalid record on line {0}: {1}")]
+    InvalidRecord(usize, String),
+    #[error("The value of '{0}' cannot be written as {1}")]
+    UnsupportedValue(String, String),
+    #[error("Offset {0} is not the start of an entry")]
+    InvalidOffset(u64),
     #[error("End Of File")]
-    EOF,
+    Eof,
     #[error("Unknown error")]
     Unknown,
 }
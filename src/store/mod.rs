@@ -1,2 +1,6 @@
 pub mod kv_error;
-pub mod kv;
\ No newline at end of file
+pub mod kv;
+pub mod verify;
+pub mod dump;
+pub mod backup;
+pub mod cdc;
\ No newline at end of file
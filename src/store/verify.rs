@@ -0,0 +1,221 @@
+use std::{
+    fmt,
+    io::{
+        BufReader,
+        BufWriter,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+};
+use super::{
+    kv::{Entry, Command, read_entry, ENTRY_META_SIZE},
+    kv_error::{KvError, Result},
+};
+
+pub struct VerifyReport {
+    pub path: String,
+    pub file_size: u64,
+    pub records: u64,
+    pub live_keys: usize,
+    pub bad_regions: Vec<BadRegion>,
+    pub orphan_tombstones: Vec<(u64, String)>,
+    pub truncated_tail: Option<(u64, u64)>,
+}
+
+pub struct BadRegion {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: String,
+}
+
+pub struct RepairReport {
+    pub source: String,
+    pub output: String,
+    pub kept: u64,
+    pub dropped_bytes: u64,
+    pub verify: VerifyReport,
+}
+
+pub(crate) enum Probe {
+    Valid(Entry),
+    Truncated(String),
+    Corrupt(String),
+}
+
+pub fn verify(path: &str) -> Result<VerifyReport> {
+    let (report, _) = scan(path)?;
+    Ok(report)
+}
+
+pub fn repair(path: &str, output: &str) -> Result<RepairReport> {
+    let (report, entries) = scan(path)?;
+    let mut writer = BufWriter::new(
+        OpenOptions::new().write(true).create(true).truncate(true).open(output)?
+    );
+    let orphans: HashSet<u64> = report.orphan_tombstones.iter().map(|(offset, _)| *offset).collect();
+    let mut kept = 0;
+    let mut written = 0;
+    for (offset, entry) in entries {
+        if orphans.contains(&offset) {
+            continue;
+        }
+        let buf = entry.encode()?;
+        writer.write_all(&buf)?;
+        written += buf.len() as u64;
+        kept += 1;
+    }
+    writer.flush()?;
+    Ok(RepairReport {
+        source: path.to_string(),
+        output: output.to_string(),
+        kept,
+        dropped_bytes: report.file_size - written,
+        verify: report,
+    })
+}
+
+fn scan(path: &str) -> Result<(VerifyReport, Vec<(u64, Entry)>)> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut report = VerifyReport {
+        path: path.to_string(),
+        file_size,
+        records: 0,
+        live_keys: 0,
+        bad_regions: Vec::new(),
+        orphan_tombstones: Vec::new(),
+        truncated_tail: None,
+    };
+    let mut entries: Vec<(u64, Entry)> = Vec::new();
+    let mut live: HashMap<String, u64> = HashMap::new();
+    let mut offset = 0;
+
+    while offset < file_size {
+        let entry = match probe(&mut reader, offset, file_size)? {
+            Probe::Valid(entry) => entry,
+            failure => {
+                let mut next = offset + 1;
+                while next < file_size {
+                    if let Probe::Valid(_) = probe(&mut reader, next, file_size)? {
+                        break;
+                    }
+                    next += 1;
+                }
+                match failure {
+                    Probe::Truncated(_) if next >= file_size => {
+                        report.truncated_tail = Some((offset, file_size - offset));
+                    }
+                    Probe::Truncated(reason) | Probe::Corrupt(reason) => {
+                        report.bad_regions.push(BadRegion { offset, len: next - offset, reason });
+                    }
+                    Probe::Valid(_) => (),
+                }
+                offset = next;
+                continue;
+            }
+        };
+
+        report.records += 1;
+        match entry.meta.command {
+            Command::Add => {
+                live.insert(entry.key.clone(), offset);
+            }
+            Command::Delete => {
+                if live.remove(&entry.key).is_none() {
+                    report.orphan_tombstones.push((offset, entry.key.clone()));
+                }
+            }
+        }
+        let size = entry.size() as u64;
+        entries.push((offset, entry));
+        offset += size;
+    }
+    report.live_keys = live.len();
+    Ok((report, entries))
+}
+
+// Decodes the record at `offset` with the same decoder the store uses, but checks the
+// declared sizes against the file first so that a damaged header cannot trigger a huge
+// allocation, and rejects records whose value does not re-encode to the declared size.
+pub(crate) fn probe<R: Read + Seek>(reader: &mut R, offset: u64, file_size: u64) -> Result<Probe> {
+    if file_size - offset < ENTRY_META_SIZE as u64 {
+        return Ok(Probe::Truncated("incomplete entry header".to_string()));
+    }
+    let mut meta_buf = [0; ENTRY_META_SIZE];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut meta_buf)?;
+    let meta = match Entry::decode(&meta_buf) {
+        Ok(meta) => meta,
+        Err(e) => return Ok(Probe::Corrupt(format!("invalid entry header: {}", e))),
+    };
+    let remaining = file_size - offset - ENTRY_META_SIZE as u64;
+    let body = (meta.key_size as u64).checked_add(meta.value_size as u64);
+    match body {
+        Some(body) if body <= remaining => (),
+        _ => return Ok(Probe::Truncated("entry runs past the end of the file".to_string())),
+    }
+
+    let entry = match read_entry(reader, offset) {
+        Ok(entry) => entry,
+        Err(KvError::DecodeUtf8Error(e)) => return Ok(Probe::Corrupt(format!("invalid key: {}", e))),
+        Err(KvError::BincodeError(e)) => return Ok(Probe::Corrupt(format!("invalid value: {}", e))),
+        Err(KvError::IOError(e)) => return Err(KvError::IOError(e)),
+        Err(e) => return Ok(Probe::Corrupt(e.to_string())),
+    };
+    if bincode::serialized_size(&entry.value)? != meta.value_size as u64 {
+        return Ok(Probe::Corrupt("value size does not match its header".to_string()));
+    }
+    Ok(Probe::Valid(entry))
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.bad_regions.is_empty()
+            && self.orphan_tombstones.is_empty()
+            && self.truncated_tail.is_none()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Datafile: {} ({} bytes)", self.path, self.file_size)?;
+        writeln!(f, "Readable records: {}, live keys: {}", self.records, self.live_keys)?;
+        for region in &self.bad_regions {
+            writeln!(f, "Bad data at offset {} ({} bytes): {}", region.offset, region.len, region.reason)?;
+        }
+        for (offset, key) in &self.orphan_tombstones {
+            writeln!(f, "Orphan tombstone at offset {} for key '{}'", offset, key)?;
+        }
+        if let Some((offset, len)) = self.truncated_tail {
+            writeln!(f, "Truncated tail at offset {} ({} bytes)", offset, len)?;
+        }
+        if self.is_clean() {
+            write!(f, "No problems found")?;
+        } else {
+            write!(f, "Run 'rdb repair' to rewrite the datafile without the damaged data")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Repaired '{}' into '{}'", self.source, self.output)?;
+        writeln!(f, "Kept {} of {} readable records", self.kept, self.verify.records)?;
+        for region in &self.verify.bad_regions {
+            writeln!(f, "Dropped {} bytes at offset {}: {}", region.len, region.offset, region.reason)?;
+        }
+        for (offset, key) in &self.verify.orphan_tombstones {
+            writeln!(f, "Dropped orphan tombstone at offset {} for key '{}'", offset, key)?;
+        }
+        if let Some((offset, len)) = self.verify.truncated_tail {
+            writeln!(f, "Dropped truncated tail of {} bytes at offset {}", len, offset)?;
+        }
+        write!(f, "{} bytes dropped in total", self.dropped_bytes)
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    sync::Arc,
+};
+use rustls::{
+    Certificate,
+    PrivateKey,
+    RootCertStore,
+    ClientConfig,
+    ServerConfig,
+    server::AllowAnyAuthenticatedClient,
+};
+use super::error::{RorError, Result};
+
+// TLS settings of a client. `ca` is the PEM file with the certificate(s) the server
+// certificate must be signed by, `cert` and `key` are only needed when the server
+// requires client certificates.
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    pub ca: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn new(ca: String) -> Self {
+        TlsOptions {
+            ca,
+            cert: None,
+            key: None,
+        }
+    }
+
+    pub fn with_client_cert(mut self, cert: String, key: String) -> Self {
+        self.cert = Some(cert);
+        self.key = Some(key);
+        self
+    }
+
+    pub fn client_config(&self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(&self.ca)?);
+        let config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(RorError::TlsConfigError("a client certificate needs both a cert and a key file".to_string())),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+// With `client_ca`, only clients presenting a certificate signed by it can connect.
+pub fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(RorError::TlsConfigError(format!("no certificate found in '{}'", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    Err(RorError::TlsConfigError(format!("no private key found in '{}'", path)))
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        if let Err(e) = roots.add(&cert) {
+            return Err(RorError::TlsConfigError(format!("invalid CA certificate in '{0}': {1}", path, e)));
+        }
+    }
+    Ok(roots)
+}
@@ -1,2 +1,3 @@
 pub mod user_error;
+#[allow(clippy::module_inception)]
 pub mod user;
\ No newline at end of file
@@ -36,14 +36,14 @@ impl User {
             return Err(UserError::PassWordFormatError(password));
         }
         let name_len = name.chars().count();
-        if name_len < 2 || name_len > 20 {
+        if !(2..=20).contains(&name_len) {
             return Err(UserError::NameLengthError(name_len));
         }
         let mut user = match level.as_str() {
             "0" | "1" | "2" | "3" => User {
-                name: name,
-                password: password,
-                level: level,
+                name,
+                password,
+                level,
             },
             _ => return Err(UserError::UnknownLevel(level)),
         };
@@ -56,7 +56,7 @@ impl User {
         if data.len() > config_max.into() && config_max != 0 {
             return Err(UserError::UserLimit);
         }
-        if let Ok(_) = Self::search(&data,user.name.clone()) {
+        if Self::search(&data,user.name.clone()).is_ok() {
             return Err(UserError::UserNameExists(user.name));
         }
         user.encode();
@@ -71,14 +71,11 @@ impl User {
         let config_path = USER_PATH.clone();
         let str_data = fs::read_to_string(&config_path)?;
         let data: Vec<User> = serde_json::from_str(&str_data)?;
-        let user = match Self::search(&data,name) {
-            Ok(user) => user,
-            Err(e) => return Err(e),
-        };
+        let user = Self::search(&data,name)?;
         if password == user.password {
-            return Ok(user);
+            Ok(user)
         } else {
-            return Err(UserError::WrongPassWord);
+            Err(UserError::WrongPassWord)
         }
     }
 
@@ -127,7 +124,7 @@ impl User {
         let path_slice = Path::new(&config_path);
         if !path_slice.exists() {
             let mut f = File::create(&config_path)?;
-            write!(f, "{}", "[]")?;
+            write!(f, "[]")?;
             return Ok(());
         }
         Ok(())
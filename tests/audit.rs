@@ -0,0 +1,66 @@
+// The audit log of a server: what its clients do is recorded without values or
+// passwords, and the file is rotated by size.
+mod common;
+
+use std::fs;
+use rdb::{Client, OperateRequest, OperateResult};
+use common::{Server, put, PASSWORD, USER};
+
+fn records(server: &Server) -> Vec<serde_json::Value> {
+    let mut records = Vec::new();
+    let mut files: Vec<_> = fs::read_dir(server.path("audit")).unwrap().map(|e| e.unwrap().path()).collect();
+    files.sort();
+    for file in files {
+        for line in fs::read_to_string(file).unwrap().lines() {
+            records.push(serde_json::from_str(line).unwrap());
+        }
+    }
+    records
+}
+
+#[test]
+fn clients_are_audited_without_secrets() {
+    let mut server = Server::new("audit_path = \"audit/audit.log\"\naudit_writes = true\n");
+    server.start();
+    let refused = Client::connect("127.0.0.1".to_string(), server.port.to_string(), USER.to_string(), "wrong password".to_string(), "default.data".to_string());
+    assert!(refused.is_err());
+    let mut client = server.client("default.data");
+    put(&mut client, "card", "4111 1111 1111 1111");
+    let create = OperateRequest::CreateUser { name: "alice".to_string(), password: "hunter2".to_string(), level: "1".to_string() };
+    assert!(matches!(client.operate(create).unwrap(), OperateResult::Success));
+    client.operate(OperateRequest::Get { key: "card".to_string() }).unwrap();
+
+    let records = records(&server);
+    let seen: Vec<(&str, &str, &str)> = records
+        .iter()
+        .map(|r| (r["event"].as_str().unwrap(), r["request"].as_str().unwrap(), r["outcome"].as_str().unwrap()))
+        .collect();
+    assert_eq!(seen[0], ("login_failed", "login", "wrong_password"));
+    assert_eq!(seen[1..], [("login", "login", "ok"), ("write", "add", "ok"), ("admin", "user create", "ok")]);
+    assert_eq!(records[2]["key"], "card");
+    assert_eq!(records[3]["target"], "alice");
+    assert!(records.iter().all(|r| r["user"] == USER));
+
+    let log = fs::read_to_string(server.path("audit/audit.log")).unwrap();
+    for secret in ["4111", "hunter2", PASSWORD, "wrong password"] {
+        assert!(!log.contains(secret), "{} in {}", secret, log);
+    }
+}
+
+#[test]
+fn the_audit_log_is_rotated_by_size() {
+    let mut server = Server::new("audit_path = \"audit/audit.log\"\naudit_writes = true\naudit_max_size = 1000\n");
+    server.start();
+    let mut client = server.client("default.data");
+    for i in 0..50 {
+        put(&mut client, &format!("key{:02}", i), "value");
+    }
+    let files: Vec<_> = fs::read_dir(server.path("audit")).unwrap().map(|e| e.unwrap().path()).collect();
+    assert!(files.len() > 2, "{:?}", files);
+    assert!(files.iter().all(|f| fs::metadata(f).unwrap().len() <= 1000));
+    // Nothing is lost between the files.
+    let keys: Vec<String> = records(&server).iter().filter_map(|r| r["key"].as_str().map(String::from)).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..50).map(|i| format!("key{:02}", i)).collect::<Vec<_>>());
+}
@@ -0,0 +1,95 @@
+// Backups taken while clients write, and restoring them.
+mod common;
+
+use std::{
+    sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    thread,
+    time::Duration,
+};
+use rdb::{Client, OperateRequest, OperateResult, Value};
+use common::{Server, wait_for};
+
+fn run(client: &mut Client, request: OperateRequest) {
+    match client.operate(request.clone()).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("{:?} failed: {:?}", request, other),
+    }
+}
+
+fn contents(client: &mut Client, db: &str) -> Vec<(String, Value)> {
+    run(client, OperateRequest::Open { path: db.to_string() });
+    let mut entries = Vec::new();
+    let mut cursor = None;
+    loop {
+        match client.operate(OperateRequest::Scan { prefix: String::new(), cursor, limit: 0 }).unwrap() {
+            OperateResult::Entries { items, next_cursor } => {
+                entries.extend(items);
+                cursor = next_cursor;
+            }
+            other => panic!("scan failed: {:?}", other),
+        }
+        if cursor.is_none() {
+            return entries;
+        }
+    }
+}
+
+fn restore(client: &mut Client, snapshot: &str, dest: &str) {
+    let request = OperateRequest::Restore {
+        snapshot: snapshot.to_string(),
+        file: "default.data".to_string(),
+        dest: dest.to_string(),
+    };
+    run(client, request);
+}
+
+#[test]
+fn a_backup_under_writes_restores_a_prefix_of_them() {
+    let mut server = Server::new("");
+    server.start();
+
+    // One writer adds key00000, key00001, ... in order and overwrites `last` after each,
+    // so any consistent copy holds a run of keys from the first and `last` naming the
+    // final key of that run.
+    let written = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let mut client = server.client("default.data");
+        let (written, stop) = (written.clone(), stop.clone());
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::SeqCst) {
+                let key = format!("key{:05}", i);
+                run(&mut client, OperateRequest::Add { key: key.clone(), value: Value::Int64(i as i64) });
+                run(&mut client, OperateRequest::Add { key: "last".to_string(), value: Value::String(key) });
+                i += 1;
+                written.store(i, Ordering::SeqCst);
+            }
+        })
+    };
+
+    let mut admin = server.client("default.data");
+    wait_for("the first writes", Duration::from_secs(10), || written.load(Ordering::SeqCst) >= 200);
+    run(&mut admin, OperateRequest::Backup { dest: "during".to_string() });
+    let at_backup = written.load(Ordering::SeqCst);
+    wait_for("writes after the backup", Duration::from_secs(10), || written.load(Ordering::SeqCst) >= at_backup + 200);
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    restore(&mut admin, "during", "during.data");
+    let restored = contents(&mut admin, "during.data");
+    let (last, keys) = restored.split_last().unwrap();
+    assert_eq!(last.0, "last");
+    assert!(keys.len() >= 200 && keys.len() <= at_backup + 1, "{} keys", keys.len());
+    for (i, (key, value)) in keys.iter().enumerate() {
+        assert_eq!((key.as_str(), value), (format!("key{:05}", i).as_str(), &Value::Int64(i as i64)));
+    }
+    assert_eq!(last.1, Value::String(keys.last().unwrap().0.clone()));
+
+    // A backup after the writes stopped restores exactly what the datafile holds.
+    run(&mut admin, OperateRequest::Backup { dest: "after".to_string() });
+    restore(&mut admin, "after", "after.data");
+    let live = contents(&mut admin, "default.data");
+    assert_eq!(live.len(), written.load(Ordering::SeqCst) + 1);
+    assert_eq!(contents(&mut admin, "after.data"), live);
+}
@@ -0,0 +1,98 @@
+// Change streams: the order of events, resuming from an offset, and subscribers that
+// stop reading.
+mod common;
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+use rdb::{ChangeEvent, ChangeKind, Client, OperateRequest, RorError, Value};
+use common::{Server, put, raw_connect, request_frame};
+
+fn next(client: &mut Client) -> ChangeEvent {
+    client.next_change().unwrap().expect("a change")
+}
+
+fn delete(client: &mut Client, key: &str) {
+    client.operate(OperateRequest::Delete { key: key.to_string() }).unwrap();
+}
+
+#[test]
+fn changes_arrive_in_the_order_of_the_log() {
+    let mut server = Server::new("");
+    server.start();
+    let mut writer = server.client("default.data");
+    let mut subscriber = server.client("default.data");
+    subscriber.subscribe(None, None).unwrap();
+
+    put(&mut writer, "a", "1");
+    put(&mut writer, "b", "2");
+    delete(&mut writer, "a");
+    put(&mut writer, "a", "3");
+    let events: Vec<ChangeEvent> = (0..4).map(|_| next(&mut subscriber)).collect();
+    let seen: Vec<(ChangeKind, &str)> = events.iter().map(|e| (e.kind.clone(), e.key.as_str())).collect();
+    assert_eq!(seen, [(ChangeKind::Add, "a"), (ChangeKind::Add, "b"), (ChangeKind::Delete, "a"), (ChangeKind::Add, "a")]);
+    assert_eq!(events[3].value, Value::String("3".to_string()));
+    // Every event starts where the one before it ended.
+    for pair in events.windows(2) {
+        assert_eq!(pair[0].next_offset, pair[1].offset);
+    }
+
+    subscriber.unsubscribe().unwrap();
+    assert!(subscriber.next_change().unwrap().is_none());
+    put(&mut subscriber, "after", "the stream");
+}
+
+#[test]
+fn a_stream_resumes_from_an_offset() {
+    let mut server = Server::new("");
+    server.start();
+    let mut writer = server.client("default.data");
+    for i in 0..5 {
+        put(&mut writer, &format!("key{}", i), "v");
+    }
+    let mut subscriber = server.client("default.data");
+    subscriber.subscribe(Some(0), None).unwrap();
+    let replayed: Vec<ChangeEvent> = (0..5).map(|_| next(&mut subscriber)).collect();
+    assert_eq!(replayed[0].offset, 0);
+    subscriber.unsubscribe().unwrap();
+    assert!(subscriber.next_change().unwrap().is_none());
+
+    // From the third entry on: the rest of the log, then the changes made after it.
+    let resume = replayed[1].next_offset;
+    subscriber.subscribe(Some(resume), Some("key".to_string())).unwrap();
+    put(&mut writer, "other", "filtered out");
+    put(&mut writer, "key5", "v");
+    let keys: Vec<String> = (0..4).map(|_| next(&mut subscriber).key).collect();
+    assert_eq!(keys, ["key2", "key3", "key4", "key5"]);
+    subscriber.unsubscribe().unwrap();
+    assert!(subscriber.next_change().unwrap().is_none());
+
+    // An offset inside an entry, or past the end of the log, is refused.
+    for offset in [resume + 1, u64::MAX] {
+        subscriber.subscribe(Some(offset), None).unwrap();
+        assert!(matches!(subscriber.next_change(), Err(RorError::SubscribeFailed)));
+    }
+}
+
+#[test]
+fn a_subscriber_that_stops_reading_is_disconnected() {
+    let mut server = Server::new("");
+    server.start();
+    let mut stream = raw_connect(server.port, "default.data");
+    stream.write_all(&request_frame(2, &OperateRequest::Subscribe { from_offset: None, prefix: None })).unwrap();
+
+    // Far more changes than the server holds for a subscriber, which reads none of them.
+    let mut writer = server.client("default.data");
+    let value = "v".repeat(16 * 1024);
+    for i in 0..4000 {
+        put(&mut writer, &format!("key{}", i), &value);
+    }
+
+    // What the server sent before it gave up ends with the connection.
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).expect("the server closed the connection");
+    assert!(received.len() < 4000 * value.len());
+    put(&mut writer, "still", "serving");
+}
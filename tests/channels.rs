@@ -0,0 +1,104 @@
+// Channels: which subscriptions a message reaches, unsubscribing, and subscribers that
+// stop reading.
+mod common;
+
+use std::{io::{Read, Write}, time::Duration};
+use rdb::{ChannelMessage, Client, OperateRequest, OperateResult, Value};
+use common::{Server, raw_connect, request_frame, wait_for};
+
+fn publish(client: &mut Client, channel: &str, value: &str) -> u64 {
+    let request = OperateRequest::Publish { channel: channel.to_string(), value: Value::String(value.to_string()) };
+    match client.operate(request).unwrap() {
+        OperateResult::Published { receivers } => receivers,
+        other => panic!("publish failed: {:?}", other),
+    }
+}
+
+// Subscriptions are not answered, so publish on `sync` until the subscriber gets it.
+fn wait_subscribed(publisher: &mut Client, receivers: u64) {
+    wait_for("the subscription", Duration::from_secs(5), || publish(publisher, "sync", "") == receivers);
+}
+
+fn next(client: &mut Client) -> ChannelMessage {
+    loop {
+        let message = client.next_message().unwrap().expect("a message");
+        if message.channel != "sync" {
+            return message;
+        }
+    }
+}
+
+#[test]
+fn messages_reach_channels_and_matching_patterns() {
+    let mut server = Server::new("");
+    server.start();
+    let mut publisher = server.client("default.data");
+    let mut subscriber = server.client("default.data");
+    let patterns = ["news.*", "h?llo", "[ab]c"].map(String::from).to_vec();
+    subscriber.subscribe_channels(vec!["sync".to_string(), "news.tech".to_string()], patterns).unwrap();
+    wait_subscribed(&mut publisher, 1);
+
+    // A channel subscribed by name and by pattern gets the message once for each.
+    assert_eq!(publish(&mut publisher, "news.tech", "1"), 2);
+    assert_eq!(publish(&mut publisher, "news", "missed"), 0);
+    assert_eq!(publish(&mut publisher, "hello", "2"), 1);
+    assert_eq!(publish(&mut publisher, "heello", "missed"), 0);
+    assert_eq!(publish(&mut publisher, "bc", "3"), 1);
+    assert_eq!(publish(&mut publisher, "cc", "missed"), 0);
+
+    let received: Vec<(String, Option<String>)> = (0..4).map(|_| next(&mut subscriber)).map(|m| (m.channel, m.pattern)).collect();
+    let mut tech = received[..2].to_vec();
+    tech.sort();
+    assert_eq!(tech, [("news.tech".to_string(), None), ("news.tech".to_string(), Some("news.*".to_string()))]);
+    assert_eq!(received[2..], [("hello".to_string(), Some("h?llo".to_string())), ("bc".to_string(), Some("[ab]c".to_string()))]);
+}
+
+#[test]
+fn an_unsubscribed_client_gets_no_more_messages() {
+    let mut server = Server::new("");
+    server.start();
+    let mut publisher = server.client("default.data");
+    let mut first = server.client("default.data");
+    let mut second = server.client("default.data");
+    first.subscribe_channels(vec!["sync".to_string(), "chat".to_string()], vec![]).unwrap();
+    second.subscribe_channels(vec!["sync".to_string()], vec!["ch*".to_string()]).unwrap();
+    wait_subscribed(&mut publisher, 2);
+    assert_eq!(publish(&mut publisher, "chat", "to both"), 2);
+    assert_eq!(next(&mut first).value, Value::String("to both".to_string()));
+
+    // The messages sent before the Success that ends the stream are still read.
+    first.unsubscribe().unwrap();
+    while first.next_message().unwrap().is_some() {}
+    assert_eq!(publish(&mut publisher, "chat", "to the second"), 1);
+    assert_eq!(next(&mut second).value, Value::String("to both".to_string()));
+    assert_eq!(next(&mut second).value, Value::String("to the second".to_string()));
+    // The connection is usable again, and can subscribe anew.
+    common::put(&mut first, "key", "value");
+    first.subscribe_channels(vec!["chat".to_string()], vec![]).unwrap();
+    wait_for("the new subscription", Duration::from_secs(5), || publish(&mut publisher, "chat", "again") == 2);
+    assert_eq!(next(&mut first).value, Value::String("again".to_string()));
+}
+
+#[test]
+fn a_subscriber_that_stops_reading_is_disconnected() {
+    let mut server = Server::new("");
+    server.start();
+    let mut publisher = server.client("default.data");
+    let mut stream = raw_connect(server.port, "default.data");
+    let request = OperateRequest::SubscribeChannels { channels: vec!["sync".to_string(), "big".to_string()], patterns: vec![] };
+    stream.write_all(&request_frame(2, &request)).unwrap();
+    wait_subscribed(&mut publisher, 1);
+
+    // The server drops the subscriber once its unread messages pass 4 MiB, well before
+    // this many 64 KiB messages are sent.
+    let value = "v".repeat(64 * 1024);
+    let mut sent = 0;
+    while publish(&mut publisher, "big", &value) == 1 {
+        sent += 1;
+        assert!(sent < 1000, "the subscriber was never disconnected");
+    }
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).expect("the server closed the connection");
+    assert!(received.len() < sent * value.len());
+    assert_eq!(publish(&mut publisher, "big", "nobody"), 0);
+}
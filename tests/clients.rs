@@ -0,0 +1,65 @@
+// The admin requests about connections and the server: clients list, clients kill and
+// server info.
+mod common;
+
+use rdb::{Client, OperateRequest, OperateResult};
+use common::{Server, clients, info, put, USER, PASSWORD};
+
+fn login(server: &Server, user: &str, db: &str) -> Client {
+    Client::connect("127.0.0.1".to_string(), server.port.to_string(), user.to_string(), "654321".to_string(), db.to_string()).unwrap()
+}
+
+#[test]
+fn clients_are_listed_in_the_order_they_connected() {
+    let mut server = Server::new("");
+    server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1")]);
+    server.start();
+    let mut admin = server.client("default.data");
+    let mut writer = login(&server, "writer", "other.data");
+    put(&mut writer, "key", "value");
+
+    let listed = clients(&mut admin);
+    assert_eq!(listed.len(), 2);
+    assert_eq!((listed[0].user.as_str(), listed[0].last_command.as_deref()), (USER, Some("clients list")));
+    assert_eq!((listed[1].user.as_str(), listed[1].last_command.as_deref()), ("writer", Some("add")));
+    assert!(listed[0].database.ends_with("default.data"));
+    assert!(listed[1].database.ends_with("other.data"));
+    assert_ne!(listed[0].address, listed[1].address);
+
+    assert!(matches!(writer.operate(OperateRequest::ListClients).unwrap(), OperateResult::PermissionDenied));
+    assert!(matches!(writer.operate(OperateRequest::ServerInfo).unwrap(), OperateResult::PermissionDenied));
+}
+
+#[test]
+fn a_killed_client_is_disconnected() {
+    let mut server = Server::new("");
+    server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1")]);
+    server.start();
+    let mut admin = server.client("default.data");
+    let mut writer = login(&server, "writer", "default.data");
+    let target = clients(&mut admin).into_iter().find(|c| c.user == "writer").unwrap().address;
+
+    let kill = |address: &str| OperateRequest::KillClient { address: address.to_string() };
+    assert!(matches!(writer.operate(kill(&target)).unwrap(), OperateResult::PermissionDenied));
+    assert!(matches!(admin.operate(kill(&target)).unwrap(), OperateResult::Success));
+    assert!(writer.operate(OperateRequest::Ping).is_err());
+    assert_eq!(clients(&mut admin).len(), 1);
+    assert!(matches!(admin.operate(kill(&target)).unwrap(), OperateResult::Failure));
+    assert!(matches!(admin.operate(kill("not an address")).unwrap(), OperateResult::Failure));
+}
+
+#[test]
+fn server_info_does_not_show_passwords() {
+    let mut server = Server::new("memcached_user = \"cache@hunter2\"\nleader_user = \"copier@swordfish\"\n");
+    server.start();
+    let mut admin = server.client("default.data");
+    let info = info(&mut admin);
+    assert_eq!((info.clients, info.protocol_version), (1, rdb::PROTOCOL_VERSION));
+    assert!(info.databases.iter().any(|d| d.ends_with("default.data")));
+    for shown in ["local_user = \"root@***\"", "memcached_user = \"cache@***\"", "leader_user = \"copier@***\""] {
+        assert!(info.config.contains(shown), "{} in {}", shown, info.config);
+    }
+    for password in [PASSWORD, "hunter2", "swordfish"] {
+        assert!(!info.config.contains(password), "{} in {}", password, info.config);
+    }
+}
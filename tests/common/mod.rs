@@ -0,0 +1,294 @@
+// Servers for the integration tests: each one is the rdb binary running in a directory
+// of its own under the system temp directory, with a root user whose password is
+// 123456. The process is killed and the directory removed when the Server is dropped.
+// A proxy is run the same way.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+use rdb::{Client, ClientInfo, OperateRequest, OperateResult, ServerInfo, Value, PROTOCOL_VERSION, CAP_PIPELINING, CAP_STREAMING};
+
+pub const USER: &str = "root";
+pub const PASSWORD: &str = "123456";
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+pub struct Server {
+    pub dir: PathBuf,
+    pub port: u16,
+    // The subcommand of rdb that runs it, "server" or "proxy".
+    command: &'static str,
+    child: Option<Child>,
+}
+
+// A port nothing listens on right now.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// Waits until `check` holds, panicking with `what` after `timeout`.
+pub fn wait_for(what: &str, timeout: Duration, mut check: impl FnMut() -> bool) {
+    let deadline = Instant::now() + timeout;
+    while !check() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+impl Server {
+    // A server listening on a free port, configured with the lines of server.toml in
+    // `config` on top of the ones every test needs. It is started with `start`.
+    pub fn new(config: &str) -> Self {
+        let (dir, port) = new_dir();
+        fs::create_dir_all(dir.join("data")).unwrap();
+        let base = format!(
+            "name = \"test\"\nip = \"127.0.0.1\"\nport = \"{}\"\ndata_path = \"./data/\"\ntimeout = 300\nrepl = false\n\
+             local_user = \"root@123456\"\ndefault_db = \"default.data\"\nauto_refresh = 20\n",
+            port
+        );
+        fs::write(dir.join("config/server.toml"), base + config).unwrap();
+        fs::write(dir.join("config/user.toml"), "path = \"users.json\"\nuser_max = 50\n").unwrap();
+        let server = Server { dir, port, command: "server", child: None };
+        server.set_users(&[(USER, PASSWORD, "3")]);
+        server
+    }
+
+    // A proxy in front of `shards`, with root as the user moving keys.
+    pub fn proxy(shards: &[&Server]) -> Self {
+        let (dir, port) = new_dir();
+        let shards: Vec<String> = shards.iter().map(|s| format!("\"{}\"", s.address())).collect();
+        let config = format!(
+            "ip = \"127.0.0.1\"\nport = \"{0}\"\nshards = [{1}]\nadmin_user = \"root@123456\"\n",
+            port,
+            shards.join(", ")
+        );
+        fs::write(dir.join("config/proxy.toml"), config).unwrap();
+        Server { dir, port, command: "proxy", child: None }
+    }
+
+    // Replaces the users with `(name, password, level)`.
+    pub fn set_users(&self, users: &[(&str, &str, &str)]) {
+        use base64::{Engine as _, engine::general_purpose};
+        let users: Vec<String> = users
+            .iter()
+            .map(|(name, password, level)| {
+                format!(
+                    "{{\"name\":\"{0}\",\"password\":\"{1}\",\"level\":\"{2}\"}}",
+                    name,
+                    general_purpose::STANDARD.encode(password),
+                    level
+                )
+            })
+            .collect();
+        fs::write(self.dir.join("users.json"), format!("[{}]", users.join(","))).unwrap();
+    }
+
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    // Starts the server and waits until it accepts connections on its port.
+    pub fn start(&mut self) {
+        self.start_on(self.port);
+    }
+
+    // Starts the server and waits until it accepts connections on `port`, for servers
+    // whose listener of interest is not the one of the binary protocol.
+    pub fn start_on(&mut self, port: u16) {
+        let log = fs::File::create(self.dir.join("server.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_rdb"))
+            .arg(self.command)
+            .current_dir(&self.dir)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        self.child = Some(child);
+        let address = format!("127.0.0.1:{}", port);
+        wait_for(&format!("a server on {}", address), Duration::from_secs(20), || {
+            if let Some(status) = self.child.as_mut().and_then(|c| c.try_wait().unwrap()) {
+                panic!("the server exited with {0}:\n{1}", status, self.log());
+            }
+            TcpStream::connect(&address).is_ok()
+        });
+    }
+
+    pub fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    // Waits for the server to exit on its own and returns how it did.
+    pub fn wait_exit(&mut self, timeout: Duration) -> std::process::ExitStatus {
+        let child = self.child.as_mut().expect("the server is not running");
+        let mut status = None;
+        wait_for("the server to exit", timeout, || {
+            status = child.try_wait().unwrap();
+            status.is_some()
+        });
+        self.child = None;
+        status.unwrap()
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.as_ref().expect("the server is not running").id()
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.join("server.log")).unwrap_or_default()
+    }
+
+    pub fn address(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    pub fn client(&self, db: &str) -> Client {
+        connect(self.port, db)
+    }
+}
+
+// A new directory with a config directory in it, and a free port for the server.
+fn new_dir() -> (PathBuf, u16) {
+    let dir = std::env::temp_dir().join(format!(
+        "rdb-test-{0}-{1}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("config")).unwrap();
+    (dir, free_port())
+}
+
+// A directory for tests of the store on its own, removed when it is dropped.
+pub struct TempDir(pub PathBuf);
+
+pub fn temp_dir() -> TempDir {
+    TempDir(new_dir().0)
+}
+
+impl TempDir {
+    pub fn path(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+pub fn connect(port: u16, db: &str) -> Client {
+    let mut client = Client::connect(
+        "127.0.0.1".to_string(),
+        port.to_string(),
+        USER.to_string(),
+        PASSWORD.to_string(),
+        db.to_string(),
+    )
+    .unwrap();
+    client.set_timeout(Some(Duration::from_secs(10))).unwrap();
+    client
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.kill();
+        if !thread::panicking() {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+pub fn put(client: &mut Client, key: &str, value: &str) {
+    let request = OperateRequest::Add { key: key.to_string(), value: Value::String(value.to_string()) };
+    match client.operate(request).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("add {0} failed with {1:?}", key, other),
+    }
+}
+
+// The value of `key`, or None if there is none.
+pub fn get(client: &mut Client, key: &str) -> Option<String> {
+    match client.operate(OperateRequest::Get { key: key.to_string() }).unwrap() {
+        OperateResult::Found(Value::String(s)) => Some(s),
+        OperateResult::KeyNotFound => None,
+        other => panic!("get {0} failed with {1:?}", key, other),
+    }
+}
+
+pub fn info(client: &mut Client) -> ServerInfo {
+    match client.operate(OperateRequest::ServerInfo).unwrap() {
+        OperateResult::ServerInfo(info) => info,
+        other => panic!("server info failed with {:?}", other),
+    }
+}
+
+pub fn clients(client: &mut Client) -> Vec<ClientInfo> {
+    match client.operate(OperateRequest::ListClients).unwrap() {
+        OperateResult::Clients(clients) => clients,
+        other => panic!("clients list failed with {:?}", other),
+    }
+}
+
+// The binary protocol by hand, for tests that send what Client does not: a frame is
+// the magic bytes, the length of the rest as a big-endian u32, the request id and the
+// bincode body.
+pub fn frame(id: u64, body: &[u8]) -> Vec<u8> {
+    let mut frame = b"RDB\x00".to_vec();
+    frame.extend_from_slice(&(8 + body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+pub fn request_frame(id: u64, request: &OperateRequest) -> Vec<u8> {
+    frame(id, &bincode::serialize(request).unwrap())
+}
+
+// A ConnectRequest of `version` as root, whose fields bincode encodes like a tuple.
+pub fn login_frame(version: u16, db: &str) -> Vec<u8> {
+    let request = (version, CAP_PIPELINING | CAP_STREAMING, db, USER, PASSWORD);
+    frame(1, &bincode::serialize(&request).unwrap())
+}
+
+// Reads one frame and returns its id and body.
+pub fn read_frame(stream: &mut TcpStream) -> (u64, Vec<u8>) {
+    let mut head = [0; 16];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(&head[..4], b"RDB\x00");
+    let len = u32::from_be_bytes(head[4..8].try_into().unwrap()) as usize;
+    let id = u64::from_be_bytes(head[8..16].try_into().unwrap());
+    let mut body = vec![0; len - 8];
+    stream.read_exact(&mut body).unwrap();
+    (id, body)
+}
+
+pub fn read_result(stream: &mut TcpStream) -> (u64, OperateResult) {
+    let (id, body) = read_frame(stream);
+    (id, bincode::deserialize(&body).unwrap())
+}
+
+// A connection logged in as root with the current protocol version. The first
+// variant of ConnectReply is Success.
+pub fn raw_connect(port: u16, db: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(&login_frame(PROTOCOL_VERSION, db)).unwrap();
+    let (_, reply) = read_frame(&mut stream);
+    assert_eq!(&reply[..4], &[0, 0, 0, 0], "the login failed");
+    stream
+}
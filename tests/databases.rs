@@ -0,0 +1,95 @@
+// Opening, creating and dropping datafiles, which must stay under data_path.
+mod common;
+
+use rdb::{Client, OperateRequest, OperateResult};
+use common::{Server, connect, get, put, USER, PASSWORD};
+
+fn run(client: &mut Client, request: OperateRequest) -> OperateResult {
+    client.operate(request).unwrap()
+}
+
+fn databases(client: &mut Client) -> Vec<String> {
+    match run(client, OperateRequest::ListDatabases) {
+        OperateResult::Databases(names) => names,
+        other => panic!("list databases failed: {:?}", other),
+    }
+}
+
+#[test]
+fn created_datafiles_are_opened_and_dropped() {
+    let mut server = Server::new("");
+    server.start();
+    let mut client = server.client("default.data");
+    put(&mut client, "where", "default");
+
+    let create = |path: &str| OperateRequest::CreateDatabase { path: path.to_string() };
+    assert!(matches!(run(&mut client, create("dir/other.data")), OperateResult::Success));
+    assert!(matches!(run(&mut client, create("dir/other.data")), OperateResult::Failure));
+    assert_eq!(databases(&mut client), ["default.data", "dir/other.data"]);
+
+    assert!(matches!(run(&mut client, OperateRequest::Open { path: "dir/other.data".to_string() }), OperateResult::Success));
+    put(&mut client, "where", "other");
+    // A second client opening the same datafile through another path sees the write.
+    let mut other = server.client("./dir/other.data");
+    assert_eq!(get(&mut other, "where"), Some("other".to_string()));
+
+    // Open datafiles cannot be dropped, until every client has moved away.
+    let drop_db = |path: &str| OperateRequest::DropDatabase { path: path.to_string() };
+    assert!(matches!(run(&mut client, drop_db("dir/other.data")), OperateResult::Failure));
+    assert!(matches!(run(&mut client, OperateRequest::Open { path: "default.data".to_string() }), OperateResult::Success));
+    assert_eq!(get(&mut client, "where"), Some("default".to_string()));
+    assert!(matches!(run(&mut client, drop_db("dir/other.data")), OperateResult::Failure));
+    drop(other);
+    common::wait_for("the other client to leave", std::time::Duration::from_secs(5), || {
+        matches!(run(&mut client, drop_db("dir/other.data")), OperateResult::Success)
+    });
+    assert!(!server.path("data/dir/other.data").exists());
+    assert_eq!(databases(&mut client), ["default.data"]);
+    assert!(matches!(run(&mut client, drop_db("dir/other.data")), OperateResult::Failure));
+}
+
+#[test]
+fn paths_outside_the_data_path_are_refused() {
+    let mut server = Server::new("");
+    server.start();
+    let mut client = server.client("default.data");
+    let outside = server.path("outside.data");
+    std::fs::write(&outside, "").unwrap();
+    let absolute = outside.to_string_lossy().to_string();
+
+    for path in ["../outside.data", "dir/../../outside.data", absolute.as_str(), ""] {
+        for request in [
+            OperateRequest::Open { path: path.to_string() },
+            OperateRequest::CreateDatabase { path: path.to_string() },
+            OperateRequest::DropDatabase { path: path.to_string() },
+        ] {
+            assert!(matches!(run(&mut client, request.clone()), OperateResult::Failure), "{:?}", request);
+        }
+    }
+    let login = Client::connect("127.0.0.1".to_string(), server.port.to_string(), USER.to_string(), PASSWORD.to_string(), "../outside.data".to_string());
+    assert!(login.is_err());
+    assert!(outside.exists());
+    // The client kept its datafile.
+    put(&mut client, "still", "here");
+    assert_eq!(databases(&mut client), ["default.data"]);
+}
+
+#[test]
+fn creating_and_dropping_need_their_level() {
+    let mut server = Server::new("");
+    server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1"), ("deleter", "654321", "2")]);
+    server.start();
+    let login = |user: &str| {
+        Client::connect("127.0.0.1".to_string(), server.port.to_string(), user.to_string(), "654321".to_string(), "default.data".to_string()).unwrap()
+    };
+    let create = OperateRequest::CreateDatabase { path: "new.data".to_string() };
+    let drop_db = OperateRequest::DropDatabase { path: "new.data".to_string() };
+
+    let mut writer = login("writer");
+    assert!(matches!(run(&mut writer, create.clone()), OperateResult::PermissionDenied));
+    let mut deleter = login("deleter");
+    assert!(matches!(run(&mut deleter, create), OperateResult::Success));
+    assert!(matches!(run(&mut deleter, drop_db.clone()), OperateResult::PermissionDenied));
+    let mut admin = connect(server.port, "default.data");
+    assert!(matches!(run(&mut admin, drop_db), OperateResult::Success));
+}
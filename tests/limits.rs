@@ -0,0 +1,50 @@
+// Connection limits and the request rate, as clients of the binary protocol see them.
+mod common;
+
+use std::time::Duration;
+use rdb::{Client, OperateRequest, OperateResult, RorError};
+use common::{Server, wait_for, PASSWORD, USER};
+
+fn login(server: &Server, user: &str, password: &str) -> Result<Client, RorError> {
+    Client::connect("127.0.0.1".to_string(), server.port.to_string(), user.to_string(), password.to_string(), "default.data".to_string())
+}
+
+#[test]
+fn connections_over_a_limit_are_refused_until_one_closes() {
+    for config in ["max_connections = 2\n", "max_connections_per_ip = 2\n", "max_connections_per_user = 2\n"] {
+        let mut server = Server::new(config);
+        server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1")]);
+        server.start();
+        let first = login(&server, USER, PASSWORD).unwrap();
+        let _second = login(&server, USER, PASSWORD).unwrap();
+        assert!(matches!(login(&server, USER, PASSWORD), Err(RorError::TooManyConnections)), "{}", config);
+        let other_user = login(&server, "writer", "654321");
+        assert_eq!(other_user.is_ok(), config.contains("per_user"), "{}", config);
+        drop(other_user);
+
+        drop(first);
+        wait_for("the slot to be released", Duration::from_secs(5), || login(&server, USER, PASSWORD).is_ok());
+    }
+}
+
+#[test]
+fn requests_over_the_rate_are_not_executed() {
+    let mut server = Server::new("rate_limit = 1\nrate_burst = 3\n");
+    server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1")]);
+    server.start();
+    let mut client = login(&server, USER, PASSWORD).unwrap();
+    let add = |key: &str| OperateRequest::Add { key: key.to_string(), value: rdb::Value::Int32(1) };
+    for key in ["a", "b", "c"] {
+        assert!(matches!(client.operate(add(key)).unwrap(), OperateResult::Success));
+    }
+    assert!(matches!(client.operate(add("d")).unwrap(), OperateResult::RateLimited));
+    // Another connection of the same user shares the bucket, another user does not.
+    let mut same = login(&server, USER, PASSWORD).unwrap();
+    assert!(matches!(same.operate(OperateRequest::Get { key: "d".to_string() }).unwrap(), OperateResult::RateLimited));
+    let mut writer = login(&server, "writer", "654321").unwrap();
+    assert!(matches!(writer.operate(OperateRequest::Get { key: "d".to_string() }).unwrap(), OperateResult::KeyNotFound));
+
+    // A second later the bucket has a token again.
+    std::thread::sleep(Duration::from_millis(1100));
+    assert!(matches!(client.operate(OperateRequest::Get { key: "a".to_string() }).unwrap(), OperateResult::Found(_)));
+}
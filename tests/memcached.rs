@@ -0,0 +1,96 @@
+// The memcached listener over its text protocol: replies to pipelined commands, and
+// expired items left in place for users who may not delete them.
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    thread,
+    time::Duration,
+};
+use rdb::{OperateRequest, OperateResult};
+use common::{PASSWORD, Server, USER, free_port};
+
+fn start(user: &str) -> (Server, u16) {
+    let port = free_port();
+    let mut server = Server::new(&format!(
+        "memcached_address = \"127.0.0.1:{0}\"\nmemcached_user = \"{1}\"\n",
+        port, user
+    ));
+    server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1")]);
+    server.start_on(port);
+    (server, port)
+}
+
+fn connect(port: u16) -> TcpStream {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream
+}
+
+// Sends `commands` at once and reads every reply until the server closes the connection.
+fn exchange(port: u16, commands: &str, close: bool) -> String {
+    let mut stream = connect(port);
+    stream.write_all(commands.as_bytes()).unwrap();
+    if close {
+        stream.shutdown(Shutdown::Write).unwrap();
+    }
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    replies
+}
+
+#[test]
+fn replies_before_quit_and_end_of_stream() {
+    let (_server, port) = start("root@123456");
+    let replies = exchange(port, "set k 0 0 1\r\nx\r\nget k\r\nquit\r\n", false);
+    assert_eq!(replies, "STORED\r\nVALUE k 0 1\r\nx\r\nEND\r\n");
+
+    let replies = exchange(port, "get k\r\nget missing\r\n", true);
+    assert_eq!(replies, "VALUE k 0 1\r\nx\r\nEND\r\nEND\r\n");
+}
+
+#[test]
+fn long_pipelines() {
+    let (_server, port) = start("root@123456");
+    let value = "v".repeat(1000);
+    assert_eq!(exchange(port, &format!("set big 0 0 1000\r\n{}\r\nquit\r\n", value), false), "STORED\r\n");
+
+    // The client does not read until it has sent everything, so the server has to write
+    // replies while commands are still coming.
+    let mut stream = connect(port);
+    let mut writer = stream.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        let commands = "get big\r\n".repeat(5000) + "quit\r\n";
+        writer.write_all(commands.as_bytes()).unwrap();
+    });
+    let mut replies = String::new();
+    stream.read_to_string(&mut replies).unwrap();
+    sender.join().unwrap();
+    assert_eq!(replies, format!("VALUE big 0 1000\r\n{}\r\nEND\r\n", value).repeat(5000));
+}
+
+#[test]
+fn expired_items_are_deleted_by_users_who_may() {
+    let (server, port) = start("writer@654321");
+    let replies = exchange(port, "set short 0 1 1\r\nx\r\nquit\r\n", false);
+    assert_eq!(replies, "STORED\r\n");
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(exchange(port, "get short\r\nquit\r\n", false), "END\r\n");
+    // The writer may not delete, the item is hidden but stays in the datafile.
+    let mut client = server.client("default.data");
+    assert!(matches!(
+        client.operate(OperateRequest::Get { key: "short".to_string() }).unwrap(),
+        OperateResult::Found(_)
+    ));
+
+    let (server, port) = start("root@123456");
+    assert_eq!(exchange(port, "set short 0 1 1\r\nx\r\nquit\r\n", false), "STORED\r\n");
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(exchange(port, "get short\r\nquit\r\n", false), "END\r\n");
+    let mut client = server.client("default.data");
+    assert!(matches!(
+        client.operate(OperateRequest::Get { key: "short".to_string() }).unwrap(),
+        OperateResult::KeyNotFound
+    ));
+}
@@ -0,0 +1,48 @@
+// The metrics listener, scraped after a few requests.
+mod common;
+
+use std::{io::{Read, Write}, net::TcpStream, time::Duration};
+use rdb::{Client, OperateRequest, OperateResult};
+use common::{Server, free_port, get, put, USER};
+
+fn scrape(port: u16) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    response.split_once("\r\n\r\n").map(|(_, b)| b.to_string()).unwrap()
+}
+
+#[test]
+fn the_listener_reports_requests_errors_and_datafiles() {
+    let metrics = free_port();
+    let mut server = Server::new(&format!("metrics_address = \"127.0.0.1:{}\"\n", metrics));
+    server.start();
+    let mut client = server.client("default.data");
+    put(&mut client, "a", "1");
+    put(&mut client, "b", "2");
+    get(&mut client, "a");
+    get(&mut client, "missing");
+    let denied = Client::connect("127.0.0.1".to_string(), server.port.to_string(), USER.to_string(), "wrong".to_string(), "default.data".to_string());
+    assert!(denied.is_err());
+    assert!(matches!(client.operate(OperateRequest::Ping).unwrap(), OperateResult::Pong));
+
+    let out = scrape(metrics);
+    for line in [
+        "rdb_requests_total{kind=\"add\"} 2",
+        "rdb_requests_total{kind=\"get\"} 2",
+        "rdb_request_duration_seconds_count{kind=\"add\"} 2",
+        "rdb_errors_total{type=\"key_not_found\"} 1",
+        "rdb_errors_total{type=\"login\"} 1",
+        "rdb_connected_clients 1",
+        // With the connection that waited for the server to start.
+        "rdb_connections_total 3",
+        "rdb_open_databases 1",
+    ] {
+        assert!(out.lines().any(|l| l == line), "no {0} in\n{1}", line, out);
+    }
+    let keys = out.lines().find(|l| l.starts_with("rdb_database_keys{")).unwrap();
+    assert!(keys.ends_with("default.data\"} 2"), "{}", keys);
+}
@@ -0,0 +1,259 @@
+// The binary protocol spoken by hand: the handshake, the size of frames, and how much a
+// connection may queue.
+mod common;
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+use rdb::{Client, OperateRequest, OperateResult, RorError, Value, PROTOCOL_VERSION};
+use common::{Server, PASSWORD, USER, frame, get, info, login_frame, put, raw_connect, read_frame, read_result, request_frame};
+
+// The ConnectReply a login with `version` gets, as bincode encodes it: the variant as a
+// little-endian u32 and then its fields.
+fn login(port: u16, version: u16) -> (TcpStream, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(&login_frame(version, "default.data")).unwrap();
+    let (id, reply) = read_frame(&mut stream);
+    assert_eq!(id, 1);
+    (stream, reply)
+}
+
+#[test]
+fn an_older_client_is_answered_in_its_version() {
+    let mut server = Server::new("max_connections = 2\n");
+    server.start();
+
+    let (mut stream, reply) = login(server.port, 1);
+    assert_eq!(&reply[..6], &[0, 0, 0, 0, 1, 0], "not a Success of version 1: {:?}", reply);
+    stream.write_all(&request_frame(2, &OperateRequest::Get { key: "missing".to_string() })).unwrap();
+    assert!(matches!(read_result(&mut stream), (2, OperateResult::KeyNotFound)));
+    // Pong came after version 1.
+    stream.write_all(&request_frame(3, &OperateRequest::Ping)).unwrap();
+    assert!(matches!(read_result(&mut stream), (3, OperateResult::Failure)));
+
+    // So did TooManyConnections, which version 1 gets as a server error.
+    let _second = raw_connect(server.port, "default.data");
+    let (_, reply) = login(server.port, 1);
+    assert_eq!(reply, [1, 0, 0, 0, 5, 0, 0, 0]);
+    let (_, reply) = login(server.port, PROTOCOL_VERSION);
+    assert_eq!(reply, [1, 0, 0, 0, 7, 0, 0, 0]);
+}
+
+#[test]
+fn an_unsupported_version_is_refused() {
+    let mut server = Server::new("");
+    server.start();
+    let min = 1u16.to_le_bytes();
+    let max = PROTOCOL_VERSION.to_le_bytes();
+    let unsupported = [1, 0, 0, 0, 6, 0, 0, 0, min[0], min[1], max[0], max[1]];
+    for version in [0, PROTOCOL_VERSION + 1, u16::MAX] {
+        let (_, reply) = login(server.port, version);
+        assert_eq!(reply, unsupported, "version {} was not refused", version);
+    }
+
+    // The versions in between are accepted.
+    let (_, reply) = login(server.port, PROTOCOL_VERSION);
+    assert_eq!(&reply[..4], &[0, 0, 0, 0]);
+    assert_eq!(u16::from_le_bytes([reply[4], reply[5]]), PROTOCOL_VERSION);
+}
+
+#[test]
+fn a_frame_without_the_magic_ends_the_connection() {
+    let mut server = Server::new("");
+    server.start();
+    let mut request = login_frame(PROTOCOL_VERSION, "default.data");
+    request[..4].copy_from_slice(b"HTTP");
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream.write_all(&request).unwrap();
+    let mut buf = [0; 16];
+    assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)), "the server answered a frame without the magic");
+
+    // The server still serves everyone else.
+    let mut client = server.client("default.data");
+    put(&mut client, "key", "value");
+}
+
+#[test]
+fn a_repl_that_cannot_log_in_leaves_the_server_running() {
+    let mut server = Server::new("");
+    let config = std::fs::read_to_string(server.path("config/server.toml")).unwrap();
+    let config = config.replace("repl = false", "repl = true").replace("root@123456", "root@wrong");
+    std::fs::write(server.path("config/server.toml"), config).unwrap();
+    server.start();
+    common::wait_for("the REPL to give up", Duration::from_secs(10), || server.log().contains("Failed to start REPL mode"));
+    let mut client = server.client("default.data");
+    put(&mut client, "key", "value");
+    assert_eq!(get(&mut client, "key"), Some("value".to_string()));
+}
+
+#[test]
+fn a_client_that_does_not_read_cannot_fill_the_server() {
+    let mut server = Server::new("");
+    server.start();
+    let mut client = server.client("default.data");
+    let before = info(&mut client).memory;
+
+    // A watch without a timeout holds back every request after it, which the client
+    // keeps sending: 64 MiB, far more than the server queues.
+    let mut stream = raw_connect(server.port, "default.data");
+    stream.write_all(&request_frame(1, &OperateRequest::Watch { key: "gate".to_string(), timeout: 0 })).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        let value = Value::String("v".repeat(1 << 20));
+        for id in 2..66 {
+            let request = OperateRequest::Add { key: format!("key{}", id), value: value.clone() };
+            writer.write_all(&request_frame(id, &request)).unwrap();
+        }
+    });
+    thread::sleep(Duration::from_secs(2));
+    assert!(!sender.is_finished(), "the server read every request behind the watch");
+
+    // Other clients are still served, and the server did not take in what was sent.
+    put(&mut client, "other", "1");
+    if let (Some(before), Some(after)) = (before, info(&mut client).memory) {
+        assert!(after < before + 40 * 1024 * 1024, "the server grew from {0} to {1} bytes", before, after);
+    }
+
+    // Once the watch fires the server reads the rest and answers every request in order.
+    put(&mut client, "gate", "open");
+    sender.join().unwrap();
+    assert!(matches!(read_result(&mut stream), (1, OperateResult::KeyChanged { .. })));
+    for id in 2..66 {
+        let (reply, result) = read_result(&mut stream);
+        assert_eq!(reply, id);
+        assert!(matches!(result, OperateResult::Success), "request {0} failed with {1:?}", id, result);
+    }
+}
+
+#[test]
+fn pipelined_replies_follow_the_requests() {
+    let mut server = Server::new("");
+    server.start();
+    let mut client = server.client("default.data");
+
+    // Several windows of requests, with a failing one in the middle.
+    let mut requests = Vec::new();
+    for i in 0..200 {
+        requests.push(OperateRequest::Add { key: format!("key{}", i), value: Value::Int64(i) });
+        requests.push(OperateRequest::Get { key: format!("key{}", i) });
+    }
+    requests.insert(201, OperateRequest::Get { key: "missing".to_string() });
+    let results = client.pipeline(requests).unwrap();
+    assert_eq!(results.len(), 401);
+    assert!(matches!(results[201], OperateResult::KeyNotFound));
+    let results: Vec<_> = results[..201].iter().chain(&results[202..]).collect();
+    for (i, pair) in results.chunks(2).enumerate() {
+        assert!(matches!(pair[0], OperateResult::Success), "add {0} failed with {1:?}", i, pair[0]);
+        match pair[1] {
+            OperateResult::Found(Value::Int64(v)) => assert_eq!(*v, i as i64),
+            other => panic!("get {0} returned {1:?}", i, other),
+        }
+    }
+
+    // Requests larger than a window each go alone.
+    let big = "v".repeat(6 * 1024 * 1024);
+    let requests = (0..4).map(|i| OperateRequest::Add { key: format!("big{}", i), value: Value::String(big.clone()) }).collect();
+    assert!(client.pipeline(requests).unwrap().iter().all(|r| matches!(r, OperateResult::Success)));
+    assert_eq!(get(&mut client, "big3").map(|v| v.len()), Some(big.len()));
+}
+
+#[test]
+fn replies_carry_the_id_of_their_request() {
+    let mut server = Server::new("");
+    server.start();
+    let mut stream = raw_connect(server.port, "default.data");
+
+    // Ids are the client's to choose, and a frame the server cannot decode is answered
+    // with a Failure without ending the connection.
+    let mut batch = request_frame(900, &OperateRequest::Add { key: "a".to_string(), value: Value::Int32(1) });
+    batch.extend(request_frame(7, &OperateRequest::Get { key: "nothing".to_string() }));
+    batch.extend(frame(42, &[0xff; 12]));
+    batch.extend(request_frame(3, &OperateRequest::Get { key: "a".to_string() }));
+    stream.write_all(&batch).unwrap();
+    assert!(matches!(read_result(&mut stream), (900, OperateResult::Success)));
+    assert!(matches!(read_result(&mut stream), (7, OperateResult::KeyNotFound)));
+    assert!(matches!(read_result(&mut stream), (42, OperateResult::Failure)));
+    assert!(matches!(read_result(&mut stream), (3, OperateResult::Found(Value::Int32(1)))));
+}
+
+// The head of a frame whose body is `len` bytes long, without the body.
+fn head(len: u32) -> Vec<u8> {
+    let mut head = b"RDB\x00".to_vec();
+    head.extend_from_slice(&len.to_be_bytes());
+    head
+}
+
+// Whether the server closes `stream` within a second, rather than waiting for more.
+fn is_closed(stream: &mut TcpStream) -> bool {
+    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0; 64];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return true,
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return false,
+            Err(_) => return true,
+        }
+    }
+}
+
+#[test]
+fn frames_over_the_limit_are_refused_by_their_head() {
+    let mut server = Server::new("max_frame_size = 1048576\n");
+    server.start();
+
+    // Before login frames may be 64 KiB. A larger head ends the connection at once,
+    // while a smaller one waits for its body.
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.write_all(&head(64 * 1024)).unwrap();
+    assert!(!is_closed(&mut stream));
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port)).unwrap();
+    stream.write_all(&head(64 * 1024 + 1)).unwrap();
+    assert!(is_closed(&mut stream));
+
+    // After it the limit is max_frame_size.
+    let mut stream = raw_connect(server.port, "default.data");
+    stream.write_all(&head(1024 * 1024)).unwrap();
+    assert!(!is_closed(&mut stream));
+    let mut stream = raw_connect(server.port, "default.data");
+    stream.write_all(&head(1024 * 1024 + 1)).unwrap();
+    assert!(is_closed(&mut stream));
+
+    // The client learns the limit when it logs in and refuses to send more.
+    let mut client = server.client("default.data");
+    let request = OperateRequest::Add { key: "big".to_string(), value: Value::String("v".repeat(1024 * 1024)) };
+    assert!(matches!(client.operate(request), Err(RorError::FrameTooLarge(_, 1048576))));
+    put(&mut client, "small", "still connected");
+}
+
+#[test]
+fn the_client_refuses_a_reply_over_its_limit_by_its_head() {
+    // A server that answers the login with the head of a frame larger than the 16 MiB
+    // the client accepts, and never sends the body.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let fake = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_frame(&mut stream);
+        stream.write_all(&head(16 * 1024 * 1024 + 1)).unwrap();
+        thread::sleep(Duration::from_secs(3));
+    });
+    let start = Instant::now();
+    let login = Client::connect("127.0.0.1".to_string(), port.to_string(), USER.to_string(), PASSWORD.to_string(), "default.data".to_string());
+    assert!(matches!(login, Err(RorError::FrameTooLarge(_, _))));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    fake.join().unwrap();
+
+    // Replies are held to what set_max_frame_size allows.
+    let mut server = Server::new("");
+    server.start();
+    let mut client = server.client("default.data");
+    put(&mut client, "big", &"v".repeat(4096));
+    client.set_max_frame_size(1024);
+    assert!(matches!(client.operate(OperateRequest::Get { key: "big".to_string() }), Err(RorError::FrameTooLarge(_, 1024))));
+}
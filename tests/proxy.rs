@@ -0,0 +1,164 @@
+// A proxy in front of servers on localhost ports: keys routed to one shard each, scans
+// merged in key order, and keys moved to a shard added while clients keep writing.
+mod common;
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::Duration,
+};
+use rdb::{Client, OperateRequest, OperateResult, ShardInfo, Value};
+use common::{Server, get, put, wait_for};
+
+fn servers(n: usize) -> Vec<Server> {
+    (0..n)
+        .map(|_| {
+            let mut server = Server::new("");
+            server.start();
+            server
+        })
+        .collect()
+}
+
+// Every entry, page by page, asserting the pages come in key order.
+fn scan_all(client: &mut Client, limit: u32) -> Vec<(String, Value)> {
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    let mut cursor = None;
+    loop {
+        let request = OperateRequest::Scan { prefix: String::new(), cursor: cursor.take(), limit };
+        let (items, next) = match client.operate(request).unwrap() {
+            OperateResult::Entries { items, next_cursor } => (items, next_cursor),
+            other => panic!("scan failed with {:?}", other),
+        };
+        for (key, value) in items {
+            if let Some((last, _)) = entries.last() {
+                assert!(*last < key, "scan returned {0} after {1}", key, last);
+            }
+            entries.push((key, value));
+        }
+        match next {
+            Some(c) => cursor = Some(c),
+            None => return entries,
+        }
+    }
+}
+
+fn shards(client: &mut Client) -> Vec<ShardInfo> {
+    match client.operate(OperateRequest::ListShards).unwrap() {
+        OperateResult::Shards(s) => s,
+        other => panic!("shard list failed with {:?}", other),
+    }
+}
+
+// The keys a server holds itself.
+fn keys_on(server: &Server) -> Vec<String> {
+    scan_all(&mut server.client("default.data"), 1000).into_iter().map(|(k, _)| k).collect()
+}
+
+fn put_all(client: &mut Client, entries: &[(String, String)]) {
+    for chunk in entries.chunks(100) {
+        let requests = chunk
+            .iter()
+            .map(|(k, v)| OperateRequest::Add { key: k.clone(), value: Value::String(v.clone()) })
+            .collect();
+        for result in client.pipeline(requests).unwrap() {
+            assert!(matches!(result, OperateResult::Success), "add failed with {:?}", result);
+        }
+    }
+}
+
+#[test]
+fn keys_are_routed_and_scans_merged() {
+    let backends = servers(2);
+    let mut proxy = Server::proxy(&[&backends[0], &backends[1]]);
+    proxy.start();
+    let mut client = proxy.client("default.data");
+
+    let entries: Vec<(String, String)> = (0..200).map(|i| (format!("key{:03}", i), i.to_string())).collect();
+    put_all(&mut client, &entries);
+    assert_eq!(get(&mut client, "key042").as_deref(), Some("42"));
+    match client.operate(OperateRequest::Delete { key: "key042".to_string() }).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("delete failed with {:?}", other),
+    }
+    assert_eq!(get(&mut client, "key042"), None);
+
+    // Every key is on exactly one backend, and both have some.
+    let (first, second) = (keys_on(&backends[0]), keys_on(&backends[1]));
+    assert!(!first.is_empty() && !second.is_empty());
+    assert!(first.iter().all(|k| !second.contains(k)));
+    assert_eq!(first.len() + second.len(), 199);
+
+    let scanned: Vec<String> = scan_all(&mut client, 7).into_iter().map(|(k, _)| k).collect();
+    let expected: Vec<String> = entries.iter().map(|(k, _)| k.clone()).filter(|k| k != "key042").collect();
+    assert_eq!(scanned, expected);
+}
+
+#[test]
+fn adding_a_shard_under_load_keeps_every_key() {
+    let backends = servers(3);
+    let mut proxy = Server::proxy(&[&backends[0], &backends[1]]);
+    proxy.start();
+    let mut client = proxy.client("default.data");
+
+    let mut expected = BTreeMap::new();
+    let initial: Vec<(String, String)> = (0..1500).map(|i| (format!("a{:05}", i), i.to_string())).collect();
+    put_all(&mut client, &initial);
+    expected.extend(initial);
+
+    // A writer adds new keys and overwrites old ones while the keys move.
+    let written = Arc::new(Mutex::new(BTreeMap::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (written, stop) = (Arc::clone(&written), Arc::clone(&stop));
+        let mut client = proxy.client("default.data");
+        thread::spawn(move || {
+            let mut i = 0;
+            while !stop.load(Ordering::SeqCst) || i < 200 {
+                let (key, value) = match i % 2 {
+                    0 => (format!("b{:05}", i), "new".to_string()),
+                    _ => (format!("a{:05}", i), format!("changed{}", i)),
+                };
+                put(&mut client, &key, &value);
+                written.lock().unwrap().insert(key, value);
+                i += 1;
+            }
+        })
+    };
+
+    match client.operate(OperateRequest::AddShard { address: backends[2].address() }).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("shard add failed with {:?}", other),
+    }
+    // Scans while keys move see every key written before them, once and in order.
+    let mut scans = 0;
+    wait_for("the keys to move", Duration::from_secs(60), || {
+        let before = written.lock().unwrap().clone();
+        let scanned: BTreeMap<String, Value> = scan_all(&mut client, 100).into_iter().collect();
+        for key in expected.keys().chain(before.keys()) {
+            assert!(scanned.contains_key(key), "{} is missing from a scan", key);
+        }
+        scans += 1;
+        shards(&mut client).iter().all(|s| s.state == "serving")
+    });
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+    assert!(scans > 0);
+    expected.extend(written.lock().unwrap().clone());
+
+    let scanned: Vec<(String, Value)> = scan_all(&mut client, 100);
+    let wanted: Vec<(String, Value)> = expected.iter().map(|(k, v)| (k.clone(), Value::String(v.clone()))).collect();
+    assert_eq!(scanned, wanted);
+
+    // No key is left behind on its old shard or copied to two.
+    let on_backends: Vec<Vec<String>> = backends.iter().map(keys_on).collect();
+    assert!(!on_backends[2].is_empty());
+    let mut all: Vec<String> = on_backends.concat();
+    all.sort();
+    assert_eq!(all, expected.keys().cloned().collect::<Vec<_>>());
+    assert_eq!(shards(&mut client).len(), 3);
+    // Keys the writer never touched only get to the new shard by moving.
+    let written = written.lock().unwrap();
+    assert!(on_backends[2].iter().any(|k| k.starts_with('a') && !written.contains_key(k)));
+}
@@ -0,0 +1,156 @@
+// Raft clusters of servers on localhost ports: election, writes that survive the loss of
+// the leader, writes the leader loses the lead over, and adding and removing a member.
+mod common;
+
+use std::time::Duration;
+use rdb::{OperateRequest, OperateResult, RorError, Value};
+use common::{Server, free_port, get, info, put, wait_for};
+
+const ELECTION: Duration = Duration::from_secs(20);
+
+struct Member {
+    server: Server,
+    raft: String,
+    alive: bool,
+}
+
+impl Member {
+    fn new(peers: &[String], raft: &str) -> Self {
+        let peers: Vec<String> = peers.iter().map(|p| format!("\"{}\"", p)).collect();
+        let config = format!(
+            "role = \"raft\"\nraft_address = \"{0}\"\nraft_peers = [{1}]\n",
+            raft,
+            peers.join(", ")
+        );
+        let mut server = Server::new(&config);
+        server.start();
+        Member { server, raft: raft.to_string(), alive: true }
+    }
+
+    // Whether this member sees itself as the leader.
+    fn is_leader(&self) -> bool {
+        if !self.alive {
+            return false;
+        }
+        let mut client = self.server.client("default.data");
+        let cluster = info(&mut client).cluster.expect("a member of a cluster has cluster info");
+        cluster.members.iter().any(|m| m.address == self.raft && m.state == "leader")
+    }
+
+    fn kill(&mut self) {
+        self.server.kill();
+        self.alive = false;
+    }
+}
+
+fn raft_addresses(n: usize) -> Vec<String> {
+    (0..n).map(|_| format!("127.0.0.1:{}", free_port())).collect()
+}
+
+fn cluster(n: usize) -> Vec<Member> {
+    let peers = raft_addresses(n);
+    peers.iter().map(|raft| Member::new(&peers, raft)).collect()
+}
+
+// The index of the leader, once the living members have elected exactly one.
+fn wait_for_leader(members: &[Member]) -> usize {
+    let mut leader = None;
+    wait_for("a leader", ELECTION, || {
+        let leaders: Vec<usize> = (0..members.len()).filter(|i| members[*i].is_leader()).collect();
+        leader = leaders.first().copied();
+        leaders.len() == 1
+    });
+    leader.unwrap()
+}
+
+#[test]
+fn acknowledged_writes_survive_the_leader() {
+    let mut members = cluster(3);
+    let leader = wait_for_leader(&members);
+    let term = info(&mut members[leader].server.client("default.data")).cluster.unwrap().term;
+
+    let mut client = members[leader].server.client("default.data");
+    put(&mut client, "name", "makiror");
+    // A write sent to a follower is redirected to the leader.
+    let follower = (leader + 1) % members.len();
+    put(&mut members[follower].server.client("default.data"), "age", "14");
+    for member in &members {
+        let mut client = member.server.client("default.data");
+        wait_for("the writes on every member", Duration::from_secs(10), || {
+            get(&mut client, "name").is_some() && get(&mut client, "age").is_some()
+        });
+    }
+
+    members[leader].kill();
+    let new_leader = wait_for_leader(&members);
+    assert_ne!(new_leader, leader);
+    let mut client = members[new_leader].server.client("default.data");
+    assert!(info(&mut client).cluster.unwrap().term > term);
+    assert_eq!(get(&mut client, "name").as_deref(), Some("makiror"));
+    assert_eq!(get(&mut client, "age").as_deref(), Some("14"));
+
+    // Two of three members are a majority, the cluster still takes writes.
+    put(&mut client, "city", "Tokyo");
+    let other = (0..members.len()).find(|i| *i != leader && *i != new_leader).unwrap();
+    let mut client = members[other].server.client("default.data");
+    wait_for("the write on the other member", Duration::from_secs(10), || get(&mut client, "city").is_some());
+}
+
+#[test]
+fn a_write_the_leader_cannot_commit_is_not_sent_again() {
+    let mut members = cluster(3);
+    let leader = wait_for_leader(&members);
+    let mut client = members[leader].server.client("default.data");
+    put(&mut client, "before", "1");
+
+    // Without its followers the leader appends the write but cannot commit it, and steps
+    // down. The client must not send it again to whichever server leads next.
+    for (i, member) in members.iter_mut().enumerate() {
+        if i != leader {
+            member.kill();
+        }
+    }
+    let request = OperateRequest::Add { key: "after".to_string(), value: Value::String("2".to_string()) };
+    match client.operate(request) {
+        Err(RorError::OutcomeUnknown(OperateRequest::Add { key, .. })) => assert_eq!(key, "after"),
+        other => panic!("the write returned {:?}", other),
+    }
+    assert_eq!(get(&mut client, "before").as_deref(), Some("1"));
+}
+
+#[test]
+fn add_and_remove_a_member() {
+    let members = cluster(3);
+    let leader = wait_for_leader(&members);
+    let mut client = members[leader].server.client("default.data");
+    put(&mut client, "before", "1");
+
+    // A member without peers waits to be added to a cluster.
+    let raft = raft_addresses(1).remove(0);
+    let joining = Member::new(&[], &raft);
+    match client.operate(OperateRequest::AddMember { address: raft.clone() }).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("cluster add failed with {:?}", other),
+    }
+    put(&mut client, "after", "2");
+    let mut joined = joining.server.client("default.data");
+    wait_for("the snapshot and the log on the new member", Duration::from_secs(20), || {
+        get(&mut joined, "before").is_some() && get(&mut joined, "after").is_some()
+    });
+    let cluster = info(&mut client).cluster.unwrap();
+    assert_eq!(cluster.members.len(), 4);
+    assert!(cluster.members.iter().any(|m| m.address == raft));
+
+    match client.operate(OperateRequest::RemoveMember { address: raft.clone() }).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("cluster delete failed with {:?}", other),
+    }
+    wait_for("the removal on every member", Duration::from_secs(10), || {
+        members.iter().all(|m| {
+            let cluster = info(&mut m.server.client("default.data")).cluster.unwrap();
+            cluster.members.len() == 3 && cluster.members.iter().all(|m| m.address != raft)
+        })
+    });
+    put(&mut client, "removed", "3");
+    assert_eq!(get(&mut joined, "removed"), None);
+}
@@ -0,0 +1,213 @@
+// The Redis listener, driven over raw RESP with a client written here so the test does
+// not depend on how some Redis client library encodes commands.
+mod common;
+
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+use common::{Server, free_port};
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+struct Resp {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Resp {
+    fn connect(port: u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Resp { reader: BufReader::new(stream.try_clone().unwrap()), writer: stream }
+    }
+
+    fn send(&mut self, args: &[&str]) {
+        let mut out = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            out.extend_from_slice(arg.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        self.writer.write_all(&out).unwrap();
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        self.send(args);
+        self.read()
+    }
+
+    fn read(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"), "incomplete reply {:?}", line);
+        let (kind, rest) = line.trim_end().split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_string()),
+            "-" => Reply::Error(rest.to_string()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                length => {
+                    let mut data = vec![0; length as usize + 2];
+                    self.reader.read_exact(&mut data).unwrap();
+                    data.truncate(length as usize);
+                    Reply::Bulk(Some(data))
+                }
+            },
+            "*" => Reply::Array((0..rest.parse::<usize>().unwrap()).map(|_| self.read()).collect()),
+            _ => panic!("unexpected reply {:?}", line),
+        }
+    }
+
+    fn login(&mut self) {
+        assert_eq!(self.call(&["AUTH", "root", "123456"]), ok());
+    }
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_string())
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.as_bytes().to_vec()))
+}
+
+fn is_error(reply: &Reply, prefix: &str) -> bool {
+    matches!(reply, Reply::Error(e) if e.starts_with(prefix))
+}
+
+fn start() -> (Server, u16) {
+    let port = free_port();
+    let mut server = Server::new(&format!("redis_address = \"127.0.0.1:{}\"\n", port));
+    server.start_on(port);
+    (server, port)
+}
+
+#[test]
+fn auth_set_get_incr() {
+    let (_server, port) = start();
+    let mut redis = Resp::connect(port);
+
+    assert!(is_error(&redis.call(&["GET", "a"]), "NOAUTH"));
+    assert!(is_error(&redis.call(&["AUTH", "root", "wrong"]), "WRONGPASS"));
+    redis.login();
+
+    assert_eq!(redis.call(&["SET", "name", "makiror"]), ok());
+    assert_eq!(redis.call(&["GET", "name"]), bulk("makiror"));
+    assert_eq!(redis.call(&["GET", "missing"]), Reply::Bulk(None));
+
+    assert_eq!(redis.call(&["INCR", "counter"]), Reply::Integer(1));
+    assert_eq!(redis.call(&["INCR", "counter"]), Reply::Integer(2));
+    assert_eq!(redis.call(&["GET", "counter"]), bulk("2"));
+    assert!(is_error(&redis.call(&["INCR", "name"]), "ERR"));
+
+    assert_eq!(redis.call(&["DEL", "name", "missing"]), Reply::Integer(1));
+    assert_eq!(redis.call(&["EXISTS", "name"]), Reply::Integer(0));
+
+    // Written by another connection, so they went to the datafile and not a cache.
+    let mut other = Resp::connect(port);
+    other.login();
+    assert_eq!(other.call(&["GET", "counter"]), bulk("2"));
+}
+
+#[test]
+fn expire() {
+    let (_server, port) = start();
+    let mut redis = Resp::connect(port);
+    redis.login();
+
+    assert_eq!(redis.call(&["EXPIRE", "missing", "1"]), Reply::Integer(0));
+    assert_eq!(redis.call(&["SET", "session", "abc"]), ok());
+    assert_eq!(redis.call(&["SET", "kept", "abc"]), ok());
+    assert_eq!(redis.call(&["EXPIRE", "session", "1"]), Reply::Integer(1));
+    assert_eq!(redis.call(&["GET", "session"]), bulk("abc"));
+
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(redis.call(&["GET", "session"]), Reply::Bulk(None));
+    assert_eq!(redis.call(&["EXISTS", "session"]), Reply::Integer(0));
+    assert_eq!(redis.call(&["GET", "kept"]), bulk("abc"));
+
+    // A time that is not positive deletes the key right away.
+    assert_eq!(redis.call(&["EXPIRE", "kept", "0"]), Reply::Integer(1));
+    assert_eq!(redis.call(&["GET", "kept"]), Reply::Bulk(None));
+}
+
+#[test]
+fn scan_visits_every_key_once() {
+    let (_server, port) = start();
+    let mut redis = Resp::connect(port);
+    redis.login();
+
+    let mut expected = BTreeSet::new();
+    for i in 0..25 {
+        let key = format!("user:{:02}", i);
+        redis.send(&["SET", &key, "x"]);
+        expected.insert(key);
+    }
+    for i in 0..5 {
+        redis.send(&["SET", &format!("order:{}", i), "x"]);
+    }
+    // The SETs were pipelined, their replies come in order.
+    for _ in 0..30 {
+        assert_eq!(redis.read(), ok());
+    }
+
+    let mut seen = Vec::new();
+    let mut cursor = "0".to_string();
+    loop {
+        let reply = redis.call(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "7"]);
+        let (next, keys) = match reply {
+            Reply::Array(mut page) if page.len() == 2 => {
+                let keys = page.pop().unwrap();
+                (page.pop().unwrap(), keys)
+            }
+            other => panic!("unexpected SCAN reply {:?}", other),
+        };
+        match keys {
+            Reply::Array(keys) => seen.extend(keys.into_iter().map(|k| match k {
+                Reply::Bulk(Some(k)) => String::from_utf8(k).unwrap(),
+                other => panic!("unexpected key {:?}", other),
+            })),
+            other => panic!("unexpected SCAN keys {:?}", other),
+        }
+        cursor = match next {
+            Reply::Bulk(Some(c)) => String::from_utf8(c).unwrap(),
+            other => panic!("unexpected cursor {:?}", other),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(seen, expected.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn inline_commands_and_malformed_requests() {
+    let (_server, port) = start();
+    let mut redis = Resp::connect(port);
+    redis.writer.write_all(b"AUTH root 123456\r\nPING\r\n").unwrap();
+    assert_eq!(redis.read(), ok());
+    assert_eq!(redis.read(), Reply::Simple("PONG".to_string()));
+
+    // An argument count over the limit is refused before anything is allocated for it.
+    let mut huge = Resp::connect(port);
+    huge.writer.write_all(b"*2000000\r\n").unwrap();
+    assert!(is_error(&huge.read(), "ERR"));
+
+    // A large count the client never fills in does not stop the server.
+    let mut partial = Resp::connect(port);
+    partial.writer.write_all(b"*1000000\r\n$3\r\nGET\r\n").unwrap();
+    drop(partial);
+    assert_eq!(redis.call(&["PING"]), Reply::Simple("PONG".to_string()));
+}
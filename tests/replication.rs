@@ -0,0 +1,98 @@
+// A leader and a follower on localhost ports: the first copy of a datafile, streaming of
+// later writes, refusal of writes on the follower and its progress in server info.
+mod common;
+
+use std::time::Duration;
+use rdb::{OperateRequest, OperateResult};
+use common::{Server, get, info, put, wait_for};
+
+const COPIED: Duration = Duration::from_secs(20);
+
+fn follower_of(leader: &Server) -> Server {
+    let mut follower = Server::new(&format!(
+        "role = \"follower\"\nleader_address = \"{}\"\nleader_user = \"root@123456\"\n",
+        leader.address()
+    ));
+    follower.start();
+    follower
+}
+
+#[test]
+fn follower_copies_and_streams_the_leader() {
+    let mut leader = Server::new("");
+    leader.start();
+    let mut writer = leader.client("default.data");
+    for i in 0..50 {
+        put(&mut writer, &format!("key{:02}", i), &i.to_string());
+    }
+    // Deleted before the follower starts, the copy must not bring it back.
+    put(&mut writer, "gone", "x");
+    match writer.operate(OperateRequest::Delete { key: "gone".to_string() }).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("delete failed with {:?}", other),
+    }
+
+    let follower = follower_of(&leader);
+    let mut reader = follower.client("default.data");
+    wait_for("the first copy", COPIED, || get(&mut reader, "key49").is_some());
+    for i in 0..50 {
+        assert_eq!(get(&mut reader, &format!("key{:02}", i)), Some(i.to_string()));
+    }
+    assert_eq!(get(&mut reader, "gone"), None);
+
+    // Writes after the copy are streamed.
+    put(&mut writer, "later", "1");
+    put(&mut writer, "key00", "changed");
+    match writer.operate(OperateRequest::Delete { key: "key01".to_string() }).unwrap() {
+        OperateResult::Success => (),
+        other => panic!("delete failed with {:?}", other),
+    }
+    wait_for("the streamed writes", COPIED, || get(&mut reader, "later").is_some());
+    assert_eq!(get(&mut reader, "key00").as_deref(), Some("changed"));
+    wait_for("the streamed delete", COPIED, || get(&mut reader, "key01").is_none());
+}
+
+#[test]
+fn follower_refuses_writes() {
+    let mut leader = Server::new("");
+    leader.start();
+    put(&mut leader.client("default.data"), "name", "makiror");
+    let follower = follower_of(&leader);
+    let mut client = follower.client("default.data");
+    wait_for("the first copy", COPIED, || get(&mut client, "name").is_some());
+
+    let add = OperateRequest::Add { key: "name".to_string(), value: rdb::Value::Null };
+    assert!(matches!(client.operate(add).unwrap(), OperateResult::ReadOnly));
+    let delete = OperateRequest::Delete { key: "name".to_string() };
+    assert!(matches!(client.operate(delete).unwrap(), OperateResult::ReadOnly));
+    assert_eq!(get(&mut client, "name").as_deref(), Some("makiror"));
+}
+
+#[test]
+fn server_info_shows_the_lag() {
+    let mut leader = Server::new("");
+    leader.start();
+    let mut writer = leader.client("default.data");
+    put(&mut writer, "name", "makiror");
+    let follower = follower_of(&leader);
+    let mut client = follower.client("default.data");
+    assert_eq!(info(&mut writer).role, "leader");
+
+    wait_for("a follower that caught up", COPIED, || {
+        let info = info(&mut client);
+        assert_eq!(info.role, "follower");
+        info.replication.iter().any(|r| {
+            r.database == "default.data" && r.state == "streaming" && r.offset > 0 && r.offset == r.leader_offset && r.lag.is_some()
+        })
+    });
+
+    // The leader sends its position every second, so the follower learns of a write
+    // and then catches up with it.
+    let before = info(&mut client).replication.into_iter().find(|r| r.database == "default.data").unwrap();
+    put(&mut writer, "age", "14");
+    wait_for("the follower to catch up again", COPIED, || {
+        let now = info(&mut client).replication.into_iter().find(|r| r.database == "default.data").unwrap();
+        now.offset > before.offset && now.offset == now.leader_offset && now.lag.is_some_and(|l| l < 5)
+    });
+    assert_eq!(get(&mut client, "age").as_deref(), Some("14"));
+}
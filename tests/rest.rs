@@ -0,0 +1,170 @@
+// The REST gateway over plain HTTP: logging in, permissions, batches, paging and Raft
+// followers.
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+use base64::{Engine as _, engine::general_purpose};
+use common::{Server, free_port, get, info, put, wait_for, PASSWORD, USER};
+
+const ELECTION: Duration = Duration::from_secs(20);
+
+// A server with the gateway on a port of its own. Logging in creates default.data.
+fn gateway(config: &str) -> (Server, u16) {
+    let rest = free_port();
+    let mut server = Server::new(&format!("rest_address = \"127.0.0.1:{0}\"\n{1}", rest, config));
+    server.start();
+    server.client("default.data");
+    (server, rest)
+}
+
+fn basic(user: &str, password: &str) -> String {
+    format!("Basic {}", general_purpose::STANDARD.encode(format!("{0}:{1}", user, password)))
+}
+
+// Sends one request and returns the status and body of the response, which ends when
+// the gateway closes the connection.
+fn http(port: u16, method: &str, path: &str, authorization: Option<&str>, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut request = format!("{0} {1} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {2}\r\n", method, path, body.len());
+    if let Some(a) = authorization {
+        request += &format!("Authorization: {}\r\n", a);
+    }
+    request += "\r\n";
+    request += body;
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split(' ').nth(1).and_then(|s| s.parse().ok()).expect("an HTTP status line");
+    let body = response.split_once("\r\n\r\n").map(|(_, b)| b.to_string()).unwrap_or_default();
+    (status, body)
+}
+
+fn json(body: &str) -> serde_json::Value {
+    serde_json::from_str(body).unwrap()
+}
+
+#[test]
+fn requests_without_valid_credentials_get_401() {
+    let (server, rest) = gateway("rest_token_ttl = 1\n");
+    put(&mut server.client("default.data"), "created", "1");
+    let path = "/db/default.data/keys/created";
+    assert_eq!(http(rest, "GET", path, None, "").0, 401);
+    assert_eq!(http(rest, "GET", path, Some(&basic(USER, "wrong")), "").0, 401);
+    assert_eq!(http(rest, "GET", path, Some(&basic("nobody", PASSWORD)), "").0, 401);
+    assert_eq!(http(rest, "GET", path, Some("Bearer 0123"), "").0, 401);
+    assert_eq!(http(rest, "POST", "/auth/token", Some(&basic(USER, "wrong")), "").0, 401);
+    assert_eq!(http(rest, "GET", path, Some(&basic(USER, PASSWORD)), "").0, 200);
+
+    let (status, body) = http(rest, "POST", "/auth/token", Some(&basic(USER, PASSWORD)), "");
+    assert_eq!(status, 200);
+    let bearer = format!("Bearer {}", json(&body)["token"].as_str().unwrap());
+    assert_eq!(http(rest, "GET", path, Some(&bearer), "").0, 200);
+    thread::sleep(Duration::from_millis(1500));
+    let (status, body) = http(rest, "GET", path, Some(&bearer), "");
+    assert_eq!(status, 401);
+    assert!(body.contains("expired"), "{}", body);
+}
+
+#[test]
+fn writes_above_the_level_of_the_user_get_403() {
+    let (server, rest) = gateway("");
+    put(&mut server.client("default.data"), "created", "1");
+    server.set_users(&[(USER, PASSWORD, "3"), ("reader", "secret", "0")]);
+    let reader = basic("reader", "secret");
+    assert_eq!(http(rest, "PUT", "/db/default.data/keys/k", Some(&reader), "{\"Int32\":1}").0, 403);
+    assert_eq!(http(rest, "DELETE", "/db/default.data/keys/created", Some(&reader), "").0, 403);
+    let batch = r#"{"operations":[{"op":"put","key":"k","value":"Null"}]}"#;
+    let (status, body) = http(rest, "POST", "/db/default.data/batch", Some(&reader), batch);
+    assert_eq!(status, 200);
+    assert_eq!(json(&body)["results"][0]["status"], 403);
+    assert_eq!(http(rest, "GET", "/db/default.data/keys/k", Some(&basic(USER, PASSWORD)), "").0, 404);
+}
+
+#[test]
+fn batch_results_follow_the_operations() {
+    let (_server, rest) = gateway("");
+    let batch = r#"{"operations":[
+        {"op":"get","key":"age"},
+        {"op":"put","key":"age","value":{"Int32":14}},
+        {"op":"get","key":"age"},
+        {"op":"put","key":"age","value":{"Int64":15}},
+        {"op":"get","key":"age"},
+        {"op":"delete","key":"age"},
+        {"op":"delete","key":"age"}
+    ]}"#;
+    let (status, body) = http(rest, "POST", "/db/default.data/batch", Some(&basic(USER, PASSWORD)), batch);
+    assert_eq!(status, 200);
+    let results = json(&body)["results"].clone();
+    let statuses: Vec<u64> = results.as_array().unwrap().iter().map(|r| r["status"].as_u64().unwrap()).collect();
+    assert_eq!(statuses, [404, 204, 200, 204, 200, 204, 404]);
+    assert_eq!(results[2]["value"], json(r#"{"Int32":14}"#));
+    assert_eq!(results[4]["value"], json(r#"{"Int64":15}"#));
+    assert_eq!(results[6]["error"], "Key not found");
+}
+
+#[test]
+fn keys_are_paged_through_with_the_cursor() {
+    let (server, rest) = gateway("");
+    let mut client = server.client("default.data");
+    for i in 0..7 {
+        put(&mut client, &format!("page:{}", i), "v");
+    }
+    let auth = basic(USER, PASSWORD);
+    let mut keys = Vec::new();
+    let mut pages = 0;
+    let mut path = "/db/default.data/keys?prefix=page%3A&limit=3".to_string();
+    loop {
+        let (status, body) = http(rest, "GET", &path, Some(&auth), "");
+        assert_eq!(status, 200, "{}", body);
+        let page = json(&body);
+        pages += 1;
+        keys.extend(page["items"].as_array().unwrap().iter().map(|i| i["key"].as_str().unwrap().to_string()));
+        match page["next_cursor"].as_str() {
+            Some(cursor) => path = format!("/db/default.data/keys?prefix=page%3A&limit=3&cursor={}", cursor.replace(':', "%3A")),
+            None => break,
+        }
+    }
+    assert_eq!(pages, 3);
+    assert_eq!(keys, (0..7).map(|i| format!("page:{}", i)).collect::<Vec<_>>());
+}
+
+#[test]
+fn writes_on_a_raft_follower_get_421() {
+    let raft: Vec<String> = (0..2).map(|_| format!("127.0.0.1:{}", free_port())).collect();
+    let peers = format!("[\"{0}\", \"{1}\"]", raft[0], raft[1]);
+    let members: Vec<(Server, u16)> = raft
+        .iter()
+        .map(|address| gateway(&format!("role = \"raft\"\nraft_address = \"{0}\"\nraft_peers = {1}\n", address, peers)))
+        .collect();
+    let mut follower = None;
+    wait_for("a leader", ELECTION, || {
+        let states: Vec<bool> = members.iter().map(|(s, _)| info(&mut s.client("default.data")).role == "leader").collect();
+        follower = states.iter().position(|leader| !leader);
+        states.iter().filter(|leader| **leader).count() == 1
+    });
+    let (server, rest) = &members[follower.unwrap()];
+    // The client follows the redirect to the leader.
+    put(&mut server.client("default.data"), "created", "1");
+    let mut client = server.client("default.data");
+    wait_for("the write on the follower", Duration::from_secs(10), || get(&mut client, "created").is_some());
+    let auth = basic(USER, PASSWORD);
+    let (status, body) = http(*rest, "PUT", "/db/default.data/keys/k", Some(&auth), "{\"Int32\":1}");
+    assert_eq!(status, 421, "{}", body);
+    // Reads are still served there.
+    assert_eq!(http(*rest, "GET", "/db/default.data/keys/created", Some(&auth), "").0, 200);
+}
+
+#[test]
+fn requests_over_the_rate_limit_get_429() {
+    let (_server, rest) = gateway("rate_limit = 1\nrate_burst = 2\n");
+    let auth = basic(USER, PASSWORD);
+    let statuses: Vec<u16> = (0..4).map(|_| http(rest, "GET", "/db/default.data/keys/k", Some(&auth), "").0).collect();
+    assert_eq!(&statuses[..2], [404, 404]);
+    assert!(statuses[2..].contains(&429), "{:?}", statuses);
+}
@@ -0,0 +1,128 @@
+// Shutting down: clients are told, requests that wait are answered, and the writes
+// made before are on disk when the server is started again.
+mod common;
+
+use std::{io::{Read, Write}, net::TcpStream, process::Command, time::Duration};
+use rdb::{Client, OperateRequest, OperateResult};
+use common::{Server, clients, get, put, raw_connect, read_result, request_frame, wait_for, USER, PASSWORD};
+
+// Reads the Shutdown sent to `stream` with `id`, after which the server hangs up.
+fn told_to_leave(stream: &mut TcpStream, id: u64) {
+    let (reply_id, result) = read_result(stream);
+    assert_eq!(reply_id, id);
+    assert!(matches!(result, OperateResult::Shutdown), "{:?}", result);
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+fn waiting_clients(port: u16) -> (TcpStream, TcpStream, TcpStream) {
+    let mut watcher = raw_connect(port, "default.data");
+    watcher.write_all(&request_frame(5, &OperateRequest::Watch { key: "w".to_string(), timeout: 60 })).unwrap();
+    let mut subscriber = raw_connect(port, "default.data");
+    subscriber.write_all(&request_frame(6, &OperateRequest::Subscribe { from_offset: None, prefix: None })).unwrap();
+    let idle = raw_connect(port, "default.data");
+    (watcher, subscriber, idle)
+}
+
+#[test]
+fn a_shutdown_answers_every_client_and_keeps_the_writes() {
+    let mut server = Server::new("shutdown_timeout = 5\n");
+    server.set_users(&[(USER, PASSWORD, "3"), ("writer", "654321", "1")]);
+    server.start();
+    let mut admin = server.client("default.data");
+    for i in 0..100 {
+        put(&mut admin, &format!("key{}", i), &i.to_string());
+    }
+    let (mut watcher, mut subscriber, mut idle) = waiting_clients(server.port);
+    wait_for("the watch and the stream", Duration::from_secs(5), || {
+        let commands: Vec<Option<String>> = clients(&mut admin).into_iter().map(|c| c.last_command).collect();
+        commands.contains(&Some("watch".to_string())) && commands.contains(&Some("subscribe".to_string()))
+    });
+
+    let mut writer = Client::connect("127.0.0.1".to_string(), server.port.to_string(), "writer".to_string(), "654321".to_string(), "default.data".to_string()).unwrap();
+    assert!(matches!(writer.operate(OperateRequest::Shutdown).unwrap(), OperateResult::PermissionDenied));
+    assert!(matches!(admin.operate(OperateRequest::Shutdown).unwrap(), OperateResult::Success));
+    told_to_leave(&mut watcher, 5);
+    told_to_leave(&mut subscriber, 6);
+    told_to_leave(&mut idle, 0);
+    assert!(server.wait_exit(Duration::from_secs(10)).success());
+    assert!(server.log().contains("Server stopped"));
+    assert!(TcpStream::connect(server.address()).is_err());
+
+    server.start();
+    let mut client = server.client("default.data");
+    for i in 0..100 {
+        assert_eq!(get(&mut client, &format!("key{}", i)), Some(i.to_string()));
+    }
+}
+
+#[test]
+fn requests_under_way_are_answered_and_kept() {
+    let mut server = Server::new("shutdown_timeout = 5\n");
+    server.start();
+    let mut writer = raw_connect(server.port, "default.data");
+    let mut admin = server.client("default.data");
+    let mut frames = Vec::new();
+    for i in 0..200u64 {
+        let request = OperateRequest::Add { key: format!("key{:03}", i), value: rdb::Value::Int64(i as i64) };
+        frames.extend(request_frame(i + 10, &request));
+    }
+    writer.write_all(&frames).unwrap();
+    assert!(matches!(admin.operate(OperateRequest::Shutdown).unwrap(), OperateResult::Success));
+
+    // Replies come in order: the requests that ran succeeded, those the server had read
+    // but not started get Shutdown, and the ones it never read get no reply and did
+    // not run either.
+    let mut replies = Vec::new();
+    writer.read_to_end(&mut replies).unwrap();
+    let mut replies = replies.as_slice();
+    let (mut done, mut answered) = (0, 0);
+    while !replies.is_empty() {
+        let len = u32::from_be_bytes(replies[4..8].try_into().unwrap()) as usize;
+        let id = u64::from_be_bytes(replies[8..16].try_into().unwrap());
+        let result: OperateResult = bincode::deserialize(&replies[16..8 + len]).unwrap();
+        assert_eq!(id, answered + 10);
+        match result {
+            OperateResult::Success if done == answered => done += 1,
+            OperateResult::Shutdown => (),
+            other => panic!("request {0} got {1:?} after {2} succeeded", id, other, done),
+        }
+        answered += 1;
+        replies = &replies[8 + len..];
+    }
+    assert!(server.wait_exit(Duration::from_secs(10)).success());
+
+    server.start();
+    let mut client = server.client("default.data");
+    let request = OperateRequest::Scan { prefix: String::new(), cursor: None, limit: 0 };
+    match client.operate(request).unwrap() {
+        OperateResult::Entries { items, .. } => assert_eq!(items.len() as u64, done),
+        other => panic!("scan failed: {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn a_signal_shuts_the_server_down() {
+    let mut server = Server::new("");
+    server.start();
+    let mut client = server.client("default.data");
+    put(&mut client, "key", "value");
+    let (mut watcher, mut subscriber, mut idle) = waiting_clients(server.port);
+    wait_for("the watch and the stream", Duration::from_secs(5), || {
+        clients(&mut client).iter().filter(|c| c.last_command.is_some()).count() == 3
+    });
+
+    let status = Command::new("kill").args(["-TERM", &server.pid().to_string()]).status().unwrap();
+    assert!(status.success());
+    told_to_leave(&mut watcher, 5);
+    told_to_leave(&mut subscriber, 6);
+    told_to_leave(&mut idle, 0);
+    assert!(server.wait_exit(Duration::from_secs(10)).success());
+    assert!(server.log().contains("signal 15"));
+
+    server.start();
+    let mut client = server.client("default.data");
+    assert_eq!(get(&mut client, "key"), Some("value".to_string()));
+}
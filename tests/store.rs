@@ -0,0 +1,312 @@
+// The datafile store on its own, without a server: paging, dumps, and verifying and
+// repairing damaged datafiles.
+mod common;
+
+use std::{fs, process::Command};
+use rdb::{DataStore, DumpFormat, Value, dump, repair, restore, verify};
+use common::temp_dir;
+
+fn keys(page: &[(String, Value)]) -> Vec<&str> {
+    page.iter().map(|(k, _)| k.as_str()).collect()
+}
+
+#[test]
+fn scan_pages_end_at_the_last_key() {
+    let dir = temp_dir();
+    let mut db = DataStore::open(&dir.path("scan.data")).unwrap();
+    for key in ["f", "b", "d", "a", "e", "c"] {
+        db.add(key.to_string(), Value::String(key.to_uppercase())).unwrap();
+    }
+
+    let (page, next) = db.scan("", None, 3).unwrap();
+    assert_eq!(keys(&page), ["a", "b", "c"]);
+    assert_eq!(page[0].1, Value::String("A".to_string()));
+    assert_eq!(next.as_deref(), Some("c"));
+    // The second page ends exactly at the last key, and says there is no third.
+    let (page, next) = db.scan("", next.as_deref(), 3).unwrap();
+    assert_eq!(keys(&page), ["d", "e", "f"]);
+    assert_eq!(next, None);
+    let (page, next) = db.scan("", Some("f"), 3).unwrap();
+    assert!(page.is_empty() && next.is_none());
+}
+
+#[test]
+fn scan_continues_after_keys_deleted_between_pages() {
+    let dir = temp_dir();
+    let mut db = DataStore::open(&dir.path("scan.data")).unwrap();
+    for i in 0..10 {
+        db.add(format!("key{}", i), Value::Int32(i)).unwrap();
+    }
+    let (page, next) = db.scan("", None, 4).unwrap();
+    assert_eq!(keys(&page), ["key0", "key1", "key2", "key3"]);
+
+    // Neither the key the cursor names nor the keys after it need to exist any more.
+    db.delete("key3".to_string()).unwrap();
+    db.delete("key4".to_string()).unwrap();
+    db.add("key35".to_string(), Value::Int32(35)).unwrap();
+    let (page, next) = db.scan("", next.as_deref(), 4).unwrap();
+    assert_eq!(keys(&page), ["key35", "key5", "key6", "key7"]);
+    let (page, next) = db.scan("", next.as_deref(), 4).unwrap();
+    assert_eq!(keys(&page), ["key8", "key9"]);
+    assert_eq!(next, None);
+}
+
+#[test]
+fn scan_returns_only_keys_with_the_prefix() {
+    let dir = temp_dir();
+    let mut db = DataStore::open(&dir.path("scan.data")).unwrap();
+    for key in ["a", "user:1", "user:2", "user:3", "user", "users", "v"] {
+        db.add(key.to_string(), Value::Null).unwrap();
+    }
+
+    let (page, next) = db.scan("user:", None, 2).unwrap();
+    assert_eq!(keys(&page), ["user:1", "user:2"]);
+    let (page, next) = db.scan("user:", next.as_deref(), 2).unwrap();
+    assert_eq!(keys(&page), ["user:3"]);
+    assert_eq!(next, None);
+
+    // A cursor before the prefix starts at it, one after it finds nothing.
+    let (page, _) = db.scan("user:", Some("a"), 10).unwrap();
+    assert_eq!(keys(&page), ["user:1", "user:2", "user:3"]);
+    let (page, next) = db.scan("user:", Some("user;"), 10).unwrap();
+    assert!(page.is_empty() && next.is_none());
+    let (page, _) = db.scan("user", None, 10).unwrap();
+    assert_eq!(keys(&page), ["user", "user:1", "user:2", "user:3", "users"]);
+
+    // The index stays sorted when the datafile is opened again.
+    drop(db);
+    let mut db = DataStore::open(&dir.path("scan.data")).unwrap();
+    let (page, _) = db.scan("", None, 10).unwrap();
+    assert_eq!(keys(&page), ["a", "user", "user:1", "user:2", "user:3", "users", "v"]);
+}
+
+#[test]
+fn subscriptions_replay_from_their_offset_and_end_with_unsubscribe() {
+    let dir = temp_dir();
+    let mut db = DataStore::open(&dir.path("subscribe.data")).unwrap();
+    for key in ["a", "b", "c"] {
+        db.add(key.to_string(), Value::Int32(0)).unwrap();
+    }
+    let replayed = db.subscribe(Some(0)).unwrap();
+    let offsets: Vec<(u64, u64)> = replayed.try_iter().map(|e| (e.offset, e.next_offset)).collect();
+    assert_eq!(offsets.len(), 3);
+
+    // Resuming at the second entry replays only what follows it.
+    let receiver = db.subscribe(Some(offsets[0].1)).unwrap();
+    assert_eq!(receiver.try_iter().map(|e| e.key).collect::<Vec<_>>(), ["b", "c"]);
+    assert!(db.subscribe(Some(offsets[0].1 + 1)).is_err());
+    assert!(db.subscribe(Some(offsets[2].1 + 1)).is_err());
+
+    // An unsubscribed handler is gone at once, not at the next write.
+    let id = db.subscribe_with(None, |_| true).unwrap();
+    assert_eq!(db.subscriber_count(), 3);
+    db.unsubscribe(id);
+    assert_eq!(db.subscriber_count(), 2);
+}
+
+// Every variant, with the values that are easy to get wrong: the same number as Int32
+// and Int64 and as Float32 and Float64, a Char next to a one letter String, floats JSON
+// has no numbers for, and text that needs quoting in CSV.
+fn every_value() -> Vec<(String, Value)> {
+    vec![
+        ("null".to_string(), Value::Null),
+        ("bool".to_string(), Value::Bool(true)),
+        ("int32".to_string(), Value::Int32(-7)),
+        ("int64".to_string(), Value::Int64(-7)),
+        ("int64 max".to_string(), Value::Int64(i64::MAX)),
+        ("float32".to_string(), Value::Float32(0.1)),
+        ("float64".to_string(), Value::Float64(0.1)),
+        ("float32 nan".to_string(), Value::Float32(f32::NAN)),
+        ("float64 inf".to_string(), Value::Float64(f64::INFINITY)),
+        ("float64 -inf".to_string(), Value::Float64(f64::NEG_INFINITY)),
+        ("char".to_string(), Value::Char('x')),
+        ("string".to_string(), Value::String("x".to_string())),
+        ("empty".to_string(), Value::String(String::new())),
+        ("a,b".to_string(), Value::String("one, two".to_string())),
+        ("\"quoted\"".to_string(), Value::String("say \"hi\"".to_string())),
+        ("lines\r\n".to_string(), Value::String("first\nsecond\r\n".to_string())),
+        (" padded ".to_string(), Value::String("  ".to_string())),
+        ("comma char".to_string(), Value::Char(',')),
+    ]
+}
+
+// Dumps `values` in `format` and restores the dump into a new datafile, then returns
+// every entry of it. Values are compared by their Debug output, which tells the
+// variants apart and shows NaN as equal to itself.
+fn round_trip(values: &[(String, Value)], format: DumpFormat) -> Vec<(String, String)> {
+    let dir = temp_dir();
+    let mut db = DataStore::open(&dir.path("source.data")).unwrap();
+    for (key, value) in values {
+        db.add(key.clone(), value.clone()).unwrap();
+    }
+    let mut out = Vec::new();
+    assert_eq!(dump(&mut db, format, &mut out).unwrap(), values.len());
+
+    let mut copy = DataStore::open(&dir.path("copy.data")).unwrap();
+    assert_eq!(restore(&mut copy, format, &mut out.as_slice()).unwrap(), values.len());
+    let (entries, next) = copy.scan("", None, 10_000).unwrap();
+    assert_eq!(next, None);
+    entries.into_iter().map(|(k, v)| (k, format!("{:?}", v))).collect()
+}
+
+fn sorted_debug(values: &[(String, Value)]) -> Vec<(String, String)> {
+    let mut expected: Vec<_> = values.iter().map(|(k, v)| (k.clone(), format!("{:?}", v))).collect();
+    expected.sort();
+    expected
+}
+
+#[test]
+fn json_dumps_restore_every_value() {
+    let mut values = every_value();
+    values.push(("array".to_string(), Value::Array(Box::new(vec![
+        Value::Int32(1),
+        Value::Float64(f64::NAN),
+        Value::Array(Box::new(vec![Value::Char('c'), Value::Float32(f32::NEG_INFINITY)])),
+    ]))));
+    assert_eq!(round_trip(&values, DumpFormat::Json), sorted_debug(&values));
+}
+
+#[test]
+fn csv_dumps_restore_every_value() {
+    let values = every_value();
+    assert_eq!(round_trip(&values, DumpFormat::Csv), sorted_debug(&values));
+
+    // CSV has no arrays, such a dump is refused rather than written in part.
+    let dir = temp_dir();
+    let mut db = DataStore::open(&dir.path("array.data")).unwrap();
+    db.add("array".to_string(), Value::Array(Box::new(vec![Value::Null]))).unwrap();
+    assert!(dump(&mut db, DumpFormat::Csv, &mut Vec::new()).is_err());
+}
+
+#[test]
+fn dumps_span_several_pages() {
+    let values: Vec<_> = (0..2500).map(|i| (format!("key{:05}", i), Value::Int32(i))).collect();
+    assert_eq!(round_trip(&values, DumpFormat::Json), sorted_debug(&values));
+}
+
+// Writes `keys` as Int32 values and returns the datafile with the offset every record
+// starts at and the offset it ends at.
+fn datafile(dir: &common::TempDir, keys: &[&str]) -> (String, Vec<u64>) {
+    let path = dir.path("damaged.data");
+    let mut db = DataStore::open(&path).unwrap();
+    let mut offsets = vec![0];
+    for (i, key) in keys.iter().enumerate() {
+        db.add(key.to_string(), Value::Int32(i as i32)).unwrap();
+        db.flush().unwrap();
+        offsets.push(fs::metadata(&path).unwrap().len());
+    }
+    drop(db);
+    let _ = fs::remove_file(format!("{}.hint", path));
+    (path, offsets)
+}
+
+// Checks that the output of `repair` verifies clean and opens with `expected` in it.
+fn check_repaired(dir: &common::TempDir, expected: &[(&str, i32)]) {
+    let output = dir.path("repaired.data");
+    let report = verify(&output).unwrap();
+    assert!(report.is_clean(), "{}", report);
+    let mut db = DataStore::open(&output).unwrap();
+    assert_eq!(db.key_count(), expected.len());
+    for (key, value) in expected {
+        assert_eq!(db.get(key.to_string()).unwrap(), Value::Int32(*value));
+    }
+}
+
+#[test]
+fn repair_drops_a_truncated_tail() {
+    let dir = temp_dir();
+    let (path, offsets) = datafile(&dir, &["a", "b", "c"]);
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(offsets[3] - 3).unwrap();
+
+    let report = verify(&path).unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(report.live_keys, 2);
+    assert_eq!(report.truncated_tail, Some((offsets[2], offsets[3] - 3 - offsets[2])));
+    assert!(report.bad_regions.is_empty() && report.orphan_tombstones.is_empty());
+
+    let repaired = repair(&path, &dir.path("repaired.data")).unwrap();
+    assert_eq!(repaired.kept, 2);
+    assert_eq!(repaired.dropped_bytes, offsets[3] - 3 - offsets[2]);
+    check_repaired(&dir, &[("a", 0), ("b", 1)]);
+}
+
+#[test]
+fn repair_drops_a_corrupt_record_in_the_middle() {
+    let dir = temp_dir();
+    let (path, offsets) = datafile(&dir, &["a", "b", "c"]);
+    // The value of "b" ends with its Int32, and starts with the variant, which becomes
+    // one Value does not have.
+    let mut data = fs::read(&path).unwrap();
+    let value = offsets[2] as usize - 8;
+    data[value..value + 4].copy_from_slice(&[0xff; 4]);
+    fs::write(&path, &data).unwrap();
+
+    let report = verify(&path).unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(report.bad_regions.len(), 1);
+    assert_eq!((report.bad_regions[0].offset, report.bad_regions[0].len), (offsets[1], offsets[2] - offsets[1]));
+    assert!(report.truncated_tail.is_none() && !report.is_clean());
+
+    let repaired = repair(&path, &dir.path("repaired.data")).unwrap();
+    assert_eq!(repaired.kept, 2);
+    assert_eq!(repaired.dropped_bytes, offsets[2] - offsets[1]);
+    check_repaired(&dir, &[("a", 0), ("c", 2)]);
+}
+
+#[test]
+fn repair_drops_orphan_tombstones() {
+    let dir = temp_dir();
+    let (path, offsets) = datafile(&dir, &["a", "b"]);
+    let mut db = DataStore::open(&path).unwrap();
+    db.delete("b".to_string()).unwrap();
+    drop(db);
+    let _ = fs::remove_file(format!("{}.hint", path));
+    // Without the record that added "b" its tombstone deletes nothing.
+    let mut data = fs::read(&path).unwrap();
+    data.drain(offsets[1] as usize..offsets[2] as usize);
+    fs::write(&path, &data).unwrap();
+
+    let report = verify(&path).unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(report.live_keys, 1);
+    assert_eq!(report.orphan_tombstones, [(offsets[1], "b".to_string())]);
+    assert!(report.bad_regions.is_empty() && report.truncated_tail.is_none());
+
+    let repaired = repair(&path, &dir.path("repaired.data")).unwrap();
+    assert_eq!(repaired.kept, 1);
+    assert_eq!(repaired.dropped_bytes, data.len() as u64 - offsets[1]);
+    check_repaired(&dir, &[("a", 0)]);
+}
+
+// Runs the rdb binary and returns its exit status and what it printed on stdout and stderr.
+fn rdb(args: &[&str]) -> (Option<i32>, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rdb")).args(args).output().unwrap();
+    let text = |bytes: Vec<u8>| String::from_utf8(bytes).unwrap();
+    (output.status.code(), text(output.stdout), text(output.stderr))
+}
+
+#[test]
+fn failed_subcommands_exit_with_an_error() {
+    let dir = temp_dir();
+    let missing = dir.path("missing.data");
+    for args in [vec!["verify", &missing], vec!["repair", &missing], vec!["dump", &missing, "--format", "xml"]] {
+        let (status, stdout, stderr) = rdb(&args);
+        assert_eq!(status, Some(1), "{:?}", args);
+        assert!(stdout.is_empty() && stderr.starts_with("Unable to"), "{0:?} printed {1:?} and {2:?}", args, stdout, stderr);
+    }
+    let input = dir.path("dump.json");
+    fs::write(&input, "not json\n").unwrap();
+    let (status, _, stderr) = rdb(&["restore", &dir.path("restored.data"), "-i", &input]);
+    assert_eq!(status, Some(1));
+    assert!(stderr.contains("line 1"), "{}", stderr);
+
+    // A damaged datafile fails verify, a clean one passes.
+    let (path, offsets) = datafile(&dir, &["a", "b"]);
+    assert_eq!(rdb(&["verify", &path]).0, Some(0));
+    fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(offsets[2] - 1).unwrap();
+    let (status, stdout, _) = rdb(&["verify", &path]);
+    assert_eq!(status, Some(1));
+    assert!(stdout.contains("Truncated tail"), "{}", stdout);
+    assert_eq!(rdb(&["repair", &path]).0, Some(0));
+}
@@ -0,0 +1,164 @@
+// TLS with certificates generated for each test: logins over TLS, servers signed by a CA
+// the client does not trust, and client certificates, as a follower presents them to
+// its leader.
+mod common;
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, SanType};
+use rdb::{Client, TlsOptions};
+use common::{PASSWORD, Server, USER, get, put, wait_for};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// A CA and the files of the certificates it signed, removed when dropped.
+struct Pki {
+    dir: PathBuf,
+    ca: Certificate,
+}
+
+// The paths of a certificate and its key.
+struct Issued {
+    cert: String,
+    key: String,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "rdb-tls-{0}-{1}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "rdb test CA");
+        let ca = Certificate::from_params(params).unwrap();
+        fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+        Pki { dir, ca }
+    }
+
+    fn ca(&self) -> String {
+        self.dir.join("ca.pem").to_string_lossy().to_string()
+    }
+
+    // A certificate for 127.0.0.1, which servers and clients both use.
+    fn issue(&self, name: &str) -> Issued {
+        let mut params = CertificateParams::new(Vec::new());
+        params.subject_alt_names.push(SanType::IpAddress("127.0.0.1".parse().unwrap()));
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = Certificate::from_params(params).unwrap();
+        let (cert_path, key_path) = (self.dir.join(format!("{}.pem", name)), self.dir.join(format!("{}.key", name)));
+        fs::write(&cert_path, cert.serialize_pem_with_signer(&self.ca).unwrap()).unwrap();
+        fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        Issued {
+            cert: cert_path.to_string_lossy().to_string(),
+            key: key_path.to_string_lossy().to_string(),
+        }
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn tls_server(issued: &Issued, client_ca: Option<&str>) -> Server {
+    let mut server = Server::new(&format!(
+        "tls_cert = \"{0}\"\ntls_key = \"{1}\"\ntls_client_ca = \"{2}\"\n",
+        issued.cert,
+        issued.key,
+        client_ca.unwrap_or("")
+    ));
+    server.start();
+    server
+}
+
+fn connect(server: &Server, tls: &TlsOptions) -> Client {
+    let mut client = Client::connect_tls(
+        "127.0.0.1".to_string(),
+        server.port.to_string(),
+        USER.to_string(),
+        PASSWORD.to_string(),
+        "default.data".to_string(),
+        tls,
+    )
+    .unwrap();
+    client.set_timeout(Some(Duration::from_secs(10))).unwrap();
+    client
+}
+
+fn refused(server: &Server, tls: &TlsOptions) -> bool {
+    let login = Client::connect_tls(
+        "127.0.0.1".to_string(),
+        server.port.to_string(),
+        USER.to_string(),
+        PASSWORD.to_string(),
+        "default.data".to_string(),
+        tls,
+    );
+    // A refused client certificate may only show on the first read after the handshake.
+    match login {
+        Ok(mut client) => client.ping().is_err(),
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn login_over_tls() {
+    let pki = Pki::new();
+    let server = tls_server(&pki.issue("server"), None);
+    let mut client = connect(&server, &TlsOptions::new(pki.ca()));
+    assert!(client.is_tls());
+    put(&mut client, "name", "makiror");
+    assert_eq!(get(&mut client, "name").as_deref(), Some("makiror"));
+}
+
+#[test]
+fn untrusted_ca_is_refused() {
+    let pki = Pki::new();
+    let server = tls_server(&pki.issue("server"), None);
+    let other = Pki::new();
+    assert!(refused(&server, &TlsOptions::new(other.ca())));
+    // And the server still serves clients that trust it.
+    connect(&server, &TlsOptions::new(pki.ca()));
+}
+
+#[test]
+fn client_certificates() {
+    let pki = Pki::new();
+    let ca = pki.ca();
+    let leader = tls_server(&pki.issue("leader"), Some(&ca));
+
+    assert!(refused(&leader, &TlsOptions::new(ca.clone())));
+    let other = Pki::new();
+    let stranger = other.issue("stranger");
+    assert!(refused(&leader, &TlsOptions::new(ca.clone()).with_client_cert(stranger.cert, stranger.key)));
+
+    let client_cert = pki.issue("client");
+    let tls = TlsOptions::new(ca.clone()).with_client_cert(client_cert.cert, client_cert.key);
+    let mut client = connect(&leader, &tls);
+    put(&mut client, "name", "makiror");
+
+    // A follower logs in to its leader with its own certificate as client certificate.
+    let follower_cert = pki.issue("follower");
+    let mut follower = Server::new(&format!(
+        "role = \"follower\"\nleader_address = \"{0}\"\nleader_user = \"root@123456\"\nleader_tls_ca = \"{1}\"\n\
+         tls_cert = \"{2}\"\ntls_key = \"{3}\"\n",
+        leader.address(),
+        ca,
+        follower_cert.cert,
+        follower_cert.key
+    ));
+    follower.start();
+    let mut reader = connect(&follower, &TlsOptions::new(ca.clone()));
+    wait_for("the follower to copy over TLS", Duration::from_secs(20), || get(&mut reader, "name").is_some());
+    put(&mut client, "age", "14");
+    wait_for("the follower to stream over TLS", Duration::from_secs(20), || get(&mut reader, "age").is_some());
+}
@@ -0,0 +1,62 @@
+// Watches and Redis expiry times on a datafile named in more than one way, which must
+// all be the same datafile.
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+use rdb::{OperateRequest, OperateResult, Value};
+use common::{Server, free_port, get, put, wait_for};
+
+fn watch(server: &Server, db: &str, key: &str) -> thread::JoinHandle<Option<Option<Value>>> {
+    let mut client = server.client(db);
+    let key = key.to_string();
+    thread::spawn(move || match client.operate(OperateRequest::Watch { key, timeout: 20 }).unwrap() {
+        OperateResult::KeyChanged { value, .. } => Some(value),
+        _ => None,
+    })
+}
+
+#[test]
+fn watch_fires_for_writes_through_another_path() {
+    let mut server = Server::new("");
+    server.start();
+    let watcher = watch(&server, "./watched.data", "name");
+    // Give the watch time to be registered.
+    thread::sleep(Duration::from_millis(300));
+    put(&mut server.client("watched.data"), "name", "makiror");
+    assert_eq!(watcher.join().unwrap(), Some(Some(Value::String("makiror".to_string()))));
+}
+
+#[test]
+fn redis_expiry_reaches_a_datafile_opened_under_another_path() {
+    let port = free_port();
+    let mut server = Server::new(&format!(
+        "redis_address = \"127.0.0.1:{}\"\nredis_databases = [\"./expiring.data\"]\n",
+        port
+    ));
+    server.start_on(port);
+    // Opened first under its plain name, so that is the name the server keeps it under.
+    let mut client = server.client("expiring.data");
+    put(&mut client, "session", "old");
+    let watcher = watch(&server, "expiring.data", "session");
+    thread::sleep(Duration::from_millis(300));
+
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    writer.write_all(b"AUTH root 123456\r\nSET session abc EX 1\r\n").unwrap();
+    for _ in 0..2 {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "+OK\r\n");
+    }
+    assert_eq!(watcher.join().unwrap(), Some(Some(Value::String("abc".to_string()))));
+
+    // Nobody reads the key, the expired key is deleted by the server on its own.
+    wait_for("the expired key to be deleted", Duration::from_secs(10), || get(&mut client, "session").is_none());
+}